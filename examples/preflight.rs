@@ -0,0 +1,11 @@
+use std::time::Duration;
+
+pub fn main() {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters("libmdns=debug");
+    builder.init();
+
+    let report = libmdns::Responder::preflight(Duration::from_millis(500));
+    println!("{:#?}", report);
+    println!("mDNS works: {}", report.mdns_works());
+}
@@ -0,0 +1,10 @@
+pub fn main() {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters("libmdns=debug");
+    builder.init();
+
+    let mut responder = libmdns::ResponderBuilder::new().build_sync().unwrap();
+    let _svc = responder.register("_http._tcp", "libmdns Web Server", 80, &["path=/"]);
+
+    responder.run()
+}
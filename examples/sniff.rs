@@ -0,0 +1,61 @@
+//! Print every mDNS packet on the network, like a minimal `tcpdump port 5353`.
+
+use libmdns::dns_parser::ResourceRecord;
+use std::time::SystemTime;
+
+fn print_records(section: &str, records: &[ResourceRecord]) {
+    for record in records {
+        println!(
+            "    {:<10} {} ttl={}{} {:?}",
+            section,
+            record.name,
+            record.ttl,
+            if record.cache_flush() { " flush" } else { "" },
+            record.data
+        );
+    }
+}
+
+pub fn main() {
+    let mut builder = env_logger::Builder::new();
+    builder.parse_filters("libmdns=debug");
+    builder.init();
+
+    let start = SystemTime::now();
+    for captured in libmdns::monitor().expect("couldn't listen on the mDNS port") {
+        let at = captured.at.duration_since(start).unwrap_or_default();
+        match captured.parse() {
+            Ok((packet, errors)) => {
+                let kind = if packet.header.query {
+                    "query"
+                } else {
+                    "response"
+                };
+                println!("+{:.3}s {} from {}", at.as_secs_f64(), kind, captured.from);
+                for question in &packet.questions {
+                    println!(
+                        "    question   {} {:?}{}",
+                        question.qname,
+                        question.qtype,
+                        if question.qu { " QU" } else { "" }
+                    );
+                }
+                print_records("answer", &packet.answers);
+                print_records("authority", &packet.nameservers);
+                print_records("additional", &packet.additional);
+                for err in errors {
+                    println!(
+                        "    skipped    {:?} #{}: {}",
+                        err.section, err.index, err.error
+                    );
+                }
+            }
+            Err(err) => println!(
+                "+{:.3}s unparseable from {}: {}",
+                at.as_secs_f64(),
+                captured.from,
+                err
+            ),
+        }
+    }
+}
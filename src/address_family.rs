@@ -1,41 +1,96 @@
 use super::MDNS_PORT;
+use crate::allow::{self, AllowedIp};
+use crate::error::{Error, IpFamily};
+use crate::netns::{self, NetworkNamespace};
 use if_addrs::{get_if_addrs, IfAddr};
+use log::warn;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use std::collections::HashSet;
 use std::io;
+#[cfg(any(feature = "responder", feature = "sync"))]
+use std::net::SocketAddrV6;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 
 pub enum Inet {}
 
 pub enum Inet6 {}
 
-pub trait AddressFamily {
+pub trait AddressFamily: Send + 'static {
     type Addr: Into<IpAddr>;
 
     const ANY_ADDR: Self::Addr;
     const MDNS_GROUP: Self::Addr;
 
     const DOMAIN: Domain;
+    const FAMILY: IpFamily;
 
-    fn join_multicast(socket: &Socket, multiaddr: &Self::Addr) -> io::Result<()>;
+    /// Try to join on every interface of this family, or only on those named in
+    /// `interfaces`, returning the outcome for each
+    fn join_each(
+        socket: &Socket,
+        multiaddr: &Self::Addr,
+        interfaces: &[String],
+    ) -> Result<Vec<(String, io::Result<()>)>, Error>;
+
+    /// Join on every interface of this family, or only on those named in `interfaces`,
+    /// failing only if none of them can be joined
+    fn join_multicast(
+        socket: &Socket,
+        multiaddr: &Self::Addr,
+        interfaces: &[String],
+    ) -> Result<(), Error> {
+        any_joined(Self::join_each(socket, multiaddr, interfaces)?)
+    }
 
     fn udp_socket() -> io::Result<Socket> {
         Socket::new(Self::DOMAIN, Type::DGRAM, Some(Protocol::UDP))
     }
 
-    fn bind() -> io::Result<UdpSocket> {
-        let addr: SockAddr = SocketAddr::new(Self::ANY_ADDR.into(), MDNS_PORT).into();
-        let socket = Self::udp_socket()?;
-        socket.set_reuse_address(true)?;
-        socket.set_nonblocking(true)?;
+    /// A non-blocking socket bound to the mDNS port, not yet joined to the group
+    fn bind_unjoined() -> Result<Socket, Error> {
+        let addr = SocketAddr::new(Self::ANY_ADDR.into(), MDNS_PORT);
+        let bind_failed = |source| Error::BindFailed {
+            family: Self::FAMILY,
+            addr,
+            source,
+        };
+
+        let socket = Self::udp_socket().map_err(bind_failed)?;
+        socket.set_reuse_address(true).map_err(bind_failed)?;
+        socket.set_nonblocking(true).map_err(bind_failed)?;
 
         #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
-        socket.set_reuse_port(true)?;
+        socket.set_reuse_port(true).map_err(bind_failed)?;
 
-        socket.bind(&addr)?;
-        Self::join_multicast(&socket, &Self::MDNS_GROUP)?;
+        socket.bind(&SockAddr::from(addr)).map_err(bind_failed)?;
+        Ok(socket)
+    }
+
+    fn bind(interfaces: &[String]) -> Result<UdpSocket, Error> {
+        let socket = Self::bind_unjoined()?;
+        Self::join_multicast(&socket, &Self::MDNS_GROUP, interfaces)?;
         Ok(socket.into())
     }
+
+    /// Like `bind`, also returning the scope ids, i.e. interface indexes, to send a
+    /// copy of each multicast packet to, see `destinations`. Empty if the system
+    /// picks the interface itself.
+    fn bind_scoped(interfaces: &[String]) -> Result<(UdpSocket, Vec<u32>), Error> {
+        Ok((Self::bind(interfaces)?, Vec::new()))
+    }
+
+    /// `bind_scoped` in the network namespace `netns`, if any, on the interfaces
+    /// `join_interfaces` picks with the allow-list. The interfaces are looked up there
+    /// too.
+    fn bind_in(
+        netns: Option<&NetworkNamespace>,
+        interfaces: &[String],
+        allowed: &[AllowedIp],
+    ) -> Result<(UdpSocket, Vec<u32>), Error> {
+        netns::within(netns, || {
+            Self::bind_scoped(&allow::join_interfaces(allowed, interfaces, Self::FAMILY)?)
+        })
+    }
 }
 
 impl AddressFamily for Inet {
@@ -45,17 +100,23 @@ impl AddressFamily for Inet {
     const MDNS_GROUP: Self::Addr = Ipv4Addr::new(224, 0, 0, 251);
 
     const DOMAIN: Domain = Domain::IPV4;
+    const FAMILY: IpFamily = IpFamily::V4;
 
-    fn join_multicast(socket: &Socket, multiaddr: &Self::Addr) -> io::Result<()> {
-        let addrs = get_one_nonloopback_ipv4_addr_per_iface()?;
-        if addrs.is_empty() {
-            socket.join_multicast_v4(multiaddr, &Ipv4Addr::UNSPECIFIED)
+    fn join_each(
+        socket: &Socket,
+        multiaddr: &Self::Addr,
+        interfaces: &[String],
+    ) -> Result<Vec<(String, io::Result<()>)>, Error> {
+        let addrs = get_one_nonloopback_ipv4_addr_per_iface(interfaces)?;
+        if addrs.is_empty() && !interfaces.is_empty() {
+            Err(no_matching_interface(Self::FAMILY, interfaces))
+        } else if addrs.is_empty() {
+            let joined = socket.join_multicast_v4(multiaddr, &Ipv4Addr::UNSPECIFIED);
+            Ok(vec![(DEFAULT_IFACE.to_owned(), joined)])
         } else {
-            // TODO: If any join succeeds return success (log failures)
-            for ip in addrs {
-                socket.join_multicast_v4(multiaddr, &ip)?;
-            }
-            Ok(())
+            Ok(join_all(addrs, |ip| {
+                socket.join_multicast_v4(multiaddr, ip)
+            }))
         }
     }
 }
@@ -67,22 +128,120 @@ impl AddressFamily for Inet6 {
     const MDNS_GROUP: Self::Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
 
     const DOMAIN: Domain = Domain::IPV6;
+    const FAMILY: IpFamily = IpFamily::V6;
 
-    fn join_multicast(socket: &Socket, multiaddr: &Self::Addr) -> io::Result<()> {
-        let indexes = get_one_nonloopback_ipv6_index_per_iface()?;
-        if indexes.is_empty() {
-            socket.join_multicast_v6(multiaddr, 0)
+    fn join_each(
+        socket: &Socket,
+        multiaddr: &Self::Addr,
+        interfaces: &[String],
+    ) -> Result<Vec<(String, io::Result<()>)>, Error> {
+        let indexes = get_one_nonloopback_ipv6_index_per_iface(interfaces)?;
+        if indexes.is_empty() && !interfaces.is_empty() {
+            Err(no_matching_interface(Self::FAMILY, interfaces))
+        } else if indexes.is_empty() {
+            let joined = socket.join_multicast_v6(multiaddr, 0);
+            Ok(vec![(DEFAULT_IFACE.to_owned(), joined)])
         } else {
-            // TODO: If any join succeeds return success (log failures)
-            for ipv6_index in indexes {
-                socket.join_multicast_v6(multiaddr, ipv6_index)?;
+            Ok(join_all(indexes, |index| {
+                socket.join_multicast_v6(multiaddr, *index)
+            }))
+        }
+    }
+
+    // Windows sends to the group without a scope id on one interface of its choosing,
+    // which leaves the other adapters of a machine with several silent
+    fn bind_scoped(interfaces: &[String]) -> Result<(UdpSocket, Vec<u32>), Error> {
+        if !cfg!(windows) {
+            return Ok((Self::bind(interfaces)?, Vec::new()));
+        }
+        let socket = Self::bind_unjoined()?;
+        let indexes = get_one_nonloopback_ipv6_index_per_iface(interfaces)?;
+        if indexes.is_empty() {
+            Self::join_multicast(&socket, &Self::MDNS_GROUP, interfaces)?;
+            return Ok((socket.into(), Vec::new()));
+        }
+
+        let mut scopes = Vec::new();
+        let results = join_all(indexes, |&index| {
+            let joined = socket.join_multicast_v6(&Self::MDNS_GROUP, index);
+            if joined.is_ok() {
+                scopes.push(index);
+            }
+            joined
+        });
+        any_joined(results)?;
+        Ok((socket.into(), scopes))
+    }
+}
+
+/// Where to send a packet for `addr`: a copy for each of `scopes` if it is for an IPv6
+/// multicast group and there are any, otherwise just `addr`
+#[cfg(any(feature = "responder", feature = "sync"))]
+pub fn destinations(addr: SocketAddr, scopes: &[u32]) -> Vec<SocketAddr> {
+    match addr {
+        SocketAddr::V6(addr) if addr.ip().is_multicast() && !scopes.is_empty() => scopes
+            .iter()
+            .map(|&scope| SocketAddr::V6(SocketAddrV6::new(*addr.ip(), addr.port(), 0, scope)))
+            .collect(),
+        _ => vec![addr],
+    }
+}
+
+/// Names the interface chosen by the system, when there are no others to join on
+const DEFAULT_IFACE: &str = "default";
+
+fn no_matching_interface(family: IpFamily, interfaces: &[String]) -> Error {
+    Error::NoMatchingInterface {
+        family,
+        interfaces: interfaces.to_vec(),
+    }
+}
+
+fn join_all<I, F>(ifaces: Vec<(String, I)>, mut join: F) -> Vec<(String, io::Result<()>)>
+where
+    F: FnMut(&I) -> io::Result<()>,
+{
+    ifaces
+        .into_iter()
+        .map(|(name, iface)| {
+            let joined = join(&iface);
+            (name, joined)
+        })
+        .collect()
+}
+
+/// Succeeds as long as one interface was joined. Virtual interfaces often refuse,
+/// and shouldn't stop mDNS working on the others.
+fn any_joined(results: Vec<(String, io::Result<()>)>) -> Result<(), Error> {
+    let mut joined = false;
+    let mut first_error = None;
+    for (name, result) in results {
+        match result {
+            Ok(()) => joined = true,
+            Err(source) => {
+                warn!("couldn't join the mDNS group on {}: {}", name, source);
+                first_error.get_or_insert(Error::MulticastJoinFailed {
+                    iface: name,
+                    source,
+                });
             }
-            Ok(())
         }
     }
+
+    match first_error {
+        Some(err) if !joined => Err(err),
+        _ => Ok(()),
+    }
 }
 
-fn get_one_nonloopback_ipv6_index_per_iface() -> io::Result<Vec<u32>> {
+/// Whether `name` is one of the `interfaces` to use, an empty list allowing all
+fn is_selected(name: &str, interfaces: &[String]) -> bool {
+    interfaces.is_empty() || interfaces.iter().any(|iface| iface == name)
+}
+
+fn get_one_nonloopback_ipv6_index_per_iface(
+    interfaces: &[String],
+) -> io::Result<Vec<(String, u32)>> {
     // There may be multiple ip addresses on a single interface and we join multicast by interface.
     // Joining multicast on the same interface multiple times returns an error
     // so we filter duplicate interfaces.
@@ -90,11 +249,11 @@ fn get_one_nonloopback_ipv6_index_per_iface() -> io::Result<Vec<u32>> {
     Ok(get_if_addrs()?
         .into_iter()
         .filter_map(|iface| {
-            if iface.is_loopback() {
+            if iface.is_loopback() || !is_selected(&iface.name, interfaces) {
                 None
             } else if matches!(iface.addr, IfAddr::V6(_)) {
                 if collected_interfaces.insert(iface.name.clone()) {
-                    iface.index
+                    iface.index.map(|index| (iface.name, index))
                 } else {
                     None
                 }
@@ -105,7 +264,9 @@ fn get_one_nonloopback_ipv6_index_per_iface() -> io::Result<Vec<u32>> {
         .collect())
 }
 
-fn get_one_nonloopback_ipv4_addr_per_iface() -> io::Result<Vec<Ipv4Addr>> {
+fn get_one_nonloopback_ipv4_addr_per_iface(
+    interfaces: &[String],
+) -> io::Result<Vec<(String, Ipv4Addr)>> {
     // There may be multiple ip addresses on a single interface and we join multicast by interface.
     // Joining multicast on the same interface multiple times returns an error
     // so we filter duplicate interfaces.
@@ -113,11 +274,11 @@ fn get_one_nonloopback_ipv4_addr_per_iface() -> io::Result<Vec<Ipv4Addr>> {
     Ok(get_if_addrs()?
         .into_iter()
         .filter_map(|iface| {
-            if iface.is_loopback() {
+            if iface.is_loopback() || !is_selected(&iface.name, interfaces) {
                 None
             } else if let IpAddr::V4(ip) = iface.ip() {
                 if collected_interfaces.insert(iface.name.clone()) {
-                    Some(ip)
+                    Some((iface.name, ip))
                 } else {
                     None
                 }
@@ -127,3 +288,41 @@ fn get_one_nonloopback_ipv4_addr_per_iface() -> io::Result<Vec<Ipv4Addr>> {
         })
         .collect())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_any_joined() {
+        let refused = || io::Error::from(io::ErrorKind::AddrNotAvailable);
+        let ifaces = vec![("veth0".to_owned(), false), ("eth0".to_owned(), true)];
+        let results = join_all(ifaces, |&ok| if ok { Ok(()) } else { Err(refused()) });
+        assert!(any_joined(results).is_ok());
+
+        let ifaces = vec![("veth0".to_owned(), ()), ("veth1".to_owned(), ())];
+        match any_joined(join_all(ifaces, |_| Err(refused()))) {
+            Err(Error::MulticastJoinFailed { iface, .. }) => assert_eq!(iface, "veth0"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[cfg(any(feature = "responder", feature = "sync"))]
+    #[test]
+    fn test_destinations() {
+        let group: SocketAddr = "[ff02::fb]:5353".parse().unwrap();
+        assert_eq!(destinations(group, &[]), vec![group]);
+        assert_eq!(
+            destinations(group, &[3, 7]),
+            vec![
+                "[ff02::fb%3]:5353".parse::<SocketAddr>().unwrap(),
+                "[ff02::fb%7]:5353".parse().unwrap(),
+            ]
+        );
+
+        let unicast: SocketAddr = "[fe80::1%2]:5353".parse().unwrap();
+        assert_eq!(destinations(unicast, &[3, 7]), vec![unicast]);
+        let v4: SocketAddr = "224.0.0.251:5353".parse().unwrap();
+        assert_eq!(destinations(v4, &[3, 7]), vec![v4]);
+    }
+}
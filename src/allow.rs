@@ -0,0 +1,246 @@
+//! The allow-list of addresses a responder publishes, set with
+//! [`ResponderBuilder::allow_list`](crate::ResponderBuilder::allow_list).
+
+use crate::error::{Error, IpFamily};
+use if_addrs::get_if_addrs;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An entry of the allow-list of a responder, matching some of the addresses of the
+/// host. Parsed from an address (`192.168.1.7`), a prefix in CIDR notation
+/// (`192.168.1.0/24`, `fd00::/8`) or the name of a network interface (`eth0`).
+///
+/// Prefixes and interfaces keep matching as DHCP hands out other addresses, which
+/// exact addresses don't.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AllowedIp {
+    /// Exactly this address
+    Addr(IpAddr),
+    /// Any address within the prefix of this length
+    Prefix(IpAddr, u8),
+    /// Any address the network interface with this name has at the time
+    Interface(String),
+}
+
+impl AllowedIp {
+    /// Whether `ip` matches, `on_interface` telling whether it is an address of the
+    /// named interface
+    pub(crate) fn allows(&self, ip: IpAddr, on_interface: impl Fn(&str) -> bool) -> bool {
+        match *self {
+            AllowedIp::Addr(own) => own == ip,
+            AllowedIp::Prefix(prefix, len) => in_prefix(prefix, len, ip),
+            AllowedIp::Interface(ref name) => on_interface(name),
+        }
+    }
+
+    /// Whether the entry can match addresses of `family`
+    fn covers(&self, family: IpFamily) -> bool {
+        match *self {
+            AllowedIp::Addr(ip) | AllowedIp::Prefix(ip, _) => family_of(ip) == family,
+            AllowedIp::Interface(_) => true,
+        }
+    }
+}
+
+fn family_of(ip: IpAddr) -> IpFamily {
+    match ip {
+        IpAddr::V4(_) => IpFamily::V4,
+        IpAddr::V6(_) => IpFamily::V6,
+    }
+}
+
+impl From<IpAddr> for AllowedIp {
+    fn from(ip: IpAddr) -> AllowedIp {
+        AllowedIp::Addr(ip)
+    }
+}
+
+impl FromStr for AllowedIp {
+    type Err = Error;
+
+    fn from_str(entry: &str) -> Result<AllowedIp, Error> {
+        let invalid = || Error::InvalidAllowedIp(entry.to_owned());
+        if let Some((ip, len)) = entry.split_once('/') {
+            let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+            let len: u8 = len.parse().map_err(|_| invalid())?;
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            if len > max {
+                return Err(invalid());
+            }
+            Ok(AllowedIp::Prefix(ip, len))
+        } else if let Ok(ip) = entry.parse() {
+            Ok(AllowedIp::Addr(ip))
+        } else if entry.is_empty() {
+            Err(invalid())
+        } else {
+            Ok(AllowedIp::Interface(entry.to_owned()))
+        }
+    }
+}
+
+fn in_prefix(prefix: IpAddr, len: u8, ip: IpAddr) -> bool {
+    match (prefix, ip) {
+        (IpAddr::V4(prefix), IpAddr::V4(ip)) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(len.min(32)))
+                .unwrap_or(0);
+            u32::from(prefix) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(prefix), IpAddr::V6(ip)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(len.min(128)))
+                .unwrap_or(0);
+            u128::from(prefix) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Whether `ip`, an address of the interfaces `on_interface` tells of, may be
+/// published. An empty list allows every address.
+pub(crate) fn is_allowed(
+    allowed: &[AllowedIp],
+    ip: IpAddr,
+    on_interface: impl Fn(&str) -> bool,
+) -> bool {
+    allowed.is_empty() || allowed.iter().any(|entry| entry.allows(ip, &on_interface))
+}
+
+/// The interfaces to join the mDNS group of `family` on: those named in `interfaces`
+/// if any, otherwise those with an address of `family` on the allow-list. All
+/// interfaces if neither selects any, e.g. while the allowed addresses are still
+/// being assigned, or if the allow-list only has addresses of the other family.
+pub(crate) fn join_interfaces(
+    allowed: &[AllowedIp],
+    interfaces: &[String],
+    family: IpFamily,
+) -> io::Result<Vec<String>> {
+    let allowed: Vec<AllowedIp> = allowed
+        .iter()
+        .filter(|entry| entry.covers(family))
+        .cloned()
+        .collect();
+    if allowed.is_empty() || !interfaces.is_empty() {
+        return Ok(interfaces.to_vec());
+    }
+    let addrs = get_if_addrs()?
+        .into_iter()
+        .filter(|iface| family_of(iface.ip()) == family)
+        .map(|iface| (iface.ip(), iface.name));
+    Ok(select_interfaces(&allowed, addrs))
+}
+
+/// The names of the interfaces with an address on the allow-list, given the
+/// addresses of each interface
+fn select_interfaces(
+    allowed: &[AllowedIp],
+    addrs: impl IntoIterator<Item = (IpAddr, String)>,
+) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for (ip, iface) in addrs {
+        if ip.is_loopback() || names.contains(&iface) {
+            continue;
+        }
+        if is_allowed(allowed, ip, |name| name == iface) {
+            names.push(iface);
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "192.168.1.7".parse::<AllowedIp>().unwrap(),
+            AllowedIp::Addr(ip("192.168.1.7"))
+        );
+        assert_eq!(
+            "fd00::/8".parse::<AllowedIp>().unwrap(),
+            AllowedIp::Prefix(ip("fd00::"), 8)
+        );
+        assert_eq!(
+            "eth0".parse::<AllowedIp>().unwrap(),
+            AllowedIp::Interface("eth0".into())
+        );
+        for invalid in ["", "10.0.0.0/33", "eth0/24", "10.0.0.0/"] {
+            assert!(matches!(
+                invalid.parse::<AllowedIp>(),
+                Err(Error::InvalidAllowedIp(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_allows() {
+        let allowed: Vec<AllowedIp> = ["192.168.1.0/24", "fe80::/10", "wlan0"]
+            .iter()
+            .map(|entry| entry.parse().unwrap())
+            .collect();
+        let on_eth0 = |name: &str| name == "eth0";
+        assert!(is_allowed(&allowed, ip("192.168.1.200"), on_eth0));
+        assert!(is_allowed(&allowed, ip("fe80::1"), on_eth0));
+        assert!(!is_allowed(&allowed, ip("192.168.2.1"), on_eth0));
+        // Neither family matches a prefix of the other
+        assert!(!is_allowed(&allowed, ip("::ffff:192.168.1.1"), on_eth0));
+        assert!(is_allowed(&allowed, ip("10.0.0.1"), |name| name == "wlan0"));
+
+        assert!(is_allowed(&[], ip("10.0.0.1"), on_eth0));
+        let any = ["0.0.0.0/0".parse().unwrap()];
+        assert!(is_allowed(&any, ip("10.0.0.1"), on_eth0));
+        assert!(!is_allowed(&any, ip("::1"), on_eth0));
+    }
+
+    #[test]
+    fn test_join_interfaces() {
+        let allowed: Vec<AllowedIp> = ["192.168.1.0/24", "wlan0"]
+            .iter()
+            .map(|entry| entry.parse().unwrap())
+            .collect();
+        let addrs = || {
+            [
+                ("127.0.0.1", "lo"),
+                ("10.0.0.2", "eth0"),
+                ("192.168.1.7", "eth1"),
+                ("fe80::7", "eth1"),
+                ("fe80::8", "wlan0"),
+            ]
+            .iter()
+            .map(|&(addr, name)| (ip(addr), name.to_owned()))
+        };
+        assert_eq!(select_interfaces(&allowed, addrs()), ["eth1", "wlan0"]);
+        assert!(select_interfaces(&["::1".parse().unwrap()], addrs()).is_empty());
+
+        // Interfaces named explicitly win over the allow-list
+        let named = ["eth0".to_owned()];
+        for family in [IpFamily::V4, IpFamily::V6].iter().cloned() {
+            assert_eq!(join_interfaces(&allowed, &named, family).unwrap(), named);
+            assert_eq!(join_interfaces(&[], &named, family).unwrap(), named);
+            assert!(join_interfaces(&[], &[], family).unwrap().is_empty());
+        }
+
+        // Addresses of one family leave the other joining every interface
+        let v6_only = ["fd00::/8".parse().unwrap()];
+        assert!(join_interfaces(&v6_only, &[], IpFamily::V4)
+            .unwrap()
+            .is_empty());
+        assert!(AllowedIp::Interface("eth0".into()).covers(IpFamily::V4));
+        assert!(!v6_only[0].covers(IpFamily::V4));
+        assert!(v6_only[0].covers(IpFamily::V6));
+
+        // Exact addresses only filter the records, an allow-list picks the interfaces
+        let builder = crate::ResponderBuilder::new().allowed_ips(vec![ip("192.168.1.7")]);
+        assert!(builder.config.join_allow_list().is_empty());
+        let builder = builder.allow_list(allowed.clone());
+        assert_eq!(builder.config.join_allow_list(), &allowed[..]);
+    }
+}
@@ -0,0 +1,198 @@
+//! Pausing all sending after errors which sending more won't fix, such as a full
+//! socket buffer (ENOBUFS) or a firewall refusing the packets (EPERM), rather than
+//! failing and logging for every packet.
+
+use crate::config::ResponderConfig;
+use crate::error::IpFamily;
+use crate::events::SendFailure;
+use crate::metrics::Counters;
+use log::{debug, warn};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The first pause, doubled after each further failure
+const INITIAL_PAUSE: Duration = Duration::from_millis(10);
+
+const MAX_PAUSE: Duration = Duration::from_secs(2);
+
+/// A packet is dropped once sending it failed this many times
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Sends failing for this long are reported with a `SendFailure`
+const PERSISTENT_FAILURE: Duration = Duration::from_secs(10);
+
+#[cfg(unix)]
+const ENOBUFS: Option<i32> = Some(libc::ENOBUFS);
+#[cfg(windows)]
+const ENOBUFS: Option<i32> = Some(windows_sys::Win32::Networking::WinSock::WSAENOBUFS);
+#[cfg(not(any(unix, windows)))]
+const ENOBUFS: Option<i32> = None;
+
+/// Whether sending is better paused after `err`
+fn is_backoff_error(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::PermissionDenied
+        || (ENOBUFS.is_some() && err.raw_os_error() == ENOBUFS)
+}
+
+pub struct SendBackoff {
+    config: Arc<ResponderConfig>,
+    counters: Arc<Counters>,
+    pause: Duration,
+    paused_until: Option<Instant>,
+    /// failed attempts at sending the packet being retried
+    attempts: u32,
+    /// when the current run of failures started
+    failing_since: Option<Instant>,
+    /// packets dropped in the current run of failures
+    dropped: u64,
+    reported: bool,
+}
+
+impl SendBackoff {
+    pub fn new(config: &Arc<ResponderConfig>, counters: &Arc<Counters>) -> SendBackoff {
+        SendBackoff {
+            config: config.clone(),
+            counters: counters.clone(),
+            pause: INITIAL_PAUSE,
+            paused_until: None,
+            attempts: 0,
+            failing_since: None,
+            dropped: 0,
+            reported: false,
+        }
+    }
+
+    /// When sending may resume, if it is paused
+    pub fn paused_until(&self, now: Instant) -> Option<Instant> {
+        self.paused_until.filter(|&until| until > now)
+    }
+
+    /// A packet was sent, ending any run of failures
+    pub fn sent(&mut self) {
+        if let Some(since) = self.failing_since.take() {
+            debug!("sending works again after {:?}", since.elapsed());
+        }
+        self.pause = INITIAL_PAUSE;
+        self.paused_until = None;
+        self.attempts = 0;
+        self.dropped = 0;
+        self.reported = false;
+    }
+
+    /// Handle an error sending `packet` to `addr`. Returns the packet if it should be
+    /// tried again once the pause is over.
+    pub fn failed(
+        &mut self,
+        err: io::Error,
+        packet: Vec<u8>,
+        addr: SocketAddr,
+        now: Instant,
+    ) -> Option<(Vec<u8>, SocketAddr)> {
+        if !is_backoff_error(&err) {
+            warn!("error sending packet {:?}", err);
+            return None;
+        }
+        self.counters.send_error();
+        let since = match self.failing_since {
+            Some(since) => {
+                debug!("sending to {:?} failed again: {}", addr, err);
+                since
+            }
+            None => {
+                warn!("sending to {:?} failed, pausing: {}", addr, err);
+                *self.failing_since.insert(now)
+            }
+        };
+
+        self.paused_until = Some(now + self.pause);
+        self.pause = (self.pause * 2).min(MAX_PAUSE);
+        self.attempts += 1;
+        let retry = if self.attempts < MAX_ATTEMPTS {
+            Some((packet, addr))
+        } else {
+            debug!(
+                "dropping packet to {:?} after {} attempts",
+                addr, self.attempts
+            );
+            self.attempts = 0;
+            self.dropped += 1;
+            self.counters.send_dropped();
+            None
+        };
+
+        let failing_for = now.duration_since(since);
+        if !self.reported && failing_for >= PERSISTENT_FAILURE {
+            self.reported = true;
+            warn!("sending has been failing for {:?}: {}", failing_for, err);
+            if let Some(ref on_send_failure) = self.config.on_send_failure {
+                on_send_failure(SendFailure {
+                    family: match addr {
+                        SocketAddr::V4(_) => IpFamily::V4,
+                        SocketAddr::V6(_) => IpFamily::V6,
+                    },
+                    kind: err.kind(),
+                    failing_for,
+                    dropped: self.dropped,
+                });
+            }
+        }
+        retry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_send_backoff() {
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let reported = failures.clone();
+        let config = Arc::new(ResponderConfig {
+            on_send_failure: Some(Arc::new(move |failure: SendFailure| {
+                reported.lock().unwrap().push(failure)
+            })),
+            ..ResponderConfig::default()
+        });
+        let counters = Arc::new(Counters::new());
+        let mut backoff = SendBackoff::new(&config, &counters);
+        let addr: SocketAddr = "224.0.0.251:5353".parse().unwrap();
+        let denied = || io::Error::from(io::ErrorKind::PermissionDenied);
+
+        let start = Instant::now();
+        assert!(backoff.paused_until(start).is_none());
+        let mut now = start;
+        for attempt in 1..MAX_ATTEMPTS {
+            let retry = backoff.failed(denied(), vec![attempt as u8], addr, now);
+            assert_eq!(retry, Some((vec![attempt as u8], addr)));
+            let until = backoff.paused_until(now).unwrap();
+            assert_eq!(until - now, INITIAL_PAUSE * 2u32.pow(attempt - 1));
+            now = until;
+        }
+        assert!(backoff.failed(denied(), vec![], addr, now).is_none());
+        #[cfg(feature = "metrics-minimal")]
+        assert_eq!(counters.metrics().send_errors, u64::from(MAX_ATTEMPTS));
+        #[cfg(feature = "metrics-minimal")]
+        assert_eq!(counters.metrics().send_dropped, 1);
+        assert!(failures.lock().unwrap().is_empty());
+
+        now = start + PERSISTENT_FAILURE;
+        backoff.failed(denied(), vec![], addr, now);
+        backoff.failed(denied(), vec![], addr, now);
+        let failures = failures.lock().unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].family, IpFamily::V4);
+        assert_eq!(failures[0].dropped, 1);
+
+        backoff.sent();
+        assert!(backoff.paused_until(now).is_none());
+
+        // Other errors don't pause sending
+        let other = io::Error::from(io::ErrorKind::InvalidInput);
+        assert!(backoff.failed(other, vec![], addr, now).is_none());
+        assert!(backoff.paused_until(now).is_none());
+    }
+}
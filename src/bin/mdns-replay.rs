@@ -0,0 +1,154 @@
+//! Replay a pcap capture of mDNS traffic through the responder, printing the packets
+//! received along with what it would have sent.
+//!
+//! ```text
+//! mdns-replay [--hostname NAME] [--address IP]... [--service TYPE/NAME/PORT[/TXT]...]...
+//!             [--settle SECONDS] CAPTURE
+//! ```
+
+use libmdns::dns_parser::{Packet, ResourceRecord};
+use libmdns::replay::{self, Replay};
+use libmdns::{FixedHostData, ResponderBuilder, ServiceBuilder};
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+const USAGE: &str = "usage: mdns-replay [--hostname NAME] [--address IP]... \
+[--service TYPE/NAME/PORT[/TXT]...]... [--settle SECONDS] CAPTURE";
+
+struct Args {
+    hostname: String,
+    addresses: Vec<IpAddr>,
+    services: Vec<ServiceBuilder>,
+    settle: Duration,
+    capture: String,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        hostname: "replay".to_owned(),
+        addresses: Vec::new(),
+        services: Vec::new(),
+        settle: Duration::from_secs(10),
+        capture: String::new(),
+    };
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--hostname" => args.hostname = value()?,
+            "--address" => {
+                let value = value()?;
+                let ip = value
+                    .parse()
+                    .map_err(|_| format!("bad address {}", value))?;
+                args.addresses.push(ip);
+            }
+            "--service" => args.services.push(parse_service(&value()?)?),
+            "--settle" => {
+                let value = value()?;
+                let secs = value
+                    .parse()
+                    .map_err(|_| format!("bad duration {}", value))?;
+                args.settle = Duration::from_secs_f64(secs);
+            }
+            "-h" | "--help" => return Err(USAGE.to_owned()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if args.capture.is_empty() => args.capture = arg,
+            _ => return Err(USAGE.to_owned()),
+        }
+    }
+    if args.capture.is_empty() {
+        return Err(USAGE.to_owned());
+    }
+    Ok(args)
+}
+
+/// A service given as `_http._tcp/My Server/80/path=/`
+fn parse_service(service: &str) -> Result<ServiceBuilder, String> {
+    let mut parts = service.split('/');
+    let (svc_type, name, port) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(svc_type), Some(name), Some(port)) => (svc_type, name, port),
+        _ => return Err(format!("bad service {}, expected TYPE/NAME/PORT", service)),
+    };
+    let port = port
+        .parse()
+        .map_err(|_| format!("bad port in service {}", service))?;
+    let txt: Vec<&str> = parts.collect();
+    Ok(ServiceBuilder::new(svc_type, name, port).txt(&txt[..]))
+}
+
+fn print_records(section: &str, records: &[ResourceRecord]) {
+    for record in records {
+        println!(
+            "    {:<10} {} ttl={}{} {:?}",
+            section,
+            record.name,
+            record.ttl,
+            if record.cache_flush() { " flush" } else { "" },
+            record.data
+        );
+    }
+}
+
+pub fn main() {
+    let args = parse_args().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(2);
+    });
+    let trace = File::open(&args.capture)
+        .and_then(|file| replay::read_pcap(BufReader::new(file)))
+        .unwrap_or_else(|err| {
+            eprintln!("couldn't read {}: {}", args.capture, err);
+            process::exit(1);
+        });
+
+    let replay = Replay::new();
+    let host = FixedHostData::new(args.hostname, args.addresses);
+    let mut engine = ResponderBuilder::new()
+        .host_data(Arc::new(host))
+        // The capture was made on another network
+        .accept_off_link_queries(true)
+        .sequential_service_ids(true)
+        .build_engine()
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+    for service in args.services {
+        if let Err(err) = engine.try_register_service(service) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+
+    let sent = replay.run(&mut engine, &trace, args.settle);
+    let mut sent = sent.iter().peekable();
+    for received in &trace {
+        while let Some(sent) = sent.next_if(|sent| sent.at < received.at) {
+            print_packet("->", sent.at, sent.to, &sent.packet);
+        }
+        print_packet("<-", received.at, received.from, &received.packet);
+    }
+    for sent in sent {
+        print_packet("->", sent.at, sent.to, &sent.packet);
+    }
+}
+
+/// A packet received (`<-`) or sent (`->`) with its records
+fn print_packet(direction: &str, at: Duration, addr: SocketAddr, packet: &[u8]) {
+    println!("+{:.3}s {} {}", at.as_secs_f64(), direction, addr);
+    match Packet::parse(packet) {
+        Ok(packet) => {
+            for question in &packet.questions {
+                println!("    question   {} {:?}", question.qname, question.qtype);
+            }
+            print_records("answer", &packet.answers);
+            print_records("additional", &packet.additional);
+        }
+        Err(err) => println!("    unparseable: {}", err),
+    }
+}
@@ -0,0 +1,816 @@
+//! Browsing for the instances of a service type, by the continuous querying of RFC
+//! 6762 section 5.2, see [`Responder::browse`](crate::Responder::browse).
+
+use crate::budget::{HeapSize, Lru, MemoryBudget};
+#[cfg(any(test, feature = "responder"))]
+use crate::clock;
+use crate::dns_parser::{self, Builder, Name, NameKey, Packet, QueryClass, QueryType, RRData};
+use crate::error::IpFamily;
+use crate::services::check_name;
+use crate::Error;
+#[cfg(feature = "responder")]
+use crate::Shutdown;
+#[cfg(feature = "responder")]
+use futures_util::Stream;
+use log::debug;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(feature = "responder")]
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+#[cfg(feature = "responder")]
+use std::task::{Context, Poll};
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+/// The interval between the first two queries, doubling after each query after
+const FIRST_QUERY_INTERVAL: Duration = Duration::from_secs(1);
+/// The longest interval between queries by default, RFC 6762 section 5.2
+const DEFAULT_MAX_QUERY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long an instance has to answer again before it is reported removed by default
+const DEFAULT_RECONFIRM_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What to browse for and how, passed to [`Responder::browse`](crate::Responder::browse).
+#[derive(Clone, Debug)]
+pub struct BrowseBuilder {
+    #[cfg_attr(not(feature = "responder"), allow(dead_code))]
+    service_type: Name<'static>,
+    max_query_interval: Duration,
+    reconfirm_timeout: Duration,
+}
+
+impl BrowseBuilder {
+    /// Browse for the instances of `service_type`, e.g. `_http._tcp`. Fails if a
+    /// label of the type is longer than 63 bytes, or the name longer than 255.
+    pub fn new(service_type: &str) -> Result<BrowseBuilder, Error> {
+        let name = format!("{}.local", service_type);
+        let parsed = Name::from_str(name.clone()).map_err(|err| match err {
+            dns_parser::Error::LabelTooLong(label) => Error::LabelTooLong { name, label },
+            err => unreachable!("{}", err),
+        })?;
+        check_name(&parsed)?;
+        Ok(BrowseBuilder {
+            service_type: parsed,
+            max_query_interval: DEFAULT_MAX_QUERY_INTERVAL,
+            reconfirm_timeout: DEFAULT_RECONFIRM_TIMEOUT,
+        })
+    }
+
+    /// Set the longest interval between queries. The interval starts at a second and
+    /// doubles after each query up to this, the default being an hour. Instances are
+    /// queried for again at 80% of their TTL regardless.
+    ///
+    /// # Panics
+    ///
+    /// If `interval` is shorter than a second.
+    pub fn max_query_interval(mut self, interval: Duration) -> BrowseBuilder {
+        assert!(
+            interval >= FIRST_QUERY_INTERVAL,
+            "the query interval must be at least a second"
+        );
+        self.max_query_interval = interval;
+        self
+    }
+
+    /// Set how long an instance whose record expired, or which said goodbye, has to
+    /// answer a reconfirmation query before it is reported removed, by default a
+    /// second. `Duration::ZERO` reports it removed straight away, without querying.
+    pub fn reconfirm_timeout(mut self, timeout: Duration) -> BrowseBuilder {
+        self.reconfirm_timeout = timeout;
+        self
+    }
+}
+
+/// How a [`DiscoveredService`] changed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Discovery {
+    /// The instance was found
+    Added,
+    /// The SRV, TXT or address records of an instance found before changed
+    Updated,
+    /// The instance said goodbye, or its record expired, and it didn't answer the
+    /// reconfirmation query
+    Removed,
+}
+
+/// An instance of a browsed service type, yielded by [`Browse`] as it changes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DiscoveredService {
+    pub discovery: Discovery,
+    /// The full instance name, e.g. `web._http._tcp.local`
+    pub name: String,
+    /// The target of the SRV record, once seen
+    pub host: Option<String>,
+    /// The port of the SRV record, once seen
+    pub port: Option<u16>,
+    /// The TXT record in wire format, empty until seen
+    pub txt: Vec<u8>,
+    /// The addresses seen published for `host`
+    pub addresses: Vec<IpAddr>,
+    /// Where the instance was last heard from
+    pub source: SocketAddr,
+}
+
+#[derive(Clone)]
+struct Instance {
+    service: DiscoveredService,
+    received: Instant,
+    ttl: Duration,
+    /// whether the query at 80% of the TTL has been sent
+    refreshed: bool,
+    /// when the instance is reported removed unless it answers before
+    reconfirming: Option<Instant>,
+}
+
+impl Instance {
+    fn expires(&self) -> Instant {
+        self.received + self.ttl
+    }
+
+    fn refresh_at(&self) -> Instant {
+        self.received + self.ttl * 4 / 5
+    }
+}
+
+impl HeapSize for Instance {
+    fn heap_size(&self) -> usize {
+        let service = &self.service;
+        service.name.heap_size()
+            + service.host.as_ref().map_or(0, HeapSize::heap_size)
+            + service.txt.heap_size()
+            + service.addresses.heap_size()
+    }
+}
+
+/// When the next query is due on an address family, and the interval after it
+#[derive(Clone, Copy)]
+struct QuerySchedule {
+    next: Instant,
+    interval: Duration,
+}
+
+struct BrowseInner {
+    /// charged to the memory budget of the responder, the least recently heard from
+    /// forgotten without being reported removed once it is spent
+    instances: Lru<NameKey, Instance>,
+    events: VecDeque<DiscoveredService>,
+    waker: Option<Waker>,
+    /// indexed by `family_index`
+    queries: [QuerySchedule; 2],
+}
+
+/// The state of one browse, shared between the [`Browse`] and the handlers
+struct BrowseState {
+    service_type: Name<'static>,
+    key: NameKey,
+    max_query_interval: Duration,
+    reconfirm_timeout: Duration,
+    inner: Mutex<BrowseInner>,
+}
+
+fn family_index(family: IpFamily) -> usize {
+    match family {
+        IpFamily::V4 => 0,
+        IpFamily::V6 => 1,
+    }
+}
+
+impl BrowseState {
+    #[cfg(any(test, feature = "responder"))]
+    fn new(browse: BrowseBuilder, budget: &Arc<MemoryBudget>, now: Instant) -> BrowseState {
+        let service_type = browse.service_type;
+        let schedule = QuerySchedule {
+            next: now,
+            interval: FIRST_QUERY_INTERVAL,
+        };
+        BrowseState {
+            key: NameKey::new(&service_type),
+            service_type,
+            max_query_interval: browse.max_query_interval,
+            reconfirm_timeout: browse.reconfirm_timeout,
+            inner: Mutex::new(BrowseInner {
+                instances: Lru::new(budget),
+                events: VecDeque::new(),
+                waker: None,
+                queries: [schedule; 2],
+            }),
+        }
+    }
+
+    fn observe_response(&self, packet: &Packet, source: SocketAddr, now: Instant) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let before: Vec<(NameKey, DiscoveredService)> = inner
+            .instances
+            .iter()
+            .map(|(key, instance)| (key.clone(), instance.service.clone()))
+            .collect();
+        let records: Vec<_> = packet.answers.iter().chain(&packet.additional).collect();
+
+        // The PTR records first, which the others are only of interest for
+        for record in &records {
+            let target = match record.data {
+                RRData::PTR(ref target) if NameKey::new(&record.name) == self.key => target,
+                _ => continue,
+            };
+            let key = NameKey::new(target);
+            let known = inner.instances.contains_key(&key);
+            match (known, record.ttl) {
+                (true, 0) => {
+                    let deadline = now + self.reconfirm_timeout;
+                    let goodbye = inner.instances.update(&key, |instance| {
+                        if instance.reconfirming.is_some() {
+                            return false;
+                        }
+                        debug!("{} said goodbye", instance.service.name);
+                        instance.reconfirming = Some(deadline);
+                        true
+                    });
+                    if goodbye == Some(true) {
+                        self.query_now(&mut inner.queries, now);
+                    }
+                }
+                (false, 0) => (),
+                (known, ttl) => {
+                    if !known {
+                        let instance = Instance {
+                            service: DiscoveredService {
+                                discovery: Discovery::Added,
+                                name: target.to_string(),
+                                host: None,
+                                port: None,
+                                txt: Vec::new(),
+                                addresses: Vec::new(),
+                                source,
+                            },
+                            received: now,
+                            ttl: Duration::ZERO,
+                            refreshed: false,
+                            reconfirming: None,
+                        };
+                        inner.instances.insert(key.clone(), instance);
+                    }
+                    inner.instances.update(&key, |instance| {
+                        instance.received = now;
+                        instance.ttl = Duration::from_secs(ttl.into());
+                        instance.refreshed = false;
+                        instance.reconfirming = None;
+                        instance.service.source = source;
+                    });
+                }
+            }
+        }
+
+        // Records which go with a goodbye don't change what will be reported removed
+        for record in &records {
+            if record.ttl == 0 {
+                continue;
+            }
+            inner
+                .instances
+                .update(&NameKey::new(&record.name), |instance| {
+                    if instance.reconfirming.is_some() {
+                        return;
+                    }
+                    let service = &mut instance.service;
+                    match record.data {
+                        RRData::SRV {
+                            port, ref target, ..
+                        } => {
+                            let host = target.to_string();
+                            if service.host.as_ref() != Some(&host) {
+                                service.addresses.clear();
+                            }
+                            service.host = Some(host);
+                            service.port = Some(port);
+                        }
+                        RRData::TXT(txt) => service.txt = txt.to_vec(),
+                        _ => (),
+                    }
+                });
+        }
+
+        for record in &records {
+            let ip: IpAddr = match record.data {
+                RRData::A(ip) => ip.into(),
+                RRData::AAAA(ip) => ip.into(),
+                _ => continue,
+            };
+            let name = NameKey::new(&record.name);
+            let on_host: Vec<NameKey> = inner
+                .instances
+                .iter()
+                .filter(|(_, instance)| {
+                    instance.reconfirming.is_none()
+                        && instance.service.host.as_ref().is_some_and(|host| {
+                            NameKey::new(&Name::from_str(host.clone()).unwrap()) == name
+                        })
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in on_host {
+                inner.instances.update(&key, |instance| {
+                    let addresses = &mut instance.service.addresses;
+                    if record.ttl == 0 {
+                        addresses.retain(|&address| address != ip);
+                    } else if !addresses.contains(&ip) {
+                        addresses.push(ip);
+                    }
+                });
+            }
+        }
+
+        let mut changed = Vec::new();
+        for (key, instance) in inner.instances.iter() {
+            let previous = before.iter().find(|(other, _)| other == key);
+            let discovery = match previous {
+                None => Discovery::Added,
+                Some((_, previous)) if !same_records(previous, &instance.service) => {
+                    Discovery::Updated
+                }
+                Some(_) => continue,
+            };
+            changed.push(DiscoveredService {
+                discovery,
+                ..instance.service.clone()
+            });
+        }
+        self.report(&mut inner, changed);
+    }
+
+    /// Make the next query on every address family due now, keeping the interval
+    fn query_now(&self, queries: &mut [QuerySchedule; 2], now: Instant) {
+        for schedule in queries {
+            schedule.next = schedule.next.min(now);
+        }
+    }
+
+    /// Report the instances whose reconfirmation timed out, and start reconfirming or
+    /// refreshing those whose records are about to expire
+    fn expire(&self, inner: &mut BrowseInner, now: Instant) {
+        let mut removed = Vec::new();
+        let mut query = false;
+        inner.instances.retain(|_, instance| {
+            if instance.reconfirming.is_none() && instance.expires() <= now {
+                debug!("{} expired", instance.service.name);
+                instance.reconfirming = Some(now + self.reconfirm_timeout);
+                query = true;
+            } else if !instance.refreshed && instance.refresh_at() <= now {
+                instance.refreshed = true;
+                query = true;
+            }
+            if instance
+                .reconfirming
+                .is_some_and(|deadline| deadline <= now)
+            {
+                removed.push(DiscoveredService {
+                    discovery: Discovery::Removed,
+                    ..instance.service.clone()
+                });
+                return false;
+            }
+            true
+        });
+        if query {
+            self.query_now(&mut inner.queries, now);
+        }
+        self.report(inner, removed);
+    }
+
+    fn report(&self, inner: &mut BrowseInner, services: Vec<DiscoveredService>) {
+        if services.is_empty() {
+            return;
+        }
+        inner.events.extend(services);
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// The query to send on `family`, if one is due
+    fn due_query(&self, family: IpFamily, now: Instant) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        self.expire(&mut inner, now);
+        let schedule = &mut inner.queries[family_index(family)];
+        if schedule.next > now {
+            return None;
+        }
+        schedule.next = now + schedule.interval;
+        schedule.interval = (schedule.interval * 2).min(self.max_query_interval);
+
+        // Known answers spare those which will still be cached for a while from
+        // answering, RFC 6762 section 7.1
+        let mut builder = Builder::new_query(0, false)
+            .add_question(&self.service_type, QueryType::PTR, QueryClass::IN)
+            .move_to();
+        for instance in inner.instances.values() {
+            let remaining = instance.expires().saturating_duration_since(now);
+            if instance.reconfirming.is_some() || remaining < instance.ttl / 2 {
+                continue;
+            }
+            builder = builder.add_answer(
+                &self.service_type,
+                QueryClass::IN,
+                remaining.as_secs() as u32,
+                &RRData::PTR(Name::from_str(instance.service.name.clone()).unwrap()),
+            );
+        }
+        // A query too large for one packet goes without the known answers which
+        // didn't fit
+        Some(builder.build().unwrap_or_else(|truncated| truncated))
+    }
+
+    /// When `due_query` has something to do next on `family`
+    fn next_deadline(&self, family: IpFamily) -> Instant {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let instances = inner
+            .instances
+            .values()
+            .map(|instance| match instance.reconfirming {
+                Some(deadline) => deadline,
+                None if instance.refreshed => instance.expires(),
+                None => instance.refresh_at(),
+            });
+        instances
+            .chain(Some(inner.queries[family_index(family)].next))
+            .min()
+            .unwrap()
+    }
+
+    #[cfg(any(test, feature = "responder"))]
+    fn instances(&self) -> Vec<DiscoveredService> {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner
+            .instances
+            .values()
+            .map(|instance| instance.service.clone())
+            .collect()
+    }
+}
+
+/// Whether the records of two reports of an instance are the same
+fn same_records(a: &DiscoveredService, b: &DiscoveredService) -> bool {
+    a.host == b.host && a.port == b.port && a.txt == b.txt && a.addresses == b.addresses
+}
+
+/// The browses of a responder, fed the responses its handlers receive
+pub(crate) struct Browsers {
+    browses: Mutex<Vec<Arc<BrowseState>>>,
+    /// that of the responder, which a replacement exchanges for its own
+    #[cfg_attr(not(feature = "responder"), allow(dead_code))]
+    budget: Mutex<Arc<MemoryBudget>>,
+}
+
+impl Browsers {
+    pub fn new(budget: &Arc<MemoryBudget>) -> Browsers {
+        Browsers {
+            browses: Mutex::new(Vec::new()),
+            budget: Mutex::new(budget.clone()),
+        }
+    }
+
+    /// Charge the instances found so far, and those found from now on, to `budget`
+    #[cfg(any(test, feature = "responder"))]
+    pub fn use_budget(&self, budget: &Arc<MemoryBudget>) {
+        *self.budget.lock().unwrap_or_else(PoisonError::into_inner) = budget.clone();
+        for state in self.browses.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+            let mut inner = state.inner.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut instances = Lru::new(budget);
+            inner.instances.retain(|key, instance| {
+                instances.insert(key.clone(), instance.clone());
+                false
+            });
+            inner.instances = instances;
+        }
+    }
+
+    #[cfg(any(test, feature = "responder"))]
+    fn add(&self, browse: BrowseBuilder) -> Arc<BrowseState> {
+        let budget = self.budget.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        let state = Arc::new(BrowseState::new(browse, &budget, clock::now()));
+        self.browses.lock().unwrap_or_else(PoisonError::into_inner).push(state.clone());
+        state
+    }
+
+    /// Browse without a `Browse` to stop it
+    #[cfg(test)]
+    pub fn start(&self, browse: BrowseBuilder) {
+        self.add(browse);
+    }
+
+    /// The instances of all browses
+    #[cfg(test)]
+    pub fn instances(&self) -> Vec<DiscoveredService> {
+        let browses = self.browses.lock().unwrap_or_else(PoisonError::into_inner);
+        browses.iter().flat_map(|state| state.instances()).collect()
+    }
+
+    #[cfg(feature = "responder")]
+    fn remove(&self, state: &Arc<BrowseState>) {
+        self.browses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|other| !Arc::ptr_eq(other, state));
+    }
+
+    pub fn observe_response(&self, packet: &Packet, source: SocketAddr, now: Instant) {
+        for state in self.browses.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+            state.observe_response(packet, source, now);
+        }
+    }
+
+    /// The queries due on `family`, also reporting the instances which expired
+    pub fn due_queries(&self, family: IpFamily, now: Instant) -> Vec<Vec<u8>> {
+        let browses = self.browses.lock().unwrap_or_else(PoisonError::into_inner);
+        browses
+            .iter()
+            .filter_map(|state| state.due_query(family, now))
+            .collect()
+    }
+
+    /// When `due_queries` next has anything to do on `family`, if browsing at all
+    pub fn next_deadline(&self, family: IpFamily) -> Option<Instant> {
+        let browses = self.browses.lock().unwrap_or_else(PoisonError::into_inner);
+        browses
+            .iter()
+            .map(|state| state.next_deadline(family))
+            .min()
+    }
+}
+
+/// The instances of a service type on the network as they come and go, started by
+/// [`Responder::browse`](crate::Responder::browse). Each instance is yielded when it
+/// is found, again when its records change, and once more when it is removed.
+///
+/// Keeps the responder running until dropped, when browsing stops.
+#[cfg(feature = "responder")]
+pub struct Browse {
+    state: Arc<BrowseState>,
+    browsers: Arc<Browsers>,
+    _shutdown: Arc<Shutdown>,
+}
+
+#[cfg(feature = "responder")]
+impl Browse {
+    pub(crate) fn start(
+        browse: BrowseBuilder,
+        browsers: &Arc<Browsers>,
+        shutdown: Arc<Shutdown>,
+    ) -> Browse {
+        let state = browsers.add(browse);
+        Browse {
+            state,
+            browsers: browsers.clone(),
+            _shutdown: shutdown,
+        }
+    }
+
+    /// The instances present at the moment, as last reported
+    pub fn instances(&self) -> Vec<DiscoveredService> {
+        self.state.instances()
+    }
+}
+
+#[cfg(feature = "responder")]
+impl Stream for Browse {
+    type Item = DiscoveredService;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<DiscoveredService>> {
+        let mut inner = self.state.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        match inner.events.pop_front() {
+            Some(service) => Poll::Ready(Some(service)),
+            None => {
+                inner.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(feature = "responder")]
+impl Drop for Browse {
+    fn drop(&mut self) {
+        self.browsers.remove(&self.state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::DEFAULT_MEMORY_BUDGET;
+    use crate::metrics::Counters;
+    use std::net::Ipv4Addr;
+
+    const SOURCE: &str = "169.254.0.2:5353";
+
+    fn http() -> BrowseBuilder {
+        BrowseBuilder::new("_http._tcp").unwrap()
+    }
+
+    fn new_state(browse: BrowseBuilder, now: Instant) -> BrowseState {
+        let budget = MemoryBudget::new(DEFAULT_MEMORY_BUDGET, Arc::new(Counters::new()));
+        BrowseState::new(browse, &Arc::new(budget), now)
+    }
+
+    fn announcement(ttl: u32, port: u16) -> Vec<u8> {
+        announcement_of("web", ttl, port)
+    }
+
+    fn announcement_of(instance: &str, ttl: u32, port: u16) -> Vec<u8> {
+        let instance = Name::from_str(format!("{}._http._tcp.local", instance)).unwrap();
+        let host = Name::from_str("box.local").unwrap();
+        Builder::new_response(0, false, true)
+            .add_answer(
+                &Name::from_str("_http._tcp.local").unwrap(),
+                QueryClass::IN,
+                ttl,
+                &RRData::PTR(instance.clone()),
+            )
+            .add_answer(
+                &instance,
+                QueryClass::IN,
+                ttl,
+                &RRData::SRV {
+                    priority: 0,
+                    weight: 0,
+                    port,
+                    target: host.clone(),
+                },
+            )
+            .add_answer(&instance, QueryClass::IN, ttl, &RRData::TXT(b"\x06path=/"))
+            .add_answer(
+                &host,
+                QueryClass::IN,
+                ttl,
+                &RRData::A(Ipv4Addr::new(169, 254, 0, 2)),
+            )
+            .build()
+            .unwrap()
+    }
+
+    fn observe(state: &BrowseState, packet: &[u8], now: Instant) {
+        let packet = Packet::parse(packet).unwrap();
+        state.observe_response(&packet, SOURCE.parse().unwrap(), now);
+    }
+
+    fn events(state: &BrowseState) -> Vec<DiscoveredService> {
+        state.inner.lock().unwrap().events.drain(..).collect()
+    }
+
+    fn known_answers(query: &[u8]) -> usize {
+        Packet::parse(query).unwrap().answers.len()
+    }
+
+    #[test]
+    fn test_added_updated_removed() {
+        let now = Instant::now();
+        let state = new_state(http(), now);
+        assert!(state.due_query(IpFamily::V4, now).is_some());
+        assert!(state.due_query(IpFamily::V4, now).is_none());
+
+        observe(&state, &announcement(120, 80), now);
+        let added = events(&state);
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].discovery, Discovery::Added);
+        assert_eq!(added[0].name, "web._http._tcp.local");
+        assert_eq!(added[0].host.as_deref(), Some("box.local"));
+        assert_eq!(added[0].port, Some(80));
+        assert_eq!(added[0].txt, b"\x06path=/");
+        assert_eq!(added[0].addresses, [IpAddr::from([169, 254, 0, 2])]);
+
+        // Hearing the same again is no change
+        observe(&state, &announcement(120, 80), now);
+        assert!(events(&state).is_empty());
+        observe(&state, &announcement(120, 8080), now);
+        let updated = events(&state);
+        assert_eq!(updated[0].discovery, Discovery::Updated);
+        assert_eq!(updated[0].port, Some(8080));
+
+        // The instance is known to the next query, and reconfirmed after its goodbye
+        let later = now + Duration::from_secs(1);
+        assert_eq!(
+            known_answers(&state.due_query(IpFamily::V4, later).unwrap()),
+            1
+        );
+        observe(&state, &announcement(0, 8080), later);
+        assert!(events(&state).is_empty());
+        assert_eq!(state.next_deadline(IpFamily::V4), later);
+        assert_eq!(
+            known_answers(&state.due_query(IpFamily::V4, later).unwrap()),
+            0
+        );
+        assert_eq!(
+            state.next_deadline(IpFamily::V4),
+            later + Duration::from_secs(1)
+        );
+
+        assert!(state
+            .due_query(IpFamily::V4, later + Duration::from_secs(1))
+            .is_none());
+        let removed = events(&state);
+        assert_eq!(removed[0].discovery, Discovery::Removed);
+        assert!(state.instances().is_empty());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let now = Instant::now();
+        let state = new_state(http(), now);
+        observe(&state, &announcement(10, 80), now);
+        events(&state);
+        for secs in [0, 1, 3, 7] {
+            let at = now + Duration::from_secs(secs);
+            assert!(state.due_query(IpFamily::V4, at).is_some());
+        }
+
+        // Queried for at 80% of the TTL, though the next query isn't due until 15s ...
+        let refresh = now + Duration::from_secs(8);
+        assert_eq!(state.next_deadline(IpFamily::V4), refresh);
+        assert!(state.due_query(IpFamily::V4, refresh).is_some());
+
+        // ... and removed unless it answers the reconfirmation once expired
+        let expired = now + Duration::from_secs(10);
+        assert!(state.due_query(IpFamily::V4, expired).is_some());
+        assert!(events(&state).is_empty());
+        observe(&state, &announcement(10, 80), expired);
+        assert!(state
+            .due_query(IpFamily::V4, expired + Duration::from_secs(1))
+            .is_none());
+        assert!(events(&state).is_empty());
+        assert_eq!(state.instances().len(), 1);
+
+        let state = new_state(http().reconfirm_timeout(Duration::ZERO), now);
+        observe(&state, &announcement(10, 80), now);
+        events(&state);
+        state.due_query(IpFamily::V4, now + Duration::from_secs(10));
+        assert_eq!(events(&state)[0].discovery, Discovery::Removed);
+
+        // A goodbye likewise
+        observe(&state, &announcement(10, 80), now);
+        events(&state);
+        observe(&state, &announcement(0, 80), now);
+        state.due_query(IpFamily::V4, now);
+        assert_eq!(events(&state)[0].discovery, Discovery::Removed);
+    }
+
+    #[test]
+    fn test_query_intervals() {
+        let now = Instant::now();
+        let state = new_state(http().max_query_interval(Duration::from_secs(4)), now);
+        let mut at = now;
+        let mut intervals = Vec::new();
+        for _ in 0..5 {
+            state.due_query(IpFamily::V4, at).unwrap();
+            let next = state.next_deadline(IpFamily::V4);
+            intervals.push(next.duration_since(at).as_secs());
+            at = next;
+        }
+        assert_eq!(intervals, [1, 2, 4, 4, 4]);
+    }
+
+    #[test]
+    fn test_invalid_type() {
+        let long = format!("_{}._tcp", "x".repeat(63));
+        assert!(matches!(
+            BrowseBuilder::new(&long),
+            Err(Error::LabelTooLong { ref label, .. }) if label.len() == 64
+        ));
+        let deep = "_a.".repeat(100) + "_tcp";
+        assert!(matches!(
+            BrowseBuilder::new(&deep),
+            Err(Error::NameTooLong(_))
+        ));
+    }
+
+    /// Instances are charged to the memory budget, forgetting the least recently
+    /// heard from once it is spent
+    #[test]
+    fn test_instances_budget() {
+        const BUDGET: usize = 4096;
+        let now = Instant::now();
+        let budget = Arc::new(MemoryBudget::new(BUDGET, Arc::new(Counters::new())));
+        let state = BrowseState::new(http(), &budget, now);
+        for i in 0..100 {
+            observe(&state, &announcement_of(&format!("web{}", i), 120, 80), now);
+            assert!(budget.used() <= BUDGET);
+        }
+        let kept = state.instances();
+        assert!(!kept.is_empty() && kept.len() < 100);
+        assert!(kept
+            .iter()
+            .any(|service| service.name == "web99._http._tcp.local"));
+
+        // A replacement responder takes over the instances, within its own budget
+        let other = Arc::new(MemoryBudget::new(BUDGET, Arc::new(Counters::new())));
+        let browsers = Browsers::new(&budget);
+        browsers.browses.lock().unwrap().push(Arc::new(state));
+        browsers.use_budget(&other);
+        assert_eq!(budget.used(), 0);
+        assert!(other.used() > 0);
+        assert_eq!(
+            browsers.browses.lock().unwrap()[0].instances().len(),
+            kept.len()
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! A memory budget shared by the caches a responder fills from network traffic, so
+//! that a hostile network can't make them grow without bound. Each cache is an
+//! [`Lru`], which forgets its least recently used entries once the budget is spent.
+
+use crate::dns_parser::NameKey;
+use crate::metrics::Counters;
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::mem::size_of;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// What the caches of a responder may take together, unless configured otherwise
+pub const DEFAULT_MEMORY_BUDGET: usize = 1024 * 1024;
+
+/// A rough allowance for the bookkeeping of a map entry besides its key and value
+const ENTRY_OVERHEAD: usize = 48;
+
+/// Memory held outside of a value itself
+pub trait HeapSize {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+impl HeapSize for () {}
+impl HeapSize for u8 {}
+impl HeapSize for u64 {}
+impl HeapSize for IpAddr {}
+impl HeapSize for std::time::Instant {}
+
+impl HeapSize for NameKey {
+    fn heap_size(&self) -> usize {
+        self.wire_len()
+    }
+}
+
+impl<A: HeapSize, B: HeapSize> HeapSize for (A, B) {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size() + self.1.heap_size()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+/// The bytes all caches of a responder may hold.
+///
+/// A cache may take more than an equal share of the budget while the others don't
+/// need it. Once a cache within its share runs out of room, the budget is squeezed,
+/// and caches beyond their share give back the excess as they next change.
+pub struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+    caches: AtomicUsize,
+    squeezed: AtomicBool,
+    counters: Arc<Counters>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize, counters: Arc<Counters>) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            used: AtomicUsize::new(0),
+            caches: AtomicUsize::new(0),
+            squeezed: AtomicBool::new(false),
+            counters,
+        }
+    }
+
+    /// The bytes taken so far
+    #[cfg(test)]
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// What each cache is sure to get
+    fn share(&self) -> usize {
+        self.limit / self.caches.load(Ordering::Relaxed).max(1)
+    }
+
+    /// Take `bytes`, unless that would exceed the limit
+    fn try_charge(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used + bytes).filter(|&used| used <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+struct Slot<V> {
+    value: V,
+    weight: usize,
+    /// when the entry was last used, its key in `Lru::order`
+    used: u64,
+}
+
+/// A map charging its entries to a `MemoryBudget`, which drops its least recently
+/// used entries to make room for new ones once the budget is spent. An entry which
+/// can't fit even then isn't kept.
+pub struct Lru<K, V> {
+    budget: Arc<MemoryBudget>,
+    entries: HashMap<K, Slot<V>>,
+    order: BTreeMap<u64, K>,
+    clock: u64,
+    /// the weight of all entries
+    weight: usize,
+}
+
+impl<K: Hash + Eq + Clone + HeapSize, V: HeapSize> Lru<K, V> {
+    pub fn new(budget: &Arc<MemoryBudget>) -> Lru<K, V> {
+        budget.caches.fetch_add(1, Ordering::Relaxed);
+        Lru {
+            budget: budget.clone(),
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+            weight: 0,
+        }
+    }
+
+    fn weigh(key: &K, value: &V) -> usize {
+        ENTRY_OVERHEAD
+            + 2 * size_of::<K>()
+            + size_of::<V>()
+            + 2 * key.heap_size()
+            + value.heap_size()
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries.contains_key(key)
+    }
+
+    /// The least recently used entry
+    pub fn oldest(&self) -> Option<(&K, &V)> {
+        let (_, key) = self.order.iter().next()?;
+        Some((key, &self.entries[key].value))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.values().map(|slot| &slot.value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, slot)| (key, &slot.value))
+    }
+
+    /// Insert or replace the value of `key`, returning whether it fits the budget
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        self.remove(&key);
+        let weight = Self::weigh(&key, &value);
+        if !self.make_room(weight) {
+            self.budget.counters.cache_evicted();
+            return false;
+        }
+        let used = self.tick();
+        self.order.insert(used, key.clone());
+        self.entries.insert(
+            key,
+            Slot {
+                value,
+                weight,
+                used,
+            },
+        );
+        true
+    }
+
+    /// Change the value of `key`, counting it as used. If the value grew beyond what
+    /// the budget allows, it is dropped.
+    pub fn update<Q, R>(&mut self, key: &Q, f: impl FnOnce(&mut V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let used = self.tick();
+        let slot = self.entries.get_mut(key)?;
+        // Out of the way of `make_room` until it is weighed again
+        let owned = self.order.remove(&slot.used).unwrap();
+        slot.used = used;
+        let result = f(&mut slot.value);
+        let old = slot.weight;
+        let new = Self::weigh(&owned, &slot.value);
+
+        if new <= old {
+            slot.weight = new;
+            self.weight -= old - new;
+            self.budget.release(old - new);
+        } else if self.make_room(new - old) {
+            self.entries.get_mut(key).unwrap().weight = new;
+        } else {
+            self.remove(key);
+            self.budget.counters.cache_evicted();
+            return Some(result);
+        }
+        self.order.insert(used, owned);
+        Some(result)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let slot = self.entries.remove(key)?;
+        self.order.remove(&slot.used);
+        self.weight -= slot.weight;
+        self.budget.release(slot.weight);
+        Some(slot.value)
+    }
+
+    /// Keep only the entries for which `f` returns true, weighing them again
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        let order = &mut self.order;
+        let mut weight = 0;
+        self.entries.retain(|key, slot| {
+            if f(key, &mut slot.value) {
+                slot.weight = Self::weigh(key, &slot.value);
+                weight += slot.weight;
+                true
+            } else {
+                order.remove(&slot.used);
+                false
+            }
+        });
+        // Growth is charged even beyond the limit, which the next insertion makes up
+        // for
+        if weight < self.weight {
+            self.budget.release(self.weight - weight);
+        } else {
+            self.budget
+                .used
+                .fetch_add(weight - self.weight, Ordering::Relaxed);
+        }
+        self.weight = weight;
+    }
+
+    /// Charge `weight` more to the budget, dropping our least recently used entries
+    /// as long as it doesn't fit
+    fn make_room(&mut self, weight: usize) -> bool {
+        if weight > self.budget.limit {
+            return false;
+        }
+        let share = self.budget.share();
+        if self.budget.squeezed.load(Ordering::Relaxed) {
+            while self.weight + weight > share && self.evict_oldest() {}
+        }
+        loop {
+            let within_share = self.weight + weight <= share;
+            if self.budget.try_charge(weight) {
+                if within_share {
+                    self.budget.squeezed.store(false, Ordering::Relaxed);
+                }
+                self.weight += weight;
+                return true;
+            }
+            // The others have taken our share, which they give back later
+            if within_share {
+                self.budget.squeezed.store(true, Ordering::Relaxed);
+                return false;
+            }
+            if !self.evict_oldest() {
+                return false;
+            }
+        }
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        let key = match self.order.values().next() {
+            Some(key) => key.clone(),
+            None => return false,
+        };
+        self.remove(&key);
+        self.budget.counters.cache_evicted();
+        true
+    }
+}
+
+impl<K, V> Drop for Lru<K, V> {
+    fn drop(&mut self) {
+        self.budget.caches.fetch_sub(1, Ordering::Relaxed);
+        self.budget.release(self.weight);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru() {
+        let counters = Arc::new(Counters::new());
+        let big = Lru::<u64, Vec<u8>>::weigh(&0, &Vec::new());
+        let small = Lru::<u64, ()>::weigh(&0, &());
+        let budget = Arc::new(MemoryBudget::new(3 * big + small, counters.clone()));
+        let mut first = Lru::<u64, Vec<u8>>::new(&budget);
+        let mut second = Lru::<u64, ()>::new(&budget);
+
+        for key in 0..3 {
+            assert!(first.insert(key, Vec::new()));
+        }
+        assert!(second.insert(0, ()));
+        assert_eq!(budget.used(), 3 * big + small);
+
+        // The least recently used entry makes room, of the cache which needs it
+        first.update(&0, |_| ());
+        assert!(first.insert(3, Vec::new()));
+        assert!(!first.contains_key(&1));
+        assert_eq!(first.oldest().map(|(&key, _)| key), Some(2));
+        assert!(second.contains_key(&0));
+        #[cfg(feature = "metrics-minimal")]
+        assert_eq!(counters.metrics().cache_evictions, 1);
+
+        // Growing makes room too, and what can't fit at all isn't kept
+        first.update(&0, |value| value.reserve_exact(big + 1));
+        assert_eq!(first.len(), 1);
+        assert!(!first.insert(4, vec![0; 4 * big]));
+        assert_eq!(first.len(), 1);
+        assert!(first
+            .update(&0, |value| value.reserve_exact(4 * big))
+            .is_some());
+        assert!(first.is_empty());
+        #[cfg(feature = "metrics-minimal")]
+        assert_eq!(counters.metrics().cache_evictions, 5);
+        assert_eq!(budget.used(), small);
+
+        drop(first);
+        drop(second);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn test_squeeze() {
+        let counters = Arc::new(Counters::new());
+        let weight = Lru::<u64, ()>::weigh(&0, &());
+        let budget = Arc::new(MemoryBudget::new(4 * weight, counters));
+        let mut greedy = Lru::<u64, ()>::new(&budget);
+        let mut other = Lru::<u64, ()>::new(&budget);
+
+        // Nobody else needed the budget
+        for key in 0..4 {
+            assert!(greedy.insert(key, ()));
+        }
+        // Until another cache does, which gets its share once the first changes
+        assert!(!other.insert(0, ()));
+        assert!(greedy.insert(4, ()));
+        assert_eq!(greedy.len(), 2);
+        assert!(other.insert(0, ()));
+        assert!(other.insert(1, ()));
+        assert_eq!(greedy.len() + other.len(), 4);
+    }
+}
@@ -0,0 +1,354 @@
+//! Records other responders publish, remembered from their responses for browsing.
+//! Records which queries stop getting answers for are dropped before their TTL runs
+//! out, the passive observation of failures of RFC 6762 section 10.5.
+
+use crate::budget::{HeapSize, Lru, MemoryBudget};
+use crate::dns_parser::{Canonical, NameKey, Packet, QueryType, ResourceRecord, Type};
+use log::debug;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How long records replaced by a cache-flush record or withdrawn by a goodbye are
+/// kept, see RFC 6762 sections 10.1 and 10.2
+const FLUSH_DELAY: Duration = Duration::from_secs(1);
+
+/// A record is dropped after this many queries for it went unanswered ...
+const POOF_QUERIES: u32 = 2;
+/// ... for this long
+const POOF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A record from another responder, see [`Responder::cached_records`].
+///
+/// [`Responder::cached_records`]: crate::Responder::cached_records
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CachedRecord {
+    pub name: String,
+    /// The record type, such as 1 for A or 33 for SRV
+    pub rtype: u16,
+    /// The record data in wire format, with any names uncompressed
+    pub rdata: Vec<u8>,
+    /// Where the record was last seen from
+    pub source: SocketAddr,
+    /// How much longer the record is valid for
+    pub ttl: Duration,
+}
+
+struct Entry {
+    name: String,
+    typ: Type,
+    rdata: Vec<u8>,
+    source: SocketAddr,
+    received: Instant,
+    expires: Instant,
+    /// queries seen which the record would answer, since it was last received
+    unanswered: u32,
+    unanswered_since: Option<Instant>,
+}
+
+impl HeapSize for Entry {
+    fn heap_size(&self) -> usize {
+        self.name.heap_size() + self.rdata.heap_size()
+    }
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires <= now
+            || (self.unanswered >= POOF_QUERIES
+                && self
+                    .unanswered_since
+                    .is_some_and(|since| now.duration_since(since) >= POOF_TIMEOUT))
+    }
+}
+
+/// The records of each name, the least recently seen or asked about dropped first
+struct Records {
+    by_name: Lru<NameKey, Vec<Entry>>,
+}
+
+impl Records {
+    fn expire(&mut self, now: Instant) {
+        self.by_name.retain(|_, entries| {
+            entries.retain(|entry| {
+                let expired = entry.is_expired(now);
+                if expired && entry.expires > now {
+                    debug!(
+                        "dropping {} {:?} after {} unanswered queries",
+                        entry.name, entry.typ, entry.unanswered
+                    );
+                }
+                !expired
+            });
+            !entries.is_empty()
+        });
+    }
+
+    fn insert(&mut self, record: &ResourceRecord, source: SocketAddr, now: Instant) {
+        let mut rdata = Vec::new();
+        if record.data.write_to(&mut rdata).is_err() {
+            return;
+        }
+        let typ = record.data.typ();
+        let expires = match record.ttl {
+            0 => now + FLUSH_DELAY,
+            ttl => now + Duration::from_secs(ttl.into()),
+        };
+
+        if !self.by_name.contains_key(&record.name as &dyn Canonical) {
+            if record.ttl == 0 {
+                return;
+            }
+            self.by_name.insert(NameKey::new(&record.name), Vec::new());
+        }
+        self.by_name
+            .update(&record.name as &dyn Canonical, |entries| {
+                if record.cache_flush {
+                    let flushed = entries.iter_mut().filter(|entry| {
+                        entry.typ == typ
+                            && entry.rdata != rdata
+                            && now.duration_since(entry.received) > FLUSH_DELAY
+                    });
+                    for entry in flushed {
+                        entry.expires = entry.expires.min(now + FLUSH_DELAY);
+                    }
+                }
+
+                match entries
+                    .iter_mut()
+                    .find(|entry| entry.typ == typ && entry.rdata == rdata)
+                {
+                    Some(entry) => {
+                        entry.source = source;
+                        entry.received = now;
+                        entry.expires = expires;
+                        entry.unanswered = 0;
+                        entry.unanswered_since = None;
+                    }
+                    None if record.ttl == 0 => (),
+                    None => entries.push(Entry {
+                        name: record.name.to_string(),
+                        typ,
+                        rdata,
+                        source,
+                        received: now,
+                        expires,
+                        unanswered: 0,
+                        unanswered_since: None,
+                    }),
+                }
+            });
+    }
+}
+
+/// The records seen in responses, shared by the handlers of all address families.
+/// Nothing is kept unless enabled.
+pub struct RecordCache {
+    enabled: bool,
+    records: Mutex<Records>,
+}
+
+impl RecordCache {
+    pub fn new(enabled: bool, budget: &Arc<MemoryBudget>) -> RecordCache {
+        RecordCache {
+            enabled,
+            records: Mutex::new(Records {
+                by_name: Lru::new(budget),
+            }),
+        }
+    }
+
+    /// Remember the records of a response from `source`
+    pub fn observe_response(&self, packet: &Packet, source: SocketAddr, now: Instant) {
+        if !self.enabled {
+            return;
+        }
+        let mut records = self.records.lock().unwrap_or_else(PoisonError::into_inner);
+        records.expire(now);
+        for record in packet.answers.iter().chain(&packet.additional) {
+            records.insert(record, source, now);
+        }
+    }
+
+    /// Count the cached records a multicast response to a query should contain,
+    /// unless it lists them as known answers
+    pub fn observe_query(&self, packet: &Packet, now: Instant) {
+        if !self.enabled {
+            return;
+        }
+        let known: Vec<(NameKey, Type, Vec<u8>)> = packet
+            .answers
+            .iter()
+            .filter_map(|record| {
+                let mut rdata = Vec::new();
+                record.data.write_to(&mut rdata).ok()?;
+                Some((NameKey::new(&record.name), record.data.typ(), rdata))
+            })
+            .collect();
+
+        let mut records = self.records.lock().unwrap_or_else(PoisonError::into_inner);
+        for question in &packet.questions {
+            // Only a multicast answer is seen by us
+            if question.wants_unicast() {
+                continue;
+            }
+            records
+                .by_name
+                .update(&question.qname as &dyn Canonical, |entries| {
+                    let qname = NameKey::new(&question.qname);
+                    let answers = entries.iter_mut().filter(|entry| {
+                        (question.qtype == QueryType::All
+                            || u16::from(question.qtype) == entry.typ as u16)
+                            && !known.iter().any(|(name, typ, rdata)| {
+                                *name == qname && *typ == entry.typ && *rdata == entry.rdata
+                            })
+                    });
+                    for entry in answers {
+                        entry.unanswered += 1;
+                        entry.unanswered_since.get_or_insert(now);
+                    }
+                });
+        }
+    }
+
+    pub fn records(&self, now: Instant) -> Vec<CachedRecord> {
+        let mut records = self.records.lock().unwrap_or_else(PoisonError::into_inner);
+        records.expire(now);
+        records
+            .by_name
+            .values()
+            .flatten()
+            .map(|entry| CachedRecord {
+                name: entry.name.clone(),
+                rtype: entry.typ as u16,
+                rdata: entry.rdata.clone(),
+                source: entry.source,
+                ttl: entry.expires.duration_since(now),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::DEFAULT_MEMORY_BUDGET;
+    use crate::dns_parser::{Builder, Name, QueryClass, RRData};
+    use crate::metrics::Counters;
+
+    const SOURCE: &str = "169.254.0.2:5353";
+
+    fn record_cache(enabled: bool) -> RecordCache {
+        let counters = Arc::new(Counters::new());
+        RecordCache::new(
+            enabled,
+            &Arc::new(MemoryBudget::new(DEFAULT_MEMORY_BUDGET, counters)),
+        )
+    }
+
+    fn response(ip: [u8; 4], ttl: u32, cache_flush: bool) -> Vec<u8> {
+        Builder::new_response(0, false, true)
+            .add_answer_with_cache_flush(
+                &Name::from_str("printer.local").unwrap(),
+                QueryClass::IN,
+                ttl,
+                &RRData::A(ip.into()),
+                cache_flush,
+            )
+            .build()
+            .unwrap()
+    }
+
+    fn query(known: Option<[u8; 4]>) -> Vec<u8> {
+        let builder = Builder::new_query(0, false).add_question(
+            &Name::from_str("printer.local").unwrap(),
+            QueryType::A,
+            QueryClass::IN,
+        );
+        match known {
+            Some(ip) => builder
+                .add_answer(
+                    &Name::from_str("printer.local").unwrap(),
+                    QueryClass::IN,
+                    120,
+                    &RRData::A(ip.into()),
+                )
+                .build(),
+            None => builder.build(),
+        }
+        .unwrap()
+    }
+
+    fn observe(cache: &RecordCache, packet: &[u8], now: Instant) {
+        let packet = Packet::parse(packet).unwrap();
+        if packet.header.query {
+            cache.observe_query(&packet, now);
+        } else {
+            cache.observe_response(&packet, SOURCE.parse().unwrap(), now);
+        }
+    }
+
+    fn addresses(cache: &RecordCache, now: Instant) -> Vec<Vec<u8>> {
+        let mut rdata: Vec<_> = cache.records(now).into_iter().map(|r| r.rdata).collect();
+        rdata.sort();
+        rdata
+    }
+
+    #[test]
+    fn test_cache_records() {
+        let disabled = record_cache(false);
+        let now = Instant::now();
+        observe(&disabled, &response([192, 0, 2, 1], 120, false), now);
+        assert!(disabled.records(now).is_empty());
+
+        let cache = record_cache(true);
+        observe(&cache, &response([192, 0, 2, 1], 120, false), now);
+        observe(&cache, &response([192, 0, 2, 2], 120, false), now);
+        let records = cache.records(now);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].name, "printer.local");
+        assert_eq!(records[0].rtype, 1);
+        assert_eq!(records[0].ttl, Duration::from_secs(120));
+        assert_eq!(records[0].source, SOURCE.parse().unwrap());
+
+        // A cache-flush record replaces the others a second later
+        let later = now + Duration::from_secs(5);
+        observe(&cache, &response([192, 0, 2, 3], 120, true), later);
+        assert_eq!(addresses(&cache, later).len(), 3);
+        assert_eq!(
+            addresses(&cache, later + FLUSH_DELAY),
+            vec![vec![192, 0, 2, 3]]
+        );
+
+        // And a goodbye removes it
+        let later = later + FLUSH_DELAY;
+        observe(&cache, &response([192, 0, 2, 3], 0, false), later);
+        assert!(cache.records(later + FLUSH_DELAY).is_empty());
+
+        // Expired records go
+        observe(&cache, &response([192, 0, 2, 4], 1, false), later);
+        assert!(cache.records(later + Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn test_passive_observation_of_failures() {
+        let cache = record_cache(true);
+        let now = Instant::now();
+        observe(&cache, &response([192, 0, 2, 1], 120, false), now);
+        observe(&cache, &response([192, 0, 2, 2], 120, false), now);
+
+        // Queries listing a record as known aren't expected to be answered with it
+        observe(&cache, &query(Some([192, 0, 2, 2])), now);
+        observe(&cache, &query(Some([192, 0, 2, 2])), now);
+        // An answer resets the count
+        observe(&cache, &query(None), now);
+        observe(&cache, &response([192, 0, 2, 2], 120, false), now);
+        observe(&cache, &query(None), now);
+
+        let later = now + POOF_TIMEOUT;
+        assert_eq!(addresses(&cache, later), vec![vec![192, 0, 2, 2]]);
+        observe(&cache, &query(None), later);
+        assert!(cache.records(later + POOF_TIMEOUT).is_empty());
+    }
+}
@@ -0,0 +1,29 @@
+//! The time the protocol logic goes by: the system's, except on a thread replaying a
+//! trace, where it is the time the packet being replayed was received at.
+
+use rand::{thread_rng, Rng};
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static REPLAYED: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+pub fn now() -> Instant {
+    REPLAYED.with(Cell::get).unwrap_or_else(Instant::now)
+}
+
+/// A random delay of `min` to `max` milliseconds, or always halfway between while
+/// replaying, so that replays of a trace send the same packets at the same times
+pub fn jitter(min: u64, max: u64) -> Duration {
+    match REPLAYED.with(Cell::get) {
+        Some(_) => Duration::from_millis(min + (max - min) / 2),
+        None => Duration::from_millis(thread_rng().gen_range(min..=max)),
+    }
+}
+
+/// Make `now` return `at` on this thread, or the system's time again if `None`
+#[cfg(feature = "replay")]
+pub fn set_replayed(at: Option<Instant>) {
+    REPLAYED.with(|replayed| replayed.set(at));
+}
@@ -0,0 +1,786 @@
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+use std::sync::Arc;
+#[cfg(feature = "responder")]
+use std::sync::Mutex;
+use std::time::Duration;
+#[cfg(feature = "responder")]
+use tokio::runtime::Handle;
+
+use crate::allow::{self, AllowedIp};
+use crate::engine::Engine;
+use crate::error::{Error, IpFamily};
+use crate::events::{
+    AddressConflict, HostnameConflict, PacketDropped, PacketPanic, QueryEvent, SendFailure,
+    ServiceEvent,
+};
+use crate::fsm::MAX_ANNOUNCEMENTS;
+use crate::hooks::{RecordValidator, TxtDecorator};
+use crate::host::{DefaultHostData, HostData, NamespaceHostData};
+use crate::netns::{Interfaces, NetworkNamespace};
+use crate::preflight::PreflightReport;
+use crate::rename::{NumericSuffix, RenameStrategy};
+use crate::services::ServiceData;
+#[cfg(feature = "snapshot")]
+use crate::snapshot::{Snapshot, SNAPSHOT_MAX_AGE};
+use crate::sockets::PreparedSockets;
+use crate::store::ServiceStorage;
+#[cfg(feature = "sync")]
+use crate::sync::SyncResponder;
+#[cfg(feature = "responder")]
+use crate::tethered::PacketSink;
+#[cfg(feature = "responder")]
+use crate::transport::Transport;
+#[cfg(feature = "responder")]
+use crate::{Responder, ResponderTask, Runner};
+#[cfg(feature = "snapshot")]
+use log::debug;
+use log::warn;
+
+/// How eagerly the responder answers, selected to suit the network it is on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum NetworkProfile {
+    /// Standard mDNS behaviour.
+    #[default]
+    Normal,
+    /// For networks which drop much of the multicast traffic. Suppression heuristics
+    /// are disabled so every query is answered, and every response is sent both
+    /// multicast and unicast to the querier.
+    Lossy,
+    /// For networks with clients which mishandle short TTLs, notably several Android
+    /// versions, which evict records with TTLs below 30 seconds as soon as they cache
+    /// them. Records are published with TTLs of at least
+    /// [`min_ttl`](NetworkProfile::min_ttl), and otherwise as with `Normal`.
+    Compatible,
+}
+
+/// The lowest TTL of the `Compatible` profile
+const COMPATIBLE_MIN_TTL: u32 = 30;
+
+impl NetworkProfile {
+    /// The lowest TTL, in seconds, records are published with under this profile.
+    /// Lower TTLs are raised as the records are sent, goodbyes still use a TTL of zero.
+    pub fn min_ttl(self) -> u32 {
+        match self {
+            NetworkProfile::Compatible => COMPATIBLE_MIN_TTL,
+            NetworkProfile::Normal | NetworkProfile::Lossy => 0,
+        }
+    }
+}
+
+/// Settings shared by the `Responder` and its tasks.
+#[derive(Clone, Default)]
+pub struct ResponderConfig {
+    pub(crate) allowed_ips: Vec<AllowedIp>,
+    /// whether `allowed_ips` also picks the interfaces to join, as with `allow_list`
+    pub(crate) join_allowed: bool,
+    pub(crate) interfaces: Vec<String>,
+    pub(crate) hostname: Option<String>,
+    pub(crate) host_info: Option<(String, String)>,
+    pub(crate) host_data: Option<Arc<dyn HostData>>,
+    pub(crate) address_refresh_interval: Option<Duration>,
+    pub(crate) txt_decorator: Option<Arc<dyn TxtDecorator>>,
+    pub(crate) record_validator: Option<Arc<dyn RecordValidator>>,
+    pub(crate) on_query: Option<Arc<dyn Fn(QueryEvent) + Send + Sync>>,
+    pub(crate) on_service_event: Option<Arc<dyn Fn(ServiceEvent) + Send + Sync>>,
+    pub(crate) on_address_conflict: Option<Arc<dyn Fn(AddressConflict) + Send + Sync>>,
+    pub(crate) on_hostname_conflict: Option<Arc<dyn Fn(HostnameConflict) + Send + Sync>>,
+    pub(crate) on_send_failure: Option<Arc<dyn Fn(SendFailure) + Send + Sync>>,
+    pub(crate) on_packet_panic: Option<Arc<dyn Fn(PacketPanic) + Send + Sync>>,
+    pub(crate) on_packet_dropped: Option<Arc<dyn Fn(PacketDropped) + Send + Sync>>,
+    pub(crate) rename_strategy: Option<Arc<dyn RenameStrategy>>,
+    pub(crate) service_storage: ServiceStorage,
+    pub(crate) network_profile: NetworkProfile,
+    pub(crate) max_packet_size: Option<usize>,
+    pub(crate) max_response_delay: Option<Duration>,
+    pub(crate) max_queued_responses: Option<usize>,
+    pub(crate) memory_budget: Option<usize>,
+    pub(crate) reflect_interfaces: Vec<String>,
+    pub(crate) announce_rate: Option<u32>,
+    pub(crate) announcements: Option<u32>,
+    pub(crate) disable_ipv4: bool,
+    pub(crate) disable_ipv6: bool,
+    pub(crate) accept_off_link_queries: bool,
+    pub(crate) answer_workers: usize,
+    pub(crate) echo_question: bool,
+    pub(crate) disable_name_compression: bool,
+    pub(crate) disable_cache_flush: bool,
+    pub(crate) cache_records: bool,
+    pub(crate) enumerate_instances: bool,
+    pub(crate) enumerate_instance_records: bool,
+    pub(crate) sequential_ids: bool,
+    #[cfg(feature = "unicode")]
+    pub(crate) disable_normalization: bool,
+    pub(crate) network_namespace: Option<NetworkNamespace>,
+    /// lists the interfaces of `network_namespace`, once started
+    pub(crate) netns_interfaces: Option<Arc<Interfaces>>,
+    /// set for a responder built with `build_with_sink`, which has no interfaces
+    pub(crate) sink: bool,
+    #[cfg(unix)]
+    pub(crate) local_coordination: Option<PathBuf>,
+    #[cfg(feature = "snapshot")]
+    pub(crate) snapshot: Option<Snapshot>,
+}
+
+impl ResponderConfig {
+    /// The allow-list to pick the interfaces to join with, empty unless set with
+    /// `allow_list`
+    pub(crate) fn join_allow_list(&self) -> &[AllowedIp] {
+        match self.join_allowed {
+            true => &self.allowed_ips,
+            false => &[],
+        }
+    }
+
+    pub(crate) fn host_data(&self) -> Arc<dyn HostData> {
+        match (&self.host_data, &self.netns_interfaces) {
+            (Some(host_data), _) => host_data.clone(),
+            (None, Some(interfaces)) => Arc::new(NamespaceHostData(interfaces.clone())),
+            (None, None) => Arc::new(DefaultHostData),
+        }
+    }
+
+    /// Start listing the interfaces of the network namespace, unless there is none or
+    /// they are listed already. The addresses and subnets of the process's own
+    /// namespace are used if it can't be entered.
+    pub(crate) fn list_netns_interfaces(&mut self) {
+        if self.netns_interfaces.is_some() {
+            return;
+        }
+        if let Some(ref netns) = self.network_namespace {
+            match Interfaces::start(netns) {
+                Ok(interfaces) => self.netns_interfaces = Some(interfaces),
+                Err(err) => warn!("listing the interfaces of the process instead: {}", err),
+            }
+        }
+    }
+
+    /// Fails if both address families are disabled, leaving nothing to listen on.
+    pub(crate) fn check_address_families(&self) -> Result<(), Error> {
+        if self.disable_ipv4 && self.disable_ipv6 {
+            return Err(Error::NoAddressFamily);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn rename_strategy(&self) -> &dyn RenameStrategy {
+        match self.rename_strategy {
+            Some(ref strategy) => &**strategy,
+            None => &NumericSuffix,
+        }
+    }
+
+    pub(crate) fn service_event(&self, event: ServiceEvent) {
+        if let Some(ref on_service_event) = self.on_service_event {
+            on_service_event(event);
+        }
+    }
+
+    /// Warn about the TTLs of a service's records which the initial network profile
+    /// raises when they are published
+    pub(crate) fn warn_short_ttls(&self, svc: &ServiceData) {
+        let min_ttl = self.network_profile.min_ttl();
+        let ttls = [(&svc.name, svc.ptr_ttl), (&svc.name, svc.ttl)];
+        let additional = svc
+            .additional
+            .iter()
+            .map(|record| (&record.name, record.ttl));
+        for (name, ttl) in ttls.iter().cloned().chain(additional) {
+            if ttl < min_ttl {
+                warn!(
+                    "publishing {} with a TTL of {}s rather than {}s",
+                    name, min_ttl, ttl
+                );
+            }
+        }
+    }
+}
+
+/// Configures and starts a `Responder`.
+///
+/// # Example
+/// ```no_run
+/// # #[cfg(feature = "responder")]
+/// use libmdns::ResponderBuilder;
+///
+/// # use std::io;
+/// # #[cfg(feature = "responder")]
+/// # fn main() -> io::Result<()> {
+/// let responder = ResponderBuilder::new()
+///     .hostname("myUniqueName".to_owned())
+///     .spawn_thread()?;
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "responder"))]
+/// # fn main() {}
+/// ```
+#[derive(Clone, Default)]
+pub struct ResponderBuilder {
+    pub(crate) config: ResponderConfig,
+}
+
+impl ResponderBuilder {
+    pub fn new() -> ResponderBuilder {
+        ResponderBuilder::default()
+    }
+
+    /// DNS response records will have the reported IPs limited to those passed in here.
+    /// This can be particularly useful on machines with lots of networks created by tools such as docker.
+    ///
+    /// Like an [`allow_list`](ResponderBuilder::allow_list) of exact addresses, except
+    /// that the mDNS group is still joined on every interface.
+    pub fn allowed_ips(mut self, allowed_ips: Vec<IpAddr>) -> Self {
+        self.config.allowed_ips = allowed_ips.into_iter().map(AllowedIp::from).collect();
+        self.config.join_allowed = false;
+        self
+    }
+
+    /// Only publish the addresses of the host which match an entry of `allowed`: an
+    /// address, a prefix such as `192.168.1.0/24` or an interface such as `eth0`,
+    /// see [`AllowedIp`]. Reverse lookups are only answered for those addresses, and
+    /// unless [`interfaces`](ResponderBuilder::interfaces) are set, the mDNS group is
+    /// only joined on the interfaces which have one when the responder starts, for
+    /// each address family separately: a family without entries on the list joins
+    /// every interface. By default every address is published.
+    pub fn allow_list(mut self, allowed: Vec<AllowedIp>) -> Self {
+        self.config.allowed_ips = allowed;
+        self.config.join_allowed = true;
+        self
+    }
+
+    /// Only join the mDNS group on the network interfaces with these names, e.g.
+    /// `&["eth0", "wlan0"]`, and only publish the addresses they have. Packets from
+    /// other interfaces are dropped: those from outside the subnets of these
+    /// interfaces, and IPv6 link-local ones with the scope of another interface. By
+    /// default all interfaces are used.
+    ///
+    /// Starting fails with [`Error::NoMatchingInterface`] if none of them have an
+    /// IPv4 address, or no IPv6 address when IPv4 is disabled.
+    pub fn interfaces(mut self, interfaces: &[&str]) -> Self {
+        self.config.interfaces = interfaces.iter().map(|&name| name.to_owned()).collect();
+        self
+    }
+
+    /// Reflect mDNS queries and responses between the network interfaces with these
+    /// names, e.g. `&["vlan10", "vlan20"]`: packets from hosts on one of them are
+    /// multicast again on the others, so that services can be found across VLANs.
+    /// The interfaces must be among those the responder uses, see [`interfaces`].
+    ///
+    /// Only the addresses the interfaces have when the responder starts are taken
+    /// into account. By default nothing is reflected.
+    ///
+    /// [`interfaces`]: ResponderBuilder::interfaces
+    pub fn reflect(mut self, interfaces: &[&str]) -> Self {
+        self.config.reflect_interfaces = interfaces.iter().map(|&name| name.to_owned()).collect();
+        self
+    }
+
+    /// SRV records will point at this hostname instead of the system hostname.
+    pub fn hostname(mut self, hostname: String) -> Self {
+        self.config.hostname = Some(hostname);
+        self
+    }
+
+    /// Answer HINFO queries for the hostname with the CPU and operating system of the
+    /// host, which some discovery tools display. Each is cut to 255 bytes. By default
+    /// HINFO queries go unanswered.
+    pub fn host_info(mut self, cpu: String, os: String) -> Self {
+        self.config.host_info = Some((cpu, os));
+        self
+    }
+
+    /// Take the hostname and addresses from `host_data` instead of the system.
+    /// A hostname set with [`hostname`](ResponderBuilder::hostname) still takes precedence.
+    pub fn host_data(mut self, host_data: Arc<dyn HostData>) -> Self {
+        self.config.host_data = Some(host_data);
+        self
+    }
+
+    /// How long the list of addresses is reused before the interfaces are listed
+    /// again. Defaults to 1 second, `Duration::ZERO` lists them for every response.
+    pub fn address_refresh_interval(mut self, interval: Duration) -> Self {
+        self.config.address_refresh_interval = Some(interval);
+        self
+    }
+
+    /// Rewrite the TXT data of every service as it is sent, e.g. to append a signature.
+    pub fn txt_decorator<D: TxtDecorator + 'static>(mut self, decorator: D) -> Self {
+        self.config.txt_decorator = Some(Arc::new(decorator));
+        self
+    }
+
+    /// Only trust service records from other responders which pass this check.
+    pub fn record_validator<V: RecordValidator + 'static>(mut self, validator: V) -> Self {
+        self.config.record_validator = Some(Arc::new(validator));
+        self
+    }
+
+    /// The initial `NetworkProfile`, which can be changed later with
+    /// [`Responder::set_network_profile`].
+    pub fn network_profile(mut self, profile: NetworkProfile) -> Self {
+        self.config.network_profile = profile;
+        self
+    }
+
+    /// Split responses larger than `max_packet_size` bytes over several packets.
+    /// The default of 1472 suits a 1500 byte MTU.
+    ///
+    /// Direct responses to a querier which advertises its EDNS0 payload size are
+    /// sized for it instead, up to 8952 bytes, but never beyond a `max_packet_size`
+    /// set here.
+    pub fn max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.config.max_packet_size = Some(max_packet_size);
+        self
+    }
+
+    /// Send at most `packets_per_second` announcements and goodbyes, for links with
+    /// little airtime to spare. Answers to queries are not limited, and are sent before
+    /// any waiting announcements.
+    ///
+    /// The responder's runtime needs the tokio time driver enabled to wake up when the
+    /// next announcement is due.
+    ///
+    /// # Panics
+    ///
+    /// If `packets_per_second` is zero.
+    pub fn announce_rate(mut self, packets_per_second: u32) -> Self {
+        assert!(
+            packets_per_second > 0,
+            "announce rate must be at least one packet per second"
+        );
+        self.config.announce_rate = Some(packets_per_second);
+        self
+    }
+
+    /// Send each announcement `count` times, first straight away and then after one
+    /// second, with the interval doubling each time, as RFC 6762 section 8.3
+    /// describes. Defaults to 2, the least the RFC allows. Repeats of an announcement
+    /// are dropped once the service is announced again or withdrawn.
+    ///
+    /// The responder's runtime needs the tokio time driver enabled to wake up when the
+    /// next repeat is due.
+    ///
+    /// # Panics
+    ///
+    /// If `count` is not between 1 and 8.
+    pub fn announcements(mut self, count: u32) -> Self {
+        assert!(
+            (1..=MAX_ANNOUNCEMENTS).contains(&count),
+            "announcements must be sent 1 to 8 times"
+        );
+        self.config.announcements = Some(count);
+        self
+    }
+
+    /// Whether to listen and answer over IPv4. Defaults to true, and failing to bind
+    /// the IPv4 socket is an error.
+    pub fn enable_ipv4(mut self, enable: bool) -> Self {
+        self.config.disable_ipv4 = !enable;
+        self
+    }
+
+    /// Whether to listen and answer over IPv6. Defaults to true, in which case failing
+    /// to bind the IPv6 socket is only logged, unless IPv4 is disabled.
+    pub fn enable_ipv6(mut self, enable: bool) -> Self {
+        self.config.disable_ipv6 = !enable;
+        self
+    }
+
+    /// Whether unicast responses to QU questions repeat the question, for clients
+    /// which ignore answers without it. Responses to legacy resolvers always do, as
+    /// RFC 6762 section 6.7 requires. Defaults to false.
+    pub fn echo_question(mut self, echo: bool) -> Self {
+        self.config.echo_question = echo;
+        self
+    }
+
+    /// Whether names in the packets sent point at earlier copies of them where
+    /// possible (RFC 1035 section 4.1.4). Defaults to true. Like
+    /// [`cache_flush`](ResponderBuilder::cache_flush), this pins down what devices on
+    /// the network see, so that a change to the encoding is opted into rather than
+    /// arriving with an upgrade. Turn it off for devices which can't follow pointers.
+    pub fn compress_names(mut self, compress: bool) -> Self {
+        self.config.disable_name_compression = !compress;
+        self
+    }
+
+    /// Whether records only we publish carry the cache-flush bit
+    /// (RFC 6762 section 10.2), so that caches replace rather than add to what they
+    /// hold for the name. Defaults to true; turning it off sends every record as a
+    /// shared one. Answers to legacy resolvers never carry it.
+    pub fn cache_flush(mut self, cache_flush: bool) -> Self {
+        self.config.disable_cache_flush = !cache_flush;
+        self
+    }
+
+    /// Whether answers to service type enumeration, queries for
+    /// `_services._dns-sd._udp.local`, also list the instances of each type with their
+    /// PTR records, as additional records since they weren't asked about. Browsers such as `dns-sd -B _services._dns-sd._udp` then see every
+    /// service without browsing each type. Defaults to false, as RFC 6763 section 9
+    /// only asks for the types.
+    pub fn enumerate_instances(mut self, enumerate: bool) -> Self {
+        self.config.enumerate_instances = enumerate;
+        self
+    }
+
+    /// Whether answers to service type enumeration also list the instances of each
+    /// type with their SRV and TXT records, and the addresses of the host, all as
+    /// additional records, saving clients such as `avahi-browse -a`, which resolve
+    /// every instance straight away, a round of queries. Implies
+    /// [`enumerate_instances`](ResponderBuilder::enumerate_instances). Defaults to
+    /// false, as the answers grow with every service registered.
+    pub fn enumerate_instance_records(mut self, enumerate: bool) -> Self {
+        self.config.enumerate_instance_records = enumerate;
+        self
+    }
+
+    /// Number services 1, 2, 3... instead of giving them random ids, to make debug
+    /// logs, which name each service's id and instance name as it is registered,
+    /// renamed or unregistered, easier to follow. Defaults to false.
+    pub fn sequential_service_ids(mut self, sequential: bool) -> Self {
+        self.config.sequential_ids = sequential;
+        self
+    }
+
+    /// Whether to remember the records other responders send, for
+    /// [`Responder::cached_records`](crate::Responder::cached_records). Records are
+    /// dropped when they expire, or earlier once queries for them stop being answered
+    /// (RFC 6762 section 10.5). Defaults to false.
+    pub fn cache_records(mut self, cache: bool) -> Self {
+        self.config.cache_records = cache;
+        self
+    }
+
+    /// Parse queries and build their answers on `workers` threads for each address
+    /// family, for responders which get more queries than one task keeps up with.
+    /// Sending, announcements and records from other responders stay on the task,
+    /// which also answers queries itself while the workers are busy. Defaults to 0,
+    /// answering everything on the task.
+    ///
+    /// Only used by a [`Responder`], as an [`Engine`] leaves threads to its caller.
+    pub fn answer_workers(mut self, workers: usize) -> Self {
+        self.config.answer_workers = workers;
+        self
+    }
+
+    /// Whether to answer queries from sources which are neither link-local nor within
+    /// the subnet of one of our interfaces. Defaults to false, as
+    /// [RFC 6762 section 11](https://www.rfc-editor.org/rfc/rfc6762#section-11) asks,
+    /// so that routed or spoofed queries learn nothing about the services.
+    pub fn accept_off_link_queries(mut self, accept: bool) -> Self {
+        self.config.accept_off_link_queries = accept;
+        self
+    }
+
+    /// Whether to register and look up instance names in Unicode Normalization Form C,
+    /// so that a name in decomposed form, as sent by macOS, matches the same name
+    /// composed. Defaults to true.
+    #[cfg(feature = "unicode")]
+    pub fn normalize_names(mut self, normalize: bool) -> Self {
+        self.config.disable_normalization = !normalize;
+        self
+    }
+
+    /// Drop responses which could not be sent within `max_response_delay` of the
+    /// query arriving, as the querier has likely given up. Defaults to 2 seconds.
+    pub fn max_response_delay(mut self, max_response_delay: Duration) -> Self {
+        self.config.max_response_delay = Some(max_response_delay);
+        self
+    }
+
+    /// How many responses may wait to be sent per address family. Responses to
+    /// queries arriving while the queue is full are dropped, so that a flood of
+    /// queries can't make the responder buffer without bound. Defaults to 256.
+    pub fn max_queued_responses(mut self, max_queued_responses: usize) -> Self {
+        self.config.max_queued_responses = Some(max_queued_responses);
+        self
+    }
+
+    /// How many bytes the state kept about other hosts may take altogether: the
+    /// cached records, the recent queries and the conflicts already reported. Beyond
+    /// it the least recently used entries are dropped, so that a hostile network
+    /// can't make the responder grow without bound. Defaults to 1 MiB.
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.config.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Coordinate with other responders on this host which use the same `path`.
+    ///
+    /// Only the first of them to claim `path` answers for the host's addresses, the
+    /// others only publish their own services. If it exits, another takes over.
+    #[cfg(unix)]
+    pub fn local_coordination<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config.local_coordination = Some(path.into());
+        self
+    }
+
+    /// Create the responder's sockets in the network namespace at `path`, such as
+    /// `/var/run/netns/<name>` or `/proc/<pid>/ns/net`, rather than the one the
+    /// process runs in, e.g. to advertise inside a container's network.
+    ///
+    /// The addresses published, and the subnets queries are accepted from, are those
+    /// of the namespace's interfaces too, unless given with
+    /// [`host_data`](Self::host_data). A thread stays in the namespace to list them,
+    /// entered along with binding the sockets, so that this keeps working after the
+    /// capability is given up, see [`PreparedSockets`].
+    ///
+    /// Only supported on Linux, where entering the namespace needs the
+    /// `CAP_SYS_ADMIN` capability. Starting fails with
+    /// [`Error::NetworkNamespace`] if it can't be entered, and with
+    /// [`Error::NetworkNamespaceUnsupported`] on other platforms.
+    pub fn network_namespace<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config.network_namespace = Some(NetworkNamespace::Path(path.into()));
+        self
+    }
+
+    /// Like [`network_namespace`](Self::network_namespace), with an open file of the
+    /// namespace instead of its path.
+    #[cfg(unix)]
+    pub fn network_namespace_fd(mut self, fd: OwnedFd) -> Self {
+        self.config.network_namespace = Some(NetworkNamespace::Fd(Arc::new(fd)));
+        self
+    }
+
+    /// Call `on_query` for every question received, e.g. for debugging or analytics.
+    ///
+    /// The callback runs on the responder task, or a worker thread with
+    /// [`answer_workers`](ResponderBuilder::answer_workers), and should return quickly.
+    pub fn on_query<F>(mut self, on_query: F) -> Self
+    where
+        F: Fn(QueryEvent) + Send + Sync + 'static,
+    {
+        self.config.on_query = Some(Arc::new(on_query));
+        self
+    }
+
+    /// Call `on_address_conflict` when another responder publishes one of our addresses
+    /// under a name which isn't ours. Only records seen on the network are compared,
+    /// nothing is sent to check.
+    ///
+    /// The callback runs on the responder task and should return quickly.
+    pub fn on_address_conflict<F>(mut self, on_address_conflict: F) -> Self
+    where
+        F: Fn(AddressConflict) + Send + Sync + 'static,
+    {
+        self.config.on_address_conflict = Some(Arc::new(on_address_conflict));
+        self
+    }
+
+    /// Call `on_hostname_conflict` when another responder publishes our hostname, or
+    /// one of its aliases, with an address which isn't ours. Only records seen on the
+    /// network are compared. Whether or not this is set, a claimed hostname is given
+    /// up for one picked by the [`rename_strategy`](ResponderBuilder::rename_strategy),
+    /// under which the services are probed for and announced again (RFC 6762
+    /// section 9). Aliases added with
+    /// [`Responder::add_hostname_alias`](crate::Responder::add_hostname_alias) are
+    /// given up, those of the [`HostData`](crate::HostData) are kept.
+    ///
+    /// The callback runs on the responder task and should return quickly.
+    pub fn on_hostname_conflict<F>(mut self, on_hostname_conflict: F) -> Self
+    where
+        F: Fn(HostnameConflict) + Send + Sync + 'static,
+    {
+        self.config.on_hostname_conflict = Some(Arc::new(on_hostname_conflict));
+        self
+    }
+
+    /// Call `on_send_failure` when sending has kept failing for a while, e.g. because a
+    /// firewall refuses the packets. Sending pauses after such errors, for longer each
+    /// time, and packets which still can't be sent after a few attempts are dropped.
+    ///
+    /// The callback runs on the responder task and should return quickly.
+    pub fn on_send_failure<F>(mut self, on_send_failure: F) -> Self
+    where
+        F: Fn(SendFailure) + Send + Sync + 'static,
+    {
+        self.config.on_send_failure = Some(Arc::new(on_send_failure));
+        self
+    }
+
+    /// Call `on_packet_panic` when handling a received packet panics, e.g. on a bug
+    /// triggered by a malformed packet. The panic is caught and the packet dropped, so
+    /// that one packet can't stop the responder advertising; it is logged as an error
+    /// whether or not this is set.
+    ///
+    /// The callback runs on the responder task and should return quickly.
+    pub fn on_packet_panic<F>(mut self, on_packet_panic: F) -> Self
+    where
+        F: Fn(PacketPanic) + Send + Sync + 'static,
+    {
+        self.config.on_packet_panic = Some(Arc::new(on_packet_panic));
+        self
+    }
+
+    /// Call `on_packet_dropped` with the reason whenever a received packet is dropped
+    /// before being answered or learned from, e.g. because it can't be parsed or came
+    /// from off the local link. Saves turning on trace logs to see why a querier gets
+    /// no answer. The reasons are counted in the `Metrics` as well.
+    ///
+    /// The callback runs on the responder task and should return quickly.
+    pub fn on_packet_dropped<F>(mut self, on_packet_dropped: F) -> Self
+    where
+        F: Fn(PacketDropped) + Send + Sync + 'static,
+    {
+        self.config.on_packet_dropped = Some(Arc::new(on_packet_dropped));
+        self
+    }
+
+    /// Call `on_service_event` when a registered service changes without being asked
+    /// to, e.g. when it is renamed.
+    pub fn on_service_event<F>(mut self, on_service_event: F) -> Self
+    where
+        F: Fn(ServiceEvent) + Send + Sync + 'static,
+    {
+        self.config.on_service_event = Some(Arc::new(on_service_event));
+        self
+    }
+
+    /// How to rename a service whose instance name is already taken, and the host
+    /// once another claims its hostname, whose first label is renamed. Defaults to
+    /// [`NumericSuffix`].
+    pub fn rename_strategy<R: RenameStrategy + 'static>(mut self, strategy: R) -> Self {
+        self.config.rename_strategy = Some(Arc::new(strategy));
+        self
+    }
+
+    /// How the registered services are stored. Defaults to [`ServiceStorage::Hashed`].
+    pub fn service_storage(mut self, storage: ServiceStorage) -> Self {
+        self.config.service_storage = storage;
+        self
+    }
+
+    /// Start from a snapshot taken with [`Responder::snapshot`].
+    ///
+    /// Services registered again under a name from the snapshot keep their previous
+    /// id, and the snapshot's hostname is used unless one was set explicitly. The
+    /// hostname is announced again as soon as the responder starts, as others may
+    /// still have it cached. Snapshots older than [`SNAPSHOT_MAX_AGE`] are ignored,
+    /// and the names of a snapshot are forgotten once it reaches that age.
+    #[cfg(feature = "snapshot")]
+    pub fn restore(mut self, snapshot: Snapshot) -> Self {
+        if snapshot.age() > SNAPSHOT_MAX_AGE {
+            debug!("ignoring snapshot taken {:?} ago", snapshot.age());
+        } else {
+            self.config.snapshot = Some(snapshot);
+        }
+        self
+    }
+
+    /// Like [`Responder::preflight`], but only checking the interfaces the responder
+    /// would join the mDNS group on, see [`interfaces`](ResponderBuilder::interfaces)
+    /// and [`allow_list`](ResponderBuilder::allow_list).
+    pub fn preflight(&self, timeout: Duration) -> PreflightReport {
+        let (allowed, named) = (self.config.join_allow_list(), &self.config.interfaces);
+        let join = |family| allow::join_interfaces(allowed, named, family);
+        // Every interface is checked if either family joins all of them
+        let interfaces = match (join(IpFamily::V4), join(IpFamily::V6)) {
+            (Ok(mut v4), Ok(v6)) if !v4.is_empty() && !v6.is_empty() => {
+                for name in v6 {
+                    if !v4.contains(&name) {
+                        v4.push(name);
+                    }
+                }
+                v4
+            }
+            (Ok(_), Ok(_)) => Vec::new(),
+            _ => named.clone(),
+        };
+        crate::preflight::run(&interfaces, timeout)
+    }
+
+    /// Bind the sockets of the responder this builder would start, to build it from
+    /// later with one of the `*_with_sockets` methods, once the privileges binding
+    /// needed are given up. See [`PreparedSockets`].
+    pub fn prepare_sockets(&self) -> Result<PreparedSockets, Error> {
+        PreparedSockets::bind(&self.config)
+    }
+
+    /// Spawn the `Responder` task on a new os thread.
+    #[cfg(feature = "responder")]
+    pub fn spawn_thread(self) -> Result<Responder, Error> {
+        Responder::spawn_thread_with_config(self.config, None)
+    }
+
+    /// Like [`spawn_thread`](ResponderBuilder::spawn_thread), with sockets from
+    /// [`prepare_sockets`](ResponderBuilder::prepare_sockets).
+    #[cfg(feature = "responder")]
+    pub fn spawn_thread_with_sockets(self, sockets: PreparedSockets) -> Result<Responder, Error> {
+        Responder::spawn_thread_with_config(self.config, Some(sockets))
+    }
+
+    /// Spawn the `Responder` task with the provided tokio `Handle`.
+    ///
+    /// The runtime needs both the IO and time drivers, as from
+    /// [`enable_all`](tokio::runtime::Builder::enable_all): each announcement is
+    /// repeated on a timer, see [`announcements`](ResponderBuilder::announcements), and
+    /// tokio panics creating a timer on a runtime without the time driver.
+    #[cfg(feature = "responder")]
+    pub fn spawn(self, handle: &Handle) -> Result<Responder, Error> {
+        let (responder, task) = self.build()?;
+        handle.spawn(task);
+        Ok(responder)
+    }
+
+    /// Create the `Responder` on the default tokio handle, leaving the task to the caller.
+    /// Its runtime needs both the IO and time drivers, see
+    /// [`spawn`](ResponderBuilder::spawn).
+    #[cfg(feature = "responder")]
+    pub fn build(self) -> Result<(Responder, ResponderTask), Error> {
+        let runner = Runner::Handle(Handle::current());
+        Responder::with_config::<tokio::net::UdpSocket>(self.config, None, runner)
+    }
+
+    /// Like [`build`](ResponderBuilder::build), with sockets from
+    /// [`prepare_sockets`](ResponderBuilder::prepare_sockets).
+    #[cfg(feature = "responder")]
+    pub fn build_with_sockets(
+        self,
+        sockets: PreparedSockets,
+    ) -> Result<(Responder, ResponderTask), Error> {
+        let runner = Runner::Handle(Handle::current());
+        Responder::with_config::<tokio::net::UdpSocket>(self.config, Some(sockets), runner)
+    }
+
+    /// Create the `Responder` with sockets and timers of `T`, leaving the task to the
+    /// caller, who may poll it on any executor `T` works with. With the `async-io`
+    /// feature, `async_io::Async<std::net::UdpSocket>` works with all of them, such as
+    /// async-std or smol. See [`Transport`].
+    #[cfg(feature = "responder")]
+    pub fn build_with_transport<T: Transport>(self) -> Result<(Responder, ResponderTask), Error> {
+        Responder::with_config::<T>(self.config, None, Runner::Caller)
+    }
+
+    /// Create the `Responder` without sockets, leaving the task to the caller. Packets
+    /// are sent through `sink`, and received ones passed to
+    /// [`Responder::feed_packet`], for environments without UDP sockets of their own
+    /// such as simulators, userspace network stacks and tests. The task uses tokio's
+    /// timers, so must be polled on a tokio runtime with time enabled.
+    #[cfg(feature = "responder")]
+    pub fn build_with_sink<S: PacketSink>(
+        self,
+        sink: S,
+    ) -> Result<(Responder, ResponderTask), Error> {
+        Responder::with_sink(self.config, Arc::new(Mutex::new(sink)))
+    }
+
+    /// Create an [`Engine`], for callers which do their own I/O.
+    pub fn build_engine(self) -> Result<Engine, Error> {
+        Engine::with_config(self.config)
+    }
+
+    /// Create a `SyncResponder`, which is driven by the caller instead of an async runtime.
+    #[cfg(feature = "sync")]
+    pub fn build_sync(self) -> Result<SyncResponder, Error> {
+        SyncResponder::with_config(self.config, None)
+    }
+
+    /// Like [`build_sync`](ResponderBuilder::build_sync), with sockets from
+    /// [`prepare_sockets`](ResponderBuilder::prepare_sockets).
+    #[cfg(feature = "sync")]
+    pub fn build_sync_with_sockets(self, sockets: PreparedSockets) -> Result<SyncResponder, Error> {
+        SyncResponder::with_config(self.config, Some(sockets))
+    }
+}
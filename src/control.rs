@@ -0,0 +1,311 @@
+//! A unix socket for debugging tools to inspect and poke a running responder, like
+//! the introspection of `avahi-daemon`.
+
+use crate::responder::WeakResponder;
+use crate::Responder;
+use log::{debug, warn, LevelFilter};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long a client may stay silent before it is disconnected
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// JSON-RPC 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// The responder has shut down since the server was started
+const SHUT_DOWN: i64 = -32000;
+
+/// The control socket of a responder, started with [`Responder::serve_control`].
+/// Stops listening and removes the socket when dropped.
+///
+/// Clients send one JSON-RPC 2.0 request per line and get one response per line,
+/// e.g. with `socat - UNIX-CONNECT:/run/mdns.sock`:
+///
+/// ```text
+/// {"jsonrpc":"2.0","id":1,"method":"list-services"}
+/// {"jsonrpc":"2.0","id":1,"result":[{"id":3,"type":"_http._tcp.local","name":"web._http._tcp.local","port":80,"txt":["path=/"]}]}
+/// ```
+///
+/// The methods are:
+///
+/// - `list-services`: the services published, with their ids, names, ports and TXT
+///   entries
+/// - `stats`: the [`Metrics`](crate::Metrics) of the responder
+/// - `reannounce`: announce every service again, returning how many there are
+/// - `set-log-level`: set the maximum level of the `log` crate to
+///   `{"level": "debug"}`, or any other level name
+pub struct ControlServer {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ControlServer {
+    pub(crate) fn start(responder: &Responder, path: &Path) -> io::Result<ControlServer> {
+        let listener = bind(path)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let responder = WeakResponder::new(responder);
+        let thread = {
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("mdns-control".into())
+                .spawn(move || accept_loop(listener, responder, &stop))?
+        };
+        debug!("serving control requests on {:?}", path);
+        Ok(ControlServer {
+            path: path.to_owned(),
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Where the socket is
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the accept loop to see it should stop
+        let _ = UnixStream::connect(&self.path);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Bind `path`, replacing a socket nobody listens on any more
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(ref err) if err.kind() == ErrorKind::AddrInUse => match UnixStream::connect(path) {
+            Err(ref err) if err.kind() == ErrorKind::ConnectionRefused => {
+                debug!("removing stale control socket {:?}", path);
+                fs::remove_file(path)?;
+                UnixListener::bind(path)
+            }
+            _ => Err(io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("{:?} is in use", path),
+            )),
+        },
+        bound => bound,
+    }
+}
+
+fn accept_loop(listener: UnixListener, responder: WeakResponder, stop: &AtomicBool) {
+    let responder = Arc::new(responder);
+    for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("failed to accept control connection: {}", err);
+                continue;
+            }
+        };
+        let responder = responder.clone();
+        let spawned = thread::Builder::new()
+            .name("mdns-control-client".into())
+            .spawn(move || {
+                if let Err(err) = serve(stream, &responder) {
+                    debug!("control connection closed: {}", err);
+                }
+            });
+        if let Err(err) = spawned {
+            warn!("failed to start control connection thread: {}", err);
+        }
+    }
+}
+
+fn serve(stream: UnixStream, responder: &WeakResponder) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut response = serde_json::to_vec(&handle_line(&line, responder))?;
+        response.push(b'\n');
+        writer.write_all(&response)?;
+    }
+    Ok(())
+}
+
+/// The response to a request
+fn handle_line(line: &str, responder: &WeakResponder) -> Value {
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(err) => return error(Value::Null, PARSE_ERROR, &err.to_string()),
+    };
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method,
+        None => return error(id, INVALID_REQUEST, "missing method"),
+    };
+    let params = request.get("params").unwrap_or(&Value::Null);
+    let responder = match responder.upgrade() {
+        Some(responder) => responder,
+        None => return error(id, SHUT_DOWN, "the responder has shut down"),
+    };
+    match call(&responder, method, params) {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err((code, message)) => error(id, code, &message),
+    }
+}
+
+fn call(responder: &Responder, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+    match method {
+        "list-services" => Ok(list_services(responder)),
+        "stats" => serde_json::to_value(responder.metrics())
+            .map_err(|err| (INTERNAL_ERROR, err.to_string())),
+        "reannounce" => Ok(reannounce(responder).into()),
+        "set-log-level" => {
+            let level = params
+                .get("level")
+                .and_then(Value::as_str)
+                .ok_or((INVALID_PARAMS, "missing level".to_owned()))?;
+            let level: LevelFilter = level
+                .parse()
+                .map_err(|_| (INVALID_PARAMS, format!("unknown level {:?}", level)))?;
+            log::set_max_level(level);
+            Ok(Value::Null)
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("unknown method {:?}", method))),
+    }
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+fn list_services(responder: &Responder) -> Value {
+    let services = responder
+        .services
+        .read()
+        .unwrap_or_else(PoisonError::into_inner);
+    let list = services
+        .into_iter()
+        .map(|svc| {
+            json!({
+                "id": services.id_by_name(&svc.name),
+                "type": svc.typ.to_string(),
+                "name": svc.name.to_string(),
+                "port": svc.port,
+                "txt": txt_entries(&svc.txt),
+            })
+        })
+        .collect();
+    Value::Array(list)
+}
+
+/// The strings of encoded TXT data, with anything but UTF-8 replaced
+fn txt_entries(mut txt: &[u8]) -> Vec<String> {
+    let mut entries = Vec::new();
+    while let Some((&len, rest)) = txt.split_first() {
+        let len = (len as usize).min(rest.len());
+        if len > 0 {
+            entries.push(String::from_utf8_lossy(&rest[..len]).into_owned());
+        }
+        txt = &rest[len..];
+    }
+    entries
+}
+
+/// Announce every service again, in one group
+fn reannounce(responder: &Responder) -> usize {
+    let svcs: Vec<_> = responder
+        .services
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .into_iter()
+        .cloned()
+        .collect();
+    let count = svcs.len();
+    if count > 0 {
+        responder
+            .commands
+            .borrow()
+            .send_unsolicited_group(svcs, false, true, None);
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ResponderBuilder, ServiceBuilder};
+
+    fn request(stream: &mut BufReader<UnixStream>, line: &str) -> Value {
+        stream.get_mut().write_all(line.as_bytes()).unwrap();
+        stream.get_mut().write_all(b"\n").unwrap();
+        let mut response = String::new();
+        stream.read_line(&mut response).unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[test]
+    fn test_txt_entries() {
+        assert_eq!(txt_entries(b"\x03a=1\x00\x01b"), ["a=1", "b"]);
+        assert_eq!(txt_entries(b"\x05ab"), ["ab"]);
+    }
+
+    #[test]
+    fn test_control_socket() {
+        let path =
+            std::env::temp_dir().join(format!("libmdns-control-{}.sock", std::process::id()));
+        let responder = ResponderBuilder::new()
+            .hostname("control-test".into())
+            .spawn_thread()
+            .unwrap();
+        let _svc = responder
+            .register_service(ServiceBuilder::new("_http._tcp", "web", 80).txt(&["path=/"]));
+        let server = responder.serve_control(&path).unwrap();
+
+        let mut stream = BufReader::new(UnixStream::connect(&path).unwrap());
+        let listed = request(
+            &mut stream,
+            r#"{"jsonrpc":"2.0","id":1,"method":"list-services"}"#,
+        );
+        assert_eq!(listed["id"], 1);
+        assert_eq!(listed["result"][0]["name"], "web._http._tcp.local");
+        assert_eq!(listed["result"][0]["port"], 80);
+        assert_eq!(listed["result"][0]["txt"], json!(["path=/"]));
+
+        let stats = request(&mut stream, r#"{"jsonrpc":"2.0","id":2,"method":"stats"}"#);
+        assert!(stats["result"]["packets_answered"].is_u64());
+        let announced = request(
+            &mut stream,
+            r#"{"jsonrpc":"2.0","id":3,"method":"reannounce"}"#,
+        );
+        assert_eq!(announced["result"], 1);
+
+        let unknown = request(&mut stream, r#"{"jsonrpc":"2.0","id":4,"method":"reboot"}"#);
+        assert_eq!(unknown["error"]["code"], METHOD_NOT_FOUND);
+        let level = request(
+            &mut stream,
+            r#"{"jsonrpc":"2.0","id":5,"method":"set-log-level","params":{"level":"loud"}}"#,
+        );
+        assert_eq!(level["error"]["code"], INVALID_PARAMS);
+        assert_eq!(request(&mut stream, "{")["error"]["code"], PARSE_ERROR);
+
+        drop(server);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,155 @@
+//! Agreement between responders on one host about which of them answers for its addresses.
+
+#[cfg(unix)]
+use log::{debug, trace, warn};
+#[cfg(unix)]
+use std::io::ErrorKind;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::sync::{Mutex, PoisonError};
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+
+use crate::config::ResponderConfig;
+
+/// How often a responder which doesn't own the address records checks whether the
+/// owner has gone away.
+#[cfg(unix)]
+const CLAIM_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Decides whether this responder publishes the host's A / AAAA records.
+///
+/// Without local coordination configured, it always does.
+pub struct AddressOwner {
+    #[cfg(unix)]
+    claim: Option<Mutex<Claim>>,
+}
+
+impl AddressOwner {
+    pub fn new(config: &ResponderConfig) -> AddressOwner {
+        #[cfg(unix)]
+        {
+            let claim = config.local_coordination.as_ref().map(|path| {
+                let mut claim = Claim {
+                    path: path.clone(),
+                    listener: None,
+                    last_attempt: Instant::now(),
+                };
+                claim.try_claim();
+                Mutex::new(claim)
+            });
+            AddressOwner { claim }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = config;
+            AddressOwner {}
+        }
+    }
+
+    pub fn owns_addresses(&self) -> bool {
+        #[cfg(unix)]
+        if let Some(ref claim) = self.claim {
+            return claim.lock().unwrap_or_else(PoisonError::into_inner).is_owner();
+        }
+        true
+    }
+}
+
+/// The owner keeps a unix socket bound at `path`, which the others probe.
+#[cfg(unix)]
+struct Claim {
+    path: PathBuf,
+    listener: Option<UnixListener>,
+    last_attempt: Instant,
+}
+
+#[cfg(unix)]
+impl Claim {
+    fn is_owner(&mut self) -> bool {
+        if self.listener.is_none() && self.last_attempt.elapsed() >= CLAIM_RETRY_INTERVAL {
+            self.try_claim();
+        }
+
+        match self.listener {
+            Some(ref listener) => {
+                // Probes only need to connect, don't let them fill the backlog
+                while listener.accept().is_ok() {}
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn try_claim(&mut self) {
+        self.last_attempt = Instant::now();
+        match self.bind() {
+            Err(ref err) if err.kind() == ErrorKind::AddrInUse => {
+                match UnixStream::connect(&self.path) {
+                    Ok(_) => trace!("address records are owned through {:?}", self.path),
+                    Err(ref err) if err.kind() == ErrorKind::ConnectionRefused => {
+                        debug!("removing stale coordination socket {:?}", self.path);
+                        if let Err(err) = std::fs::remove_file(&self.path) {
+                            warn!("failed to remove {:?}: {}", self.path, err);
+                            return;
+                        }
+                        if let Err(err) = self.bind() {
+                            warn!("failed to bind {:?}: {}", self.path, err);
+                        }
+                    }
+                    Err(err) => warn!("failed to connect to {:?}: {}", self.path, err),
+                }
+            }
+            Err(err) => warn!("failed to bind {:?}: {}", self.path, err),
+            Ok(()) => (),
+        }
+    }
+
+    fn bind(&mut self) -> std::io::Result<()> {
+        let listener = UnixListener::bind(&self.path)?;
+        listener.set_nonblocking(true)?;
+        debug!("claimed address records through {:?}", self.path);
+        self.listener = Some(listener);
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if self.listener.take().is_some() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_owner() {
+        let path = std::env::temp_dir().join(format!("libmdns-test-{}.sock", std::process::id()));
+        let config = ResponderConfig {
+            local_coordination: Some(path.clone()),
+            ..ResponderConfig::default()
+        };
+
+        let first = AddressOwner::new(&config);
+        let second = AddressOwner::new(&config);
+        assert!(first.owns_addresses());
+        assert!(!second.owns_addresses());
+
+        drop(first);
+        let mut claim = second.claim.as_ref().unwrap().lock().unwrap_or_else(PoisonError::into_inner);
+        claim.try_claim();
+        assert!(claim.is_owner());
+
+        drop(claim);
+        drop(second);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,334 @@
+use crate::budget::{Lru, MemoryBudget};
+use crate::dns_parser::{Canonical, Packet, Question};
+use crate::error::IpFamily;
+use crate::MDNS_PORT;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Copies of one packet arriving on several sockets or interfaces show up well within
+/// this, while RFC 6762 section 5.2 requires queriers to wait at least a second before
+/// repeating a query.
+const DUPLICATE_WINDOW: Duration = Duration::from_millis(500);
+
+/// Our own packets come back through multicast loopback well within this
+const SENT_WINDOW: Duration = Duration::from_secs(5);
+
+/// At most this many sent packets are remembered
+const MAX_SENT: usize = 256;
+
+/// A record isn't multicast again within this of the last time, RFC 6762 section 6
+pub const MULTICAST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Or within this when defending it against a probe
+pub const PROBE_MULTICAST_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Recently answered queries are shared between the sockets of a responder.
+pub type SeenQueries = Arc<Mutex<SeenQueriesInner>>;
+
+pub struct SeenQueriesInner {
+    /// when each query was first seen, oldest first as they are never touched again
+    seen: Lru<u64, Instant>,
+}
+
+impl SeenQueriesInner {
+    pub fn new(budget: &Arc<MemoryBudget>) -> Self {
+        SeenQueriesInner {
+            seen: Lru::new(budget),
+        }
+    }
+
+    /// Remember a query, returns whether the same query was already seen in the last
+    /// `DUPLICATE_WINDOW`. A querier may send it from its IPv4 and IPv6 addresses, so
+    /// these don't tell queries apart, except for legacy unicast queries which are
+    /// only answered to their source, RFC 6762 section 6.7.
+    pub fn is_duplicate(&mut self, packet: &Packet, source: SocketAddr, now: Instant) -> bool {
+        while let Some((&key, &seen_at)) = self.seen.oldest() {
+            if now.duration_since(seen_at) < DUPLICATE_WINDOW {
+                break;
+            }
+            self.seen.remove(&key);
+        }
+
+        let key = query_key(packet, source);
+        if self.seen.contains_key(&key) {
+            return true;
+        }
+        self.seen.insert(key, now);
+        false
+    }
+}
+
+/// When the answers to questions were last multicast, shared between the sockets of
+/// a responder. Each address family is kept apart, as the hosts listening on one of
+/// them don't see the answers multicast on the other.
+pub type RecentMulticasts = Arc<Mutex<RecentMulticastsInner>>;
+
+pub struct RecentMulticastsInner {
+    /// when the answer to each question was last multicast, oldest first
+    multicast: Lru<u64, Instant>,
+}
+
+impl RecentMulticastsInner {
+    pub fn new(budget: &Arc<MemoryBudget>) -> Self {
+        RecentMulticastsInner {
+            multicast: Lru::new(budget),
+        }
+    }
+
+    /// Whether the answer to `question` was multicast over `family` in the last
+    /// `interval`
+    pub fn is_recent(
+        &mut self,
+        family: IpFamily,
+        question: &Question,
+        interval: Duration,
+        now: Instant,
+    ) -> bool {
+        while let Some((&key, &multicast_at)) = self.multicast.oldest() {
+            if now.duration_since(multicast_at) < MULTICAST_INTERVAL {
+                break;
+            }
+            self.multicast.remove(&key);
+        }
+
+        self.multicast
+            .update(&question_key(family, question), |&mut multicast_at| {
+                now.duration_since(multicast_at) < interval
+            })
+            .unwrap_or(false)
+    }
+
+    /// Remember that the answer to `question` has been multicast over `family`
+    pub fn record(&mut self, family: IpFamily, question: &Question, now: Instant) {
+        self.multicast.insert(question_key(family, question), now);
+    }
+}
+
+/// Packets recently sent by a responder, shared between its sockets, to recognise our
+/// own packets delivered back by multicast loopback, possibly from an address we don't
+/// know as ours such as a temporary IPv6 address.
+pub type SentPackets = Arc<Mutex<SentPacketsInner>>;
+
+pub struct SentPacketsInner {
+    /// keyed per responder, so that the packets of another responder in this process
+    /// don't pass for ours
+    keys: RandomState,
+    sent: VecDeque<(Instant, u64)>,
+}
+
+impl SentPacketsInner {
+    pub fn new() -> Self {
+        SentPacketsInner {
+            keys: RandomState::new(),
+            sent: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, packet: &[u8], now: Instant) {
+        self.expire(now);
+        if self.sent.len() >= MAX_SENT {
+            self.sent.pop_front();
+        }
+        let key = self.key(packet);
+        self.sent.push_back((now, key));
+    }
+
+    /// Whether `packet` is one we sent in the last `SENT_WINDOW`
+    pub fn is_own(&mut self, packet: &[u8], now: Instant) -> bool {
+        self.expire(now);
+        let key = self.key(packet);
+        self.sent.iter().any(|&(_, sent)| sent == key)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(sent_at, _)) = self.sent.front() {
+            if now.duration_since(sent_at) < SENT_WINDOW {
+                break;
+            }
+            self.sent.pop_front();
+        }
+    }
+
+    fn key(&self, packet: &[u8]) -> u64 {
+        let mut hasher = self.keys.build_hasher();
+        // Not `hash_one`, which needs Rust 1.71
+        hasher.write(packet);
+        hasher.finish()
+    }
+}
+
+/// Drop questions which repeat an earlier one in the same packet with the same name,
+/// type and class, as some buggy clients send. A copy asking for a unicast response
+/// makes the one kept ask for it too. Returns the remaining questions and how many
+/// were dropped.
+pub fn dedup_questions(questions: Vec<Question>) -> (Vec<Question>, u64) {
+    let mut unique: Vec<Question> = Vec::with_capacity(questions.len());
+    let mut duplicates = 0;
+    for question in questions {
+        let earlier = unique.iter_mut().find(|other| {
+            other.qtype == question.qtype
+                && other.qclass == question.qclass
+                && &other.qname as &dyn Canonical == &question.qname as &dyn Canonical
+        });
+        match earlier {
+            Some(earlier) => {
+                earlier.qu |= question.qu;
+                duplicates += 1;
+            }
+            None => unique.push(question),
+        }
+    }
+    (unique, duplicates)
+}
+
+fn question_key(family: IpFamily, question: &Question) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    family.hash(&mut hasher);
+    (&question.qname as &dyn Canonical).hash(&mut hasher);
+    question.qtype.hash(&mut hasher);
+    question.qclass.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn query_key(packet: &Packet, source: SocketAddr) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    packet.header.id.hash(&mut hasher);
+    if source.port() != MDNS_PORT {
+        source.hash(&mut hasher);
+    }
+    for question in &packet.questions {
+        (&question.qname as &dyn Canonical).hash(&mut hasher);
+        question.qtype.hash(&mut hasher);
+        question.qclass.hash(&mut hasher);
+        question.qu.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::DEFAULT_MEMORY_BUDGET;
+    use crate::dns_parser::{Builder, Name, QueryClass, QueryType};
+    use crate::error::IpFamily::{V4, V6};
+    use crate::metrics::Counters;
+
+    #[test]
+    fn test_is_duplicate() {
+        let query = |name: &'static str| {
+            Builder::new_query(0, false)
+                .add_question(
+                    &Name::from_str(name).unwrap(),
+                    QueryType::PTR,
+                    QueryClass::IN,
+                )
+                .build()
+                .unwrap()
+        };
+        let http = query("_http._tcp.local");
+        let http = Packet::parse(&http).unwrap();
+        let ipp = query("_ipp._tcp.local");
+        let ipp = Packet::parse(&ipp).unwrap();
+        let source: SocketAddr = "192.0.2.1:5353".parse().unwrap();
+        let v6_source: SocketAddr = "[fe80::1%2]:5353".parse().unwrap();
+        let legacy: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let other_legacy: SocketAddr = "192.0.2.2:40000".parse().unwrap();
+
+        let counters = Arc::new(Counters::new());
+        let budget = Arc::new(MemoryBudget::new(DEFAULT_MEMORY_BUDGET, counters));
+        let mut seen = SeenQueriesInner::new(&budget);
+        let now = Instant::now();
+        assert!(!seen.is_duplicate(&http, source, now));
+        assert!(seen.is_duplicate(&http, source, now));
+        // Whichever address family it arrives over
+        assert!(seen.is_duplicate(&http, v6_source, now));
+        assert!(!seen.is_duplicate(&ipp, source, now));
+        // But each legacy querier gets its own answer
+        assert!(!seen.is_duplicate(&http, legacy, now));
+        assert!(seen.is_duplicate(&http, legacy, now));
+        assert!(!seen.is_duplicate(&http, other_legacy, now));
+        assert!(!seen.is_duplicate(&http, source, now + DUPLICATE_WINDOW));
+    }
+
+    #[test]
+    fn test_recent_multicasts() {
+        let packet = Builder::new_query(0, false)
+            .add_question(
+                &Name::from_str("_http._tcp.local").unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            )
+            .add_question(
+                &Name::from_str("_http._tcp.local").unwrap(),
+                QueryType::TXT,
+                QueryClass::IN,
+            )
+            .add_question(
+                &Name::from_str("_HTTP._TCP.local").unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        let packet = Packet::parse(&packet).unwrap();
+        let (ptr, txt) = (&packet.questions[0], &packet.questions[1]);
+        let upper_ptr = &packet.questions[2];
+
+        let counters = Arc::new(Counters::new());
+        let budget = Arc::new(MemoryBudget::new(DEFAULT_MEMORY_BUDGET, counters));
+        let mut recent = RecentMulticastsInner::new(&budget);
+        let now = Instant::now();
+        assert!(!recent.is_recent(V4, ptr, MULTICAST_INTERVAL, now));
+        recent.record(V4, ptr, now);
+        assert!(recent.is_recent(V4, ptr, MULTICAST_INTERVAL, now));
+        assert!(!recent.is_recent(V4, txt, MULTICAST_INTERVAL, now));
+        // Whatever case the name is asked in
+        assert!(recent.is_recent(V4, upper_ptr, MULTICAST_INTERVAL, now));
+
+        // A probe may be answered sooner
+        let later = now + PROBE_MULTICAST_INTERVAL;
+        assert!(recent.is_recent(V4, ptr, MULTICAST_INTERVAL, later));
+        assert!(!recent.is_recent(V4, ptr, PROBE_MULTICAST_INTERVAL, later));
+        let expired = now + MULTICAST_INTERVAL;
+        assert!(!recent.is_recent(V4, ptr, MULTICAST_INTERVAL, expired));
+
+        // Hosts on the other address family haven't seen it
+        assert!(!recent.is_recent(V6, ptr, MULTICAST_INTERVAL, now));
+    }
+
+    #[test]
+    fn test_sent_packets() {
+        let mut sent = SentPacketsInner::new();
+        let now = Instant::now();
+        sent.record(b"announcement", now);
+        assert!(sent.is_own(b"announcement", now));
+        assert!(!sent.is_own(b"other announcement", now));
+        assert!(!SentPacketsInner::new().is_own(b"announcement", now));
+        assert!(!sent.is_own(b"announcement", now + SENT_WINDOW));
+    }
+
+    #[test]
+    fn test_dedup_questions() {
+        // The same PTR question three times, the later ones compressed and one
+        // with the QU bit, as sent by a misbehaving client
+        let packet = b"\x00\x00\x00\x00\x00\x04\x00\x00\x00\x00\x00\x00\
+            \x05_http\x04_tcp\x05local\x00\x00\x0c\x00\x01\
+            \xc0\x0c\x00\x0c\x00\x01\
+            \xc0\x0c\x00\x0c\x80\x01\
+            \xc0\x0c\x00\x10\x00\x01";
+        let packet = Packet::parse(packet).unwrap();
+        assert_eq!(packet.questions.len(), 4);
+
+        let (questions, duplicates) = dedup_questions(packet.questions);
+        assert_eq!(duplicates, 2);
+        assert_eq!(questions.len(), 2);
+        assert_eq!(questions[0].qtype, QueryType::PTR);
+        // The copy with the QU bit still gets its unicast response
+        assert!(questions[0].qu);
+        assert_eq!(questions[1].qtype, QueryType::TXT);
+    }
+}
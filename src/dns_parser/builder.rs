@@ -1,12 +1,15 @@
+use std::collections::HashMap;
 use std::marker::PhantomData;
 
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 
-use super::{Header, Name, Opcode, QueryClass, QueryType, RRData, ResponseCode};
+use super::{
+    Header, Name, Opcode, Opt, Packet, QueryClass, QueryType, Question, RRData, ResourceRecord,
+    ResponseCode, Type, CACHE_FLUSH_BIT, QU_BIT,
+};
 
 pub enum Questions {}
 pub enum Answers {}
-#[allow(dead_code)]
 pub enum Nameservers {}
 pub enum Additional {}
 
@@ -29,6 +32,12 @@ impl MoveTo<Additional> for Nameservers {}
 pub struct Builder<S> {
     buf: Vec<u8>,
     max_size: Option<usize>,
+    /// offsets of the names already written, for compression
+    names: HashMap<Vec<u8>, u16>,
+    /// whether names point at earlier copies, see `set_compression`
+    compress: bool,
+    /// records for the additional section, written once the answers are complete
+    deferred: Option<Box<Builder<Additional>>>,
     _state: PhantomData<S>,
 }
 
@@ -41,7 +50,7 @@ impl Builder<Questions> {
     pub fn new_query(id: u16, recursion: bool) -> Builder<Questions> {
         let mut buf = Vec::with_capacity(512);
         let head = Header {
-            id: id,
+            id,
             query: true,
             opcode: Opcode::StandardQuery,
             authoritative: false,
@@ -57,8 +66,11 @@ impl Builder<Questions> {
         buf.extend([0u8; 12].iter());
         head.write(&mut buf[..12]);
         Builder {
-            buf: buf,
+            buf,
             max_size: Some(512),
+            names: HashMap::new(),
+            compress: true,
+            deferred: None,
             _state: PhantomData,
         }
     }
@@ -66,10 +78,10 @@ impl Builder<Questions> {
     pub fn new_response(id: u16, recursion: bool, authoritative: bool) -> Builder<Questions> {
         let mut buf = Vec::with_capacity(512);
         let head = Header {
-            id: id,
+            id,
             query: false,
             opcode: Opcode::StandardQuery,
-            authoritative: authoritative,
+            authoritative,
             truncated: false,
             recursion_desired: recursion,
             recursion_available: false,
@@ -82,25 +94,100 @@ impl Builder<Questions> {
         buf.extend([0u8; 12].iter());
         head.write(&mut buf[..12]);
         Builder {
-            buf: buf,
+            buf,
             max_size: Some(512),
+            names: HashMap::new(),
+            compress: true,
+            deferred: None,
             _state: PhantomData,
         }
     }
 }
 
+impl Builder<Answers> {
+    /// Creates a response carrying `questions`, the ones of the query it answers,
+    /// ready for answers. Legacy unicast responses must repeat the query's id and
+    /// questions (RFC 6762 section 6.7), which `build_split` repeats in every packet.
+    pub fn new_response_to<'a, 'b: 'a>(
+        id: u16,
+        authoritative: bool,
+        questions: impl IntoIterator<Item = &'a Question<'b>>,
+    ) -> Builder<Answers> {
+        Builder::new_response(id, false, authoritative)
+            .add_questions(questions)
+            .move_to()
+    }
+}
+
 impl<T> Builder<T> {
-    fn write_rr(&mut self, name: &Name, cls: QueryClass, ttl: u32, data: &RRData) {
-        name.write_to(&mut self.buf).unwrap();
-        self.buf.write_u16::<BigEndian>(data.typ() as u16).unwrap();
-        self.buf.write_u16::<BigEndian>(cls as u16).unwrap();
+    /// Write `name`, pointing at an earlier copy of any suffix of it
+    /// (RFC 1035 section 4.1.4)
+    fn write_name(&mut self, name: &Name) {
+        let labels: Vec<&[u8]> = name.labels().collect();
+        for i in 0..labels.len() {
+            if self.compress {
+                let suffix: Vec<u8> = labels[i..]
+                    .iter()
+                    .flat_map(|label| {
+                        Some(label.len() as u8)
+                            .into_iter()
+                            .chain(label.iter().cloned())
+                    })
+                    .collect();
+                if let Some(&offset) = self.names.get(&suffix) {
+                    self.buf.write_u16::<BigEndian>(0xc000 | offset).unwrap();
+                    return;
+                }
+                // Pointers only have 14 bits
+                if self.buf.len() < 0x4000 {
+                    self.names.insert(suffix, self.buf.len() as u16);
+                }
+            }
+            self.buf.push(labels[i].len() as u8);
+            self.buf.extend_from_slice(labels[i]);
+        }
+        self.buf.push(0);
+    }
+
+    /// Names in RDATA may be compressed too, see RFC 6762 section 18.14
+    fn write_rdata(&mut self, data: &RRData) {
+        match *data {
+            RRData::CNAME(ref name) | RRData::NS(ref name) | RRData::PTR(ref name) => {
+                self.write_name(name)
+            }
+            RRData::SRV {
+                priority,
+                weight,
+                port,
+                ref target,
+            } => {
+                self.buf.write_u16::<BigEndian>(priority).unwrap();
+                self.buf.write_u16::<BigEndian>(weight).unwrap();
+                self.buf.write_u16::<BigEndian>(port).unwrap();
+                self.write_name(target);
+            }
+            RRData::MX {
+                preference,
+                ref exchange,
+            } => {
+                self.buf.write_u16::<BigEndian>(preference).unwrap();
+                self.write_name(exchange);
+            }
+            _ => data.write_to(&mut self.buf).unwrap(),
+        }
+    }
+
+    fn write_rr(&mut self, name: &Name, cls: u16, ttl: u32, data: &RRData) {
+        self.write_name(name);
+        self.buf.write_u16::<BigEndian>(data.type_code()).unwrap();
+        self.buf.write_u16::<BigEndian>(cls).unwrap();
         self.buf.write_u32::<BigEndian>(ttl).unwrap();
 
         let size_offset = self.buf.len();
         self.buf.write_u16::<BigEndian>(0).unwrap();
 
         let data_offset = self.buf.len();
-        data.write_to(&mut self.buf).unwrap();
+        self.write_rdata(data);
         let data_size = self.buf.len() - data_offset;
 
         BigEndian::write_u16(
@@ -124,7 +211,7 @@ impl<T> Builder<T> {
     // TODO(tailhook) does the truncation make sense for TCP, and how
     // to treat it for EDNS0?
     pub fn build(mut self) -> Result<Vec<u8>, Vec<u8>> {
-        // TODO(tailhook) optimize labels
+        self.write_deferred();
         match self.max_size {
             Some(max_size) if self.buf.len() > max_size => {
                 Header::set_truncated(&mut self.buf[..12]);
@@ -141,14 +228,59 @@ impl<T> Builder<T> {
         Builder {
             buf: self.buf,
             max_size: self.max_size,
+            names: self.names,
+            compress: self.compress,
+            deferred: self.deferred,
             _state: PhantomData,
         }
     }
 
+    /// Write the records added with
+    /// [`add_deferred_additional`](Builder::add_deferred_additional) to the
+    /// additional section, leaving out those already in the packet
+    fn write_deferred(&mut self) {
+        let deferred = match self.deferred.take() {
+            Some(deferred) => deferred,
+            None => return,
+        };
+        let deferred_packet = match Packet::parse_lenient(&deferred.buf) {
+            Ok((packet, _)) => packet,
+            Err(_) => return,
+        };
+        let mut written: Vec<Vec<u8>> = match Packet::parse_lenient(&self.buf) {
+            Ok((packet, _)) => packet
+                .answers
+                .iter()
+                .chain(&packet.additional)
+                .map(record_bytes)
+                .collect(),
+            Err(_) => return,
+        };
+        for record in &deferred_packet.additional {
+            let bytes = record_bytes(record);
+            if written.contains(&bytes) {
+                continue;
+            }
+            written.push(bytes);
+            self.write_rr(&record.name, record_class(record), record.ttl, &record.data);
+            Header::inc_additional(&mut self.buf).expect("Too many additional answers");
+        }
+    }
+
     pub fn set_max_size(&mut self, max_size: Option<usize>) {
         self.max_size = max_size;
     }
 
+    /// Whether names are written as pointers to earlier copies of them where
+    /// possible (RFC 1035 section 4.1.4), which is the default
+    pub fn set_compression(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    pub fn answer_count(&self) -> u16 {
+        Header::answer_count(&self.buf)
+    }
+
     pub fn is_empty(&self) -> bool {
         Header::question_count(&self.buf) == 0
             && Header::answer_count(&self.buf) == 0
@@ -163,21 +295,53 @@ impl<T: MoveTo<Questions>> Builder<T> {
     /// # Panics
     ///
     /// * There are already 65535 questions in the buffer.
-    #[allow(dead_code)]
     pub fn add_question(
         self,
         qname: &Name,
         qtype: QueryType,
         qclass: QueryClass,
     ) -> Builder<Questions> {
+        self.write_question(qname, qtype, qclass as u16)
+    }
+
+    /// Adds a question asking for a unicast response (RFC 6762 section 5.4)
+    ///
+    /// # Panics
+    ///
+    /// * There are already 65535 questions in the buffer.
+    pub fn add_unicast_question(
+        self,
+        qname: &Name,
+        qtype: QueryType,
+        qclass: QueryClass,
+    ) -> Builder<Questions> {
+        self.write_question(qname, qtype, qclass as u16 | QU_BIT)
+    }
+
+    fn write_question(self, qname: &Name, qtype: QueryType, qclass: u16) -> Builder<Questions> {
         let mut builder = self.move_to::<Questions>();
 
-        qname.write_to(&mut builder.buf).unwrap();
-        builder.buf.write_u16::<BigEndian>(qtype as u16).unwrap();
-        builder.buf.write_u16::<BigEndian>(qclass as u16).unwrap();
+        builder.write_name(qname);
+        builder
+            .buf
+            .write_u16::<BigEndian>(u16::from(qtype))
+            .unwrap();
+        builder.buf.write_u16::<BigEndian>(qclass).unwrap();
         Header::inc_questions(&mut builder.buf).expect("Too many questions");
         builder
     }
+
+    /// Adds `questions`, e.g. to repeat those of a query in its response
+    pub fn add_questions<'a, 'b: 'a>(
+        self,
+        questions: impl IntoIterator<Item = &'a Question<'b>>,
+    ) -> Builder<Questions> {
+        let mut builder = self.move_to::<Questions>();
+        for question in questions {
+            builder = builder.add_question(&question.qname, question.qtype, question.qclass);
+        }
+        builder
+    }
 }
 
 impl<T: MoveTo<Answers>> Builder<T> {
@@ -187,9 +351,27 @@ impl<T: MoveTo<Answers>> Builder<T> {
         cls: QueryClass,
         ttl: u32,
         data: &RRData,
+    ) -> Builder<Answers> {
+        self.add_answer_with_cache_flush(name, cls, ttl, data, false)
+    }
+
+    /// Adds an answer, with the cache-flush bit set if `cache_flush` is true. It
+    /// marks records which only the sender publishes, so that receivers drop any
+    /// other copies they have cached (RFC 6762 section 10.2).
+    pub fn add_answer_with_cache_flush(
+        self,
+        name: &Name,
+        cls: QueryClass,
+        ttl: u32,
+        data: &RRData,
+        cache_flush: bool,
     ) -> Builder<Answers> {
         let mut builder = self.move_to::<Answers>();
 
+        let mut cls = cls as u16;
+        if cache_flush {
+            cls |= CACHE_FLUSH_BIT;
+        }
         builder.write_rr(name, cls, ttl, data);
         Header::inc_answers(&mut builder.buf).expect("Too many answers");
 
@@ -197,8 +379,180 @@ impl<T: MoveTo<Answers>> Builder<T> {
     }
 }
 
+impl Builder<Answers> {
+    /// Adds a record to the additional section, where it goes after all the answers,
+    /// including those added later. It is left out if the packet ends up carrying the
+    /// same record already, e.g. as the answer to another question.
+    pub fn add_deferred_additional(
+        mut self,
+        name: &Name,
+        cls: QueryClass,
+        ttl: u32,
+        data: &RRData,
+        cache_flush: bool,
+    ) -> Builder<Answers> {
+        let deferred = match self.deferred.take() {
+            Some(deferred) => *deferred,
+            None => {
+                let mut builder = Builder::new_response(0, false, false).move_to();
+                builder.set_max_size(None);
+                builder
+            }
+        };
+        let deferred = deferred.add_additional_with_cache_flush(name, cls, ttl, data, cache_flush);
+        self.deferred = Some(Box::new(deferred));
+        self
+    }
+
+    /// Adds the answers of `records` as [deferred
+    /// additional](Builder::add_deferred_additional) records, for records written by
+    /// code which only adds answers
+    pub fn add_deferred_additional_from(mut self, records: Builder<Answers>) -> Builder<Answers> {
+        let buf = match records.build() {
+            Ok(buf) | Err(buf) => buf,
+        };
+        if let Ok((packet, _)) = Packet::parse_lenient(&buf) {
+            for record in &packet.answers {
+                self = self.add_deferred_additional(
+                    &record.name,
+                    QueryClass::IN,
+                    record.ttl,
+                    &record.data,
+                    record.cache_flush,
+                );
+            }
+        }
+        self
+    }
+
+    /// Returns the final packets, with the answers spread over as many as needed
+    /// to keep each within the maximum size
+    ///
+    /// Every packet repeats the header and questions. An answer which doesn't fit
+    /// even on its own is sent alone in an oversized packet. Authority and additional
+    /// records follow the answers in the last packet, as far as they fit there; the
+    /// others are left out, which additional records may be (RFC 6762 section 18.14).
+    pub fn build_split(mut self) -> Vec<Vec<u8>> {
+        self.write_deferred();
+        let records = self.answer_count()
+            + Header::nameserver_count(&self.buf)
+            + Header::additional_count(&self.buf);
+        let max_size = match self.max_size {
+            Some(max_size) if self.buf.len() > max_size && records > 1 => max_size,
+            _ => return vec![self.buf],
+        };
+
+        // Compression pointers may refer to any earlier answer, so the answers
+        // are written again rather than copied. Records which can't be read back
+        // are dropped rather than sent broken.
+        let packet = match Packet::parse_lenient(&self.buf) {
+            Ok((packet, _)) => packet,
+            Err(_) => return vec![self.buf],
+        };
+        let mut packets = Vec::new();
+        let mut builder = self.start_split(&packet);
+        for answer in &packet.answers {
+            let cls = record_class(answer);
+            let start = builder.buf.len();
+            builder.write_rr(&answer.name, cls, answer.ttl, &answer.data);
+            if builder.buf.len() > max_size && builder.answer_count() > 0 {
+                builder.buf.truncate(start);
+                packets.push(builder.buf);
+                builder = self.start_split(&packet);
+                builder.write_rr(&answer.name, cls, answer.ttl, &answer.data);
+            }
+            Header::inc_answers(&mut builder.buf).expect("Too many answers");
+        }
+
+        builder.write_fitting(&packet.nameservers, max_size, Header::inc_nameservers);
+        builder.write_fitting(&packet.additional, max_size, Header::inc_additional);
+        packets.push(builder.buf);
+
+        packets
+    }
+
+    /// Write those of `records` which fit within `max_size`, counting them with `inc`
+    fn write_fitting(
+        &mut self,
+        records: &[ResourceRecord],
+        max_size: usize,
+        inc: fn(&mut [u8]) -> Option<u16>,
+    ) {
+        for record in records {
+            let start = self.buf.len();
+            self.write_rr(&record.name, record_class(record), record.ttl, &record.data);
+            if self.buf.len() > max_size {
+                self.buf.truncate(start);
+            } else {
+                inc(&mut self.buf).expect("Too many records");
+            }
+        }
+    }
+
+    /// Like [`build_split`](Builder::build_split), with `opt` at the end of every
+    /// packet and counted towards the maximum size
+    pub fn build_split_with_opt(mut self, opt: &Opt) -> Vec<Vec<u8>> {
+        let mut options = Vec::new();
+        opt.write_options(&mut options).unwrap();
+        // Root name, type, class, TTL and length
+        let opt_size = 11 + options.len();
+        self.max_size = self.max_size.map(|size| size.saturating_sub(opt_size));
+
+        self.build_split()
+            .into_iter()
+            .map(|buf| {
+                let builder = Builder::<Answers> {
+                    buf,
+                    max_size: None,
+                    names: HashMap::new(),
+                    compress: true,
+                    deferred: None,
+                    _state: PhantomData,
+                };
+                builder.add_opt(opt).buf
+            })
+            .collect()
+    }
+
+    /// An empty packet with the same header and questions
+    fn start_split(&self, packet: &Packet) -> Builder<Answers> {
+        let mut builder = Builder::<Questions> {
+            buf: self.buf[..12].to_vec(),
+            max_size: self.max_size,
+            names: HashMap::new(),
+            compress: self.compress,
+            deferred: None,
+            _state: PhantomData,
+        };
+        builder.buf[4..12].copy_from_slice(&[0; 8]);
+        builder.add_questions(&packet.questions).move_to()
+    }
+}
+
+/// The class field of `record`, with its cache-flush bit
+fn record_class(record: &ResourceRecord) -> u16 {
+    let mut cls = record.cls as u16;
+    if record.cache_flush {
+        cls |= CACHE_FLUSH_BIT;
+    }
+    cls
+}
+
+/// `record` written out on its own, to compare it with others
+fn record_bytes(record: &ResourceRecord) -> Vec<u8> {
+    let mut builder = Builder::<Answers> {
+        buf: Vec::new(),
+        max_size: None,
+        names: HashMap::new(),
+        compress: true,
+        deferred: None,
+        _state: PhantomData,
+    };
+    builder.write_rr(&record.name, record_class(record), record.ttl, &record.data);
+    builder.buf
+}
+
 impl<T: MoveTo<Nameservers>> Builder<T> {
-    #[allow(dead_code)]
     pub fn add_nameserver(
         self,
         name: &Name,
@@ -208,14 +562,14 @@ impl<T: MoveTo<Nameservers>> Builder<T> {
     ) -> Builder<Nameservers> {
         let mut builder = self.move_to::<Nameservers>();
 
-        builder.write_rr(name, cls, ttl, data);
+        builder.write_rr(name, cls as u16, ttl, data);
         Header::inc_nameservers(&mut builder.buf).expect("Too many nameservers");
 
         builder
     }
 }
 
-impl Builder<Additional> {
+impl<T: MoveTo<Additional>> Builder<T> {
     #[allow(dead_code)]
     pub fn add_additional(
         self,
@@ -223,11 +577,57 @@ impl Builder<Additional> {
         cls: QueryClass,
         ttl: u32,
         data: &RRData,
+    ) -> Builder<Additional> {
+        self.add_additional_with_cache_flush(name, cls, ttl, data, false)
+    }
+
+    /// Adds an additional record, with the cache-flush bit set if `cache_flush` is
+    /// true, see [`add_answer_with_cache_flush`](Builder::add_answer_with_cache_flush)
+    pub fn add_additional_with_cache_flush(
+        self,
+        name: &Name,
+        cls: QueryClass,
+        ttl: u32,
+        data: &RRData,
+        cache_flush: bool,
     ) -> Builder<Additional> {
         let mut builder = self.move_to::<Additional>();
+        builder.write_deferred();
 
+        let mut cls = cls as u16;
+        if cache_flush {
+            cls |= CACHE_FLUSH_BIT;
+        }
         builder.write_rr(name, cls, ttl, data);
-        Header::inc_nameservers(&mut builder.buf).expect("Too many additional answers");
+        Header::inc_additional(&mut builder.buf).expect("Too many additional answers");
+
+        builder
+    }
+
+    /// Adds the EDNS0 pseudo-record with `opt`'s payload size, flags and options
+    pub fn add_opt(self, opt: &Opt) -> Builder<Additional> {
+        let mut builder = self.move_to::<Additional>();
+        builder.write_deferred();
+
+        // The root name, which can't be compressed
+        builder.buf.push(0);
+        builder
+            .buf
+            .write_u16::<BigEndian>(Type::OPT as u16)
+            .unwrap();
+        builder
+            .buf
+            .write_u16::<BigEndian>(opt.udp_payload_size)
+            .unwrap();
+        builder.buf.write_u32::<BigEndian>(opt.ttl()).unwrap();
+        let mut options = Vec::new();
+        opt.write_options(&mut options).unwrap();
+        builder
+            .buf
+            .write_u16::<BigEndian>(options.len() as u16)
+            .unwrap();
+        builder.buf.extend_from_slice(&options);
+        Header::inc_additional(&mut builder.buf).expect("Too many additional answers");
 
         builder
     }
@@ -239,6 +639,10 @@ mod test {
     use super::Name;
     use super::QueryClass as QC;
     use super::QueryType as QT;
+    use super::RRData;
+    use crate::dns_parser::{Answers, Packet};
+    use crate::dns_parser::{EdnsOption, Opt, Owner, SvcBinding, SvcParam};
+    use std::net::Ipv4Addr;
 
     #[test]
     fn build_query() {
@@ -259,4 +663,301 @@ mod test {
             \x0c_xmpp-server\x04_tcp\x05gmail\x03com\x00\x00!\x00\x01";
         assert_eq!(&bld.build().unwrap()[..], &result[..]);
     }
+
+    #[test]
+    fn build_split_response() {
+        let name = Name::from_str("example.local").unwrap();
+        let mut bld = Builder::new_response(7, false, true)
+            .add_question(&name, QT::TXT, QC::IN)
+            .move_to::<Answers>();
+        bld.set_max_size(Some(300));
+        let txt = [b'x'; 100];
+        for _ in 0..5 {
+            bld = bld.add_answer(&name, QC::IN, 60, &RRData::TXT(&txt));
+        }
+
+        let packets = bld.build_split();
+        assert_eq!(packets.len(), 3);
+        let mut answers = 0;
+        for packet in &packets {
+            assert!(packet.len() <= 300);
+            let parsed = Packet::parse(packet).unwrap();
+            assert_eq!(parsed.header.id, 7);
+            assert_eq!(parsed.questions.len(), 1);
+            answers += parsed.answers.len();
+        }
+        assert_eq!(answers, 5);
+    }
+
+    #[test]
+    fn build_split_keeps_additional() {
+        let name = Name::from_str("example.local").unwrap();
+        let mut bld = Builder::new_response(7, false, true).move_to::<Answers>();
+        bld.set_max_size(Some(300));
+        let txt = [b'x'; 100];
+        for _ in 0..3 {
+            bld = bld.add_answer(&name, QC::IN, 60, &RRData::TXT(&txt));
+        }
+        bld = bld
+            .add_deferred_additional(&name, QC::IN, 60, &RRData::A([192, 0, 2, 1].into()), true)
+            .add_deferred_additional(&name, QC::IN, 60, &RRData::TXT(&[b'y'; 200]), true);
+
+        let packets = bld.build_split();
+        assert_eq!(packets.len(), 2);
+        let (last, errors) = Packet::parse_lenient(&packets[1]).unwrap();
+        assert!(errors.is_empty());
+        assert!(packets[1].len() <= 300);
+        // The address fits after the last answer, the long TXT doesn't
+        assert_eq!(last.answers.len(), 1);
+        assert_eq!(last.additional.len(), 1);
+        assert!(matches!(last.additional[0].data, RRData::A(_)));
+    }
+
+    #[test]
+    fn build_compressed_response() {
+        let typ = Name::from_str("_http._tcp.local").unwrap();
+        let mut bld = Builder::new_response(0, false, true).move_to::<Answers>();
+        for instance in &["one._http._tcp.local", "two._http._tcp.local"] {
+            let instance = Name::from_str(*instance).unwrap();
+            bld = bld.add_answer(&typ, QC::IN, 60, &RRData::PTR(instance));
+        }
+        let packet = bld.build().unwrap();
+        // the second record's name and the type in its PTR are pointers
+        assert_eq!(packet.len(), 12 + (18 + 10 + 6) + (2 + 10 + 6));
+
+        let parsed = Packet::parse(&packet).unwrap();
+        assert_eq!(parsed.answers.len(), 2);
+        assert_eq!(parsed.answers[1].name, typ);
+        match parsed.answers[1].data {
+            RRData::PTR(ref name) => assert_eq!(name.to_string(), "two._http._tcp.local"),
+            ref other => panic!("unexpected answer {:?}", other),
+        }
+    }
+
+    #[test]
+    fn build_uncompressed_response() {
+        let typ = Name::from_str("_http._tcp.local").unwrap();
+        let mut bld = Builder::new_response(0, false, true).move_to::<Answers>();
+        bld.set_compression(false);
+        for instance in &["one._http._tcp.local", "two._http._tcp.local"] {
+            let instance = Name::from_str(*instance).unwrap();
+            bld = bld.add_answer(&typ, QC::IN, 60, &RRData::PTR(instance));
+        }
+        let packet = bld.build().unwrap();
+        assert_eq!(packet.len(), 12 + 2 * (18 + 10 + 22));
+        assert_eq!(Packet::parse(&packet).unwrap().answers.len(), 2);
+    }
+
+    #[test]
+    fn build_cache_flush_response() {
+        let name = Name::from_str("host.local").unwrap();
+        let packet = Builder::new_response(0, false, true)
+            .add_answer_with_cache_flush(&name, QC::IN, 60, &RRData::A([192, 0, 2, 1].into()), true)
+            .add_answer(&name, QC::IN, 60, &RRData::A([192, 0, 2, 2].into()))
+            .build()
+            .unwrap();
+
+        let parsed = Packet::parse(&packet).unwrap();
+        assert!(parsed.answers[0].cache_flush());
+        assert_eq!(parsed.answers[0].cls, crate::dns_parser::Class::IN);
+        assert!(!parsed.answers[1].cache_flush());
+    }
+
+    #[test]
+    fn build_deferred_additional() {
+        let name = Name::from_str("host.local").unwrap();
+        let a = RRData::A([192, 0, 2, 1].into());
+        let aaaa = RRData::AAAA("2001:db8::1".parse().unwrap());
+        // As if answering questions for A and AAAA in one packet
+        let packet = Builder::new_response(0, false, true)
+            .add_answer_with_cache_flush(&name, QC::IN, 120, &a, true)
+            .add_deferred_additional(&name, QC::IN, 120, &aaaa, true)
+            .add_answer_with_cache_flush(&name, QC::IN, 120, &aaaa, true)
+            .add_deferred_additional(&name, QC::IN, 120, &a, true)
+            .add_deferred_additional(&name, QC::IN, 120, &RRData::TXT(b"\x03a=b"), true)
+            .build()
+            .unwrap();
+
+        let (parsed, errors) = Packet::parse_lenient(&packet).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(parsed.answers.len(), 2);
+        assert_eq!(parsed.additional.len(), 1);
+        assert!(matches!(parsed.additional[0].data, RRData::TXT(b"\x03a=b")));
+        assert!(parsed.additional[0].cache_flush());
+    }
+
+    #[test]
+    fn build_nsec_response() {
+        let name = Name::from_str("host.local").unwrap();
+        let data = RRData::NSEC {
+            next: name.clone(),
+            types: vec![28, 1, 65534],
+        };
+        let packet = Builder::new_response(0, false, true)
+            .add_answer(&name, QC::IN, 60, &data)
+            .build()
+            .unwrap();
+        // window 0 with A and AAAA in 4 bytes, window 255 with its last type
+        let mut bitmaps = vec![0x00, 0x04, 0x40, 0x00, 0x00, 0x08, 0xff, 0x20];
+        bitmaps.extend([0; 31]);
+        bitmaps.push(0x02);
+        assert!(packet.ends_with(&bitmaps));
+
+        let parsed = Packet::parse(&packet).unwrap();
+        match parsed.answers[0].data {
+            RRData::NSEC {
+                ref next,
+                ref types,
+            } => {
+                assert_eq!(*next, name);
+                assert_eq!(types, &[1, 28, 65534]);
+            }
+            ref other => panic!("unexpected answer {:?}", other),
+        }
+    }
+
+    #[test]
+    fn build_hinfo_response() {
+        let name = Name::from_str("host.local").unwrap();
+        let data = RRData::HINFO {
+            cpu: b"ARM64",
+            os: b"Linux",
+        };
+        let packet = Builder::new_response(0, false, true)
+            .add_answer(&name, QC::IN, 60, &data)
+            .build()
+            .unwrap();
+        assert!(packet.ends_with(b"\x00\x0c\x05ARM64\x05Linux"));
+
+        let parsed = Packet::parse(&packet).unwrap();
+        match parsed.answers[0].data {
+            RRData::HINFO { cpu, os } => assert_eq!((cpu, os), (&b"ARM64"[..], &b"Linux"[..])),
+            ref other => panic!("unexpected answer {:?}", other),
+        }
+    }
+
+    #[test]
+    fn build_raw_response() {
+        let name = Name::from_str("host.local").unwrap();
+        let data = RRData::Raw {
+            typ: 65280,
+            data: vec![1, 2, 3],
+        };
+        let packet = Builder::new_response(0, false, true)
+            .add_answer(&name, QC::IN, 60, &data)
+            .build()
+            .unwrap();
+        // The type code is written though the parser doesn't know it
+        assert!(packet.ends_with(b"\xff\x00\x00\x01\x00\x00\x00\x3c\x00\x03\x01\x02\x03"));
+    }
+
+    #[test]
+    fn build_svcb_response() {
+        let name = Name::from_str("_8080._printer.local").unwrap();
+        let binding = SvcBinding {
+            priority: 1,
+            target: Name::from_str("printer.local").unwrap(),
+            params: vec![
+                SvcParam::Mandatory(vec![3]),
+                SvcParam::Port(8080),
+                SvcParam::Ipv4Hint(vec![Ipv4Addr::new(192, 0, 2, 1)]),
+                SvcParam::Unknown {
+                    key: 65000,
+                    value: b"x",
+                },
+            ],
+        };
+        let bld = Builder::new_response(0, false, true).add_answer(
+            &name,
+            QC::IN,
+            60,
+            &RRData::SVCB(binding.clone()),
+        );
+        let packet = bld.build().unwrap();
+
+        let parsed = Packet::parse(&packet).unwrap();
+        match parsed.answers[0].data {
+            RRData::SVCB(ref parsed) => {
+                assert_eq!(parsed.priority, binding.priority);
+                assert_eq!(parsed.target, binding.target);
+                assert_eq!(parsed.params, binding.params);
+            }
+            ref other => panic!("unexpected answer {:?}", other),
+        }
+    }
+
+    #[test]
+    fn build_opt_record() {
+        let opt = Opt {
+            udp_payload_size: 1440,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: vec![
+                EdnsOption::Owner(Owner {
+                    version: 0,
+                    sequence: 3,
+                    primary_mac: [0x02, 0x11, 0x22, 0x33, 0x44, 0x55],
+                    wakeup_mac: None,
+                    password: Some(b"pass"),
+                }),
+                EdnsOption::Unknown {
+                    code: 65001,
+                    data: b"",
+                },
+            ],
+        };
+        let packet = Builder::new_response(0, false, true)
+            .add_answer(
+                &Name::from_str("test.local").unwrap(),
+                QC::IN,
+                120,
+                &RRData::A(Ipv4Addr::new(10, 0, 0, 1)),
+            )
+            .add_opt(&opt)
+            .build()
+            .unwrap();
+
+        let (parsed, errors) = Packet::parse_lenient(&packet).unwrap();
+        assert!(errors.is_empty());
+        let parsed = parsed.opt.unwrap();
+        assert_eq!(parsed.udp_payload_size, 1440);
+        // A password needs a wakeup MAC, which defaults to the primary one
+        let owner = parsed.owner().unwrap();
+        assert_eq!(owner.wakeup_mac, Some(owner.primary_mac));
+        assert_eq!(owner.password, Some(&b"pass"[..]));
+        assert_eq!(parsed.options[1], opt.options[1]);
+    }
+
+    #[test]
+    fn build_response_to_query() {
+        let query = Builder::new_query(4321, false)
+            .add_question(&Name::from_str("printer.local").unwrap(), QT::A, QC::IN)
+            .build()
+            .unwrap();
+        let query = Packet::parse(&query).unwrap();
+
+        let mut builder = Builder::new_response_to(query.header.id, true, &query.questions);
+        for i in 0..3 {
+            builder = builder.add_answer(
+                &Name::from_str("printer.local").unwrap(),
+                QC::IN,
+                10,
+                &RRData::A(Ipv4Addr::new(192, 0, 2, i)),
+            );
+        }
+        builder.set_max_size(Some(60));
+
+        // Every packet of the response carries the question
+        let packets = builder.build_split();
+        assert!(packets.len() > 1);
+        for packet in &packets {
+            let parsed = Packet::parse(packet).unwrap();
+            assert_eq!(parsed.header.id, 4321);
+            assert!(!parsed.header.query);
+            assert_eq!(parsed.questions.len(), 1);
+            assert_eq!(parsed.questions[0].qname, query.questions[0].qname);
+            assert_eq!(parsed.questions[0].qtype, QT::A);
+        }
+    }
 }
@@ -49,55 +49,68 @@ pub enum Type {
     NSEC = 47,
     /// DNS Key record (RFC 4034)
     DNSKEY = 48,
+    /// General purpose service binding (RFC 9460)
+    SVCB = 64,
+    /// Service binding for HTTPS origins (RFC 9460)
+    HTTPS = 65,
 }
 
 /// The QTYPE value according to RFC 1035
 ///
-/// All "EXPERIMENTAL" markers here are from the RFC
+/// All "EXPERIMENTAL" markers here are from the RFC. Questions may be for any type,
+/// so unlisted ones are kept as `Unknown`.
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum QueryType {
     /// a host addresss
-    A = 1,
+    A,
     /// an authoritative name server
-    NS = 2,
+    NS,
     /// a mail forwarder (Obsolete - use MX)
-    MF = 4,
+    MF,
     /// the canonical name for an alias
-    CNAME = 5,
+    CNAME,
     /// marks the start of a zone of authority
-    SOA = 6,
+    SOA,
     /// a mailbox domain name (EXPERIMENTAL)
-    MB = 7,
+    MB,
     /// a mail group member (EXPERIMENTAL)
-    MG = 8,
+    MG,
     /// a mail rename domain name (EXPERIMENTAL)
-    MR = 9,
+    MR,
     /// a null RR (EXPERIMENTAL)
-    NULL = 10,
+    NULL,
     /// a well known service description
-    WKS = 11,
+    WKS,
     /// a domain name pointer
-    PTR = 12,
+    PTR,
     /// host information
-    HINFO = 13,
+    HINFO,
     /// mailbox or mail list information
-    MINFO = 14,
+    MINFO,
     /// mail exchange
-    MX = 15,
+    MX,
     /// text strings
-    TXT = 16,
+    TXT,
     /// IPv6 host address (RFC 2782)
-    AAAA = 28,
+    AAAA,
     /// service record (RFC 2782)
-    SRV = 33,
+    SRV,
+    /// EDNS0 options (RFC 6891)
+    OPT,
+    /// General purpose service binding (RFC 9460)
+    SVCB,
+    /// Service binding for HTTPS origins (RFC 9460)
+    HTTPS,
     /// A request for a transfer of an entire zone
-    AXFR = 252,
+    AXFR,
     /// A request for mailbox-related records (MB, MG or MR)
-    MAILB = 253,
+    MAILB,
     /// A request for mail agent RRs (Obsolete - see MX)
-    MAILA = 254,
+    MAILA,
     /// A request for all records
-    All = 255,
+    All,
+    /// A type not listed here, such as one from the private use range
+    Unknown(u16),
 }
 
 /// The CLASS value according to RFC 1035
@@ -114,6 +127,17 @@ pub enum Class {
     HS = 4,
 }
 
+/// Top bit of the QCLASS field of a question, set when a unicast response is
+/// requested (RFC 6762 section 5.4)
+pub const QU_BIT: u16 = 0x8000;
+
+/// Top bit of the CLASS field of a resource record, set when the record should
+/// replace previously cached ones (RFC 6762 section 10.2)
+pub const CACHE_FLUSH_BIT: u16 = 0x8000;
+
+/// The bits of a CLASS or QCLASS field which hold the class itself
+pub const CLASS_MASK: u16 = 0x7fff;
+
 /// The QCLASS value according to RFC 1035
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum QueryClass {
@@ -162,10 +186,10 @@ impl From<u16> for Opcode {
         }
     }
 }
-impl Into<u16> for Opcode {
-    fn into(self) -> u16 {
+impl From<Opcode> for u16 {
+    fn from(val: Opcode) -> Self {
         use self::Opcode::*;
-        match self {
+        match val {
             StandardQuery => 0,
             InverseQuery => 1,
             ServerStatusRequest => 2,
@@ -189,10 +213,10 @@ impl From<u8> for ResponseCode {
         }
     }
 }
-impl Into<u8> for ResponseCode {
-    fn into(self) -> u8 {
+impl From<ResponseCode> for u8 {
+    fn from(val: ResponseCode) -> Self {
         use self::ResponseCode::*;
-        match self {
+        match val {
             NoError => 0,
             FormatError => 1,
             ServerFailure => 2,
@@ -225,11 +249,47 @@ impl QueryType {
             16 => Ok(TXT),
             28 => Ok(AAAA),
             33 => Ok(SRV),
+            41 => Ok(OPT),
+            64 => Ok(SVCB),
+            65 => Ok(HTTPS),
             252 => Ok(AXFR),
             253 => Ok(MAILB),
             254 => Ok(MAILA),
             255 => Ok(All),
-            x => Err(Error::InvalidQueryType(x)),
+            x => Ok(Unknown(x)),
+        }
+    }
+}
+
+impl From<QueryType> for u16 {
+    fn from(val: QueryType) -> Self {
+        use self::QueryType::*;
+        match val {
+            A => 1,
+            NS => 2,
+            MF => 4,
+            CNAME => 5,
+            SOA => 6,
+            MB => 7,
+            MG => 8,
+            MR => 9,
+            NULL => 10,
+            WKS => 11,
+            PTR => 12,
+            HINFO => 13,
+            MINFO => 14,
+            MX => 15,
+            TXT => 16,
+            AAAA => 28,
+            SRV => 33,
+            OPT => 41,
+            SVCB => 64,
+            HTTPS => 65,
+            AXFR => 252,
+            MAILB => 253,
+            MAILA => 254,
+            All => 255,
+            Unknown(code) => code,
         }
     }
 }
@@ -274,6 +334,8 @@ impl Type {
             46 => Ok(RRSIG),
             47 => Ok(NSEC),
             48 => Ok(DNSKEY),
+            64 => Ok(SVCB),
+            65 => Ok(HTTPS),
             x => Err(Error::InvalidType(x)),
         }
     }
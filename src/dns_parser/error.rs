@@ -24,6 +24,10 @@ pub enum Error {
     InvalidClass(u16),
     #[error("invalid characters encountered while reading label")]
     LabelIsNotAscii,
+    #[error("label {0:?} in domain name is longer than 63 bytes")]
+    LabelTooLong(String),
+    #[error("packet has more than one OPT record")]
+    DuplicateOpt,
     #[error("parser is in the wrong state")]
     WrongState,
 }
@@ -1,15 +1,23 @@
 mod error;
 pub use self::error::Error;
 mod enums;
-pub use self::enums::{Class, Opcode, QueryClass, QueryType, ResponseCode, Type};
+pub use self::enums::{
+    Class, Opcode, QueryClass, QueryType, ResponseCode, Type, CACHE_FLUSH_BIT, CLASS_MASK, QU_BIT,
+};
 mod structs;
-pub use self::structs::{Packet, Question, ResourceRecord};
+pub use self::structs::{Packet, Question, RecordError, ResourceRecord, Section};
 mod name;
-pub use self::name::Name;
+pub use self::name::{escape_label, Canonical, Name, NameKey, MAX_LABEL_LEN, MAX_NAME_LEN};
 mod header;
 mod parser;
 pub use self::header::Header;
 mod rrdata;
 pub use self::rrdata::RRData;
+#[cfg(any(test, feature = "parser"))]
+pub use self::rrdata::{SvcBinding, SvcParam};
+mod opt;
+pub use self::opt::Opt;
+#[cfg(any(test, feature = "parser"))]
+pub use self::opt::{EdnsOption, Owner};
 mod builder;
-pub use self::builder::{Answers, Builder, Questions};
+pub use self::builder::{Additional, Answers, Builder, Nameservers};
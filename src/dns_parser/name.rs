@@ -1,4 +1,5 @@
-use std::borrow::Cow;
+use std::borrow::{Borrow, Cow};
+use std::cmp::Ordering;
 use std::fmt;
 use std::fmt::Write;
 use std::hash;
@@ -9,6 +10,11 @@ use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 
 use super::Error;
 
+/// The longest label DNS can encode (RFC 1035 section 2.3.4)
+pub const MAX_LABEL_LEN: usize = 63;
+/// The longest name in wire format, length bytes and root label included
+pub const MAX_NAME_LEN: usize = 255;
+
 /// The DNS name as stored in the original packet
 ///
 /// This is contains just a reference to a slice that contains the data.
@@ -23,6 +29,9 @@ pub enum Name<'a> {
     },
 
     FromStr(Cow<'a, str>),
+
+    /// A name parsed from a string with escapes, in uncompressed wire format
+    Escaped(Box<[u8]>),
 }
 
 impl<'a> Name<'a> {
@@ -37,7 +46,7 @@ impl<'a> Name<'a> {
                 return Ok((
                     Name::FromPacket {
                         labels: &data[..pos + 1],
-                        original: original,
+                        original,
                     },
                     pos + 1,
                 ));
@@ -55,7 +64,7 @@ impl<'a> Name<'a> {
                 return Ok((
                     Name::FromPacket {
                         labels: &data[..pos + 2],
-                        original: original,
+                        original,
                     },
                     pos + 2,
                 ));
@@ -78,8 +87,35 @@ impl<'a> Name<'a> {
         }
     }
 
+    /// A name in presentation format, in which labels may contain dots and other
+    /// bytes escaped as `\.` or `\DDD` (RFC 6763 section 4.3)
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str<T: Into<Cow<'static, str>>>(name: T) -> Result<Name<'a>, Error> {
-        Ok(Name::FromStr(name.into()))
+        let name = name.into();
+        if name.contains('\\') {
+            return Ok(Name::Escaped(unescape(&name)?.into_boxed_slice()));
+        }
+        Ok(Name::FromStr(name))
+    }
+
+    /// Iterates over the labels of the name, without the terminating root label
+    pub fn labels(&self) -> Labels<'_> {
+        match *self {
+            Name::FromPacket { labels, original } => Labels::Wire {
+                data: labels,
+                original,
+            },
+            Name::FromStr(ref name) => Labels::Str(name.split('.')),
+            Name::Escaped(ref wire) => Labels::Wire {
+                data: wire,
+                original: wire,
+            },
+        }
+    }
+
+    /// The length of the name in wire format, without compression
+    pub fn wire_len(&self) -> usize {
+        self.labels().map(|label| label.len() + 1).sum::<usize>() + 1
     }
 
     pub fn write_to<T: io::Write>(&self, writer: &mut T) -> io::Result<()> {
@@ -111,7 +147,7 @@ impl<'a> Name<'a> {
 
             Name::FromStr(ref name) => {
                 for part in name.split('.') {
-                    assert!(part.len() < 63);
+                    assert!(part.len() <= MAX_LABEL_LEN);
                     let ln = part.len() as u8;
                     writer.write_u8(ln)?;
                     writer.write_all(part.as_bytes())?;
@@ -120,69 +156,445 @@ impl<'a> Name<'a> {
 
                 Ok(())
             }
+
+            Name::Escaped(ref wire) => writer.write_all(wire),
+        }
+    }
+}
+
+/// Write `label` in presentation format, escaping dots, backslashes, control
+/// characters and bytes which aren't UTF-8, so that parsing it with
+/// [`Name::from_str`] gives the label back
+pub fn escape_label<W: Write>(label: &[u8], out: &mut W) -> fmt::Result {
+    let mut rest = label;
+    while !rest.is_empty() {
+        let (valid, invalid) = match from_utf8(rest) {
+            Ok(valid) => (valid, 0),
+            Err(err) => {
+                let valid = from_utf8(&rest[..err.valid_up_to()]).unwrap();
+                let invalid = err.error_len().unwrap_or(rest.len() - valid.len());
+                (valid, invalid)
+            }
+        };
+        for c in valid.chars() {
+            match c {
+                '.' | '\\' => {
+                    out.write_char('\\')?;
+                    out.write_char(c)?;
+                }
+                c if c.is_ascii_control() => write!(out, "\\{:03}", c as u8)?,
+                c => out.write_char(c)?,
+            }
+        }
+        rest = &rest[valid.len()..];
+        for byte in &rest[..invalid] {
+            write!(out, "\\{:03}", byte)?;
+        }
+        rest = &rest[invalid..];
+    }
+    Ok(())
+}
+
+/// The wire format of a name in presentation format. A backslash which doesn't
+/// start an escape stands for itself.
+fn unescape(name: &str) -> Result<Vec<u8>, Error> {
+    fn push_label(wire: &mut Vec<u8>, label: &mut Vec<u8>, escaped: &str) -> Result<(), Error> {
+        if label.len() > MAX_LABEL_LEN {
+            return Err(Error::LabelTooLong(escaped.to_owned()));
+        }
+        if !label.is_empty() {
+            wire.push(label.len() as u8);
+            wire.append(label);
+        }
+        Ok(())
+    }
+
+    let mut wire = Vec::new();
+    let mut label = Vec::new();
+    // where the label starts in `name`
+    let mut start = 0;
+    let mut bytes = name.as_bytes();
+    while let Some((&byte, rest)) = bytes.split_first() {
+        bytes = rest;
+        match byte {
+            b'.' => {
+                let end = name.len() - bytes.len() - 1;
+                push_label(&mut wire, &mut label, &name[start..end])?;
+                start = end + 1;
+            }
+            b'\\' => {
+                let decimal = bytes
+                    .get(..3)
+                    .filter(|digits| digits.iter().all(u8::is_ascii_digit))
+                    .and_then(|digits| from_utf8(digits).ok()?.parse::<u8>().ok());
+                if let Some(decimal) = decimal {
+                    label.push(decimal);
+                    bytes = &bytes[3..];
+                } else if let Some((&escaped, rest)) = bytes.split_first() {
+                    label.push(escaped);
+                    bytes = rest;
+                } else {
+                    label.push(byte);
+                }
+            }
+            byte => label.push(byte),
         }
     }
+    push_label(&mut wire, &mut label, &name[start..])?;
+    wire.push(0);
+    Ok(wire)
 }
 
 impl<'a> fmt::Display for Name<'a> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Name::FromPacket { labels, original } => {
-                let mut pos = 0;
-                loop {
-                    let byte = labels[pos];
-                    if byte == 0 {
-                        return Ok(());
-                    } else if byte & 0b1100_0000 == 0b1100_0000 {
-                        let off = (BigEndian::read_u16(&labels[pos..pos + 2])
-                            & !0b1100_0000_0000_0000) as usize;
-                        if pos != 0 {
-                            fmt.write_char('.')?;
-                        }
-                        return fmt::Display::fmt(
-                            &Name::scan(&original[off..], original).unwrap().0,
-                            fmt,
-                        );
-                    } else if byte & 0b1100_0000 == 0 {
-                        if pos != 0 {
-                            fmt.write_char('.')?;
-                        }
-                        let end = pos + byte as usize + 1;
-                        fmt.write_str(from_utf8(&labels[pos + 1..end]).unwrap())?;
-                        pos = end;
-                        continue;
-                    } else {
-                        unreachable!();
+            Name::FromStr(ref name) => fmt.write_str(name),
+            _ => {
+                for (i, label) in self.labels().enumerate() {
+                    if i != 0 {
+                        fmt.write_char('.')?;
                     }
+                    escape_label(label, fmt)?;
                 }
+                Ok(())
             }
-
-            Name::FromStr(ref name) => fmt.write_str(&name),
         }
     }
 }
 
+/// Names are compared and hashed ignoring ASCII case, as DNS requires (RFC 4343)
 impl<'a> hash::Hash for Name<'a> {
     fn hash<H>(&self, state: &mut H)
     where
         H: hash::Hasher,
     {
-        let mut buffer = Vec::new();
-        self.write_to(&mut buffer).unwrap();
-        hash::Hash::hash(&buffer, state)
+        hash::Hash::hash(self as &dyn Canonical, state)
     }
 }
 
 impl<'a> PartialEq for Name<'a> {
     fn eq(&self, other: &Name) -> bool {
-        let mut buffer = Vec::new();
-        self.write_to(&mut buffer).unwrap();
+        self as &dyn Canonical == other as &dyn Canonical
+    }
+}
 
-        let mut other_buffer = Vec::new();
-        other.write_to(&mut other_buffer).unwrap();
+impl<'a> Eq for Name<'a> {}
+
+/// Iterator over the labels of a `Name`, following compression pointers
+pub enum Labels<'a> {
+    Wire {
+        data: &'a [u8],
+        original: &'a [u8],
+    },
+    Str(std::str::Split<'a, char>),
+    /// The canonical form stored by a `NameKey`
+    Key(&'a [u8]),
+}
 
-        buffer == other_buffer
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        match *self {
+            Labels::Wire {
+                ref mut data,
+                original,
+            } => loop {
+                // Names are validated by `Name::scan`, so the data is always well formed
+                let byte = data[0];
+                if byte == 0 {
+                    return None;
+                } else if byte & 0b1100_0000 == 0b1100_0000 {
+                    let off = (BigEndian::read_u16(&data[..2]) & !0b1100_0000_0000_0000) as usize;
+                    *data = &original[off..];
+                } else {
+                    let end = byte as usize + 1;
+                    let label = &data[1..end];
+                    *data = &data[end..];
+                    return Some(label);
+                }
+            },
+            Labels::Str(ref mut parts) => loop {
+                let part = parts.next()?;
+                if !part.is_empty() {
+                    return Some(part.as_bytes());
+                }
+            },
+            Labels::Key(ref mut data) => {
+                let (len, rest) = data.split_at(KEY_LEN_SIZE);
+                let len = BigEndian::read_u32(len) as usize;
+                if len == 0 {
+                    return None;
+                }
+                let (label, rest) = rest.split_at(len);
+                *data = rest;
+                Some(label)
+            }
+        }
     }
 }
 
-impl<'a> Eq for Name<'a> {}
+/// The size of the label lengths of the canonical form, wider than the byte of the
+/// wire format, as labels of names made from strings aren't limited to 63 bytes
+const KEY_LEN_SIZE: usize = 4;
+
+/// Feed the canonical form of `labels` to `write`: each label lowercased after its
+/// length, then a zero length. `NameKey` stores this form and names are hashed by it.
+fn write_canonical<F: FnMut(&[u8])>(labels: Labels, mut write: F) {
+    let mut buf = [0u8; 64];
+    for label in labels {
+        let mut len = [0; KEY_LEN_SIZE];
+        BigEndian::write_u32(&mut len, label.len() as u32);
+        write(&len);
+        for chunk in label.chunks(buf.len()) {
+            let lower = &mut buf[..chunk.len()];
+            lower.copy_from_slice(chunk);
+            lower.make_ascii_lowercase();
+            write(lower);
+        }
+    }
+    write(&[0; KEY_LEN_SIZE]);
+}
+
+/// Canonical (lowercased, uncompressed) form of a name, used as a lookup key
+///
+/// Maps keyed by `NameKey` may be queried with any `&dyn Canonical`, such as a
+/// `Name` borrowed from a parsed packet, without allocating.
+#[derive(Debug, Clone)]
+pub struct NameKey(Box<[u8]>);
+
+impl NameKey {
+    pub fn new(name: &Name) -> NameKey {
+        let mut buf = Vec::new();
+        write_canonical(name.labels(), |bytes| buf.extend_from_slice(bytes));
+        NameKey(buf.into_boxed_slice())
+    }
+
+    /// The length of the name in wire format
+    pub fn wire_len(&self) -> usize {
+        self.canonical_labels()
+            .map(|label| label.len() + 1)
+            .sum::<usize>()
+            + 1
+    }
+}
+
+impl<'a> From<&Name<'a>> for NameKey {
+    fn from(name: &Name<'a>) -> NameKey {
+        NameKey::new(name)
+    }
+}
+
+/// Anything which can be compared against a `NameKey`
+pub trait Canonical {
+    fn canonical_labels(&self) -> Labels<'_>;
+}
+
+impl<'a> Canonical for Name<'a> {
+    fn canonical_labels(&self) -> Labels<'_> {
+        self.labels()
+    }
+}
+
+impl Canonical for NameKey {
+    fn canonical_labels(&self) -> Labels<'_> {
+        Labels::Key(&self.0)
+    }
+}
+
+impl<'a> hash::Hash for dyn Canonical + 'a {
+    fn hash<H>(&self, state: &mut H)
+    where
+        H: hash::Hasher,
+    {
+        write_canonical(self.canonical_labels(), |bytes| state.write(bytes));
+    }
+}
+
+impl<'a> PartialEq for dyn Canonical + 'a {
+    fn eq(&self, other: &Self) -> bool {
+        let mut ours = self.canonical_labels();
+        let mut theirs = other.canonical_labels();
+        loop {
+            match (ours.next(), theirs.next()) {
+                (None, None) => return true,
+                (Some(a), Some(b)) if a.eq_ignore_ascii_case(b) => (),
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl<'a> Eq for dyn Canonical + 'a {}
+
+/// Orders names canonically (RFC 4034 section 6.1): label by label from the root,
+/// each compared as lowercase bytes, a name coming before the names below it
+impl<'a> Ord for dyn Canonical + 'a {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let mut ours = [&[][..]; MAX_LABELS];
+        let mut theirs = [&[][..]; MAX_LABELS];
+        match (
+            collect_labels(self, &mut ours),
+            collect_labels(other, &mut theirs),
+        ) {
+            (Some(ours), Some(theirs)) => cmp_from_root(ours, theirs),
+            // Too many labels to be sent, but names made from strings aren't checked
+            _ => {
+                let ours: Vec<&[u8]> = self.canonical_labels().collect();
+                let theirs: Vec<&[u8]> = other.canonical_labels().collect();
+                cmp_from_root(&ours, &theirs)
+            }
+        }
+    }
+}
+
+/// The most labels a name DNS can encode has
+const MAX_LABELS: usize = MAX_NAME_LEN / 2;
+
+/// The labels of `name` in `buf`, unless there are too many
+fn collect_labels<'a, 'b>(
+    name: &'a (dyn Canonical + 'b),
+    buf: &'a mut [&'a [u8]; MAX_LABELS],
+) -> Option<&'a [&'a [u8]]> {
+    let mut len = 0;
+    for label in name.canonical_labels() {
+        *buf.get_mut(len)? = label;
+        len += 1;
+    }
+    Some(&buf[..len])
+}
+
+fn cmp_from_root(ours: &[&[u8]], theirs: &[&[u8]]) -> Ordering {
+    for (a, b) in ours.iter().rev().zip(theirs.iter().rev()) {
+        let a = a.iter().map(u8::to_ascii_lowercase);
+        let b = b.iter().map(u8::to_ascii_lowercase);
+        let ordering = a.cmp(b);
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    ours.len().cmp(&theirs.len())
+}
+
+impl<'a> PartialOrd for dyn Canonical + 'a {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> Borrow<dyn Canonical + 'a> for NameKey {
+    fn borrow(&self) -> &(dyn Canonical + 'a) {
+        self
+    }
+}
+
+impl hash::Hash for NameKey {
+    fn hash<H>(&self, state: &mut H)
+    where
+        H: hash::Hasher,
+    {
+        hash::Hash::hash(self as &dyn Canonical, state)
+    }
+}
+
+impl PartialEq for NameKey {
+    fn eq(&self, other: &NameKey) -> bool {
+        self as &dyn Canonical == other as &dyn Canonical
+    }
+}
+
+impl Eq for NameKey {}
+
+impl Ord for NameKey {
+    fn cmp(&self, other: &NameKey) -> Ordering {
+        (self as &dyn Canonical).cmp(other as &dyn Canonical)
+    }
+}
+
+impl PartialOrd for NameKey {
+    fn partial_cmp(&self, other: &NameKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Canonical, Name, NameKey};
+    use std::collections::HashMap;
+
+    #[test]
+    fn compressed_name_equals_str_name() {
+        let packet = b"\x07example\x03com\x00\x03www\xc0\x00";
+        let (name, _) = Name::scan(&packet[13..], &packet[..]).unwrap();
+        assert_eq!(name, Name::from_str("www.example.com").unwrap());
+        assert_eq!(name, Name::from_str("www.Example.com").unwrap());
+        assert_ne!(name, Name::from_str("www.example.org").unwrap());
+    }
+
+    #[test]
+    fn name_hash_ignores_case() {
+        let mut map = HashMap::new();
+        map.insert(Name::from_str("My Service._http._tcp.local").unwrap(), 1);
+        let packet = b"\x0amy service\x05_HTTP\x04_tcp\x05LOCAL\x00";
+        let (name, _) = Name::scan(&packet[..], &packet[..]).unwrap();
+        assert_eq!(map.get(&name), Some(&1));
+    }
+
+    #[test]
+    fn name_key_lookup_is_case_insensitive() {
+        let mut map = HashMap::new();
+        map.insert(
+            NameKey::new(&Name::from_str("My Service._http._tcp.local").unwrap()),
+            1,
+        );
+
+        let packet = b"\x0amy service\x05_HTTP\x04_tcp\x05local\x00";
+        let (name, _) = Name::scan(&packet[..], &packet[..]).unwrap();
+        assert_eq!(map.get(&name as &dyn Canonical), Some(&1));
+
+        let other = Name::from_str("other._http._tcp.local").unwrap();
+        assert_eq!(map.get(&other as &dyn Canonical), None);
+    }
+
+    #[test]
+    fn canonical_order() {
+        // The example of RFC 4034 section 6.1
+        let names = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "\\001.z.example",
+            "*.z.example",
+            "\\200.z.example",
+        ];
+        let mut keys: Vec<NameKey> = names
+            .iter()
+            .rev()
+            .map(|name| NameKey::new(&Name::from_str(*name).unwrap()))
+            .collect();
+        keys.sort();
+        let expected: Vec<NameKey> = names
+            .iter()
+            .map(|name| NameKey::new(&Name::from_str(*name).unwrap()))
+            .collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn name_key_keeps_long_labels() {
+        // Names made from strings aren't checked, so their labels may be longer
+        // than a length byte can tell
+        let long = Name::from_str(format!("{}.local", "a".repeat(300))).unwrap();
+        let key = NameKey::new(&long);
+        let lengths: Vec<usize> = key.canonical_labels().map(<[u8]>::len).collect();
+        assert_eq!(lengths, vec![300, 5]);
+        assert_eq!(key.wire_len(), long.wire_len());
+        assert!(&key as &dyn Canonical == &long as &dyn Canonical);
+
+        let wrapped = Name::from_str(format!("{}.local", "a".repeat(44))).unwrap();
+        assert!(NameKey::new(&wrapped) != key);
+    }
+}
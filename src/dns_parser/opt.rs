@@ -0,0 +1,162 @@
+use std::io;
+
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+use super::Error;
+
+/// Option code of the EDNS0 OWNER option
+const OWNER_OPTION: u16 = 4;
+
+/// The DNSSEC OK bit of the extended flags
+const DNSSEC_OK_BIT: u32 = 0x8000;
+
+/// The EDNS0 pseudo-record (RFC 6891 section 6.1), which reuses the class and TTL
+/// fields of its record for the sender's UDP payload size and extended flags
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Opt<'a> {
+    /// The largest UDP payload the sender can receive
+    pub udp_payload_size: u16,
+    /// The upper 8 bits of the 12 bit response code
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// The DNSSEC OK bit (RFC 3225)
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption<'a>>,
+}
+
+/// An option of an [`Opt`] record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EdnsOption<'a> {
+    Owner(Owner<'a>),
+    Unknown { code: u16, data: &'a [u8] },
+}
+
+/// The OWNER option (draft-cheshire-edns0-owner-option), with which Bonjour hosts
+/// tell a sleep proxy which device the records of a packet belong to and how to
+/// wake it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Owner<'a> {
+    pub version: u8,
+    /// Incremented every time the device wakes up
+    pub sequence: u8,
+    /// The MAC address of the interface the records are for
+    pub primary_mac: [u8; 6],
+    /// The MAC address to send a wake-on-LAN magic packet to, if not the primary one
+    pub wakeup_mac: Option<[u8; 6]>,
+    /// A wake-on-LAN SecureOn password of 4 or 6 bytes, which needs a `wakeup_mac`
+    pub password: Option<&'a [u8]>,
+}
+
+impl<'a> Opt<'a> {
+    pub fn parse(cls: u16, ttl: u32, rdata: &'a [u8]) -> Result<Opt<'a>, Error> {
+        let mut options = Vec::new();
+        let mut data = rdata;
+        while !data.is_empty() {
+            if data.len() < 4 {
+                return Err(Error::WrongRdataLength);
+            }
+            let code = BigEndian::read_u16(&data[..2]);
+            let len = BigEndian::read_u16(&data[2..4]) as usize;
+            if data.len() < 4 + len {
+                return Err(Error::WrongRdataLength);
+            }
+            options.push(EdnsOption::parse(code, &data[4..4 + len])?);
+            data = &data[4 + len..];
+        }
+
+        Ok(Opt {
+            udp_payload_size: cls,
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            dnssec_ok: ttl & DNSSEC_OK_BIT != 0,
+            options,
+        })
+    }
+
+    /// The value of the TTL field
+    pub fn ttl(&self) -> u32 {
+        let mut ttl = u32::from(self.extended_rcode) << 24 | u32::from(self.version) << 16;
+        if self.dnssec_ok {
+            ttl |= DNSSEC_OK_BIT;
+        }
+        ttl
+    }
+
+    /// The OWNER option, if any
+    pub fn owner(&self) -> Option<&Owner<'a>> {
+        self.options.iter().find_map(|option| match *option {
+            EdnsOption::Owner(ref owner) => Some(owner),
+            _ => None,
+        })
+    }
+
+    pub fn write_options<T: io::Write>(&self, writer: &mut T) -> io::Result<()> {
+        for option in &self.options {
+            let mut data = Vec::new();
+            option.write_data(&mut data)?;
+            writer.write_u16::<BigEndian>(option.code())?;
+            writer.write_u16::<BigEndian>(data.len() as u16)?;
+            writer.write_all(&data)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> EdnsOption<'a> {
+    fn parse(code: u16, data: &'a [u8]) -> Result<EdnsOption<'a>, Error> {
+        let option = match code {
+            OWNER_OPTION => {
+                let mac = |at: usize| {
+                    let mut mac = [0; 6];
+                    mac.copy_from_slice(&data[at..at + 6]);
+                    mac
+                };
+                let (wakeup_mac, password) = match data.len() {
+                    8 => (None, None),
+                    14 => (Some(mac(8)), None),
+                    18 | 20 => (Some(mac(8)), Some(&data[14..])),
+                    _ => return Err(Error::WrongRdataLength),
+                };
+                EdnsOption::Owner(Owner {
+                    version: data[0],
+                    sequence: data[1],
+                    primary_mac: mac(2),
+                    wakeup_mac,
+                    password,
+                })
+            }
+            code => EdnsOption::Unknown { code, data },
+        };
+        Ok(option)
+    }
+
+    /// The OPTION-CODE
+    pub fn code(&self) -> u16 {
+        match *self {
+            EdnsOption::Owner(..) => OWNER_OPTION,
+            EdnsOption::Unknown { code, .. } => code,
+        }
+    }
+
+    fn write_data<T: io::Write>(&self, writer: &mut T) -> io::Result<()> {
+        match *self {
+            EdnsOption::Owner(ref owner) => {
+                writer.write_u8(owner.version)?;
+                writer.write_u8(owner.sequence)?;
+                writer.write_all(&owner.primary_mac)?;
+                // A password can only follow a wakeup MAC, which defaults to the primary
+                if let Some(wakeup_mac) = owner
+                    .wakeup_mac
+                    .or(owner.password.map(|_| owner.primary_mac))
+                {
+                    writer.write_all(&wakeup_mac)?;
+                }
+                if let Some(password) = owner.password {
+                    writer.write_all(password)?;
+                }
+            }
+            EdnsOption::Unknown { data, .. } => writer.write_all(data)?,
+        }
+        Ok(())
+    }
+}
@@ -1,85 +1,212 @@
-use std::i32;
-
 use byteorder::{BigEndian, ByteOrder};
 
-use super::{Class, RRData, ResourceRecord, Type};
-use super::{Error, Header, Name, Packet, QueryClass, QueryType, Question};
+use super::{Class, Opt, RRData, ResourceRecord, Type, CACHE_FLUSH_BIT, CLASS_MASK, QU_BIT};
+use super::{Error, Header, Name, Packet, QueryClass, QueryType, Question, RecordError, Section};
 
 impl<'a> Packet<'a> {
-    pub fn parse(data: &[u8]) -> Result<Packet, Error> {
+    pub fn parse(data: &[u8]) -> Result<Packet<'_>, Error> {
         let header = Header::parse(data)?;
         let mut offset = Header::size();
         let mut questions = Vec::with_capacity(header.questions as usize);
         for _ in 0..header.questions {
-            let (name, name_size) = Name::scan(&data[offset..], data)?;
-            offset += name_size;
-            if offset + 4 > data.len() {
-                return Err(Error::UnexpectedEOF);
-            }
-            let qtype = QueryType::parse(BigEndian::read_u16(&data[offset..offset + 2]))?;
-            offset += 2;
-            let qclass_qu = BigEndian::read_u16(&data[offset..offset + 2]);
-            let qclass = QueryClass::parse(qclass_qu & 0x7fff)?;
-            let qu = (qclass_qu & 0x8000) != 0;
-
-            offset += 2;
-            questions.push(Question {
-                qname: name,
-                qtype: qtype,
-                qclass: qclass,
-                qu: qu,
-            });
+            questions.push(scan_question(data, &mut offset)?.parse()?);
         }
         let mut answers = Vec::with_capacity(header.answers as usize);
         for _ in 0..header.answers {
-            answers.push(parse_record(data, &mut offset)?);
+            answers.push(scan_record(data, &mut offset)?.parse(data)?);
         }
         let mut nameservers = Vec::with_capacity(header.nameservers as usize);
         for _ in 0..header.nameservers {
-            nameservers.push(parse_record(data, &mut offset)?);
+            nameservers.push(scan_record(data, &mut offset)?.parse(data)?);
         }
         Ok(Packet {
-            header: header,
-            questions: questions,
-            answers: answers,
-            nameservers: nameservers,
+            header,
+            questions,
+            answers,
+            nameservers,
             additional: Vec::new(), // TODO(tailhook)
+            opt: None,
+        })
+    }
+
+    /// Parse as much of a packet as possible. Questions and records which can't be
+    /// understood, e.g. because of an unknown type, are skipped and their errors
+    /// returned alongside the packet. Once a record can't even be delimited, the
+    /// rest of the packet is given up on.
+    ///
+    /// Unlike [`parse`](Packet::parse), the additional section is parsed too, with
+    /// its OPT record in `opt`. Only a broken header fails the whole packet.
+    pub fn parse_lenient(data: &[u8]) -> Result<(Packet<'_>, Vec<RecordError>), Error> {
+        let header = Header::parse(data)?;
+        let mut packet = Packet {
+            header,
+            questions: Vec::new(),
+            answers: Vec::new(),
+            nameservers: Vec::new(),
+            additional: Vec::new(),
+            opt: None,
+        };
+        let mut errors = Vec::new();
+        let mut offset = Header::size();
+
+        let mut failed = |section, index, error| {
+            errors.push(RecordError {
+                section,
+                index,
+                error,
+            })
+        };
+        'sections: {
+            for index in 0..header.questions as usize {
+                match scan_question(data, &mut offset) {
+                    Ok(raw) => match raw.parse() {
+                        Ok(question) => packet.questions.push(question),
+                        Err(error) => failed(Section::Question, index, error),
+                    },
+                    Err(error) => {
+                        failed(Section::Question, index, error);
+                        break 'sections;
+                    }
+                }
+            }
+            let sections = [
+                (Section::Answer, header.answers, &mut packet.answers),
+                (
+                    Section::Nameserver,
+                    header.nameservers,
+                    &mut packet.nameservers,
+                ),
+                (
+                    Section::Additional,
+                    header.additional,
+                    &mut packet.additional,
+                ),
+            ];
+            for (section, count, records) in sections {
+                for index in 0..count as usize {
+                    match scan_record(data, &mut offset) {
+                        Ok(raw)
+                            if section == Section::Additional && raw.typ == Type::OPT as u16 =>
+                        {
+                            match Opt::parse(raw.cls, raw.ttl, raw.rdata) {
+                                Ok(_) if packet.opt.is_some() => {
+                                    failed(section, index, Error::DuplicateOpt)
+                                }
+                                Ok(opt) => packet.opt = Some(opt),
+                                Err(error) => failed(section, index, error),
+                            }
+                        }
+                        Ok(raw) => match raw.parse(data) {
+                            Ok(record) => records.push(record),
+                            Err(error) => failed(section, index, error),
+                        },
+                        Err(error) => {
+                            failed(section, index, error);
+                            break 'sections;
+                        }
+                    }
+                }
+            }
+        }
+        Ok((packet, errors))
+    }
+}
+
+/// A question whose name and length are known, but whose type and class are not
+/// yet checked
+struct RawQuestion<'a> {
+    qname: Name<'a>,
+    qtype: u16,
+    qclass: u16,
+}
+
+impl<'a> RawQuestion<'a> {
+    fn parse(self) -> Result<Question<'a>, Error> {
+        Ok(Question {
+            qname: self.qname,
+            qtype: QueryType::parse(self.qtype)?,
+            qclass: QueryClass::parse(self.qclass & CLASS_MASK)?,
+            qu: (self.qclass & QU_BIT) != 0,
         })
     }
 }
 
-// Generic function to parse answer, nameservers, and additional records.
-fn parse_record<'a>(data: &'a [u8], offset: &mut usize) -> Result<ResourceRecord<'a>, Error> {
+fn scan_question<'a>(data: &'a [u8], offset: &mut usize) -> Result<RawQuestion<'a>, Error> {
+    let (qname, name_size) = Name::scan(&data[*offset..], data)?;
+    *offset += name_size;
+    if *offset + 4 > data.len() {
+        return Err(Error::UnexpectedEOF);
+    }
+    let qtype = BigEndian::read_u16(&data[*offset..*offset + 2]);
+    let qclass = BigEndian::read_u16(&data[*offset + 2..*offset + 4]);
+    *offset += 4;
+    Ok(RawQuestion {
+        qname,
+        qtype,
+        qclass,
+    })
+}
+
+/// A record whose name and length are known, but whose type, class and data are
+/// not yet checked
+struct RawRecord<'a> {
+    name: Name<'a>,
+    typ: u16,
+    cls: u16,
+    ttl: u32,
+    rdata: &'a [u8],
+}
+
+impl<'a> RawRecord<'a> {
+    fn parse(self, data: &'a [u8]) -> Result<ResourceRecord<'a>, Error> {
+        let typ = Type::parse(self.typ)?;
+        let cls = Class::parse(self.cls & CLASS_MASK)?;
+        Ok(ResourceRecord {
+            name: self.name,
+            cls,
+            cache_flush: (self.cls & CACHE_FLUSH_BIT) != 0,
+            ttl: if self.ttl > i32::MAX as u32 {
+                0
+            } else {
+                self.ttl
+            },
+            data: RRData::parse(typ, self.rdata, data)?,
+        })
+    }
+}
+
+// Generic function to delimit answer, nameservers, and additional records.
+fn scan_record<'a>(data: &'a [u8], offset: &mut usize) -> Result<RawRecord<'a>, Error> {
     let (name, name_size) = Name::scan(&data[*offset..], data)?;
     *offset += name_size;
     if *offset + 10 > data.len() {
         return Err(Error::UnexpectedEOF);
     }
-    let typ = Type::parse(BigEndian::read_u16(&data[*offset..*offset + 2]))?;
+    let typ = BigEndian::read_u16(&data[*offset..*offset + 2]);
     *offset += 2;
-    let cls = Class::parse(BigEndian::read_u16(&data[*offset..*offset + 2]) & 0x7fff)?;
+    let cls = BigEndian::read_u16(&data[*offset..*offset + 2]);
     *offset += 2;
-    let mut ttl = BigEndian::read_u32(&data[*offset..*offset + 4]);
-    if ttl > i32::MAX as u32 {
-        ttl = 0;
-    }
+    // Not yet clamped, as an OPT record uses the field for flags
+    let ttl = BigEndian::read_u32(&data[*offset..*offset + 4]);
     *offset += 4;
     let rdlen = BigEndian::read_u16(&data[*offset..*offset + 2]) as usize;
     *offset += 2;
     if *offset + rdlen > data.len() {
         return Err(Error::UnexpectedEOF);
     }
-    let data = RRData::parse(typ, &data[*offset..*offset + rdlen], data)?;
+    let rdata = &data[*offset..*offset + rdlen];
     *offset += rdlen;
-    Ok(ResourceRecord {
-        name: name,
-        cls: cls,
-        ttl: ttl,
-        data: data,
+    Ok(RawRecord {
+        name,
+        typ,
+        cls,
+        ttl,
+        rdata,
     })
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_range_loop, clippy::useless_vec, clippy::deref_addrof)]
 mod test {
 
     use super::super::Opcode::*;
@@ -88,9 +215,19 @@ mod test {
     use super::QueryClass as QC;
     use super::QueryType as QT;
     use super::RRData;
+    use crate::dns_parser::{EdnsOption, Owner, Section, SvcParam};
     use std::net::{Ipv4Addr, Ipv6Addr};
     use {super::Header, super::Packet};
 
+    #[test]
+    fn parse_unknown_query_type() {
+        let query = b"\x06%\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+                      \x07example\x03com\x00\xff\x00\x00\x01";
+        let packet = Packet::parse(query).unwrap();
+        assert_eq!(packet.questions[0].qtype, QT::Unknown(65280));
+        assert_eq!(u16::from(packet.questions[0].qtype), 65280);
+    }
+
     #[test]
     fn parse_example_query() {
         let query = b"\x06%\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
@@ -120,6 +257,28 @@ mod test {
         assert_eq!(packet.answers.len(), 0);
     }
 
+    #[test]
+    fn parse_qu_question() {
+        let query = b"\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+                      \x04test\x05local\x00\x00\x01\x80\x01";
+        let packet = Packet::parse(query).unwrap();
+        assert_eq!(packet.questions[0].qclass, QC::IN);
+        assert!(packet.questions[0].wants_unicast());
+    }
+
+    #[test]
+    fn parse_cache_flush_record() {
+        let response = b"\x00\x00\x84\x00\x00\x00\x00\x02\x00\x00\x00\x00\
+                         \x04test\x05local\x00\x00\x01\x80\x01\x00\x00\x00\x78\x00\x04\x0a\x00\x00\x01\
+                         \xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x78\x00\x04\x0a\x00\x00\x02";
+        let packet = Packet::parse(response).unwrap();
+        assert_eq!(packet.answers.len(), 2);
+        assert_eq!(packet.answers[0].cls, C::IN);
+        assert!(packet.answers[0].cache_flush());
+        assert_eq!(packet.answers[1].cls, C::IN);
+        assert!(!packet.answers[1].cache_flush());
+    }
+
     #[test]
     fn parse_name_length_too_long_query() {
         // If the name length provided in the query exceeds the available data we should error
@@ -562,4 +721,105 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn parse_https_record() {
+        let response = b"\x00\x00\x84\x00\x00\x00\x00\x01\x00\x00\x00\x00\
+                         \x07example\x05local\x00\x00\x41\x00\x01\x00\x00\x00\x78\x00\x10\
+                         \x00\x01\x00\x00\x01\x00\x03\x02h2\x00\x03\x00\x02\x01\xbb";
+        let packet = Packet::parse(response).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+        let binding = match packet.answers[0].data {
+            RRData::HTTPS(ref binding) => binding,
+            ref x => panic!("Wrong rdata {:?}", x),
+        };
+        assert_eq!(binding.priority, 1);
+        assert_eq!(&binding.target.to_string()[..], "");
+        assert_eq!(
+            binding.params,
+            vec![SvcParam::Alpn(vec![b"h2"]), SvcParam::Port(443)]
+        );
+    }
+
+    #[test]
+    fn parse_lenient_skips_bad_records() {
+        let response = b"\x00\x00\x84\x00\x00\x02\x00\x02\x00\x00\x00\x01\
+                         \x04test\x05local\x00\x00\x01\x12\x34\
+                         \xc0\x0c\x00\x01\x00\x01\
+                         \xc0\x0c\xff\xf0\x00\x01\x00\x00\x00\x78\x00\x02\x00\x00\
+                         \xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x78\x00\x04\x0a\x00\x00\x01\
+                         \xc0\x0c\x00\x01";
+        assert!(Packet::parse(response).is_err());
+
+        let (packet, errors) = Packet::parse_lenient(response).unwrap();
+        assert_eq!(packet.questions.len(), 1);
+        assert_eq!(packet.questions[0].qtype, QT::A);
+        assert_eq!(packet.answers.len(), 1);
+        match packet.answers[0].data {
+            RRData::A(addr) => assert_eq!(addr, Ipv4Addr::new(10, 0, 0, 1)),
+            ref x => panic!("Wrong rdata {:?}", x),
+        }
+        assert!(packet.additional.is_empty());
+
+        let errors: Vec<_> = errors
+            .iter()
+            .map(|e| (e.section, e.index, e.error.to_string()))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (
+                    Section::Question,
+                    0,
+                    "query class 4660 is invalid".to_owned()
+                ),
+                (Section::Answer, 0, "type 65520 is invalid".to_owned()),
+                (
+                    Section::Additional,
+                    0,
+                    "packet is has incomplete data".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_opt_record() {
+        let response = b"\x00\x00\x84\x00\x00\x00\x00\x01\x00\x00\x00\x01\
+                         \x04test\x05local\x00\x00\x01\x80\x01\x00\x00\x00\x78\x00\x04\x0a\x00\x00\x01\
+                         \x00\x00\x29\x05\xa0\x00\x00\x80\x00\x00\x17\
+                         \x00\x04\x00\x0e\x00\x07\x02\x11\x22\x33\x44\x55\x02\x11\x22\x33\x44\x66\
+                         \xfd\xe9\x00\x01\x2a";
+        let (packet, errors) = Packet::parse_lenient(response).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(packet.answers.len(), 1);
+        assert!(packet.additional.is_empty());
+
+        let opt = packet.opt.unwrap();
+        assert_eq!(opt.udp_payload_size, 1440);
+        assert_eq!((opt.extended_rcode, opt.version), (0, 0));
+        assert!(opt.dnssec_ok);
+        assert_eq!(
+            opt.options,
+            [
+                EdnsOption::Owner(Owner {
+                    version: 0,
+                    sequence: 7,
+                    primary_mac: [0x02, 0x11, 0x22, 0x33, 0x44, 0x55],
+                    wakeup_mac: Some([0x02, 0x11, 0x22, 0x33, 0x44, 0x66]),
+                    password: None,
+                }),
+                EdnsOption::Unknown {
+                    code: 0xfde9,
+                    data: b"\x2a",
+                },
+            ]
+        );
+        assert_eq!(opt.owner().unwrap().sequence, 7);
+    }
+
+    #[test]
+    fn parse_lenient_header() {
+        assert!(Packet::parse_lenient(b"\x00\x00\x84").is_err());
+    }
 }
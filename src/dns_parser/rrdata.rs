@@ -24,14 +24,36 @@ pub enum RRData<'a> {
         exchange: Name<'a>,
     },
     TXT(&'a [u8]),
+    /// The CPU and operating system of a host (RFC 1035 section 3.3.2), at most 255
+    /// bytes each
+    HINFO {
+        cpu: &'a [u8],
+        os: &'a [u8],
+    },
+    SVCB(SvcBinding<'a>),
+    HTTPS(SvcBinding<'a>),
+    /// The types of the records which exist for a name (RFC 4034 section 4), used
+    /// by mDNS to assert that there are no others (RFC 6762 section 6.1)
+    NSEC {
+        next: Name<'a>,
+        types: Vec<u16>,
+    },
     // Anything that can't be parsed yet
     Unknown {
         typ: Type,
         data: &'a [u8],
     },
+    /// Data written as is under any type code, for types not modelled here. Never
+    /// produced by the parser.
+    Raw {
+        typ: u16,
+        data: Vec<u8>,
+    },
 }
 
 impl<'a> RRData<'a> {
+    /// The type of the record. `Raw` data of a type code missing from `Type` is
+    /// reported as `Type::NULL`, see [`type_code`](Self::type_code).
     pub fn typ(&self) -> Type {
         match *self {
             RRData::CNAME(..) => Type::CNAME,
@@ -42,7 +64,20 @@ impl<'a> RRData<'a> {
             RRData::SRV { .. } => Type::SRV,
             RRData::MX { .. } => Type::MX,
             RRData::TXT(..) => Type::TXT,
+            RRData::HINFO { .. } => Type::HINFO,
+            RRData::SVCB(..) => Type::SVCB,
+            RRData::HTTPS(..) => Type::HTTPS,
+            RRData::NSEC { .. } => Type::NSEC,
             RRData::Unknown { typ, .. } => typ,
+            RRData::Raw { typ, .. } => Type::parse(typ).unwrap_or(Type::NULL),
+        }
+    }
+
+    /// The numeric type of the record, as written to packets
+    pub fn type_code(&self) -> u16 {
+        match *self {
+            RRData::Raw { typ, .. } => typ,
+            _ => self.typ() as u16,
         }
     }
 
@@ -79,7 +114,24 @@ impl<'a> RRData<'a> {
                 exchange.write_to(writer)
             }
             RRData::TXT(data) => writer.write_all(data),
+            RRData::HINFO { cpu, os } => {
+                for string in [cpu, os] {
+                    let string = &string[..string.len().min(255)];
+                    writer.write_u8(string.len() as u8)?;
+                    writer.write_all(string)?;
+                }
+                Ok(())
+            }
+            RRData::SVCB(ref binding) | RRData::HTTPS(ref binding) => binding.write_to(writer),
+            RRData::NSEC {
+                ref next,
+                ref types,
+            } => {
+                next.write_to(writer)?;
+                write_type_bitmaps(writer, types)
+            }
             RRData::Unknown { data, .. } => writer.write_all(data),
+            RRData::Raw { ref data, .. } => writer.write_all(data),
         }
     }
 
@@ -130,10 +182,256 @@ impl<'a> RRData<'a> {
                 })
             }
             Type::TXT => Ok(RRData::TXT(rdata)),
-            typ => Ok(RRData::Unknown {
-                typ: typ,
-                data: rdata,
-            }),
+            Type::HINFO => {
+                let (cpu, rest) = character_string(rdata)?;
+                let (os, rest) = character_string(rest)?;
+                if !rest.is_empty() {
+                    return Err(Error::WrongRdataLength);
+                }
+                Ok(RRData::HINFO { cpu, os })
+            }
+            Type::SVCB => Ok(RRData::SVCB(SvcBinding::parse(rdata, original)?)),
+            Type::HTTPS => Ok(RRData::HTTPS(SvcBinding::parse(rdata, original)?)),
+            Type::NSEC => {
+                let (next, next_len) = Name::scan(rdata, original)?;
+                Ok(RRData::NSEC {
+                    next,
+                    types: parse_type_bitmaps(&rdata[next_len..])?,
+                })
+            }
+            typ => Ok(RRData::Unknown { typ, data: rdata }),
+        }
+    }
+}
+
+/// Splits a length-prefixed character-string (RFC 1035 section 3.3) off `data`
+fn character_string(data: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let len = *data.first().ok_or(Error::WrongRdataLength)? as usize;
+    if data.len() < 1 + len {
+        return Err(Error::WrongRdataLength);
+    }
+    Ok((&data[1..1 + len], &data[1 + len..]))
+}
+
+/// Writes `types` as the windowed bitmaps of RFC 4034 section 4.1.2
+fn write_type_bitmaps<T: io::Write>(writer: &mut T, types: &[u16]) -> io::Result<()> {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+
+    let mut rest = &types[..];
+    while let Some(&first) = rest.first() {
+        let window = first >> 8;
+        let end = rest
+            .iter()
+            .position(|&typ| typ >> 8 != window)
+            .unwrap_or(rest.len());
+
+        let mut bitmap = [0u8; 32];
+        let mut len = 0;
+        for &typ in &rest[..end] {
+            let bit = (typ & 0xff) as usize;
+            bitmap[bit / 8] |= 0x80 >> (bit % 8);
+            len = bit / 8 + 1;
+        }
+        writer.write_u8(window as u8)?;
+        writer.write_u8(len as u8)?;
+        writer.write_all(&bitmap[..len])?;
+        rest = &rest[end..];
+    }
+    Ok(())
+}
+
+fn parse_type_bitmaps(mut data: &[u8]) -> Result<Vec<u16>, Error> {
+    let mut types = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 2 {
+            return Err(Error::WrongRdataLength);
+        }
+        let window = data[0] as u16;
+        let len = data[1] as usize;
+        if len == 0 || len > 32 || data.len() < 2 + len {
+            return Err(Error::WrongRdataLength);
+        }
+        for (i, &byte) in data[2..2 + len].iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push(window << 8 | (i * 8 + bit) as u16);
+                }
+            }
+        }
+        data = &data[2 + len..];
+    }
+    Ok(types)
+}
+
+/// The data of an SVCB or HTTPS record (RFC 9460 section 2.2)
+#[derive(Debug, Clone)]
+pub struct SvcBinding<'a> {
+    /// 0 for alias mode, otherwise the preference in service mode
+    pub priority: u16,
+    pub target: Name<'a>,
+    pub params: Vec<SvcParam<'a>>,
+}
+
+/// A parameter of an `SvcBinding` (RFC 9460 section 7)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SvcParam<'a> {
+    /// Keys of the parameters a client must understand to use the record
+    Mandatory(Vec<u16>),
+    /// Protocol ids, such as `h2` or `h3`
+    Alpn(Vec<&'a [u8]>),
+    NoDefaultAlpn,
+    Port(u16),
+    Ipv4Hint(Vec<Ipv4Addr>),
+    /// An encrypted ClientHello config list
+    Ech(&'a [u8]),
+    Ipv6Hint(Vec<Ipv6Addr>),
+    Unknown {
+        key: u16,
+        value: &'a [u8],
+    },
+}
+
+impl<'a> SvcBinding<'a> {
+    fn parse(rdata: &'a [u8], original: &'a [u8]) -> Result<SvcBinding<'a>, Error> {
+        if rdata.len() < 3 {
+            return Err(Error::WrongRdataLength);
+        }
+        let priority = BigEndian::read_u16(&rdata[..2]);
+        let (target, target_len) = Name::scan(&rdata[2..], original)?;
+
+        let mut params = Vec::new();
+        let mut data = &rdata[2 + target_len..];
+        while !data.is_empty() {
+            if data.len() < 4 {
+                return Err(Error::WrongRdataLength);
+            }
+            let key = BigEndian::read_u16(&data[..2]);
+            let len = BigEndian::read_u16(&data[2..4]) as usize;
+            if data.len() < 4 + len {
+                return Err(Error::WrongRdataLength);
+            }
+            params.push(SvcParam::parse(key, &data[4..4 + len])?);
+            data = &data[4 + len..];
+        }
+
+        Ok(SvcBinding {
+            priority,
+            target,
+            params,
+        })
+    }
+
+    fn write_to<T: io::Write>(&self, writer: &mut T) -> io::Result<()> {
+        writer.write_u16::<BigEndian>(self.priority)?;
+        // The target must not be compressed
+        self.target.write_to(writer)?;
+        for param in &self.params {
+            let mut value = Vec::new();
+            param.write_value(&mut value)?;
+            writer.write_u16::<BigEndian>(param.key())?;
+            writer.write_u16::<BigEndian>(value.len() as u16)?;
+            writer.write_all(&value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> SvcParam<'a> {
+    fn parse(key: u16, value: &'a [u8]) -> Result<SvcParam<'a>, Error> {
+        let param = match key {
+            0 => {
+                let keys = value.chunks_exact(2);
+                if !keys.remainder().is_empty() {
+                    return Err(Error::WrongRdataLength);
+                }
+                SvcParam::Mandatory(keys.map(BigEndian::read_u16).collect())
+            }
+            1 => {
+                let mut ids = Vec::new();
+                let mut data = value;
+                while !data.is_empty() {
+                    let len = data[0] as usize;
+                    if len == 0 || data.len() < 1 + len {
+                        return Err(Error::WrongRdataLength);
+                    }
+                    ids.push(&data[1..1 + len]);
+                    data = &data[1 + len..];
+                }
+                SvcParam::Alpn(ids)
+            }
+            2 if value.is_empty() => SvcParam::NoDefaultAlpn,
+            3 if value.len() == 2 => SvcParam::Port(BigEndian::read_u16(value)),
+            4 => {
+                let ips = value.chunks_exact(4);
+                if value.is_empty() || !ips.remainder().is_empty() {
+                    return Err(Error::WrongRdataLength);
+                }
+                SvcParam::Ipv4Hint(
+                    ips.map(|ip| Ipv4Addr::from(BigEndian::read_u32(ip)))
+                        .collect(),
+                )
+            }
+            5 => SvcParam::Ech(value),
+            6 => {
+                let ips = value.chunks_exact(16);
+                if value.is_empty() || !ips.remainder().is_empty() {
+                    return Err(Error::WrongRdataLength);
+                }
+                SvcParam::Ipv6Hint(
+                    ips.map(|ip| Ipv6Addr::from(BigEndian::read_u128(ip)))
+                        .collect(),
+                )
+            }
+            2 | 3 => return Err(Error::WrongRdataLength),
+            key => SvcParam::Unknown { key, value },
+        };
+        Ok(param)
+    }
+
+    /// The SvcParamKey
+    pub fn key(&self) -> u16 {
+        match *self {
+            SvcParam::Mandatory(..) => 0,
+            SvcParam::Alpn(..) => 1,
+            SvcParam::NoDefaultAlpn => 2,
+            SvcParam::Port(..) => 3,
+            SvcParam::Ipv4Hint(..) => 4,
+            SvcParam::Ech(..) => 5,
+            SvcParam::Ipv6Hint(..) => 6,
+            SvcParam::Unknown { key, .. } => key,
+        }
+    }
+
+    fn write_value<T: io::Write>(&self, writer: &mut T) -> io::Result<()> {
+        match *self {
+            SvcParam::Mandatory(ref keys) => {
+                for key in keys {
+                    writer.write_u16::<BigEndian>(*key)?;
+                }
+            }
+            SvcParam::Alpn(ref ids) => {
+                for id in ids {
+                    writer.write_u8(id.len() as u8)?;
+                    writer.write_all(id)?;
+                }
+            }
+            SvcParam::NoDefaultAlpn => (),
+            SvcParam::Port(port) => writer.write_u16::<BigEndian>(port)?,
+            SvcParam::Ipv4Hint(ref ips) => {
+                for ip in ips {
+                    writer.write_all(&ip.octets())?;
+                }
+            }
+            SvcParam::Ech(config) => writer.write_all(config)?,
+            SvcParam::Ipv6Hint(ref ips) => {
+                for ip in ips {
+                    writer.write_all(&ip.octets())?;
+                }
+            }
+            SvcParam::Unknown { value, .. } => writer.write_all(value)?,
         }
+        Ok(())
     }
 }
@@ -1,13 +1,16 @@
-use super::{Class, Header, Name, QueryClass, QueryType, RRData};
+use super::{Class, Error, Header, Name, Opt, QueryClass, QueryType, RRData};
 
 /// Parsed DNS packet
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct Packet<'a> {
     pub header: Header,
     pub questions: Vec<Question<'a>>,
     pub answers: Vec<ResourceRecord<'a>>,
     pub nameservers: Vec<ResourceRecord<'a>>,
     pub additional: Vec<ResourceRecord<'a>>,
+    /// The EDNS0 pseudo-record of the additional section, which isn't in `additional`
+    pub opt: Option<Opt<'a>>,
 }
 
 /// A parsed chunk of data in the Query section of the packet
@@ -19,15 +22,51 @@ pub struct Question<'a> {
     pub qu: bool,
 }
 
+impl<'a> Question<'a> {
+    /// Whether the querier asked for a unicast response (the QU bit)
+    pub fn wants_unicast(&self) -> bool {
+        self.qu
+    }
+}
+
 /// A single DNS record
 ///
 /// We aim to provide whole range of DNS records available. But as time is
 /// limited we have some types of packets which are parsed and other provided
 /// as unparsed slice of bytes.
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct ResourceRecord<'a> {
     pub name: Name<'a>,
     pub cls: Class,
+    pub cache_flush: bool,
     pub ttl: u32,
     pub data: RRData<'a>,
 }
+
+impl<'a> ResourceRecord<'a> {
+    /// Whether the record replaces previously cached records of the same name,
+    /// type and class (the cache-flush bit)
+    #[allow(dead_code)]
+    pub fn cache_flush(&self) -> bool {
+        self.cache_flush
+    }
+}
+
+/// The part of a packet a question or record is in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Section {
+    Question,
+    Answer,
+    Nameserver,
+    Additional,
+}
+
+/// A question or record skipped by [`Packet::parse_lenient`]
+#[derive(Debug)]
+pub struct RecordError {
+    pub section: Section,
+    /// The position within the section, counting from zero
+    pub index: usize,
+    pub error: Error,
+}
@@ -0,0 +1,632 @@
+//! The protocol logic of a responder, independent of how packets are sent and received.
+
+use log::trace;
+use std::net::SocketAddr;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Instant;
+
+use crate::address_family::{Inet, Inet6};
+#[cfg(feature = "sync")]
+use crate::backoff::SendBackoff;
+use crate::cache::{CachedRecord, RecordCache};
+use crate::clock;
+use crate::config::{NetworkProfile, ResponderConfig};
+use crate::error::Error;
+use crate::fsm::{Handler, Shared};
+use crate::host::HostData;
+use crate::metrics::Counters;
+#[cfg(feature = "metrics-minimal")]
+use crate::metrics::Metrics;
+#[cfg(feature = "sync")]
+use crate::reflector::Reflector;
+use crate::service_builder::ServiceBuilder;
+use crate::services::{ServiceData, Services, ServicesInner};
+use crate::txt::TxtRecord;
+
+/// Identifies a service registered with an `Engine` or `SyncResponder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ServiceId(usize);
+
+/// Everything a responder does apart from I/O: the registered services, the answers
+/// to queries, and the scheduling of announcements.
+///
+/// The caller owns the sockets, joined to the mDNS group on port 5353. Received
+/// packets are passed to [`handle_packet`](Engine::handle_packet), packets to send
+/// are taken from [`poll_transmit`](Engine::poll_transmit), and
+/// [`next_timeout`](Engine::next_timeout) tells when to check again if nothing
+/// arrives.
+///
+/// # Example
+/// ```no_run
+/// use libmdns::ResponderBuilder;
+/// use std::net::{Ipv4Addr, UdpSocket};
+///
+/// # use std::io;
+/// # fn main() -> io::Result<()> {
+/// let socket = UdpSocket::bind("0.0.0.0:5353")?;
+/// socket.join_multicast_v4(&Ipv4Addr::new(224, 0, 0, 251), &Ipv4Addr::UNSPECIFIED)?;
+/// let mut engine = ResponderBuilder::new().enable_ipv6(false).build_engine()?;
+/// let _svc = engine.register("_http._tcp", "my http server", 80, &["path=/"]);
+///
+/// let mut buf = [0u8; 65536];
+/// loop {
+///     while let Some((packet, addr)) = engine.poll_transmit() {
+///         socket.send_to(&packet, addr)?;
+///     }
+///     let (len, addr) = socket.recv_from(&mut buf)?;
+///     engine.handle_packet(&buf[..len], addr);
+/// }
+/// # }
+/// ```
+pub struct Engine {
+    services: Services,
+    config: Arc<ResponderConfig>,
+    host: Arc<dyn HostData>,
+    /// read by `metrics` and `send_backoff`
+    #[cfg_attr(
+        not(any(feature = "metrics-minimal", feature = "sync")),
+        allow(dead_code)
+    )]
+    counters: Arc<Counters>,
+    cache: Arc<RecordCache>,
+    /// used by `SyncResponder`, which owns the sockets
+    #[cfg(feature = "sync")]
+    reflector: Option<Arc<Reflector>>,
+    v4: Option<Handler<Inet>>,
+    v6: Option<Handler<Inet6>>,
+}
+
+/// The services of a new responder configured by `config`, with those of a snapshot
+/// to restore. The tokio `Responder` starts out from these like an `Engine`.
+pub(crate) fn new_services(config: &ResponderConfig) -> Result<Services, Error> {
+    let hostname = resolve_hostname(config)?;
+    let mut services = ServicesInner::with_storage(hostname, config.service_storage);
+    #[cfg(feature = "unicode")]
+    services.normalize_names(!config.disable_normalization);
+    services.sequential_ids(config.sequential_ids);
+    #[cfg(feature = "snapshot")]
+    if let Some(ref snapshot) = config.snapshot {
+        services.restore(snapshot);
+    }
+    Ok(Arc::new(RwLock::new(services)))
+}
+
+/// A service registered by `register_services`
+pub(crate) struct Registered {
+    pub id: usize,
+    pub svc: ServiceData,
+    /// whether the name was restored from a recent snapshot, see
+    /// `ServicesInner::is_defended`, and needn't be probed for
+    #[cfg_attr(not(feature = "responder"), allow(dead_code))]
+    pub defended: bool,
+}
+
+/// Build and register `builders`, renaming those whose name is taken as configured,
+/// all of them or none. Returns them, to be announced by the caller.
+///
+/// This is how services are registered with an `Engine`, the tokio `Responder` and
+/// its `ServiceGroup`, which differ only in how the announcements reach the handlers.
+pub(crate) fn register_services<I>(
+    services: &Services,
+    config: &ResponderConfig,
+    builders: I,
+) -> Result<Vec<Registered>, Error>
+where
+    I: IntoIterator<Item = ServiceBuilder>,
+{
+    let built = builders
+        .into_iter()
+        .map(ServiceBuilder::build)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut registered = Vec::new();
+    let mut events = Vec::new();
+    {
+        let mut services = services.write().unwrap_or_else(PoisonError::into_inner);
+        for mut svc in built {
+            config.warn_short_ttls(&svc);
+            let defended = services.is_defended(&svc.name);
+            let result = services
+                .make_unique(&mut svc, config.rename_strategy())
+                .and_then(|renamed| Ok((services.register(svc.clone())?, renamed)));
+            match result {
+                Ok((id, renamed)) => {
+                    registered.push(Registered { id, svc, defended });
+                    events.extend(renamed);
+                }
+                Err(err) => {
+                    for registered in registered {
+                        services.unregister(registered.id);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+    }
+    // Called without the lock, which the callbacks may want
+    for event in events {
+        config.service_event(event);
+    }
+    Ok(registered)
+}
+
+impl Engine {
+    pub(crate) fn with_config(config: ResponderConfig) -> Result<Engine, Error> {
+        config.check_address_families()?;
+        let services = new_services(&config)?;
+        let shared = Shared::new(&services, config);
+        shared.watch_host(None);
+
+        Ok(Engine {
+            v4: (!shared.config.disable_ipv4).then(|| Handler::new(&shared)),
+            v6: (!shared.config.disable_ipv6).then(|| Handler::new(&shared)),
+            services,
+            config: shared.config,
+            host: shared.host,
+            counters: shared.counters,
+            cache: shared.cache,
+            #[cfg(feature = "sync")]
+            reflector: shared.reflector,
+        })
+    }
+
+    /// Register a service to be advertised. It is announced by the next packets
+    /// returned from `poll_transmit`.
+    pub fn register(
+        &mut self,
+        svc_type: &str,
+        svc_name: &str,
+        port: u16,
+        txt: &[&str],
+    ) -> ServiceId {
+        self.register_service(ServiceBuilder::new(svc_type, svc_name, port).txt(txt))
+    }
+
+    /// Like [`register`](Engine::register), with the TXT data given as a
+    /// [`TxtRecord`].
+    pub fn register_txt(
+        &mut self,
+        svc_type: &str,
+        svc_name: &str,
+        port: u16,
+        txt: TxtRecord,
+    ) -> ServiceId {
+        self.register_service(ServiceBuilder::new(svc_type, svc_name, port).txt(txt))
+    }
+
+    /// Register a service described by a [`ServiceBuilder`].
+    ///
+    /// # Panics
+    ///
+    /// If the service uses [`ConflictStrategy::Fail`] and its name is taken, or one of
+    /// its names is too long for DNS, see
+    /// [`try_register_service`](Engine::try_register_service).
+    ///
+    /// [`ConflictStrategy::Fail`]: crate::ConflictStrategy::Fail
+    pub fn register_service(&mut self, service: ServiceBuilder) -> ServiceId {
+        self.try_register_service(service)
+            .expect("couldn't register service")
+    }
+
+    /// Like [`register_service`](Engine::register_service), but failing with
+    /// [`Error::NameConflict`] when the name is taken by a service which may not be
+    /// renamed, and with [`Error::LabelTooLong`] or [`Error::NameTooLong`] when one
+    /// of its names can't be encoded in DNS, or [`Error::StoreFull`] when a fixed
+    /// service storage has no room left.
+    pub fn try_register_service(&mut self, service: ServiceBuilder) -> Result<ServiceId, Error> {
+        let registered = register_services(&self.services, &self.config, Some(service))?;
+        let Registered { id, svc, .. } = registered.into_iter().next().unwrap();
+        self.send_unsolicited(&svc, false, true);
+        Ok(ServiceId(id))
+    }
+
+    /// The instance name a service is registered under, which differs from the
+    /// requested one if that was taken.
+    pub fn name(&self, id: ServiceId) -> String {
+        let services = self.services.read().unwrap_or_else(PoisonError::into_inner);
+        services.get(id.0).expect("unknown service").instance()
+    }
+
+    /// Stop advertising a service, queueing a goodbye for it.
+    pub fn unregister(&mut self, id: ServiceId) {
+        let svc = self.services.write().unwrap_or_else(PoisonError::into_inner).unregister(id.0);
+        self.send_unsolicited(&svc, true, false);
+    }
+
+    fn send_unsolicited(&mut self, svc: &ServiceData, goodbye: bool, include_ip: bool) {
+        if let Some(ref mut v4) = self.v4 {
+            v4.send_unsolicited(svc, goodbye, include_ip);
+        }
+        if let Some(ref mut v6) = self.v6 {
+            v6.send_unsolicited(svc, goodbye, include_ip);
+        }
+    }
+
+    /// Handle a packet received from `from`. Responses are queued for
+    /// `poll_transmit`.
+    pub fn handle_packet(&mut self, packet: &[u8], from: SocketAddr) {
+        match from {
+            SocketAddr::V4(_) => match self.v4 {
+                Some(ref mut v4) => v4.handle_packet(packet, from),
+                None => trace!("ignoring packet from {:?}, IPv4 is disabled", from),
+            },
+            SocketAddr::V6(_) => match self.v6 {
+                Some(ref mut v6) => v6.handle_packet(packet, from),
+                None => trace!("ignoring packet from {:?}, IPv6 is disabled", from),
+            },
+        }
+    }
+
+    /// The next packet to send, and where to send it.
+    pub fn poll_transmit(&mut self) -> Option<(Vec<u8>, SocketAddr)> {
+        if let Some(packet) = self.v4.as_mut().and_then(Handler::pop_outgoing) {
+            return Some(packet);
+        }
+        self.v6.as_mut().and_then(Handler::pop_outgoing)
+    }
+
+    /// When `poll_transmit` may next have something to send, if packets are being
+    /// held back by pacing or goodbyes are waiting to be repeated.
+    pub fn next_timeout(&mut self) -> Option<Instant> {
+        let v4 = self.v4.as_mut().and_then(Handler::next_unsolicited_at);
+        let v6 = self.v6.as_mut().and_then(Handler::next_unsolicited_at);
+        match (v4, v6) {
+            (Some(v4), Some(v6)) => Some(v4.min(v6)),
+            (v4, v6) => v4.or(v6),
+        }
+    }
+
+    /// Pauses sending after errors, for callers which send on their own sockets
+    #[cfg(feature = "sync")]
+    pub(crate) fn send_backoff(&self) -> SendBackoff {
+        SendBackoff::new(&self.config, &self.counters)
+    }
+
+    /// The interfaces to reflect received packets to, for callers which send on their
+    /// own sockets
+    #[cfg(feature = "sync")]
+    pub(crate) fn reflector(&self) -> Option<Arc<Reflector>> {
+        self.reflector.clone()
+    }
+
+    /// Switch how eagerly queries are answered.
+    pub fn set_network_profile(&mut self, profile: NetworkProfile) {
+        if let Some(ref mut v4) = self.v4 {
+            v4.set_network_profile(profile);
+        }
+        if let Some(ref mut v6) = self.v6 {
+            v6.set_network_profile(profile);
+        }
+    }
+
+    /// Publish the host's addresses under `alias` as well as the hostname, see
+    /// [`Responder::add_hostname_alias`](crate::Responder::add_hostname_alias). The
+    /// probes for it and then its announcement are among the packets taken from the
+    /// engine.
+    pub fn add_hostname_alias(&mut self, alias: &str) -> Result<(), Error> {
+        let alias = local_hostname(alias.to_owned())?;
+        self.services.write().unwrap_or_else(PoisonError::into_inner).add_alias(alias);
+        Ok(())
+    }
+
+    /// Stop publishing `alias`. Returns whether it was added with
+    /// [`add_hostname_alias`](Engine::add_hostname_alias).
+    pub fn remove_hostname_alias(&mut self, alias: &str) -> bool {
+        match local_hostname(alias.to_owned()) {
+            Ok(alias) => self.services.write().unwrap_or_else(PoisonError::into_inner).remove_alias(&alias),
+            Err(_) => false,
+        }
+    }
+
+    /// The source of the hostname and addresses this engine publishes.
+    pub fn host(&self) -> Arc<dyn HostData> {
+        self.host.clone()
+    }
+
+    /// Counters describing what the engine has been doing.
+    #[cfg(feature = "metrics-minimal")]
+    pub fn metrics(&self) -> Metrics {
+        self.counters.metrics()
+    }
+
+    /// The records other responders have sent, if enabled with
+    /// [`ResponderBuilder::cache_records`](crate::ResponderBuilder::cache_records).
+    pub fn cached_records(&self) -> Vec<CachedRecord> {
+        self.cache.records(clock::now())
+    }
+}
+
+/// The configured, restored or system hostname, in the `.local` domain.
+pub(crate) fn resolve_hostname(config: &ResponderConfig) -> Result<String, Error> {
+    #[cfg(feature = "snapshot")]
+    let restored_hostname = config.snapshot.as_ref().map(|s| s.hostname.clone());
+    #[cfg(not(feature = "snapshot"))]
+    let restored_hostname = None;
+
+    let hostname = match config.hostname.clone().or(restored_hostname) {
+        Some(hostname) => hostname,
+        None => config.host_data().hostname()?,
+    };
+    local_hostname(hostname)
+}
+
+/// `hostname` in the `.local` domain, if it can be used as a DNS name
+pub(crate) fn local_hostname(mut hostname: String) -> Result<String, Error> {
+    if !hostname.ends_with(".local") {
+        hostname.push_str(".local");
+    }
+
+    // Labels are limited to 63 bytes and whole names to 255 on the wire, RFC 1035
+    // section 2.3.4
+    let valid = hostname.len() < 254
+        && hostname
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63);
+    if !valid {
+        return Err(Error::InvalidHostname(hostname));
+    }
+
+    Ok(hostname)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns_parser::{self, Name, QueryClass, QueryType, RRData};
+    use crate::error::Error;
+    use crate::host::FixedHostData;
+    use crate::{
+        NetworkProfile, RecordData, ResponderBuilder, ServiceBuilder, TxtRecord, DEFAULT_TTL,
+    };
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_engine() {
+        let host = FixedHostData::new("engine-host".into(), vec!["192.0.2.3".parse().unwrap()]);
+        let mut engine = ResponderBuilder::new()
+            .host_data(Arc::new(host))
+            .enable_ipv6(false)
+            .build_engine()
+            .unwrap();
+
+        let id = engine.register("_engine._tcp", "instance", 80, &["a=b"]);
+        let (announcement, addr) = engine.poll_transmit().unwrap();
+        assert_eq!(addr, "224.0.0.251:5353".parse().unwrap());
+        assert!(!dns_parser::Packet::parse(&announcement)
+            .unwrap()
+            .answers
+            .is_empty());
+        assert!(engine.poll_transmit().is_none());
+
+        let query = dns_parser::Builder::new_query(0, false)
+            .add_question(
+                &Name::from_str("_engine._tcp.local").unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        engine.handle_packet(&query, "[2001:db8::1]:5353".parse().unwrap());
+        assert!(engine.poll_transmit().is_none());
+        engine.handle_packet(&query, "169.254.0.1:5353".parse().unwrap());
+        assert!(engine.poll_transmit().is_some());
+        #[cfg(feature = "metrics-minimal")]
+        assert_eq!(engine.metrics().packets_answered, 1);
+
+        engine.unregister(id);
+        let (goodbye, _) = engine.poll_transmit().unwrap();
+        assert_eq!(
+            dns_parser::Packet::parse(&goodbye).unwrap().answers[0].ttl,
+            0
+        );
+    }
+
+    #[test]
+    fn test_register_txt() {
+        let host = FixedHostData::new("engine-host".into(), vec!["192.0.2.3".parse().unwrap()]);
+        let mut engine = ResponderBuilder::new()
+            .host_data(Arc::new(host))
+            .enable_ipv6(false)
+            .build_engine()
+            .unwrap();
+
+        // Entries collected at runtime are passed as they were before `TxtRecord`
+        let entries: Vec<&str> = vec!["a=b"];
+        engine.register("_http._tcp", "plain", 80, &entries);
+        let mut txt = TxtRecord::new();
+        txt.insert("a", "b").unwrap();
+        engine.register_txt("_http._tcp", "built", 80, txt);
+
+        let mut records = Vec::new();
+        while let Some((packet, _)) = engine.poll_transmit() {
+            let packet = dns_parser::Packet::parse(&packet).unwrap();
+            for answer in packet.answers {
+                if let RRData::TXT(data) = answer.data {
+                    records.push((answer.name.to_string(), data.to_vec()));
+                }
+            }
+        }
+        assert_eq!(
+            records,
+            [
+                ("plain._http._tcp.local".into(), b"\x03a=b".to_vec()),
+                ("built._http._tcp.local".into(), b"\x03a=b".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hostname_alias() {
+        let host = FixedHostData::new("engine-host".into(), vec!["192.0.2.3".parse().unwrap()]);
+        let mut engine = ResponderBuilder::new()
+            .host_data(Arc::new(host))
+            .enable_ipv6(false)
+            .build_engine()
+            .unwrap();
+        let name = |packet: &[u8]| {
+            let packet = dns_parser::Packet::parse(packet).unwrap();
+            (packet.answers[0].name.to_string(), packet.answers[0].ttl)
+        };
+
+        engine.add_hostname_alias("myapp").unwrap();
+        engine.add_hostname_alias("MyApp.local").unwrap();
+        engine.add_hostname_alias("engine-host").unwrap();
+
+        // Announced once the probes for it have gone unanswered
+        let mut probes = 0;
+        let announcement = loop {
+            match engine.poll_transmit() {
+                Some((packet, _)) if dns_parser::Packet::parse(&packet).unwrap().header.query => {
+                    probes += 1
+                }
+                Some((packet, _)) => break packet,
+                None => {
+                    let at = engine.next_timeout().unwrap();
+                    thread::sleep(at.saturating_duration_since(Instant::now()))
+                }
+            }
+        };
+        assert_eq!(probes, 3);
+        assert_eq!(name(&announcement), ("myapp.local".into(), DEFAULT_TTL));
+        assert!(engine.poll_transmit().is_none());
+
+        let query = dns_parser::Builder::new_query(0, false)
+            .add_question(
+                &Name::from_str("myapp.local").unwrap(),
+                QueryType::A,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        engine.handle_packet(&query, "169.254.0.1:5353".parse().unwrap());
+        let (answer, _) = engine.poll_transmit().unwrap();
+        assert_eq!(name(&answer), ("myapp.local".into(), DEFAULT_TTL));
+
+        assert!(matches!(
+            engine.add_hostname_alias("bad..name"),
+            Err(Error::InvalidHostname(_))
+        ));
+        assert!(!engine.remove_hostname_alias("other"));
+        assert!(engine.remove_hostname_alias("myapp"));
+        let (goodbye, _) = engine.poll_transmit().unwrap();
+        assert_eq!(name(&goodbye), ("myapp.local".into(), 0));
+    }
+
+    #[test]
+    fn test_profile_min_ttl() {
+        let host = FixedHostData::new("engine-host".into(), vec!["192.0.2.3".parse().unwrap()]);
+        let mut engine = ResponderBuilder::new()
+            .host_data(Arc::new(host))
+            .enable_ipv6(false)
+            .network_profile(NetworkProfile::Compatible)
+            .build_engine()
+            .unwrap();
+
+        let service = ServiceBuilder::new("_engine._tcp", "instance", 80)
+            .ttl(10)
+            .additional_record("fast.local", 5, RecordData::A([192, 0, 2, 7].into()))
+            .additional_record("slow.local", 600, RecordData::A([192, 0, 2, 8].into()));
+        let id = engine.register_service(service);
+        let (announcement, _) = engine.poll_transmit().unwrap();
+        let announcement = dns_parser::Packet::parse(&announcement).unwrap();
+        let ttl = |name: &str| {
+            announcement
+                .answers
+                .iter()
+                .find(|answer| answer.name.to_string() == name)
+                .unwrap()
+                .ttl
+        };
+        assert_eq!(ttl("fast.local"), 30);
+        assert_eq!(ttl("slow.local"), 600);
+        assert_eq!(ttl("instance._engine._tcp.local"), 30);
+
+        // Other profiles publish the TTLs as registered
+        engine.set_network_profile(NetworkProfile::Normal);
+        let query = dns_parser::Builder::new_query(0, false)
+            .add_question(
+                &dns_parser::Name::from_str("instance._engine._tcp.local").unwrap(),
+                dns_parser::QueryType::SRV,
+                dns_parser::QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        engine.handle_packet(&query, "169.254.0.1:5353".parse().unwrap());
+        let (answer, _) = engine.poll_transmit().unwrap();
+        let answer = dns_parser::Packet::parse(&answer).unwrap();
+        assert_eq!(answer.answers[0].ttl, 10);
+
+        engine.unregister(id);
+        let (goodbye, _) = engine.poll_transmit().unwrap();
+        let goodbye = dns_parser::Packet::parse(&goodbye).unwrap();
+        assert!(goodbye.answers.iter().all(|answer| answer.ttl == 0));
+    }
+
+    #[cfg(feature = "fixed-store")]
+    #[test]
+    fn test_engine_store_full() {
+        let mut engine = ResponderBuilder::new()
+            .hostname("test-host".into())
+            .service_storage(crate::ServiceStorage::Fixed { capacity: 1 })
+            .build_engine()
+            .unwrap();
+        let first = engine.register("_http._tcp", "first", 80, &[]);
+        let second = ServiceBuilder::new("_http._tcp", "second", 80);
+        assert!(matches!(
+            engine.try_register_service(second),
+            Err(Error::StoreFull(1))
+        ));
+
+        // The services are still there to be used
+        assert_eq!(engine.name(first), "first");
+        engine.unregister(first);
+        engine.register("_http._tcp", "third", 80, &[]);
+    }
+
+    #[test]
+    fn test_engine_errors() {
+        let engine = ResponderBuilder::new()
+            .hostname("bad..name".into())
+            .build_engine();
+        assert!(matches!(engine, Err(Error::InvalidHostname(_))));
+
+        let engine = ResponderBuilder::new()
+            .enable_ipv4(false)
+            .enable_ipv6(false)
+            .build_engine();
+        assert!(matches!(engine, Err(Error::NoAddressFamily)));
+
+        // Names DNS can't encode are refused rather than panicking when sent
+        let mut engine = ResponderBuilder::new()
+            .hostname("test-host".into())
+            .build_engine()
+            .unwrap();
+        let long = "x".repeat(64);
+        let service = ServiceBuilder::new("_http._tcp", &long, 80);
+        match engine.try_register_service(service) {
+            Err(Error::LabelTooLong { label, .. }) => assert_eq!(label, long),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let service = ServiceBuilder::new("_http._tcp", "printer", 80).additional_record(
+            &format!("{}.local", vec!["x".repeat(63); 4].join(".")),
+            120,
+            RecordData::A([192, 0, 2, 1].into()),
+        );
+        assert!(matches!(
+            engine.try_register_service(service),
+            Err(Error::NameTooLong(_))
+        ));
+
+        // The longest instance name fits, and is cut short to make room for a suffix
+        let longest = "x".repeat(63);
+        let first = engine
+            .try_register_service(ServiceBuilder::new("_http._tcp", &longest, 80))
+            .unwrap();
+        assert_eq!(engine.name(first), longest);
+        let second = engine
+            .try_register_service(ServiceBuilder::new("_http._tcp", &longest, 81))
+            .unwrap();
+        assert_eq!(engine.name(second), format!("{} (2)", "x".repeat(59)));
+        while engine.poll_transmit().is_some() {}
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use thiserror::Error;
+
+/// The IP version of a socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpFamily::V4 => f.write_str("IPv4"),
+            IpFamily::V6 => f.write_str("IPv6"),
+        }
+    }
+}
+
+/// Error starting a responder or registering a service
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The mDNS socket could not be set up, typically because another process has
+    /// port 5353 without allowing it to be shared.
+    #[error("couldn't bind {family} socket to {addr}: {source}")]
+    BindFailed {
+        family: IpFamily,
+        addr: SocketAddr,
+        source: io::Error,
+    },
+    /// The mDNS multicast group could not be joined on an interface
+    #[error("couldn't join the mDNS group on interface {iface}: {source}")]
+    MulticastJoinFailed { iface: String, source: io::Error },
+    /// None of the interfaces set with
+    /// [`ResponderBuilder::interfaces`](crate::ResponderBuilder::interfaces) exist
+    /// with an address of this family
+    #[error("none of the interfaces {interfaces:?} has an {family} address")]
+    NoMatchingInterface {
+        family: IpFamily,
+        interfaces: Vec<String>,
+    },
+    /// The configured or system hostname can't be used as a DNS name
+    #[error("{0:?} is not a valid hostname")]
+    InvalidHostname(String),
+    /// A label of a service's names, such as its instance name, is longer than the
+    /// 63 bytes DNS allows
+    #[error("the label {label:?} of {name:?} is longer than 63 bytes")]
+    LabelTooLong { name: String, label: String },
+    /// A name of a service is longer than the 255 bytes DNS allows
+    #[error("the name {0:?} is longer than 255 bytes")]
+    NameTooLong(String),
+    /// Another service of the responder has the instance name of a service registered
+    /// with [`ConflictStrategy::Fail`](crate::ConflictStrategy::Fail), or the
+    /// [`RenameStrategy`](crate::RenameStrategy) gave no name which is free
+    #[error("the instance name {0:?} is already registered")]
+    NameConflict(String),
+    /// An entry of the allow-list is neither an address, a prefix nor an interface
+    /// name, see [`AllowedIp`](crate::AllowedIp)
+    #[error("{0:?} is not an address, prefix or interface name")]
+    InvalidAllowedIp(String),
+    /// Both IPv4 and IPv6 were disabled, leaving nothing to listen on
+    #[error("both IPv4 and IPv6 are disabled")]
+    NoAddressFamily,
+    /// The network namespace set with
+    /// [`ResponderBuilder::network_namespace`](crate::ResponderBuilder::network_namespace)
+    /// could not be entered, e.g. for lack of the `CAP_SYS_ADMIN` capability
+    #[error("couldn't enter network namespace {namespace}: {source}")]
+    NetworkNamespace {
+        namespace: String,
+        source: io::Error,
+    },
+    /// A network namespace was set on a platform other than Linux
+    #[error("network namespaces are only supported on Linux")]
+    NetworkNamespaceUnsupported,
+    /// The services fill the storage set with `ServiceStorage::Fixed`, which never
+    /// grows
+    #[error("no room for more than {0} services")]
+    StoreFull(usize),
+    /// The responder stopped before the service was announced, see
+    /// [`Service::announced`](crate::Service::announced)
+    #[error("the responder shut down before the service was announced")]
+    ShutDown,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Lets `?` keep working in functions returning `io::Result`.
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        let kind = match err {
+            Error::Io(err) => return err,
+            Error::BindFailed { ref source, .. } => source.kind(),
+            Error::MulticastJoinFailed { ref source, .. } => source.kind(),
+            Error::NetworkNamespace { ref source, .. } => source.kind(),
+            Error::NetworkNamespaceUnsupported => io::ErrorKind::Unsupported,
+            Error::ShutDown => io::ErrorKind::Interrupted,
+            Error::NoMatchingInterface { .. } => io::ErrorKind::NotFound,
+            Error::NameConflict(_) => io::ErrorKind::AlreadyExists,
+            Error::StoreFull(_) => io::ErrorKind::Other,
+            Error::InvalidHostname(_)
+            | Error::InvalidAllowedIp(_)
+            | Error::LabelTooLong { .. }
+            | Error::NameTooLong(_)
+            | Error::NoAddressFamily => io::ErrorKind::InvalidInput,
+        };
+        io::Error::new(kind, err)
+    }
+}
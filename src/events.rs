@@ -0,0 +1,147 @@
+use crate::error::IpFamily;
+use crate::rename::ConflictStrategy;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// A question received by the responder, passed to the
+/// [`on_query`](crate::ResponderBuilder::on_query) callback.
+#[derive(Clone, Debug)]
+pub struct QueryEvent {
+    /// Where the query came from
+    pub source: SocketAddr,
+    /// The name being asked about
+    pub name: String,
+    /// The numeric QTYPE of the question
+    pub qtype: u16,
+    /// Whether the querier asked for a unicast response (the QU bit)
+    pub unicast: bool,
+    /// Whether we added any answers for this question
+    pub answered: bool,
+}
+
+/// A change to a registered service, passed to the
+/// [`on_service_event`](crate::ResponderBuilder::on_service_event) callback.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum ServiceEvent {
+    /// The requested instance name was taken, so the service was registered under
+    /// the name picked by the [`RenameStrategy`](crate::RenameStrategy).
+    Renamed {
+        requested: String,
+        name: String,
+        /// How many names were tried before `name`
+        attempts: u32,
+        /// The [`describe`](crate::RenameStrategy::describe) text of the strategy
+        strategy: String,
+    },
+    /// The [`RenameStrategy`](crate::RenameStrategy) only came up with names in use,
+    /// so the service of another device's conflicting records was withdrawn instead.
+    /// A registration failing like this returns
+    /// [`Error::NameConflict`](crate::Error::NameConflict).
+    RenameFailed {
+        requested: String,
+        /// How many names were tried
+        attempts: u32,
+        strategy: String,
+    },
+    /// Another device on the network published different records under the name of
+    /// the service, which was handled according to `strategy`. A rename is followed by
+    /// a `Renamed` event with the new name, or `RenameFailed`.
+    Conflict {
+        name: String,
+        strategy: ConflictStrategy,
+    },
+}
+
+/// Another responder published one of our addresses under a name which isn't ours,
+/// passed to the [`on_address_conflict`](crate::ResponderBuilder::on_address_conflict)
+/// callback. Usually means two hosts were given the same IP address, or our own
+/// traffic coming back through NAT hairpinning. Reported once per name and address.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct AddressConflict {
+    /// Our address, as found in the other responder's record
+    pub address: IpAddr,
+    /// The name the other responder published it under
+    pub name: String,
+    /// Where the record came from
+    pub source: SocketAddr,
+}
+
+/// Another responder published our hostname, or one of its aliases, with an address
+/// which isn't ours, passed to the
+/// [`on_hostname_conflict`](crate::ResponderBuilder::on_hostname_conflict) callback.
+/// Usually means another host was given the same name. Reported once per name and
+/// address.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct HostnameConflict {
+    /// Our hostname or alias
+    pub name: String,
+    /// The address the other responder published for it
+    pub address: IpAddr,
+    /// Where the record came from
+    pub source: SocketAddr,
+}
+
+/// Handling a packet panicked, passed to the
+/// [`on_packet_panic`](crate::ResponderBuilder::on_packet_panic) callback. The packet
+/// was dropped, and the responder carries on with the next one.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct PacketPanic {
+    /// Where the packet came from
+    pub source: SocketAddr,
+    /// The panic message
+    pub message: String,
+}
+
+/// Why a received packet was dropped, see [`PacketDropped`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DropReason {
+    /// The packet could not be parsed as DNS
+    Unparseable,
+    /// One of our own responses, brought back by multicast loopback
+    OwnPacket,
+    /// A query with the TC bit set, whose known answers continue in further packets
+    Truncated,
+    /// A query from off the local link, see
+    /// [`accept_off_link_queries`](crate::ResponderBuilder::accept_off_link_queries)
+    OffLink,
+    /// A query which arrived on another socket already, and was handled there
+    Duplicate,
+    /// Handling the packet panicked, see [`PacketPanic`]
+    Panicked,
+    /// A packet from a network interface other than those selected with
+    /// [`interfaces`](crate::ResponderBuilder::interfaces)
+    OtherInterface,
+}
+
+/// A received packet was dropped before being answered or learned from, passed to
+/// the [`on_packet_dropped`](crate::ResponderBuilder::on_packet_dropped) callback.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct PacketDropped {
+    pub reason: DropReason,
+    /// Where the packet came from
+    pub source: SocketAddr,
+}
+
+/// Sending has kept failing with errors such as a full socket buffer or a firewall
+/// refusing packets, passed to the
+/// [`on_send_failure`](crate::ResponderBuilder::on_send_failure) callback. Reported
+/// once per run of failures.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct SendFailure {
+    /// The family of the socket the packets were sent on
+    pub family: IpFamily,
+    /// The kind of the last error
+    pub kind: io::ErrorKind,
+    /// How long sends have been failing
+    pub failing_for: Duration,
+    /// Packets given up on so far
+    pub dropped: u64,
+}
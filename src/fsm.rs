@@ -1,67 +1,397 @@
-use crate::dns_parser::{self, Name, QueryClass, QueryType, RRData};
-use if_addrs::get_if_addrs;
+use crate::dns_parser::{
+    self, Canonical, Name, NameKey, QueryClass, QueryType, Question, RRData, Type,
+};
 use log::{debug, error, trace, warn};
-use socket2::Domain;
+use std::any::Any;
 use std::borrow::Cow;
-use std::collections::VecDeque;
-use std::io;
-use std::io::ErrorKind::WouldBlock;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+#[cfg(feature = "responder")]
+use std::io::{self, ErrorKind::WouldBlock};
 use std::marker::PhantomData;
 use std::net::{IpAddr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+#[cfg(feature = "responder")]
 use std::{
     future::Future,
     pin::Pin,
     task::{Context, Poll},
 };
 
-use tokio::{net::UdpSocket, sync::mpsc};
+#[cfg(feature = "responder")]
+use tokio::sync::{mpsc, oneshot};
 
 use super::{DEFAULT_TTL, MDNS_PORT};
+#[cfg(feature = "responder")]
+use crate::address_family::destinations;
 use crate::address_family::AddressFamily;
-use crate::services::{ServiceData, Services};
+use crate::allow;
+#[cfg(feature = "responder")]
+use crate::backoff::SendBackoff;
+use crate::browse::Browsers;
+use crate::budget::{Lru, MemoryBudget, DEFAULT_MEMORY_BUDGET};
+use crate::cache::RecordCache;
+use crate::clock;
+use crate::config::{NetworkProfile, ResponderConfig};
+use crate::coordination::AddressOwner;
+use crate::dedup::{
+    dedup_questions, RecentMulticasts, RecentMulticastsInner, SeenQueries, SeenQueriesInner,
+    SentPackets, SentPacketsInner, MULTICAST_INTERVAL, PROBE_MULTICAST_INTERVAL,
+};
+#[cfg(feature = "responder")]
+use crate::error::Error;
+use crate::error::IpFamily;
+use crate::events::{
+    AddressConflict, DropReason, HostnameConflict, PacketDropped, PacketPanic, QueryEvent,
+    ServiceEvent,
+};
+use crate::host::{AddressCache, HostData, HostWatcher, InterfaceSource};
+use crate::metrics::Counters;
+use crate::pacing::Pacer;
+use crate::reflector::Reflector;
+#[cfg(feature = "responder")]
+use crate::reflector::{self, Via};
+use crate::rename::ConflictStrategy;
+use crate::records::{AdditionalRecord, ProbeBuilder};
+use crate::services::{ServiceData, Services, ServicesInner};
+#[cfg(feature = "responder")]
+use crate::transport::{Link, Timer, Transport};
+#[cfg(feature = "responder")]
+use crate::workers::Pool;
 
 pub type AnswerBuilder = dns_parser::Builder<dns_parser::Answers>;
 
+/// A response built by a worker, with the time after which it is dropped
+pub type Answer = (Vec<u8>, SocketAddr, Instant);
+
+/// An announcement or goodbye waiting to be sent
+#[derive(Clone)]
+struct Unsolicited {
+    packet: Vec<u8>,
+    addr: SocketAddr,
+    service: NameKey,
+    rate: Option<u32>,
+    announced: Option<Announced>,
+    /// for a scheduled repeat of an announcement or goodbye, the names it is about,
+    /// whose next announcement or goodbye drops it
+    repeats: Vec<NameKey>,
+    #[cfg_attr(not(feature = "responder"), allow(dead_code))]
+    goodbye: bool,
+}
+
+/// Tells whoever waits for the announcement of a service when it has been sent.
+/// Each clone stands for a part of the work yet to be done: a command on its way to
+/// the handler of an address family, or a packet of the announcement waiting to be
+/// sent. Once the last clone is gone, the receiver learns whether all of them were
+/// `sent`, or some were dropped on the way.
+pub struct Announced(Arc<AnnouncedInner>);
+
+struct AnnouncedInner {
+    unsent: AtomicUsize,
+    #[cfg(feature = "responder")]
+    tx: Option<oneshot::Sender<bool>>,
+}
+
+impl Announced {
+    #[cfg(feature = "responder")]
+    pub fn new() -> (Announced, oneshot::Receiver<bool>) {
+        let (tx, rx) = oneshot::channel();
+        let inner = AnnouncedInner {
+            unsent: AtomicUsize::new(1),
+            tx: Some(tx),
+        };
+        (Announced(Arc::new(inner)), rx)
+    }
+
+    /// This part is done
+    pub fn sent(self) {
+        self.0.unsent.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Clone for Announced {
+    fn clone(&self) -> Announced {
+        self.0.unsent.fetch_add(1, Ordering::Relaxed);
+        Announced(self.0.clone())
+    }
+}
+
+impl std::fmt::Debug for Announced {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Announced")
+            .field("unsent", &self.0.unsent.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+#[cfg(feature = "responder")]
+impl Drop for AnnouncedInner {
+    fn drop(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(*self.unsent.get_mut() == 0);
+        }
+    }
+}
+
+/// A record published under the name being asked about
+enum Record<'a> {
+    /// A / AAAA records of the hostname or an alias, and which of them there are
+    Address(Name<'a>, Vec<Type>),
+    /// An entry of the service type enumeration
+    ServiceType(&'a Name<'static>),
+    /// The names published for one of our addresses
+    Reverse(Vec<Name<'a>>),
+    /// A PTR to a service instance of the type asked about
+    Instance(&'a ServiceData),
+    Srv(&'a ServiceData),
+    Txt(&'a ServiceData),
+    Additional(&'a AdditionalRecord),
+    /// The HINFO record of the hostname
+    HostInfo(&'a Name<'a>),
+}
+
+impl<'a> Record<'a> {
+    /// The numeric types of the records
+    fn types(&self) -> Vec<u16> {
+        match *self {
+            Record::Address(_, ref types) => types.iter().map(|&typ| typ as u16).collect(),
+            Record::ServiceType(_) | Record::Reverse(_) | Record::Instance(_) => {
+                vec![Type::PTR as u16]
+            }
+            Record::Srv(_) => vec![Type::SRV as u16],
+            Record::Txt(_) => vec![Type::TXT as u16],
+            Record::Additional(record) => vec![record.typ()],
+            Record::HostInfo(..) => vec![Type::HINFO as u16],
+        }
+    }
+
+    /// Whether this record answers a question of type `qtype`
+    fn answers(&self, qtype: QueryType) -> bool {
+        qtype == QueryType::All || self.types().contains(&u16::from(qtype))
+    }
+
+    /// Whether the name is ours alone, rather than shared with other responders
+    fn is_unique(&self) -> bool {
+        match *self {
+            Record::Address(..)
+            | Record::Reverse(_)
+            | Record::Srv(_)
+            | Record::Txt(_)
+            | Record::HostInfo(..) => true,
+            Record::Additional(record) => record.is_unique(),
+            _ => false,
+        }
+    }
+}
+
+/// What became of a received packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PacketOutcome {
+    Answered,
+    /// A query with nothing to answer
+    Ignored,
+    /// Records from another responder rather than a query
+    Response,
+    Dropped(DropReason),
+}
+
+#[cfg(feature = "tracing")]
+impl PacketOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            PacketOutcome::Answered => "answered",
+            PacketOutcome::Ignored => "ignored",
+            PacketOutcome::Response => "response",
+            PacketOutcome::Dropped(DropReason::Unparseable) => "parse_failed",
+            PacketOutcome::Dropped(DropReason::OwnPacket) => "own_packet",
+            PacketOutcome::Dropped(DropReason::Truncated) => "truncated",
+            PacketOutcome::Dropped(DropReason::OffLink) => "off_link",
+            PacketOutcome::Dropped(DropReason::Duplicate) => "duplicate",
+            PacketOutcome::Dropped(DropReason::Panicked) => "panicked",
+            PacketOutcome::Dropped(DropReason::OtherInterface) => "other_interface",
+        }
+    }
+}
+
+/// Largest response which fits a 1500 byte Ethernet MTU without fragmenting
+const DEFAULT_MAX_PACKET_SIZE: usize = 1472;
+
+/// Largest response to a querier advertising a bigger EDNS0 payload size: 9000 bytes
+/// with IPv6 and UDP headers, RFC 6762 section 17
+const MAX_EDNS_PACKET_SIZE: usize = 8952;
+
+/// Smallest EDNS0 payload size, which any smaller one stands for, RFC 6891
+/// section 6.2.5
+const MIN_EDNS_PAYLOAD_SIZE: usize = 512;
+
+/// How long a response may wait to be sent before it is no use to the querier
+const DEFAULT_MAX_RESPONSE_DELAY: Duration = Duration::from_secs(2);
+
+/// How many responses may wait to be sent before further ones are dropped
+const DEFAULT_MAX_QUEUED_RESPONSES: usize = 256;
+
+/// Goodbyes are sent this many more times, as they are easily lost on Wi-Fi
+const GOODBYE_REPEATS: u32 = 2;
+
+/// Range of the random delay in milliseconds before each repeat of a goodbye
+const GOODBYE_REPEAT_DELAY_MS: (u64, u64) = (250, 750);
+
+/// How many times each announcement is sent, see `ResponderBuilder::announcements`
+const DEFAULT_ANNOUNCEMENTS: u32 = 2;
+
+/// RFC 6762 section 8.3 allows up to eight announcements
+pub const MAX_ANNOUNCEMENTS: u32 = 8;
+
+/// Range of the interval in milliseconds before the first repeat of an announcement,
+/// which doubles for each further one. At least a second, RFC 6762 section 8.3.
+const ANNOUNCE_INTERVAL_MS: (u64, u64) = (1000, 1100);
+
+/// A name is probed for this many times before being announced, RFC 6762 section 8.1
+const PROBES: u32 = 3;
+
+/// Milliseconds between probes, which is also the longest random delay before the first
+const PROBE_INTERVAL_MS: u64 = 250;
+
+/// How long the addresses of the host are reused before being listed again
+const DEFAULT_ADDRESS_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Maximum TTL for answers to legacy unicast queries, see RFC 6762 section 6.7
+const LEGACY_UNICAST_TTL: u32 = 10;
+
 const SERVICE_TYPE_ENUMERATION_NAME: Cow<'static, str> =
     Cow::Borrowed("_services._dns-sd._udp.local");
 
 #[derive(Clone, Debug)]
+#[cfg(feature = "responder")]
 pub enum Command {
     SendUnsolicited {
         svc: ServiceData,
-        ttl: u32,
+        /// goodbyes, with a TTL of 0, rather than an announcement
+        goodbye: bool,
+        include_ip: bool,
+        /// to tell once the announcement has been sent
+        announced: Option<Announced>,
+    },
+    /// Like `SendUnsolicited`, combining the records of several services
+    SendUnsolicitedGroup {
+        svcs: Vec<ServiceData>,
+        goodbye: bool,
         include_ip: bool,
+        announced: Option<Announced>,
+    },
+    /// Probe for the names of `svcs`, then announce them together
+    ProbeGroup {
+        svcs: Vec<ServiceData>,
+        announced: Announced,
+    },
+    /// Goodbyes for the SRV records of `svc` which `current` no longer has
+    SendSrvGoodbye {
+        svc: ServiceData,
+        current: ServiceData,
+    },
+    /// Goodbyes for the records published under a hostname which was replaced, see
+    /// `Responder::replace`
+    WithdrawHostname(Name<'static>),
+    SetNetworkProfile(NetworkProfile),
+    /// A browse was started, whose first query is due
+    Browse,
+    /// The `HostData` reported a change
+    HostChanged,
+    /// The host woke from sleep, see `Responder::notify_resumed`
+    Resumed,
+    /// A packet from the caller, see `Responder::feed_packet`. Handled by the family
+    /// it was sent from only.
+    Received {
+        packet: Vec<u8>,
+        from: SocketAddr,
     },
     Shutdown,
 }
 
-pub struct FSM<AF: AddressFamily> {
-    socket: UdpSocket,
-    services: Services,
+/// How many commands, received packets and sent packets an FSM handles per poll before
+/// it yields, so that a storm on one address family doesn't starve the other, or other
+/// tasks on a current-thread runtime
+#[cfg(feature = "responder")]
+const POLL_BUDGET: usize = 32;
+
+#[cfg(feature = "responder")]
+pub struct FSM<AF: AddressFamily, T: Link = tokio::net::UdpSocket> {
+    socket: T,
     commands: mpsc::UnboundedReceiver<Command>,
-    outgoing: VecDeque<(Vec<u8>, SocketAddr)>,
-    _af: PhantomData<AF>,
-    allowed_ip: Vec<IpAddr>,
+    handler: Handler<AF>,
+    /// answers queries on other threads, if configured
+    workers: Option<Pool>,
+    backoff: SendBackoff,
+    /// a packet to send again once the backoff allows
+    retry: Option<(Vec<u8>, SocketAddr)>,
+    /// interfaces to send a copy of each multicast packet on, see `AF::bind_scoped`
+    scopes: Vec<u32>,
+    /// packets taken from the handler but not sent yet: copies of a packet for further
+    /// scopes, or one the socket wasn't ready for
+    pending: VecDeque<(Vec<u8>, SocketAddr)>,
+    reflector: Option<Arc<Reflector>>,
+    /// copies of received packets to multicast on other interfaces
+    reflected: VecDeque<(Vec<u8>, Via)>,
+    /// wakes the task when pacing allows the next announcement
+    pacing_timer: Option<Timer<T>>,
+    /// set once shut down, when the task only sends the goodbyes still waiting
+    shutting_down: bool,
 }
 
-impl<AF: AddressFamily> FSM<AF> {
+#[cfg(feature = "responder")]
+impl<AF: AddressFamily, T: Transport> FSM<AF, T> {
     // Will panic if called from outside the context of a runtime
-    pub fn new(
-        services: &Services,
-        allowed_ip: Vec<IpAddr>,
-    ) -> io::Result<(FSM<AF>, mpsc::UnboundedSender<Command>)> {
-        let std_socket = AF::bind()?;
-        let socket = UdpSocket::from_std(std_socket)?;
+    pub fn new(shared: &Shared) -> Result<(FSM<AF, T>, mpsc::UnboundedSender<Command>), Error> {
+        let config = &shared.config;
+        let (std_socket, scopes) = AF::bind_in(
+            config.network_namespace.as_ref(),
+            &config.interfaces,
+            config.join_allow_list(),
+        )?;
+        Self::with_socket(shared, std_socket, scopes)
+    }
+
+    /// Like `new`, with a socket bound already
+    pub fn with_socket(
+        shared: &Shared,
+        std_socket: std::net::UdpSocket,
+        scopes: Vec<u32>,
+    ) -> Result<(FSM<AF, T>, mpsc::UnboundedSender<Command>), Error> {
+        Self::with_link(shared, T::from_std(std_socket)?, scopes)
+    }
+}
 
+#[cfg(feature = "responder")]
+impl<AF: AddressFamily, T: Link> FSM<AF, T> {
+    /// Like `with_socket`, sending and receiving through `socket`, e.g. the sink of a
+    /// responder without sockets
+    pub fn with_link(
+        shared: &Shared,
+        socket: T,
+        scopes: Vec<u32>,
+    ) -> Result<(FSM<AF, T>, mpsc::UnboundedSender<Command>), Error> {
         let (tx, rx) = mpsc::unbounded_channel();
+        let workers = match shared.config.answer_workers {
+            0 => None,
+            workers => Some(Pool::new::<AF>(shared, workers)?),
+        };
 
         let fsm = FSM {
-            socket: socket,
-            services: services.clone(),
+            socket,
             commands: rx,
-            outgoing: VecDeque::new(),
-            _af: PhantomData,
-            allowed_ip: allowed_ip,
+            handler: Handler::new(shared),
+            workers,
+            backoff: SendBackoff::new(&shared.config, &shared.counters),
+            retry: None,
+            scopes,
+            pending: VecDeque::new(),
+            reflector: shared.reflector.clone(),
+            reflected: VecDeque::new(),
+            pacing_timer: None,
+            shutting_down: false,
         };
 
         Ok((fsm, tx))
@@ -70,303 +400,4970 @@ impl<AF: AddressFamily> FSM<AF> {
     fn recv_packets(&mut self, cx: &mut Context) -> io::Result<()> {
         // Buffer size discussed in: https://github.com/librespot-org/libmdns/pull/40
         let mut recv_buf = [0u8; 65536];
-        let mut buf = tokio::io::ReadBuf::new(&mut recv_buf);
-        loop {
-            let addr = match self.socket.poll_recv_from(cx, &mut buf) {
-                Poll::Ready(Ok(addr)) => addr,
+        for _ in 0..POLL_BUDGET {
+            let (len, addr) = match self.socket.poll_recv_from(cx, &mut recv_buf) {
+                Poll::Ready(Ok(received)) => received,
                 Poll::Ready(Err(err)) => return Err(err),
-                Poll::Pending => break,
+                // Woken by the socket once more arrives
+                Poll::Pending => return Ok(()),
             };
-            self.handle_packet(buf.filled(), addr);
+            let packet = &recv_buf[..len];
+            let submitted = match self.workers {
+                Some(ref pool) => pool.try_submit(packet, addr),
+                None => false,
+            };
+            if !submitted {
+                self.handler.handle_packet(packet, addr);
+            }
+            if let Some(ref reflector) = self.reflector {
+                for via in reflector.reflect(addr) {
+                    if self.reflected.len() >= reflector::MAX_QUEUED {
+                        debug!("dropping reflected packet from {:?}", addr);
+                        break;
+                    }
+                    self.reflected.push_back((packet.to_vec(), via));
+                }
+            }
         }
 
+        // Out of budget, more may be waiting
+        cx.waker().wake_by_ref();
         Ok(())
     }
 
-    fn handle_packet(&mut self, buffer: &[u8], addr: SocketAddr) {
-        trace!("received packet from {:?}", addr);
-
-        let packet = match dns_parser::Packet::parse(buffer) {
-            Ok(packet) => packet,
-            Err(error) => {
-                warn!("couldn't parse packet from {:?}: {}", addr, error);
+    fn send_packets(&mut self, cx: &mut Context) {
+        let mut budget = POLL_BUDGET;
+        loop {
+            let now = Instant::now();
+            if self.backoff.paused_until(now).is_some() {
                 return;
             }
-        };
+            if budget == 0 {
+                cx.waker().wake_by_ref();
+                return;
+            }
+            let (response, addr) = match self.retry.take().or_else(|| self.pending.pop_front()) {
+                Some(packet) => packet,
+                None => match self.handler.pop_outgoing() {
+                    Some((packet, addr)) => {
+                        for dest in destinations(addr, &self.scopes) {
+                            self.pending.push_back((packet.clone(), dest));
+                        }
+                        continue;
+                    }
+                    None => match self.reflected.pop_front() {
+                        Some((packet, via)) => {
+                            budget -= 1;
+                            if !self.send_reflected(cx, packet, via) {
+                                return;
+                            }
+                            continue;
+                        }
+                        None => return,
+                    },
+                },
+            };
+            trace!("sending packet to {:?}", addr);
+            budget -= 1;
 
-        if !packet.header.query {
-            trace!("received packet from {:?} with no query", addr);
-            return;
+            match self.socket.poll_send_to(cx, &response, addr) {
+                Poll::Ready(Ok(bytes_sent)) if bytes_sent == response.len() => self.backoff.sent(),
+                Poll::Ready(Ok(_)) => warn!("failed to send entire packet"),
+                Poll::Ready(Err(ref ioerr)) if ioerr.kind() == WouldBlock => {
+                    self.pending.push_front((response, addr));
+                    return;
+                }
+                Poll::Ready(Err(err)) => self.retry = self.backoff.failed(err, response, addr, now),
+                // Keep the packet until the socket can take it
+                Poll::Pending => {
+                    self.pending.push_front((response, addr));
+                    return;
+                }
+            }
         }
+    }
 
-        if packet.header.truncated {
-            warn!("dropping truncated packet from {:?}", addr);
-            return;
+    /// Send a reflected packet, best effort. Returns false if the socket isn't ready.
+    fn send_reflected(&mut self, cx: &mut Context, packet: Vec<u8>, via: Via) -> bool {
+        // Without a socket there is no interface to pick
+        let socket = match self.socket.socket() {
+            Some(socket) => socket,
+            None => return true,
+        };
+        let sent = via
+            .select(&socket)
+            .map(|()| self.socket.poll_send_to(cx, &packet, via.destination()));
+        if let Err(err) = via.reset(&socket) {
+            warn!("failed to reset the multicast interface: {}", err);
         }
+        match sent {
+            Ok(Poll::Ready(Ok(_))) => true,
+            Ok(Poll::Ready(Err(ref err))) if err.kind() == WouldBlock => {
+                self.reflected.push_front((packet, via));
+                false
+            }
+            Ok(Poll::Pending) => {
+                self.reflected.push_front((packet, via));
+                false
+            }
+            Ok(Poll::Ready(Err(err))) | Err(err) => {
+                debug!("failed to reflect packet via {:?}: {}", via, err);
+                true
+            }
+        }
+    }
+}
 
-        let mut unicast_builder = dns_parser::Builder::new_response(packet.header.id, false, true)
-            .move_to::<dns_parser::Answers>();
-        let mut multicast_builder =
-            dns_parser::Builder::new_response(packet.header.id, false, true)
-                .move_to::<dns_parser::Answers>();
-        unicast_builder.set_max_size(None);
-        multicast_builder.set_max_size(None);
+/// Wakes the tasks of all address families, set once they are running
+pub type SharedWake = Arc<Mutex<Option<Arc<dyn Fn() + Send + Sync>>>>;
 
-        for question in packet.questions {
-            debug!(
-                "received question: {:?} {}",
-                question.qclass, question.qname
-            );
+/// State shared by the handlers of all address families of one responder.
+#[derive(Clone)]
+pub struct Shared {
+    pub services: Services,
+    pub host: Arc<dyn HostData>,
+    pub addresses: Arc<AddressCache>,
+    pub seen: SeenQueries,
+    pub sent: SentPackets,
+    pub multicast: RecentMulticasts,
+    pub owner: Arc<AddressOwner>,
+    pub counters: Arc<Counters>,
+    pub cache: Arc<RecordCache>,
+    pub browsers: Arc<Browsers>,
+    /// bounds the state kept about other hosts
+    pub budget: Arc<MemoryBudget>,
+    #[cfg_attr(not(any(feature = "responder", feature = "sync")), allow(dead_code))]
+    pub reflector: Option<Arc<Reflector>>,
+    pub config: Arc<ResponderConfig>,
+    /// set by `watch_host`
+    pub wake: SharedWake,
+    /// the current `NetworkProfile`, followed by answer workers too
+    pub profile: Arc<Mutex<NetworkProfile>>,
+}
 
-            if question.qclass == QueryClass::IN || question.qclass == QueryClass::Any {
-                if question.qu {
-                    unicast_builder = self.handle_question(&question, unicast_builder);
-                } else {
-                    multicast_builder = self.handle_question(&question, multicast_builder);
+impl Shared {
+    pub fn new(services: &Services, config: ResponderConfig) -> Shared {
+        Shared::with_counters(services, config, Arc::new(Counters::new()))
+    }
+
+    /// Like `new`, counting into `counters`, e.g. those of a responder being replaced
+    pub fn with_counters(
+        services: &Services,
+        mut config: ResponderConfig,
+        counters: Arc<Counters>,
+    ) -> Shared {
+        config.list_netns_interfaces();
+        let host = config.host_data();
+        let refresh_interval = config
+            .address_refresh_interval
+            .unwrap_or(DEFAULT_ADDRESS_REFRESH_INTERVAL);
+        let reflector = if config.reflect_interfaces.is_empty() {
+            None
+        } else {
+            match Reflector::new(&config.reflect_interfaces) {
+                Ok(reflector) => Some(Arc::new(reflector)),
+                Err(err) => {
+                    warn!("failed to list the interfaces to reflect between: {}", err);
+                    None
                 }
             }
+        };
+        let budget = Arc::new(MemoryBudget::new(
+            config.memory_budget.unwrap_or(DEFAULT_MEMORY_BUDGET),
+            counters.clone(),
+        ));
+        Shared {
+            services: services.clone(),
+            addresses: Arc::new(AddressCache::new(
+                host.clone(),
+                refresh_interval,
+                config.interfaces.clone(),
+                match config.sink {
+                    true => InterfaceSource::Sink,
+                    false => InterfaceSource::new(config.netns_interfaces.as_ref()),
+                },
+            )),
+            host,
+            seen: Arc::new(Mutex::new(SeenQueriesInner::new(&budget))),
+            sent: Arc::new(Mutex::new(SentPacketsInner::new())),
+            multicast: Arc::new(Mutex::new(RecentMulticastsInner::new(&budget))),
+            owner: Arc::new(AddressOwner::new(&config)),
+            counters,
+            cache: Arc::new(RecordCache::new(config.cache_records, &budget)),
+            browsers: Arc::new(Browsers::new(&budget)),
+            budget,
+            reflector,
+            profile: Arc::new(Mutex::new(config.network_profile)),
+            config: Arc::new(config),
+            wake: Arc::new(Mutex::new(None)),
         }
+    }
 
-        if !multicast_builder.is_empty() {
-            let response = multicast_builder.build().unwrap_or_else(|x| x);
-            let addr = SocketAddr::new(AF::MDNS_GROUP.into(), MDNS_PORT);
-            self.outgoing.push_back((response, addr));
+    /// Let the `HostData` report changes, calling `wake` to get them handled.
+    pub fn watch_host(&self, wake: Option<Arc<dyn Fn() + Send + Sync>>) {
+        self.wake
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone_from(&wake);
+        self.host.watch(HostWatcher::new(&self.addresses, wake));
+    }
+}
+
+/// The protocol state of one address family, independent of how packets are
+/// sent and received.
+pub struct Handler<AF: AddressFamily> {
+    services: Services,
+    addresses: Arc<AddressCache>,
+    seen: SeenQueries,
+    sent: SentPackets,
+    multicast: RecentMulticasts,
+    owner: Arc<AddressOwner>,
+    counters: Arc<Counters>,
+    cache: Arc<RecordCache>,
+    browsers: Arc<Browsers>,
+    /// answers to send, with the time after which they are dropped instead
+    outgoing: VecDeque<(Vec<u8>, SocketAddr, Instant)>,
+    /// announcements and goodbyes, sent once there are no answers waiting
+    unsolicited: VecDeque<Unsolicited>,
+    /// repeats of goodbyes, moved to `unsolicited` once they are due
+    scheduled: Vec<(Instant, Unsolicited)>,
+    announce_pacer: Option<Pacer>,
+    /// pacers of services with their own announce rate, dropped once idle
+    service_pacers: HashMap<NameKey, Pacer>,
+    /// hostname aliases which have been announced, the alias sequence number they are
+    /// from, and the cache generation whose aliases were last handed to the services
+    aliases: Vec<Name<'static>>,
+    aliases_seq: Option<u64>,
+    host_generation: Option<u64>,
+    /// our addresses seen published under other names, reported once each
+    address_conflicts: Lru<(IpAddr, NameKey), ()>,
+    /// other addresses seen published under our names, reported once each
+    hostname_conflicts: Lru<(IpAddr, NameKey), ()>,
+    /// the last service rename after a conflict which has been announced
+    renamed_seen: u64,
+    /// the last rename of the hostname after a conflict which has been announced
+    hostname_seen: u64,
+    /// set for the handlers of answer workers, which leave announcements to the task
+    answer_only: bool,
+    /// set on shutdown, when only the goodbyes still waiting are sent, without pacing
+    draining: bool,
+    wake: SharedWake,
+    _af: PhantomData<AF>,
+    config: Arc<ResponderConfig>,
+    profile: Arc<Mutex<NetworkProfile>>,
+}
+
+impl<AF: AddressFamily> Handler<AF> {
+    pub fn new(shared: &Shared) -> Handler<AF> {
+        let mut handler = Handler::with_shared(shared);
+        // Restarted with the hostname of a recent snapshot, which others still have
+        // cached: announce it straight away rather than wait to be asked
+        let services = shared.services.read().unwrap_or_else(PoisonError::into_inner);
+        if services.hostname_defended() {
+            let hostname = services.get_hostname().clone();
+            drop(services);
+            debug!("announcing restored hostname {}", hostname);
+            handler.announce_alias(&hostname, None);
         }
+        handler
+    }
 
-        if !unicast_builder.is_empty() {
-            let response = unicast_builder.build().unwrap_or_else(|x| x);
-            self.outgoing.push_back((response, addr));
+    fn with_shared(shared: &Shared) -> Handler<AF> {
+        Handler {
+            services: shared.services.clone(),
+            addresses: shared.addresses.clone(),
+            seen: shared.seen.clone(),
+            sent: shared.sent.clone(),
+            multicast: shared.multicast.clone(),
+            owner: shared.owner.clone(),
+            counters: shared.counters.clone(),
+            cache: shared.cache.clone(),
+            browsers: shared.browsers.clone(),
+            outgoing: VecDeque::new(),
+            unsolicited: VecDeque::new(),
+            scheduled: Vec::new(),
+            announce_pacer: shared
+                .config
+                .announce_rate
+                .map(|rate| Pacer::new(rate, clock::now())),
+            service_pacers: HashMap::new(),
+            aliases: Vec::new(),
+            aliases_seq: None,
+            host_generation: None,
+            address_conflicts: Lru::new(&shared.budget),
+            hostname_conflicts: Lru::new(&shared.budget),
+            renamed_seen: shared.services.read().unwrap_or_else(PoisonError::into_inner).renamed_seq(),
+            hostname_seen: shared.services.read().unwrap_or_else(PoisonError::into_inner).hostname_seq(),
+            answer_only: false,
+            draining: false,
+            wake: shared.wake.clone(),
+            _af: PhantomData,
+            config: shared.config.clone(),
+            profile: shared.profile.clone(),
         }
     }
 
-    /// https://www.rfc-editor.org/rfc/rfc6763#section-9
-    fn handle_service_type_enumeration<'a>(
-        question: &dns_parser::Question,
-        services: impl Iterator<Item = &'a ServiceData>,
-        mut builder: AnswerBuilder,
-    ) -> AnswerBuilder {
-        let service_type_enumeration_name = Name::FromStr(SERVICE_TYPE_ENUMERATION_NAME);
-        if question.qname == service_type_enumeration_name {
-            for svc in services {
-                let svc_type = ServiceData {
-                    name: svc.typ.clone(),
-                    typ: service_type_enumeration_name.clone(),
-                    port: svc.port,
-                    txt: vec![],
-                };
-                builder = svc_type.add_ptr_rr(builder, DEFAULT_TTL);
-            }
+    /// A handler which only answers queries, for a worker thread. It shares the
+    /// duplicate detection, multicast suppression and network profile of the task's
+    /// handlers, so that queries are answered as if the task answered them all.
+    #[cfg(feature = "responder")]
+    pub fn answer_only(shared: &Shared) -> Handler<AF> {
+        Handler {
+            answer_only: true,
+            ..Handler::with_shared(shared)
         }
+    }
 
-        builder
+    pub fn set_network_profile(&mut self, profile: NetworkProfile) {
+        debug!("switching to {:?} network profile", profile);
+        *self.profile.lock().unwrap_or_else(PoisonError::into_inner) = profile;
     }
 
-    fn handle_question(
-        &self,
-        question: &dns_parser::Question,
-        mut builder: AnswerBuilder,
-    ) -> AnswerBuilder {
-        let services = self.services.read().unwrap();
-        let hostname = services.get_hostname();
+    /// Next packet waiting to be sent. Answers to queries go first, then announcements
+    /// and goodbyes as far as pacing allows.
+    pub fn pop_outgoing(&mut self) -> Option<(Vec<u8>, SocketAddr)> {
+        let outgoing = self.next_outgoing();
+        if let Some((ref packet, _)) = outgoing {
+            self.counters.packet_sent(packet.len());
+            self.sent
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .record(packet, clock::now());
+        }
+        outgoing
+    }
 
-        match question.qtype {
-            QueryType::A | QueryType::AAAA if question.qname == *hostname => {
-                builder = self.add_ip_rr(hostname, builder, DEFAULT_TTL);
-            }
-            QueryType::All => {
-                // A / AAAA
-                if question.qname == *hostname {
-                    builder = self.add_ip_rr(hostname, builder, DEFAULT_TTL);
-                }
-                // PTR
-                builder =
-                    Self::handle_service_type_enumeration(question, services.into_iter(), builder);
-                for svc in services.find_by_type(&question.qname) {
-                    builder = svc.add_ptr_rr(builder, DEFAULT_TTL);
-                    builder = svc.add_srv_rr(hostname, builder, DEFAULT_TTL);
-                    builder = svc.add_txt_rr(builder, DEFAULT_TTL);
-                    builder = self.add_ip_rr(hostname, builder, DEFAULT_TTL);
-                }
-                // SRV
-                if let Some(svc) = services.find_by_name(&question.qname) {
-                    builder = svc.add_srv_rr(hostname, builder, DEFAULT_TTL);
-                    builder = self.add_ip_rr(hostname, builder, DEFAULT_TTL);
-                }
-            }
-            QueryType::PTR => {
-                builder =
-                    Self::handle_service_type_enumeration(question, services.into_iter(), builder);
-                for svc in services.find_by_type(&question.qname) {
-                    builder = svc.add_ptr_rr(builder, DEFAULT_TTL);
-                    builder = svc.add_srv_rr(hostname, builder, DEFAULT_TTL);
-                    builder = svc.add_txt_rr(builder, DEFAULT_TTL);
-                    builder = self.add_ip_rr(hostname, builder, DEFAULT_TTL);
-                }
-            }
-            QueryType::SRV => {
-                if let Some(svc) = services.find_by_name(&question.qname) {
-                    builder = svc.add_srv_rr(hostname, builder, DEFAULT_TTL);
-                    builder = self.add_ip_rr(hostname, builder, DEFAULT_TTL);
-                }
-            }
-            QueryType::TXT => {
-                if let Some(svc) = services.find_by_name(&question.qname) {
-                    builder = svc.add_txt_rr(builder, DEFAULT_TTL);
-                }
+    fn next_outgoing(&mut self) -> Option<(Vec<u8>, SocketAddr)> {
+        let now = clock::now();
+        if !self.draining {
+            self.check_aliases();
+            self.check_renamed();
+            self.promote_scheduled(now);
+            self.queue_browse_queries(now);
+        }
+        while let Some((packet, addr, expires)) = self.outgoing.pop_front() {
+            if expires < now {
+                debug!("dropping stale response to {:?}", addr);
+                self.counters.stale_response_dropped();
+            } else {
+                return Some((packet, addr));
             }
-            _ => (),
         }
+        self.pop_unsolicited(now)
+    }
 
-        builder
+    /// Next answer to a query, without dropping it if stale
+    #[cfg(feature = "responder")]
+    pub fn pop_response(&mut self) -> Option<Answer> {
+        self.outgoing.pop_front()
     }
 
-    fn add_ip_rr(&self, hostname: &Name, mut builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        let interfaces = match get_if_addrs() {
-            Ok(interfaces) => interfaces,
-            Err(err) => {
-                error!("could not get list of interfaces: {}", err);
-                return builder;
-            }
-        };
+    /// Queue an answer built by a worker
+    pub fn push_response(&mut self, answer: Answer) {
+        let max_queued = self
+            .config
+            .max_queued_responses
+            .unwrap_or(DEFAULT_MAX_QUEUED_RESPONSES);
+        if self.outgoing.len() >= max_queued {
+            debug!("send queue full, dropping response to {:?}", answer.1);
+            self.counters.response_queue_full();
+            return;
+        }
+        self.outgoing.push_back(answer);
+    }
 
-        for iface in interfaces {
-            if iface.is_loopback() {
-                continue;
+    fn pop_unsolicited(&mut self, now: Instant) -> Option<(Vec<u8>, SocketAddr)> {
+        if let Some(ref mut pacer) = self.announce_pacer {
+            if pacer.ready_at(now) > now {
+                return None;
             }
+        }
 
-            trace!("found interface {:?}", iface);
-            if !self.allowed_ip.is_empty() && !self.allowed_ip.contains(&iface.ip()) {
-                trace!("  -> interface dropped");
-                continue;
+        let service_pacers = &mut self.service_pacers;
+        let index = self.unsolicited.iter().position(|packet| {
+            match service_pacers.get_mut(&packet.service) {
+                Some(pacer) => pacer.ready_at(now) <= now,
+                None => true,
             }
+        })?;
+        let packet = self.unsolicited.remove(index).unwrap();
 
-            match (iface.ip(), AF::DOMAIN) {
-                (IpAddr::V4(ip), Domain::IPV4) => {
-                    builder = builder.add_answer(hostname, QueryClass::IN, ttl, &RRData::A(ip))
-                }
-                (IpAddr::V6(ip), Domain::IPV6) => {
-                    builder = builder.add_answer(hostname, QueryClass::IN, ttl, &RRData::AAAA(ip))
-                }
-                _ => (),
-            }
+        if let Some(ref mut pacer) = self.announce_pacer {
+            pacer.take(now);
         }
+        if let Some(rate) = packet.rate {
+            self.service_pacers
+                .entry(packet.service)
+                .or_insert_with(|| Pacer::new(rate, now))
+                .take(now);
+        }
+        self.service_pacers.retain(|_, pacer| !pacer.is_idle(now));
 
-        builder
+        if let Some(announced) = packet.announced {
+            announced.sent();
+        }
+        Some((packet.packet, packet.addr))
     }
 
-    fn send_unsolicited(&mut self, svc: &ServiceData, ttl: u32, include_ip: bool) {
-        let mut builder =
-            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
-        builder.set_max_size(None);
-
-        let services = self.services.read().unwrap();
-
-        builder = svc.add_ptr_rr(builder, ttl);
-        builder = svc.add_srv_rr(services.get_hostname(), builder, ttl);
-        builder = svc.add_txt_rr(builder, ttl);
-        if include_ip {
-            builder = self.add_ip_rr(services.get_hostname(), builder, ttl);
+    fn promote_scheduled(&mut self, now: Instant) {
+        let mut i = 0;
+        while i < self.scheduled.len() {
+            if self.scheduled[i].0 <= now {
+                let (_, packet) = self.scheduled.swap_remove(i);
+                self.unsolicited.push_back(packet);
+            } else {
+                i += 1;
+            }
         }
+    }
 
-        if !builder.is_empty() {
-            let response = builder.build().unwrap_or_else(|x| x);
-            let addr = SocketAddr::new(AF::MDNS_GROUP.into(), MDNS_PORT);
-            self.outgoing.push_back((response, addr));
+    /// Queue the browse queries which are due, after any answers waiting
+    fn queue_browse_queries(&mut self, now: Instant) {
+        if self.answer_only {
+            return;
+        }
+        let addr = SocketAddr::new(AF::MDNS_GROUP.into(), MDNS_PORT);
+        for query in self.browsers.due_queries(AF::FAMILY, now) {
+            self.outgoing
+                .push_back((query, addr, now + DEFAULT_MAX_RESPONSE_DELAY));
         }
     }
-}
 
-impl<AF: Unpin + AddressFamily> Future for FSM<AF> {
-    type Output = ();
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
-        let pinned = Pin::get_mut(self);
-        while let Poll::Ready(cmd) = Pin::new(&mut pinned.commands).poll_recv(cx) {
-            match cmd {
-                Some(Command::Shutdown) => return Poll::Ready(()),
-                Some(Command::SendUnsolicited {
-                    svc,
-                    ttl,
-                    include_ip,
-                }) => {
-                    pinned.send_unsolicited(&svc, ttl, include_ip);
-                }
-                None => {
-                    warn!("responder disconnected without shutdown");
-                    return Poll::Ready(());
-                }
-            }
+    /// When pacing next allows an announcement or goodbye to be sent, if any are
+    /// waiting, or a browse next has a query to send or an instance to expire
+    pub fn next_unsolicited_at(&mut self) -> Option<Instant> {
+        let unsolicited = self.next_announcement_at();
+        let browse = if self.answer_only {
+            None
+        } else {
+            self.browsers.next_deadline(AF::FAMILY)
+        };
+        match (unsolicited, browse) {
+            (Some(unsolicited), Some(browse)) => Some(unsolicited.min(browse)),
+            (unsolicited, browse) => unsolicited.or(browse),
         }
+    }
 
-        match pinned.recv_packets(cx) {
-            Ok(_) => (),
-            Err(e) => error!("ResponderRecvPacket Error: {:?}", e),
+    fn next_announcement_at(&mut self) -> Option<Instant> {
+        let now = clock::now();
+        self.promote_scheduled(now);
+        let scheduled = self.scheduled.iter().map(|&(at, _)| at).min();
+        if self.unsolicited.is_empty() {
+            return scheduled;
         }
 
-        while let Some((ref response, addr)) = pinned.outgoing.pop_front() {
-            trace!("sending packet to {:?}", addr);
+        let service_pacers = &mut self.service_pacers;
+        let service_ready = self
+            .unsolicited
+            .iter()
+            .map(|packet| {
+                service_pacers
+                    .get_mut(&packet.service)
+                    .map_or(now, |pacer| pacer.ready_at(now))
+            })
+            .min()?;
+        let ready = match self.announce_pacer {
+            Some(ref mut pacer) => pacer.ready_at(now),
+            None => now,
+        };
+        let ready = ready.max(service_ready);
+        Some(scheduled.map_or(ready, |scheduled| scheduled.min(ready)))
+    }
 
-            match pinned.socket.poll_send_to(cx, response, addr) {
-                Poll::Ready(Ok(bytes_sent)) if bytes_sent == response.len() => (),
-                Poll::Ready(Ok(_)) => warn!("failed to send entire packet"),
-                Poll::Ready(Err(ref ioerr)) if ioerr.kind() == WouldBlock => (),
-                Poll::Ready(Err(err)) => warn!("error sending packet {:?}", err),
-                Poll::Pending => (),
-            }
+    fn queue_unsolicited(
+        &mut self,
+        packet: Vec<u8>,
+        name: &Name,
+        rate: Option<u32>,
+        goodbye: bool,
+    ) {
+        self.unsolicited.push_back(Unsolicited {
+            packet,
+            addr: SocketAddr::new(AF::MDNS_GROUP.into(), MDNS_PORT),
+            service: NameKey::new(name),
+            rate,
+            announced: None,
+            repeats: Vec::new(),
+            goodbye,
+        });
+    }
+
+    /// Queue an announcement, or schedule it at `start` once the names have been
+    /// probed for, and schedule its repeats at doubling intervals
+    fn queue_announcement(
+        &mut self,
+        packet: Vec<u8>,
+        name: &Name,
+        rate: Option<u32>,
+        announced: Option<Announced>,
+        names: &[NameKey],
+        start: Option<Instant>,
+    ) {
+        self.queue_unsolicited(packet, name, rate, false);
+        let first = self.unsolicited.back_mut().unwrap();
+        let repeat = Unsolicited {
+            repeats: names.to_vec(),
+            ..first.clone()
+        };
+        first.announced = announced;
+
+        let mut at = clock::now();
+        if let Some(start) = start {
+            // Like its repeats, dropped if the probes turn up a conflict meanwhile
+            let mut first = self.unsolicited.pop_back().unwrap();
+            first.repeats = names.to_vec();
+            self.scheduled.push((start, first));
+            at = start;
         }
 
-        Poll::Pending
+        let count = self.config.announcements.unwrap_or(DEFAULT_ANNOUNCEMENTS);
+        let (min, max) = ANNOUNCE_INTERVAL_MS;
+        let mut interval = clock::jitter(min, max);
+        for _ in 1..count {
+            at += interval;
+            interval *= 2;
+            self.scheduled.push((at, repeat.clone()));
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{address_family::Inet, services::ServicesInner};
-    use std::sync::{Arc, RwLock};
+    /// Drop the repeats of earlier announcements and goodbyes of `names`, which are
+    /// out of date: a goodbye arriving after the next announcement would flush it.
+    /// Those of a group keep the records of its other services.
+    fn cancel_repeats(&mut self, names: &[NameKey]) {
+        let compress = self.compress_names();
+        for (at, mut packet) in std::mem::take(&mut self.scheduled) {
+            if packet.repeats.iter().any(|name| names.contains(name)) {
+                packet.repeats.retain(|name| !names.contains(name));
+                packet.packet = match without_names(&packet.packet, names, compress) {
+                    Some(rest) if !packet.repeats.is_empty() => rest,
+                    _ => continue,
+                };
+            }
+            self.scheduled.push((at, packet));
+        }
+    }
 
-    #[test]
-    fn test_service_type_enumeration() {
-        let question = dns_parser::Question {
-            qname: dns_parser::Name::from_str("_services._dns-sd._udp.local").unwrap(),
-            qtype: dns_parser::QueryType::PTR,
-            qclass: dns_parser::QueryClass::IN,
-            qu: false,
-        };
-        let services = Arc::new(RwLock::new(ServicesInner::new(
-            "test-hostname.local".into(),
-        )));
-        let service_data = ServiceData {
-            name: Name::from_str("test-instance").unwrap(),
-            typ: Name::from_str("_test-service-name._tcp").unwrap(),
-            port: 8008,
-            txt: vec![],
+    /// Queue a goodbye, and schedule its repeats a little apart
+    fn queue_goodbye(
+        &mut self,
+        packet: Vec<u8>,
+        name: &Name,
+        rate: Option<u32>,
+        names: &[NameKey],
+    ) {
+        self.queue_unsolicited(packet, name, rate, true);
+        let goodbye = Unsolicited {
+            repeats: names.to_vec(),
+            ..self.unsolicited.back().unwrap().clone()
         };
-        services.write().unwrap().register(service_data);
 
-        let mut answer_builder =
-            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
-        answer_builder.set_max_size(None);
+        let mut at = clock::now();
+        let (min, max) = GOODBYE_REPEAT_DELAY_MS;
+        for _ in 0..GOODBYE_REPEATS {
+            at += clock::jitter(min, max);
+            self.scheduled.push((at, goodbye.clone()));
+        }
+    }
 
-        answer_builder = FSM::<Inet>::handle_service_type_enumeration(
-            &question,
-            services.read().unwrap().into_iter(),
-            answer_builder,
-        );
+    /// Probe for hostname aliases which have appeared and then announce them, and send
+    /// goodbyes for those which have gone. The aliases of the `HostData` are kept with
+    /// the services, along with those added through the responder.
+    fn check_aliases(&mut self) {
+        let generation = self.addresses.generation();
+        if self.host_generation != Some(generation) {
+            self.host_generation = Some(generation);
+            let address_names = self.addresses.address_names();
+            let host_aliases = self
+                .addresses
+                .aliases()
+                .into_iter()
+                .chain(address_names.into_iter().map(|(name, _)| name))
+                .map(|alias| Name::from_str(alias).unwrap())
+                .collect();
+            self.services.write().unwrap_or_else(PoisonError::into_inner).set_host_aliases(host_aliases);
+        }
 
-        let packet = answer_builder.build().unwrap();
+        let services = self.services.read().unwrap_or_else(PoisonError::into_inner);
+        if self.aliases_seq == Some(services.aliases_seq()) {
+            return;
+        }
+        self.aliases_seq = Some(services.aliases_seq());
+        let aliases = services.aliases();
 
-        let parsed = dns_parser::Packet::parse(&packet).unwrap();
-        assert_eq!(parsed.answers.len(), 1);
+        // The task announces them
+        if self.answer_only {
+            self.aliases = aliases;
+            return;
+        }
+
+        let contains = |names: &[Name], name: &Name| {
+            names
+                .iter()
+                .any(|other| other as &dyn Canonical == name as &dyn Canonical)
+        };
+        let old = std::mem::take(&mut self.aliases);
+        let gone: Vec<&Name> = old
+            .iter()
+            .filter(|alias| !contains(&aliases, alias) && !services.alias_lost(alias))
+            .collect();
+        drop(services);
+        for alias in gone {
+            debug!("hostname alias {} is gone", alias);
+            self.withdraw_alias(alias);
+        }
+        for alias in &aliases {
+            if !contains(&old, alias) {
+                debug!("probing for hostname alias {}", alias);
+                self.probe_alias(alias);
+            }
+        }
+        self.aliases = aliases;
+    }
+
+    /// Probe for `alias` (RFC 6762 section 8.1), and announce it once the probes have
+    /// gone unanswered. Another host publishing it meanwhile drops the probes and the
+    /// announcement, see `check_hostname_conflicts`.
+    fn probe_alias(&mut self, alias: &Name) {
+        if self.ordered_ips_of(alias).is_empty() {
+            return;
+        }
+        let mut at = clock::now() + clock::jitter(0, PROBE_INTERVAL_MS);
+        let interval = Duration::from_millis(PROBE_INTERVAL_MS);
+        self.announce_alias(alias, Some(at + interval * PROBES));
+
+        let names = vec![NameKey::new(alias)];
+        for i in 0..PROBES {
+            let probe = Unsolicited {
+                packet: self.build_alias_probe(alias, i == 0),
+                addr: SocketAddr::new(AF::MDNS_GROUP.into(), MDNS_PORT),
+                service: NameKey::new(alias),
+                rate: None,
+                announced: None,
+                repeats: names.clone(),
+                goodbye: false,
+            };
+            self.scheduled.push((at, probe));
+            at += interval;
+        }
+    }
+
+    /// A query for `alias` with the address records we are about to claim in the
+    /// authority section, the first one asking for unicast responses
+    fn build_alias_probe(&self, alias: &Name, first: bool) -> Vec<u8> {
+        let builder = dns_parser::Builder::new_query(0, false);
+        let builder = match first {
+            true => builder.add_unicast_question(alias, QueryType::All, QueryClass::IN),
+            false => builder.add_question(alias, QueryType::All, QueryClass::IN),
+        };
+        let mut builder: ProbeBuilder = builder.move_to();
+        builder.set_max_size(None);
+        builder.set_compression(self.compress_names());
+        let ttl = self.host_ttl();
+        for ip in self.ordered_ips_of(alias) {
+            let data = match ip {
+                IpAddr::V4(ip) => RRData::A(ip),
+                IpAddr::V6(ip) => RRData::AAAA(ip),
+            };
+            builder = builder.add_nameserver(alias, QueryClass::IN, ttl, &data);
+        }
+        builder.build().unwrap_or_else(|x| x)
+    }
+
+    /// Announce the host's addresses under `alias`, at `start` if given, after probes
+    /// for it
+    fn announce_alias(&mut self, alias: &Name, start: Option<Instant>) {
+        let names = [NameKey::new(alias)];
+        self.cancel_repeats(&names);
+        let builder = self.alias_records(alias, self.host_ttl());
+        if !builder.is_empty() {
+            for response in builder.build_split() {
+                self.queue_announcement(response, alias, None, None, &names, start);
+            }
+        }
+    }
+
+    /// Send goodbyes for the host's addresses under `alias`
+    fn withdraw_alias(&mut self, alias: &Name) {
+        let names = [NameKey::new(alias)];
+        self.cancel_repeats(&names);
+        let builder = self.alias_records(alias, 0);
+        if !builder.is_empty() {
+            for response in builder.build_split() {
+                self.queue_goodbye(response, alias, None, &names);
+            }
+        }
+    }
+
+    /// The host's addresses under `alias`, with a TTL of `ttl`
+    fn alias_records(&self, alias: &Name, ttl: u32) -> AnswerBuilder {
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        builder.set_max_size(Some(self.max_packet_size()));
+        builder.set_compression(self.compress_names());
+        self.add_ip_rr(alias, builder, ttl, self.cache_flush())
+    }
+
+    fn queue_response(&mut self, response: Vec<u8>, addr: SocketAddr) {
+        let max_delay = self
+            .config
+            .max_response_delay
+            .unwrap_or(DEFAULT_MAX_RESPONSE_DELAY);
+        let expires = clock::now() + max_delay;
+        self.push_response((response, addr, expires));
+    }
+
+    /// Handle any command other than `Shutdown`
+    #[cfg(feature = "responder")]
+    pub fn handle_command(&mut self, cmd: Command) {
+        // Like a packet, a command mustn't end the task by panicking
+        let handled = panic::catch_unwind(AssertUnwindSafe(|| self.handle_command_inner(cmd)));
+        if let Err(payload) = handled {
+            error!("panicked handling a command: {}", panic_message(&*payload));
+        }
+    }
+
+    #[cfg(feature = "responder")]
+    fn handle_command_inner(&mut self, cmd: Command) {
+        match cmd {
+            Command::SendUnsolicited {
+                svc,
+                goodbye,
+                include_ip,
+                announced,
+            } => {
+                let svcs = std::slice::from_ref(&svc);
+                self.announce_group(svcs, goodbye, include_ip, announced, None)
+            }
+            Command::SendUnsolicitedGroup {
+                svcs,
+                goodbye,
+                include_ip,
+                announced,
+            } => self.announce_group(&svcs, goodbye, include_ip, announced, None),
+            Command::ProbeGroup { svcs, announced } => self.probe_group(&svcs, Some(announced)),
+            Command::SendSrvGoodbye { svc, current } => self.send_srv_goodbye(&svc, &current),
+            Command::WithdrawHostname(hostname) => self.withdraw_hostname(&hostname),
+            Command::SetNetworkProfile(profile) => self.set_network_profile(profile),
+            // Sent once the command wakes the task
+            Command::Browse => (),
+            Command::HostChanged => {
+                self.check_aliases();
+                self.check_renamed();
+            }
+            Command::Resumed => self.resumed(),
+            Command::Received { packet, from } => {
+                if from.is_ipv6() == (AF::FAMILY == IpFamily::V6) {
+                    self.handle_packet(&packet, from);
+                }
+            }
+            Command::Shutdown => self.shutdown(),
+        }
+    }
+
+    /// Prepare to stop: drop the announcements still waiting, and make the goodbyes,
+    /// including their repeats, due now so that the records are flushed from caches
+    #[cfg(feature = "responder")]
+    fn shutdown(&mut self) {
+        self.draining = true;
+        self.announce_pacer = None;
+        self.service_pacers.clear();
+        self.unsolicited.retain(|packet| packet.goodbye);
+        let scheduled = std::mem::take(&mut self.scheduled);
+        for (_, packet) in scheduled {
+            // Repeats sent back to back add nothing over the first copy
+            if packet.goodbye && !self.unsolicited.iter().any(|p| p.packet == packet.packet) {
+                self.unsolicited.push_back(packet);
+            }
+        }
+    }
+
+    /// Whether everything there is to send has been taken, after shutdown
+    #[cfg(feature = "responder")]
+    pub fn is_drained(&self) -> bool {
+        self.draining && self.outgoing.is_empty() && self.unsolicited.is_empty()
+    }
+
+    pub fn handle_packet(&mut self, buffer: &[u8], addr: SocketAddr) {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("packet", source = %addr, outcome = tracing::field::Empty);
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+
+        // A bug triggered by one packet mustn't end the advertising of everything. The
+        // locks shared with other tasks are taken whether poisoned or not, so that the
+        // panic doesn't make every later packet panic too.
+        let handled =
+            panic::catch_unwind(AssertUnwindSafe(|| self.handle_packet_inner(buffer, addr)));
+        let outcome = handled.unwrap_or_else(|payload| {
+            let message = panic_message(&*payload);
+            error!("panicked handling packet from {:?}: {}", addr, message);
+            if let Some(ref on_packet_panic) = self.config.on_packet_panic {
+                on_packet_panic(PacketPanic {
+                    source: addr,
+                    message,
+                });
+            }
+            PacketOutcome::Dropped(DropReason::Panicked)
+        });
+        match outcome {
+            PacketOutcome::Answered => self.counters.packet_answered(),
+            PacketOutcome::Ignored => self.counters.packet_ignored(),
+            PacketOutcome::Response => (),
+            PacketOutcome::Dropped(reason) => {
+                self.counters.packet_dropped(reason);
+                if let Some(ref on_packet_dropped) = self.config.on_packet_dropped {
+                    on_packet_dropped(PacketDropped {
+                        reason,
+                        source: addr,
+                    });
+                }
+            }
+        }
+
+        #[cfg(feature = "tracing")]
+        span.record("outcome", outcome.as_str());
+    }
+
+    fn handle_packet_inner(&mut self, buffer: &[u8], addr: SocketAddr) -> PacketOutcome {
+        trace!("received packet from {:?}", addr);
+        if !self.addresses.is_from_interfaces(addr) {
+            debug!(
+                "dropping packet from {:?} on an interface not selected",
+                addr
+            );
+            return PacketOutcome::Dropped(DropReason::OtherInterface);
+        }
+        self.check_aliases();
+
+        // Records we can't make sense of shouldn't stop us answering the questions we can
+        let packet = match dns_parser::Packet::parse_lenient(buffer) {
+            Ok((packet, errors)) => {
+                for error in errors {
+                    debug!(
+                        "skipping {:?} {} in packet from {:?}: {}",
+                        error.section, error.index, addr, error.error
+                    );
+                }
+                packet
+            }
+            Err(error) => {
+                warn!("couldn't parse packet from {:?}: {}", addr, error);
+                return PacketOutcome::Dropped(DropReason::Unparseable);
+            }
+        };
+
+        if !packet.header.query {
+            trace!("received packet from {:?} with no query", addr);
+            // Nothing learns from the records the `RecordValidator` rejects
+            let rejected = self.check_records(&packet, addr);
+            if rejected.is_empty() {
+                return self.handle_response(&packet, buffer, addr);
+            }
+            let rest = match without_names(buffer, &rejected, true) {
+                Some(rest) => rest,
+                None => return PacketOutcome::Response,
+            };
+            return match dns_parser::Packet::parse_lenient(&rest) {
+                Ok((packet, _)) => self.handle_response(&packet, buffer, addr),
+                Err(_) => PacketOutcome::Response,
+            };
+        }
+
+        if packet.header.truncated {
+            warn!("dropping truncated packet from {:?}", addr);
+            return PacketOutcome::Dropped(DropReason::Truncated);
+        }
+
+        // https://www.rfc-editor.org/rfc/rfc6762#section-11
+        if !self.config.accept_off_link_queries && !self.addresses.is_on_link(addr.ip()) {
+            debug!("refusing query from off-link source {:?}", addr);
+            return PacketOutcome::Dropped(DropReason::OffLink);
+        }
+
+        // The same query may arrive on more than one socket, only answer it once
+        let lossy = self.profile() == NetworkProfile::Lossy;
+        if !lossy
+            && self
+                .seen
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .is_duplicate(&packet, addr, clock::now())
+        {
+            trace!("ignoring duplicate query from {:?}", addr);
+            return PacketOutcome::Dropped(DropReason::Duplicate);
+        }
+
+        self.cache.observe_query(&packet, clock::now());
+
+        // https://www.rfc-editor.org/rfc/rfc6762#section-6.7
+        let legacy = addr.port() != MDNS_PORT;
+        if legacy {
+            trace!("legacy unicast query from {:?}", addr);
+        }
+
+        // Probes are answered sooner than other queries, RFC 6762 section 6
+        let interval = if packet.nameservers.is_empty() {
+            MULTICAST_INTERVAL
+        } else {
+            PROBE_MULTICAST_INTERVAL
+        };
+        let (questions, duplicates) = dedup_questions(packet.questions);
+        if duplicates > 0 {
+            debug!("ignoring {} repeated questions from {:?}", duplicates, addr);
+            self.counters.duplicate_questions(duplicates);
+        }
+
+        // Legacy resolvers need the questions repeated, and some others want them
+        let echoed = questions
+            .iter()
+            .filter(|question| legacy || (self.config.echo_question && question.wants_unicast()));
+        let mut unicast_builder =
+            dns_parser::Builder::new_response_to(packet.header.id, true, echoed);
+        let mut multicast_builder =
+            dns_parser::Builder::new_response(packet.header.id, false, true)
+                .move_to::<dns_parser::Answers>();
+        // Direct responses are sized for the querier if it tells us what it can take
+        let edns_size = packet.opt.as_ref().map(|opt| opt.udp_payload_size);
+        unicast_builder.set_max_size(Some(match edns_size {
+            Some(size) => self.edns_packet_size(size),
+            None => self.max_packet_size(),
+        }));
+        multicast_builder.set_max_size(Some(self.max_packet_size()));
+        unicast_builder.set_compression(self.compress_names());
+        multicast_builder.set_compression(self.compress_names());
+
+        for question in questions {
+            #[cfg(feature = "tracing")]
+            let _question = tracing::debug_span!(
+                "question",
+                qname = %question.qname,
+                qtype = ?question.qtype,
+            )
+            .entered();
+
+            debug!(
+                "received question: {:?} {}",
+                question.qclass, question.qname
+            );
+            self.counters.question(question.qtype);
+
+            let mut answered = false;
+            if question.qclass == QueryClass::IN || question.qclass == QueryClass::Any {
+                if question.wants_unicast() || legacy {
+                    let answers = unicast_builder.answer_count();
+                    unicast_builder = self.handle_question(&question, unicast_builder, legacy);
+                    answered = unicast_builder.answer_count() > answers;
+                } else if !lossy && self.multicast_recently(&question, interval) {
+                    trace!(
+                        "answer to {} multicast within {:?}",
+                        question.qname,
+                        interval
+                    );
+                } else {
+                    let answers = multicast_builder.answer_count();
+                    multicast_builder = self.handle_question(&question, multicast_builder, legacy);
+                    answered = multicast_builder.answer_count() > answers;
+                    if answered {
+                        let mut multicast = self
+                            .multicast
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner);
+                        multicast.record(AF::FAMILY, &question, clock::now());
+                    }
+                }
+            }
+
+            if let Some(ref on_query) = self.config.on_query {
+                on_query(QueryEvent {
+                    source: addr,
+                    name: question.qname.to_string(),
+                    qtype: u16::from(question.qtype),
+                    unicast: question.wants_unicast(),
+                    answered,
+                });
+            }
+        }
+
+        let answered = !multicast_builder.is_empty() || unicast_builder.answer_count() > 0;
+
+        // On lossy networks every response goes out both ways, in the hope one arrives
+        let group = SocketAddr::new(AF::MDNS_GROUP.into(), MDNS_PORT);
+
+        if !multicast_builder.is_empty() {
+            for response in multicast_builder.build_split() {
+                if lossy {
+                    self.queue_response(response.clone(), addr);
+                }
+                self.queue_response(response, group);
+            }
+        }
+
+        if unicast_builder.answer_count() > 0 {
+            // A querier using EDNS0 expects an OPT record back, RFC 6891 section 7
+            let responses = match edns_size {
+                Some(_) => unicast_builder.build_split_with_opt(&dns_parser::Opt {
+                    udp_payload_size: MAX_EDNS_PACKET_SIZE as u16,
+                    extended_rcode: 0,
+                    version: 0,
+                    dnssec_ok: false,
+                    options: Vec::new(),
+                }),
+                None => unicast_builder.build_split(),
+            };
+            for response in responses {
+                if lossy && !legacy {
+                    self.queue_response(response.clone(), group);
+                }
+                self.queue_response(response, addr);
+            }
+        }
+
+        if answered {
+            PacketOutcome::Answered
+        } else {
+            PacketOutcome::Ignored
+        }
+    }
+
+    /// Learn from the records of another responder, `packet` as parsed from `buffer`
+    fn handle_response(
+        &mut self,
+        packet: &dns_parser::Packet,
+        buffer: &[u8],
+        addr: SocketAddr,
+    ) -> PacketOutcome {
+        // Our own services are browsed for like any others
+        self.browsers.observe_response(packet, addr, clock::now());
+        // Multicast loopback brings back our own responses, which must not pass
+        // for another responder's
+        if self.is_own(buffer, addr) {
+            trace!("ignoring our own packet from {:?}", addr);
+            return PacketOutcome::Dropped(DropReason::OwnPacket);
+        }
+        self.check_conflicts(packet, addr);
+        self.check_address_conflicts(packet, addr);
+        self.check_hostname_conflicts(packet, addr);
+        self.cache.observe_response(packet, addr, clock::now());
+        PacketOutcome::Response
+    }
+
+    /// Whether the answer to `question` was multicast over this address family in the
+    /// last `interval`
+    fn multicast_recently(&self, question: &Question, interval: Duration) -> bool {
+        let mut multicast = self
+            .multicast
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        multicast.is_recent(AF::FAMILY, question, interval, clock::now())
+    }
+
+    fn profile(&self) -> NetworkProfile {
+        *self.profile.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The TTL of the host's address records, wherever they are sent: the default,
+    /// raised to the minimum of the network profile
+    fn host_ttl(&self) -> u32 {
+        DEFAULT_TTL.max(self.profile().min_ttl())
+    }
+
+    /// Whether names in the packets sent are compressed, see
+    /// `ResponderBuilder::compress_names`
+    fn compress_names(&self) -> bool {
+        !self.config.disable_name_compression
+    }
+
+    /// Whether unique records carry the cache-flush bit, see
+    /// `ResponderBuilder::cache_flush`
+    fn cache_flush(&self) -> bool {
+        !self.config.disable_cache_flush
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.config
+            .max_packet_size
+            .unwrap_or(DEFAULT_MAX_PACKET_SIZE)
+    }
+
+    /// The size of a direct response to a querier advertising an EDNS0 payload size
+    /// of `advertised`, within any configured `max_packet_size`
+    fn edns_packet_size(&self, advertised: u16) -> usize {
+        let size = (advertised as usize).clamp(MIN_EDNS_PAYLOAD_SIZE, MAX_EDNS_PACKET_SIZE);
+        size.min(self.config.max_packet_size.unwrap_or(MAX_EDNS_PACKET_SIZE))
+    }
+
+    /// Whether `packet` from `addr` is one of ours: from one of our addresses, or the
+    /// same as one recently sent
+    fn is_own(&self, packet: &[u8], addr: SocketAddr) -> bool {
+        let own = self.addresses.addresses().unwrap_or_default();
+        own.contains(&addr.ip())
+            || self
+                .sent
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .is_own(packet, clock::now())
+    }
+
+    /// Handle records from another device which use the name of one of our services
+    /// with different data, see RFC 6762 section 9
+    fn check_conflicts(&mut self, packet: &dns_parser::Packet, addr: SocketAddr) {
+        let mut conflicts = Vec::new();
+        {
+            let services = self.services.read().unwrap_or_else(PoisonError::into_inner);
+            let hostname = services.get_hostname();
+            for record in packet.answers.iter().chain(&packet.additional) {
+                // A goodbye gives up the name rather than claiming it, and only a record
+                // with the cache-flush bit claims it alone, RFC 6762 section 10.2
+                if record.ttl == 0 || !record.cache_flush {
+                    continue;
+                }
+                let svc = match record.data {
+                    RRData::SRV { .. } | RRData::TXT(_) => services.find_by_name(&record.name),
+                    _ => None,
+                };
+                let svc = match svc {
+                    Some(svc) => svc,
+                    None => continue,
+                };
+                let ours = match record.data {
+                    RRData::SRV {
+                        priority,
+                        weight,
+                        port,
+                        ref target,
+                    } => svc.has_srv(hostname, priority, weight, port, target),
+                    RRData::TXT(txt) => self.txt_data(svc) == txt,
+                    _ => unreachable!(),
+                };
+                if ours {
+                    continue;
+                }
+
+                // Unregistered meanwhile by another thread
+                let id = match services.id_by_name(&svc.name) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                if !conflicts.iter().any(|&(other, _, _, _)| other == id) {
+                    warn!("{} is also published by {:?}", record.name, addr);
+                    let key = NameKey::new(&svc.name);
+                    conflicts.push((id, key, svc.instance(), svc.conflict));
+                }
+            }
+        }
+
+        let mut renamed = false;
+        for (id, key, name, strategy) in conflicts {
+            // The probes and announcements still waiting are for a name given up
+            if strategy != ConflictStrategy::Ignore {
+                self.cancel_repeats(&[key]);
+            }
+            let mut events = vec![ServiceEvent::Conflict { name, strategy }];
+            let mut services = self.services.write().unwrap_or_else(PoisonError::into_inner);
+            match strategy {
+                ConflictStrategy::Rename => {
+                    let strategy = self.config.rename_strategy();
+                    events.push(services.rename_conflicting(id, strategy));
+                    renamed = true;
+                }
+                ConflictStrategy::Fail => services.withdraw(id),
+                ConflictStrategy::Ignore => (),
+            }
+            drop(services);
+
+            for event in events {
+                self.config.service_event(event);
+            }
+        }
+
+        // The handlers of the other address families announce the new names too
+        if renamed {
+            if let Some(ref wake) = *self.wake.lock().unwrap_or_else(PoisonError::into_inner) {
+                wake();
+            }
+        }
+    }
+
+    /// Look for our addresses in the address records of other responders
+    fn check_address_conflicts(&mut self, packet: &dns_parser::Packet, addr: SocketAddr) {
+        let on_address_conflict = match self.config.on_address_conflict {
+            Some(ref on_address_conflict) => on_address_conflict.clone(),
+            None => return,
+        };
+        let own = match self.addresses.addresses() {
+            Ok(own) => own,
+            Err(_) => return,
+        };
+        // Another responder on this host may well publish our addresses
+        if own.contains(&addr.ip()) {
+            return;
+        }
+
+        let mut conflicts = Vec::new();
+        let services = self.services.read().unwrap_or_else(PoisonError::into_inner);
+        let hostname = services.get_hostname();
+        for record in packet.answers.iter().chain(&packet.additional) {
+            let ip = match record.data {
+                RRData::A(ip) => IpAddr::V4(ip),
+                RRData::AAAA(ip) => IpAddr::V6(ip),
+                _ => continue,
+            };
+            if record.ttl == 0 || ip.is_loopback() || !own.contains(&ip) {
+                continue;
+            }
+            let ours = Some(hostname)
+                .into_iter()
+                .chain(&self.aliases)
+                .any(|name| name as &dyn Canonical == &record.name as &dyn Canonical);
+            if ours {
+                continue;
+            }
+            let key = (ip, NameKey::new(&record.name));
+            if self.address_conflicts.contains_key(&key) {
+                continue;
+            }
+            self.address_conflicts.insert(key, ());
+
+            warn!(
+                "{:?} publishes our address {} for {}",
+                addr, ip, record.name
+            );
+            conflicts.push(AddressConflict {
+                address: ip,
+                name: record.name.to_string(),
+                source: addr,
+            });
+        }
+        drop(services);
+
+        for conflict in conflicts {
+            on_address_conflict(conflict);
+        }
+    }
+
+    /// Look for our hostname and aliases in the address records of other responders,
+    /// with addresses which aren't ours
+    fn check_hostname_conflicts(&mut self, packet: &dns_parser::Packet, addr: SocketAddr) {
+        let own = match self.addresses.addresses() {
+            Ok(own) => own,
+            Err(_) => return,
+        };
+
+        let mut conflicts = Vec::new();
+        // Whether the hostname itself, rather than an alias, was claimed
+        let mut claimed = false;
+        let mut lost: Vec<Name> = Vec::new();
+        let services = self.services.read().unwrap_or_else(PoisonError::into_inner);
+        let hostname = services.get_hostname();
+        for record in packet.answers.iter().chain(&packet.additional) {
+            let ip = match record.data {
+                RRData::A(ip) => IpAddr::V4(ip),
+                RRData::AAAA(ip) => IpAddr::V6(ip),
+                _ => continue,
+            };
+            // Shared address records don't claim the name
+            if record.ttl == 0 || !record.cache_flush || own.contains(&ip) {
+                continue;
+            }
+            let is = |name: &Name| name as &dyn Canonical == &record.name as &dyn Canonical;
+            let is_hostname = is(hostname);
+            if !is_hostname && !self.aliases.iter().any(is) {
+                continue;
+            }
+            claimed |= is_hostname;
+            if !is_hostname && !lost.iter().any(is) {
+                lost.push(record.name.clone());
+            }
+            let key = (ip, NameKey::new(&record.name));
+            if self.hostname_conflicts.contains_key(&key) {
+                continue;
+            }
+            self.hostname_conflicts.insert(key, ());
+
+            warn!("{:?} publishes {} for our name {}", addr, ip, record.name);
+            conflicts.push(HostnameConflict {
+                name: record.name.to_string(),
+                address: ip,
+                source: addr,
+            });
+        }
+        drop(services);
+
+        // Aliases added through the responder are given up, as they would be renamed
+        // to something nobody asked for. Those of the `HostData` are only reported.
+        for alias in lost {
+            if self.services.write().unwrap_or_else(PoisonError::into_inner).lose_alias(&alias) {
+                warn!("giving up hostname alias {} after a conflict", alias);
+                // The probes and announcements still waiting are for a name given up
+                self.cancel_repeats(&[NameKey::new(&alias)]);
+            }
+        }
+
+        if let Some(ref on_hostname_conflict) = self.config.on_hostname_conflict {
+            for conflict in conflicts {
+                on_hostname_conflict(conflict);
+            }
+        }
+
+        if claimed {
+            let strategy = self.config.rename_strategy();
+            let mut services = self.services.write().unwrap_or_else(PoisonError::into_inner);
+            let hostname = services.rename_hostname(strategy);
+            warn!("moving to hostname {} after a conflict", hostname);
+            drop(services);
+            // The handlers of the other address families announce it too
+            if let Some(ref wake) = *self.wake.lock().unwrap_or_else(PoisonError::into_inner) {
+                wake();
+            }
+        }
+    }
+
+    /// Announce the services renamed after conflicts since last time, or all of them
+    /// with the host's addresses once the hostname has been
+    fn check_renamed(&mut self) {
+        let services = self.services.read().unwrap_or_else(PoisonError::into_inner);
+        let (seq, renamed) = services.renamed_since(self.renamed_seen);
+        self.renamed_seen = seq;
+        let renamed: Vec<ServiceData> = renamed.into_iter().cloned().collect();
+        let hostname_renamed = services.hostname_seq() != self.hostname_seen;
+        self.hostname_seen = services.hostname_seq();
+        if hostname_renamed {
+            let hostname = services.get_hostname().clone();
+            let all: Vec<ServiceData> = services.into_iter().cloned().collect();
+            drop(services);
+            debug!("probing for the services of host {}", hostname);
+            match all.is_empty() {
+                true => self.announce_alias(&hostname, None),
+                false => self.probe_group(&all, None),
+            }
+            return;
+        }
+        drop(services);
+
+        for svc in renamed {
+            debug!("probing for {} after a conflict", svc.name);
+            self.probe_group(std::slice::from_ref(&svc), None);
+        }
+    }
+
+    /// Probe for the names of `svcs` (RFC 6762 section 8.1), and announce them once
+    /// the probes have gone unanswered, telling `announced`. A response claiming one of
+    /// the names meanwhile renames its service, which drops the probes and
+    /// announcement still waiting.
+    fn probe_group(&mut self, svcs: &[ServiceData], announced: Option<Announced>) {
+        // The names of withdrawn services are someone else's now
+        let svcs: Vec<&ServiceData> = svcs.iter().filter(|svc| !svc.withdrawn).collect();
+        let first = match svcs.first() {
+            Some(&first) => first,
+            None => return,
+        };
+        let names: Vec<NameKey> = svcs.iter().map(|svc| NameKey::new(&svc.name)).collect();
+
+        let mut at = clock::now() + clock::jitter(0, PROBE_INTERVAL_MS);
+        let interval = Duration::from_millis(PROBE_INTERVAL_MS);
+        let start = at + interval * PROBES;
+        let group: Vec<ServiceData> = svcs.iter().map(|&svc| svc.clone()).collect();
+        self.announce_group(&group, false, true, announced, Some(start));
+
+        for i in 0..PROBES {
+            let probe = Unsolicited {
+                packet: self.build_probe(&svcs, i == 0),
+                addr: SocketAddr::new(AF::MDNS_GROUP.into(), MDNS_PORT),
+                service: NameKey::new(&first.name),
+                rate: None,
+                announced: None,
+                repeats: names.clone(),
+                goodbye: false,
+            };
+            self.scheduled.push((at, probe));
+            at += interval;
+        }
+    }
+
+    /// A query for the names of `svcs` with the records we are about to claim in the
+    /// authority section, the first one asking for unicast responses, RFC 6762 section 8
+    fn build_probe(&self, svcs: &[&ServiceData], first: bool) -> Vec<u8> {
+        let mut builder = dns_parser::Builder::new_query(0, false);
+        for svc in svcs {
+            builder = match first {
+                true => builder.add_unicast_question(&svc.name, QueryType::All, QueryClass::IN),
+                false => builder.add_question(&svc.name, QueryType::All, QueryClass::IN),
+            };
+        }
+        let mut builder: ProbeBuilder = builder.move_to();
+        builder.set_max_size(None);
+        builder.set_compression(self.compress_names());
+
+        let services = self.services.read().unwrap_or_else(PoisonError::into_inner);
+        for svc in svcs {
+            builder = svc.add_probe_rr(services.get_hostname(), builder);
+            let txt = self.txt_data(svc);
+            builder =
+                builder.add_nameserver(&svc.name, QueryClass::IN, svc.ttl, &RRData::TXT(&txt));
+        }
+        builder.build().unwrap_or_else(|x| x)
+    }
+
+    /// Run the configured `RecordValidator` over the TXT records of a response,
+    /// returning the instances whose records are rejected
+    fn check_records(&self, packet: &dns_parser::Packet, addr: SocketAddr) -> Vec<NameKey> {
+        let validator = match self.config.record_validator {
+            Some(ref validator) => validator,
+            None => return Vec::new(),
+        };
+
+        let mut rejected = Vec::new();
+        let records = || packet.answers.iter().chain(&packet.additional);
+        for record in records() {
+            let txt = match record.data {
+                RRData::TXT(txt) => txt,
+                _ => continue,
+            };
+            let port = records().find_map(|srv| match srv.data {
+                RRData::SRV { port, .. } if srv.name == record.name => Some(port),
+                _ => None,
+            });
+            if !validator.validate(&record.name.to_string(), port, txt) {
+                debug!("ignoring unverified record {} from {:?}", record.name, addr);
+                rejected.push(NameKey::new(&record.name));
+            }
+        }
+        rejected
+    }
+
+    /// Every record published under `qname`, whatever its type
+    fn resolve<'a>(&self, qname: &'a Name<'a>, services: &'a ServicesInner) -> Vec<Record<'a>> {
+        let mut records = Vec::new();
+        let hostname = services.get_hostname();
+        let is = |name: &Name| qname as &dyn Canonical == name as &dyn Canonical;
+
+        let mut addresses = self
+            .aliases
+            .iter()
+            .chain(Some(hostname))
+            .filter(|&name| is(name));
+        if let Some(name) = addresses.next() {
+            let ips = self.advertised_ips_of(name);
+            let mut types = vec![];
+            if ips.iter().any(IpAddr::is_ipv4) {
+                types.push(Type::A);
+            }
+            if ips.iter().any(IpAddr::is_ipv6) {
+                types.push(Type::AAAA);
+            }
+            records.push(Record::Address(name.clone(), types));
+        }
+        if self.config.host_info.is_some() && is(hostname) {
+            records.push(Record::HostInfo(hostname));
+        }
+
+        // https://www.rfc-editor.org/rfc/rfc6763#section-9
+        if is(&Name::FromStr(SERVICE_TYPE_ENUMERATION_NAME)) {
+            let mut types: Vec<&Name> = Vec::new();
+            for svc in services {
+                if !types
+                    .iter()
+                    .any(|&typ| typ as &dyn Canonical == &svc.typ as &dyn Canonical)
+                {
+                    types.push(&svc.typ);
+                }
+            }
+            records.extend(types.into_iter().map(Record::ServiceType));
+        }
+
+        // Avoid listing the interfaces for every other name
+        let arpa = qname.labels().last();
+        if arpa.is_some_and(|label| label.eq_ignore_ascii_case(b"arpa")) {
+            let names = self.reverse_names(hostname, |ip| is(&reverse_name(ip)));
+            if !names.is_empty() {
+                records.push(Record::Reverse(names));
+            }
+        }
+
+        records.extend(services.find_by_type(qname).map(Record::Instance));
+        if let Some(svc) = services.find_by_name(qname) {
+            records.push(Record::Srv(svc));
+            records.push(Record::Txt(svc));
+        }
+
+        for svc in services {
+            for record in &svc.additional {
+                if is(&record.name) {
+                    records.push(Record::Additional(record));
+                }
+            }
+        }
+
+        records
+    }
+
+    fn handle_question(
+        &self,
+        question: &dns_parser::Question,
+        mut builder: AnswerBuilder,
+        legacy: bool,
+    ) -> AnswerBuilder {
+        let services = self.services.read().unwrap_or_else(PoisonError::into_inner);
+        let hostname = services.get_hostname();
+        let min_ttl = self.profile().min_ttl();
+        let ttl_of = |ttl: u32| {
+            if legacy {
+                ttl.min(LEGACY_UNICAST_TTL)
+            } else {
+                ttl.max(min_ttl)
+            }
+        };
+        let ttl = ttl_of(DEFAULT_TTL);
+        let host_ttl = ttl_of(self.host_ttl());
+        let qname = &question.qname;
+        // Legacy resolvers don't know about the bit, RFC 6762 section 10.2
+        let cache_flush = !legacy && self.cache_flush();
+
+        let records = self.resolve(qname, &services);
+        // Whether instances were resolved in the enumeration of service types
+        let mut enumerated = false;
+        for record in &records {
+            if !record.answers(question.qtype) {
+                continue;
+            }
+
+            match *record {
+                Record::Address(ref name, _) => {
+                    builder = match question.qtype {
+                        QueryType::A => {
+                            self.add_family_rr(name, builder, host_ttl, cache_flush, false)
+                        }
+                        QueryType::AAAA => {
+                            self.add_family_rr(name, builder, host_ttl, cache_flush, true)
+                        }
+                        _ => self.add_ip_rr(name, builder, host_ttl, cache_flush),
+                    }
+                }
+                Record::ServiceType(typ) => {
+                    builder =
+                        builder.add_answer(qname, QueryClass::IN, ttl, &RRData::PTR(typ.clone()));
+                    // Saves the querier browsing each type in turn. The instances
+                    // weren't asked about, so they are additional records.
+                    let records = self.config.enumerate_instance_records;
+                    if self.config.enumerate_instances || records {
+                        let mut instances = additional_builder();
+                        for svc in services.find_by_type(typ) {
+                            instances = svc.add_ptr_rr(instances, ttl_of(svc.ptr_ttl));
+                            // and resolving each instance
+                            if records {
+                                let ttl = ttl_of(svc.ttl);
+                                instances = svc.add_srv_rr(hostname, instances, ttl, cache_flush);
+                                instances = self.add_txt_rr(svc, instances, ttl, cache_flush);
+                                enumerated = true;
+                            }
+                        }
+                        builder = builder.add_deferred_additional_from(instances);
+                    }
+                }
+                Record::Reverse(ref names) => {
+                    for name in names {
+                        builder = builder.add_answer_with_cache_flush(
+                            qname,
+                            QueryClass::IN,
+                            ttl,
+                            &RRData::PTR(name.clone()),
+                            cache_flush,
+                        );
+                    }
+                }
+                // The querier will want to resolve the instances it browsed for
+                Record::Instance(svc) => {
+                    let ttl = ttl_of(svc.ttl);
+                    builder = svc.add_ptr_rr(builder, ttl_of(svc.ptr_ttl));
+                    builder = svc.add_srv_rr(hostname, builder, ttl, cache_flush);
+                    builder = self.add_txt_rr(svc, builder, ttl, cache_flush);
+                    builder = self.add_ip_rr(hostname, builder, host_ttl, cache_flush);
+                }
+                Record::Srv(svc) => {
+                    let ttl = ttl_of(svc.ttl);
+                    builder = svc.add_srv_rr(hostname, builder, ttl, cache_flush);
+                    builder = self.add_ip_rr(hostname, builder, host_ttl, cache_flush);
+                }
+                Record::Txt(svc) => {
+                    builder = self.add_txt_rr(svc, builder, ttl_of(svc.ttl), cache_flush)
+                }
+                Record::Additional(record) => {
+                    builder = record.add_rr(builder, ttl_of(record.ttl), cache_flush);
+                }
+                Record::HostInfo(name) => {
+                    let (cpu, os) = self.config.host_info.as_ref().unwrap();
+                    let hinfo = RRData::HINFO {
+                        cpu: cpu.as_bytes(),
+                        os: os.as_bytes(),
+                    };
+                    builder = builder.add_answer_with_cache_flush(
+                        name,
+                        QueryClass::IN,
+                        ttl,
+                        &hinfo,
+                        cache_flush,
+                    );
+                }
+            }
+        }
+        if enumerated {
+            let addresses = self.add_ip_rr(hostname, additional_builder(), host_ttl, cache_flush);
+            builder = builder.add_deferred_additional_from(addresses);
+        }
+
+        // Tell the querier there is nothing more to ask about names which only we
+        // answer for, see RFC 6762 section 6.1
+        if question.qtype == QueryType::All {
+            let mut types: Vec<u16> = records
+                .iter()
+                .filter(|record| record.is_unique())
+                .flat_map(Record::types)
+                .collect();
+            if !types.is_empty() {
+                types.push(Type::NSEC as u16);
+                let nsec = RRData::NSEC {
+                    next: qname.clone(),
+                    types,
+                };
+                builder = builder.add_answer_with_cache_flush(
+                    qname,
+                    QueryClass::IN,
+                    ttl,
+                    &nsec,
+                    cache_flush,
+                );
+            }
+        }
+
+        // Only PTRs are published at the enumeration name, by whoever publishes it, so
+        // the other types can be denied even though the name is shared, RFC 6763
+        // section 9
+        let enumerated = records
+            .iter()
+            .any(|record| matches!(*record, Record::ServiceType(_)));
+        if enumerated && !matches!(question.qtype, QueryType::PTR | QueryType::All) {
+            let nsec = RRData::NSEC {
+                next: qname.clone(),
+                types: vec![Type::PTR as u16, Type::NSEC as u16],
+            };
+            builder = builder.add_answer(qname, QueryClass::IN, ttl, &nsec);
+        }
+
+        builder
+    }
+
+    fn add_txt_rr(
+        &self,
+        svc: &ServiceData,
+        builder: AnswerBuilder,
+        ttl: u32,
+        cache_flush: bool,
+    ) -> AnswerBuilder {
+        match self.config.txt_decorator {
+            Some(_) => builder.add_answer_with_cache_flush(
+                &svc.name,
+                QueryClass::IN,
+                ttl,
+                &RRData::TXT(&self.txt_data(svc)),
+                cache_flush,
+            ),
+            None => svc.add_txt_rr(builder, ttl, cache_flush),
+        }
+    }
+
+    /// The TXT data sent for `svc`, after the `TxtDecorator`
+    fn txt_data<'a>(&self, svc: &'a ServiceData) -> Cow<'a, [u8]> {
+        match self.config.txt_decorator {
+            Some(ref decorator) => {
+                Cow::Owned(decorator.decorate(&svc.name.to_string(), svc.port, &svc.txt))
+            }
+            None => Cow::Borrowed(&svc.txt),
+        }
+    }
+
+    /// Addresses advertised in A / AAAA records. Both families are included whichever
+    /// one the responses are sent over, see RFC 6762 section 6.2.
+    fn advertised_ips(&self) -> Vec<IpAddr> {
+        match self.addresses.addresses() {
+            Ok(addresses) => self.advertisable(addresses),
+            Err(err) => {
+                error!("could not get list of addresses: {}", err);
+                vec![]
+            }
+        }
+    }
+
+    /// The addresses published for `name`, which are those of the host unless it is
+    /// one of the `HostData::address_names`
+    fn advertised_ips_of(&self, name: &Name) -> Vec<IpAddr> {
+        let own = self
+            .addresses
+            .address_names()
+            .into_iter()
+            .find(|(other, _)| is_name(other, name));
+        match own {
+            Some((_, ips)) => self.advertisable(ips),
+            None => self.advertised_ips(),
+        }
+    }
+
+    /// `advertised_ips_of` in the order sent: those of the family the response goes
+    /// out over first, as it is the querier's, and several embedded HTTP clients just
+    /// take the first address record whichever family it is
+    fn ordered_ips_of(&self, name: &Name) -> Vec<IpAddr> {
+        let mut ips = self.advertised_ips_of(name);
+        // Stable, keeping the host's order within each family
+        ips.sort_by_key(|ip| ip.is_ipv6() != (AF::FAMILY == IpFamily::V6));
+        ips
+    }
+
+    fn advertisable(&self, addresses: Vec<IpAddr>) -> Vec<IpAddr> {
+        if !self.owner.owns_addresses() {
+            trace!("another responder on this host owns the address records");
+            return vec![];
+        }
+
+        let mut ips = vec![];
+        for ip in addresses {
+            if ip.is_loopback() {
+                continue;
+            }
+
+            trace!("found address {:?}", ip);
+            let on_interface = |name: &str| self.addresses.is_on_interface(ip, name);
+            if !allow::is_allowed(&self.config.allowed_ips, ip, on_interface) {
+                trace!("  -> address dropped");
+                continue;
+            }
+
+            ips.push(ip);
+        }
+
+        ips
+    }
+
+    /// The names published for the first of our addresses which `matches`: the
+    /// hostname and aliases for the addresses of the host, and the address names
+    /// which have it. Keeps reverse answers consistent with the forward records.
+    fn reverse_names<'a>(
+        &self,
+        hostname: &Name<'a>,
+        matches: impl Fn(IpAddr) -> bool,
+    ) -> Vec<Name<'a>> {
+        let address_names = self.addresses.address_names();
+        let host_ips = self.advertised_ips();
+        let own_ips = address_names
+            .iter()
+            .flat_map(|(_, ips)| self.advertisable(ips.clone()));
+        let ip = match host_ips
+            .iter()
+            .copied()
+            .chain(own_ips)
+            .find(|&ip| matches(ip))
+        {
+            Some(ip) => ip,
+            None => return vec![],
+        };
+
+        let mut names = vec![];
+        if host_ips.contains(&ip) {
+            names.push(hostname.clone());
+            let aliases = self
+                .aliases
+                .iter()
+                .filter(|alias| !address_names.iter().any(|(name, _)| is_name(name, alias)));
+            names.extend(aliases.cloned());
+        }
+        for (name, ips) in address_names {
+            if self.advertisable(ips).contains(&ip) {
+                names.push(Name::from_str(name).unwrap());
+            }
+        }
+        names
+    }
+
+    fn add_ip_rr(
+        &self,
+        hostname: &Name,
+        mut builder: AnswerBuilder,
+        ttl: u32,
+        cache_flush: bool,
+    ) -> AnswerBuilder {
+        for ip in self.ordered_ips_of(hostname) {
+            let data = match ip {
+                IpAddr::V4(ip) => RRData::A(ip),
+                IpAddr::V6(ip) => RRData::AAAA(ip),
+            };
+            builder = builder.add_answer_with_cache_flush(
+                hostname,
+                QueryClass::IN,
+                ttl,
+                &data,
+                cache_flush,
+            );
+        }
+
+        builder
+    }
+
+    /// The address records of `name` of the family asked for, with those of the
+    /// other family as additional records, see RFC 6762 section 6.2
+    fn add_family_rr(
+        &self,
+        name: &Name,
+        mut builder: AnswerBuilder,
+        ttl: u32,
+        cache_flush: bool,
+        ipv6: bool,
+    ) -> AnswerBuilder {
+        for ip in self.ordered_ips_of(name) {
+            let data = match ip {
+                IpAddr::V4(ip) => RRData::A(ip),
+                IpAddr::V6(ip) => RRData::AAAA(ip),
+            };
+            builder = if ip.is_ipv6() == ipv6 {
+                builder.add_answer_with_cache_flush(name, QueryClass::IN, ttl, &data, cache_flush)
+            } else {
+                builder.add_deferred_additional(name, QueryClass::IN, ttl, &data, cache_flush)
+            };
+        }
+
+        builder
+    }
+
+    /// Announce `svc` with its own TTLs, or send goodbyes for it if `ttl` is zero
+    pub fn send_unsolicited(&mut self, svc: &ServiceData, goodbye: bool, include_ip: bool) {
+        self.send_unsolicited_group(std::slice::from_ref(svc), goodbye, include_ip);
+    }
+
+    /// Announce the records of all of `svcs` together, or if `goodbye` send goodbyes
+    /// for them, with a TTL of 0. The packets are paced as the first service's.
+    pub fn send_unsolicited_group(
+        &mut self,
+        svcs: &[ServiceData],
+        goodbye: bool,
+        include_ip: bool,
+    ) {
+        self.announce_group(svcs, goodbye, include_ip, None, None);
+    }
+
+    /// `send_unsolicited_group`, telling `announced` once the packets have been sent.
+    /// An announcement is scheduled at `start` if given, after probes for the names.
+    fn announce_group(
+        &mut self,
+        svcs: &[ServiceData],
+        goodbye: bool,
+        include_ip: bool,
+        announced: Option<Announced>,
+        start: Option<Instant>,
+    ) {
+        // The names of withdrawn services are someone else's now
+        let svcs: Vec<&ServiceData> = svcs.iter().filter(|svc| !svc.withdrawn).collect();
+        let first = match svcs.first() {
+            Some(&first) => first,
+            None => return,
+        };
+        let names: Vec<NameKey> = svcs.iter().map(|svc| NameKey::new(&svc.name)).collect();
+        self.cancel_repeats(&names);
+
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        builder.set_max_size(Some(self.max_packet_size()));
+        builder.set_compression(self.compress_names());
+        let cache_flush = self.cache_flush();
+        let min_ttl = self.profile().min_ttl();
+
+        let services = self.services.read().unwrap_or_else(PoisonError::into_inner);
+
+        for svc in &svcs {
+            // Goodbyes withdraw every record, other TTLs are the service's own
+            let (ptr_ttl, svc_ttl) = match goodbye {
+                true => (0, 0),
+                false => (svc.ptr_ttl.max(min_ttl), svc.ttl.max(min_ttl)),
+            };
+            builder = svc.add_ptr_rr(builder, ptr_ttl);
+            builder = svc.add_srv_rr(services.get_hostname(), builder, svc_ttl, cache_flush);
+            builder = self.add_txt_rr(svc, builder, svc_ttl, cache_flush);
+            for record in &svc.additional {
+                let record_ttl = if goodbye { 0 } else { record.ttl.max(min_ttl) };
+                builder = record.add_rr(builder, record_ttl, cache_flush);
+            }
+        }
+        if include_ip {
+            let ttl = if goodbye { 0 } else { self.host_ttl() };
+            builder = self.add_ip_rr(services.get_hostname(), builder, ttl, cache_flush);
+        }
+        drop(services);
+
+        if !builder.is_empty() {
+            for response in builder.build_split() {
+                if goodbye {
+                    self.queue_goodbye(response, &first.name, first.announce_rate, &names);
+                } else {
+                    self.queue_announcement(
+                        response,
+                        &first.name,
+                        first.announce_rate,
+                        announced.clone(),
+                        &names,
+                        start,
+                    );
+                }
+            }
+        }
+        if let Some(announced) = announced {
+            announced.sent();
+        }
+    }
+
+    /// Announce every service again with the full schedule of repeats, on the
+    /// addresses the host has after waking, as others may have flushed them meanwhile
+    #[cfg(feature = "responder")]
+    fn resumed(&mut self) {
+        debug!("resumed, announcing services again");
+        self.addresses.invalidate();
+        let svcs: Vec<ServiceData> = self.services.read().unwrap_or_else(PoisonError::into_inner).into_iter().cloned().collect();
+        self.announce_group(&svcs, false, true, None, None);
+    }
+
+    /// Goodbyes for the host's addresses under `hostname` and the SRV records pointing
+    /// at it, after the hostname was replaced
+    #[cfg(feature = "responder")]
+    fn withdraw_hostname(&mut self, hostname: &Name) {
+        debug!("withdrawing replaced hostname {}", hostname);
+        self.withdraw_alias(hostname);
+
+        let svcs: Vec<ServiceData> = self.services.read().unwrap_or_else(PoisonError::into_inner).into_iter().cloned().collect();
+        let first = match svcs.first() {
+            Some(first) => first,
+            None => return,
+        };
+        let names: Vec<NameKey> = svcs.iter().map(|svc| NameKey::new(&svc.name)).collect();
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        builder.set_max_size(Some(self.max_packet_size()));
+        builder.set_compression(self.compress_names());
+        let cache_flush = self.cache_flush();
+        for svc in &svcs {
+            builder = svc.add_srv_rr(hostname, builder, 0, cache_flush);
+        }
+        for response in builder.build_split() {
+            self.queue_goodbye(response, &first.name, first.announce_rate, &names);
+        }
+    }
+
+    #[cfg(feature = "responder")]
+    pub fn send_srv_goodbye(&mut self, svc: &ServiceData, current: &ServiceData) {
+        if svc.withdrawn {
+            return;
+        }
+        // The repeats of its last announcement have the old SRV records
+        let names = [NameKey::new(&svc.name)];
+        self.cancel_repeats(&names);
+
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        builder.set_max_size(None);
+        builder.set_compression(self.compress_names());
+
+        let services = self.services.read().unwrap_or_else(PoisonError::into_inner);
+        let hostname = services.get_hostname();
+        builder = svc.add_srv_goodbye_rr(current, hostname, builder, self.cache_flush());
+        drop(services);
+
+        if builder.is_empty() {
+            return;
+        }
+        let response = builder.build().unwrap_or_else(|x| x);
+        self.queue_goodbye(response, &svc.name, svc.announce_rate, &names);
+    }
+}
+
+/// `packet` without the questions and records about `names`, those owned by them
+/// and the PTR records pointing at them, or `None` if that leaves nothing. Names are
+/// compressed if `compress`.
+fn without_names(packet: &[u8], names: &[NameKey], compress: bool) -> Option<Vec<u8>> {
+    let (packet, _) = dns_parser::Packet::parse_lenient(packet).ok()?;
+    let about = |name: &Name| names.contains(&NameKey::new(name));
+    let kept = |record: &&dns_parser::ResourceRecord| match record.data {
+        RRData::PTR(ref target) if about(target) => false,
+        _ => !about(&record.name),
+    };
+
+    let mut builder = match packet.header.query {
+        true => dns_parser::Builder::new_query(0, false),
+        false => dns_parser::Builder::new_response(0, false, true),
+    };
+    builder.set_max_size(None);
+    builder.set_compression(compress);
+    for question in packet.questions.iter().filter(|q| !about(&q.qname)) {
+        let (qname, qtype, qclass) = (&question.qname, question.qtype, question.qclass);
+        builder = match question.qu {
+            true => builder.add_unicast_question(qname, qtype, qclass),
+            false => builder.add_question(qname, qtype, qclass),
+        };
+    }
+    let mut builder = builder.move_to::<dns_parser::Answers>();
+    for record in packet.answers.iter().filter(kept) {
+        let (name, ttl, data) = (&record.name, record.ttl, &record.data);
+        builder = builder.add_answer_with_cache_flush(
+            name,
+            QueryClass::IN,
+            ttl,
+            data,
+            record.cache_flush,
+        );
+    }
+    let mut builder: ProbeBuilder = builder.move_to();
+    for record in packet.nameservers.iter().filter(kept) {
+        builder = builder.add_nameserver(&record.name, QueryClass::IN, record.ttl, &record.data);
+    }
+    let mut builder = builder.move_to::<dns_parser::Additional>();
+    for record in packet.additional.iter().filter(kept) {
+        let (name, ttl, data) = (&record.name, record.ttl, &record.data);
+        builder = builder.add_additional_with_cache_flush(
+            name,
+            QueryClass::IN,
+            ttl,
+            data,
+            record.cache_flush,
+        );
+    }
+
+    match builder.is_empty() {
+        true => None,
+        false => Some(builder.build().unwrap_or_else(|x| x)),
+    }
+}
+
+/// The message a panic was started with, if it was given one
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => (*message).to_owned(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "unknown panic".to_owned(),
+        },
+    }
+}
+
+/// A builder to collect records in, which are then added to a response as
+/// additional records with `add_deferred_additional_from`
+fn additional_builder() -> AnswerBuilder {
+    let mut builder = dns_parser::Builder::new_response(0, false, true).move_to();
+    builder.set_max_size(None);
+    builder
+}
+
+/// Whether `name` is `other`, ignoring case
+fn is_name(name: &str, other: &Name) -> bool {
+    Name::from_str(name.to_owned())
+        .is_ok_and(|name| &name as &dyn Canonical == other as &dyn Canonical)
+}
+
+/// The name used to look up the hostname for `ip`, see RFC 1035 section 3.5 and
+/// RFC 3596 section 2.5
+fn reverse_name(ip: IpAddr) -> Name<'static> {
+    let mut name = String::new();
+    match ip {
+        IpAddr::V4(ip) => {
+            for octet in ip.octets().iter().rev() {
+                write!(name, "{}.", octet).unwrap();
+            }
+            name.push_str("in-addr.arpa");
+        }
+        IpAddr::V6(ip) => {
+            for octet in ip.octets().iter().rev() {
+                write!(name, "{:x}.{:x}.", octet & 0xf, octet >> 4).unwrap();
+            }
+            name.push_str("ip6.arpa");
+        }
+    }
+    Name::from_str(name).unwrap()
+}
+
+#[cfg(feature = "responder")]
+impl<AF: Unpin + AddressFamily, T: Link> Future for FSM<AF, T> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let pinned = Pin::get_mut(self);
+        let mut budget = POLL_BUDGET;
+        while !pinned.shutting_down {
+            let cmd = match Pin::new(&mut pinned.commands).poll_recv(cx) {
+                Poll::Ready(cmd) => cmd,
+                Poll::Pending => break,
+            };
+            match cmd {
+                Some(Command::Shutdown) => {
+                    pinned.handler.handle_command(Command::Shutdown);
+                    pinned.shutting_down = true;
+                }
+                Some(cmd) => pinned.handler.handle_command(cmd),
+                None => {
+                    warn!("responder disconnected without shutdown");
+                    return Poll::Ready(());
+                }
+            }
+            budget -= 1;
+            if budget == 0 {
+                cx.waker().wake_by_ref();
+                break;
+            }
+        }
+
+        if pinned.shutting_down {
+            // Flush the goodbyes, then stop
+            pinned.send_packets(cx);
+            if pinned.handler.is_drained() && pinned.pending.is_empty() && pinned.retry.is_none() {
+                return Poll::Ready(());
+            }
+            if let Some(until) = pinned.backoff.paused_until(Instant::now()) {
+                let timer = pinned.pacing_timer.get_or_insert_with(|| Timer::new(until));
+                timer.reset(until);
+                if timer.poll(cx).is_ready() {
+                    cx.waker().wake_by_ref();
+                }
+            }
+            return Poll::Pending;
+        }
+
+        match pinned.recv_packets(cx) {
+            Ok(_) => (),
+            Err(e) => error!("ResponderRecvPacket Error: {:?}", e),
+        }
+
+        if let Some(ref mut pool) = pinned.workers {
+            while let Poll::Ready(Some(answer)) = pool.poll_answer(cx) {
+                pinned.handler.push_response(answer);
+            }
+        }
+
+        pinned.send_packets(cx);
+
+        // While paused, nothing can be sent before the pause is over
+        let ready = match pinned.backoff.paused_until(Instant::now()) {
+            Some(until) => Some(until),
+            None => pinned.handler.next_unsolicited_at(),
+        };
+        if let Some(ready) = ready {
+            let timer = pinned.pacing_timer.get_or_insert_with(|| Timer::new(ready));
+            timer.reset(ready);
+            if timer.poll(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_family::{Inet, Inet6};
+    use crate::browse::BrowseBuilder;
+    use crate::host::FixedHostData;
+    use crate::records::ServiceRecords;
+    use crate::service_builder::{RecordData, ServiceBuilder};
+    use crate::services::ServicesInner;
+    use rand::{thread_rng, Rng};
+    use std::io;
+    use std::sync::{Arc, RwLock};
+
+    /// The services of "test-hostname.local", to register in, and the state of a
+    /// responder for them configured with `config`
+    fn handler_with(config: ResponderConfig) -> (Services, Shared) {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let shared = Shared::new(&services, config);
+        (services, shared)
+    }
+
+    /// The response of `handler` to a question for `name`, of any size
+    fn respond<AF: AddressFamily>(handler: &Handler<AF>, name: &str, qtype: QueryType) -> Vec<u8> {
+        let question = dns_parser::Question {
+            qname: Name::from_str(name.to_owned()).unwrap(),
+            qtype,
+            qclass: QueryClass::IN,
+            qu: false,
+        };
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        builder.set_max_size(None);
+        handler
+            .handle_question(&question, builder, false)
+            .build()
+            .unwrap()
+    }
+
+    /// A record as its type and name, followed by the data of the types tests look at
+    fn describe(record: &dns_parser::ResourceRecord) -> String {
+        let name = &record.name;
+        match record.data {
+            RRData::PTR(ref target) => format!("PTR {} {}", name, target),
+            RRData::A(ip) => format!("A {} {}", name, ip),
+            RRData::AAAA(ip) => format!("AAAA {} {}", name, ip),
+            RRData::HINFO { cpu, os } => format!(
+                "HINFO {} {} {}",
+                name,
+                String::from_utf8_lossy(cpu),
+                String::from_utf8_lossy(os)
+            ),
+            ref data => format!("{:?} {}", data.typ(), name),
+        }
+    }
+
+    /// The answers of `handler` to a question for `name`, see `describe`
+    fn ask<AF: AddressFamily>(handler: &Handler<AF>, name: &str, qtype: QueryType) -> Vec<String> {
+        let response = respond(handler, name, qtype);
+        let parsed = dns_parser::Packet::parse(&response).unwrap();
+        parsed.answers.iter().map(describe).collect()
+    }
+
+    /// The additional records sent with the answers of `ask`
+    fn ask_additional<AF: AddressFamily>(
+        handler: &Handler<AF>,
+        name: &str,
+        qtype: QueryType,
+    ) -> Vec<String> {
+        let response = respond(handler, name, qtype);
+        let (parsed, _) = dns_parser::Packet::parse_lenient(&response).unwrap();
+        parsed.additional.iter().map(describe).collect()
+    }
+
+    #[test]
+    fn test_service_type_enumeration() {
+        let question = dns_parser::Question {
+            qname: dns_parser::Name::from_str("_services._dns-sd._udp.local").unwrap(),
+            qtype: dns_parser::QueryType::PTR,
+            qclass: dns_parser::QueryClass::IN,
+            qu: false,
+        };
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let service_data = ServiceData::with_records(ServiceRecords {
+            name: Name::from_str("test-instance").unwrap(),
+            typ: Name::from_str("_test-service-name._tcp").unwrap(),
+            port: 8008,
+            extra_srv: vec![],
+            txt: vec![],
+            additional: vec![],
+            ptr_ttl: DEFAULT_TTL,
+            ttl: DEFAULT_TTL,
+        });
+        services
+            .write()
+            .unwrap()
+            .register(service_data.clone())
+            .unwrap();
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::with_records(ServiceRecords {
+                name: Name::from_str("other-instance").unwrap(),
+                ..service_data.records
+            }))
+            .unwrap();
+        let shared = Shared::new(&services, ResponderConfig::default());
+        let handler = Handler::<Inet>::new(&shared);
+
+        let mut answer_builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        answer_builder.set_max_size(None);
+
+        answer_builder = handler.handle_question(&question, answer_builder, false);
+
+        let packet = answer_builder.build().unwrap();
+
+        let parsed = dns_parser::Packet::parse(&packet).unwrap();
+        assert_eq!(parsed.answers.len(), 1);
+        assert_eq!(
+            parsed.answers[0].name,
+            Name::from_str(SERVICE_TYPE_ENUMERATION_NAME).unwrap()
+        );
+        assert_eq!(parsed.answers[0].cls, dns_parser::Class::IN);
+        assert_eq!(parsed.answers[0].ttl, 60);
+        let ptr = match &parsed.answers[0].data {
+            RRData::PTR(ptr) => ptr,
+            other => panic!("Unexpected answer RR data type: {:?}", other),
+        };
+        assert_eq!(*ptr, Name::from_str("_test-service-name._tcp").unwrap());
+    }
+
+    #[test]
+    fn test_enumerate_instances() {
+        let (services, shared) = handler_with(ResponderConfig {
+            enumerate_instances: true,
+            ..ResponderConfig::default()
+        });
+        for name in ["first", "second"] {
+            let svc = ServiceBuilder::new("_http._tcp", name, 80)
+                .ptr_ttl(4500)
+                .build()
+                .unwrap();
+            services.write().unwrap().register(svc).unwrap();
+        }
+        let handler = Handler::<Inet>::new(&shared);
+
+        let name = &SERVICE_TYPE_ENUMERATION_NAME;
+        assert_eq!(
+            ask(&handler, name, QueryType::PTR),
+            ["PTR _services._dns-sd._udp.local _http._tcp.local"]
+        );
+        // The instances weren't asked about
+        let mut instances = ask_additional(&handler, name, QueryType::PTR);
+        instances.sort();
+        assert_eq!(
+            instances,
+            [
+                "PTR _http._tcp.local first._http._tcp.local",
+                "PTR _http._tcp.local second._http._tcp.local"
+            ]
+        );
+        let response = respond(&handler, name, QueryType::PTR);
+        let (parsed, _) = dns_parser::Packet::parse_lenient(&response).unwrap();
+        assert_eq!(parsed.answers[0].ttl, DEFAULT_TTL);
+        assert!(parsed.additional.iter().all(|record| record.ttl == 4500));
+    }
+
+    #[test]
+    fn test_legacy_service_type_enumeration() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new(
+                "_test-service-name._tcp",
+                "test-instance",
+                8008,
+                (&[]).into(),
+            ))
+            .unwrap();
+        let shared = Shared::new(&services, ResponderConfig::default());
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let query = dns_parser::Builder::new_query(42, false).add_question(
+            &Name::from_str(SERVICE_TYPE_ENUMERATION_NAME).unwrap(),
+            QueryType::PTR,
+            QueryClass::IN,
+        );
+        let source: SocketAddr = "169.254.0.1:54321".parse().unwrap();
+        handler.handle_packet(&query.build().unwrap(), source);
+
+        let (response, addr) = handler.pop_outgoing().unwrap();
+        assert_eq!(addr, source);
+        assert!(handler.pop_outgoing().is_none());
+
+        let parsed = dns_parser::Packet::parse(&response).unwrap();
+        assert_eq!(parsed.header.id, 42);
+        assert_eq!(parsed.questions.len(), 1);
+        assert_eq!(
+            parsed.questions[0].qname,
+            Name::from_str(SERVICE_TYPE_ENUMERATION_NAME).unwrap()
+        );
+        assert_eq!(parsed.answers.len(), 1);
+        assert_eq!(parsed.answers[0].ttl, LEGACY_UNICAST_TTL);
+    }
+
+    #[test]
+    fn test_cache_flush() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let svc = ServiceData::new("_http._tcp", "test-instance", 8008, (&[]).into());
+        services.write().unwrap().register(svc.clone()).unwrap();
+        let host = FixedHostData::new("test-hostname".into(), vec!["192.0.2.9".parse().unwrap()]);
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(host)),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        handler.send_unsolicited(&svc, false, true);
+        let (announcement, _) = handler.pop_outgoing().unwrap();
+        let parsed = dns_parser::Packet::parse(&announcement).unwrap();
+        let flushed: Vec<bool> = parsed.answers.iter().map(|a| a.cache_flush()).collect();
+        // Only the PTR record is shared with other responders
+        assert_eq!(flushed, [false, true, true, true]);
+
+        let query = dns_parser::Builder::new_query(0, false).add_question(
+            &Name::from_str("test-instance._http._tcp.local").unwrap(),
+            QueryType::SRV,
+            QueryClass::IN,
+        );
+        let query = query.build().unwrap();
+        handler.handle_packet(&query, "169.254.0.1:5353".parse().unwrap());
+        let (response, _) = handler.pop_outgoing().unwrap();
+        let parsed = dns_parser::Packet::parse(&response).unwrap();
+        assert!(parsed.answers.iter().all(|a| a.cache_flush()));
+
+        handler.handle_packet(&query, "169.254.0.1:54321".parse().unwrap());
+        let (response, _) = handler.pop_outgoing().unwrap();
+        let parsed = dns_parser::Packet::parse(&response).unwrap();
+        assert!(parsed.answers.iter().all(|a| !a.cache_flush()));
+    }
+
+    #[test]
+    fn test_reverse_name() {
+        assert_eq!(
+            reverse_name("192.0.2.1".parse().unwrap()),
+            Name::from_str("1.2.0.192.in-addr.arpa").unwrap()
+        );
+        assert_eq!(
+            reverse_name("2001:db8::567:89ab".parse().unwrap()),
+            Name::from_str(
+                "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_drop_stale_response() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new(
+                "_test-service-name._tcp",
+                "test-instance",
+                8008,
+                (&[]).into(),
+            ))
+            .unwrap();
+        let config = ResponderConfig {
+            max_response_delay: Some(Duration::ZERO),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let query = dns_parser::Builder::new_query(0, false).add_question(
+            &Name::from_str("_test-service-name._tcp.local").unwrap(),
+            QueryType::PTR,
+            QueryClass::IN,
+        );
+        handler.handle_packet(&query.build().unwrap(), "169.254.0.1:5353".parse().unwrap());
+        std::thread::sleep(Duration::from_millis(1));
+
+        assert!(handler.pop_outgoing().is_none());
+        #[cfg(feature = "metrics-minimal")]
+        assert_eq!(shared.counters.metrics().stale_responses_dropped, 1);
+    }
+
+    #[test]
+    fn test_bounded_response_queue() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new(
+                "_test-service-name._tcp",
+                "test-instance",
+                8008,
+                (&[]).into(),
+            ))
+            .unwrap();
+        let config = ResponderConfig {
+            max_queued_responses: Some(2),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let query = dns_parser::Builder::new_query(0, false).add_question(
+            &Name::from_str("_test-service-name._tcp.local").unwrap(),
+            QueryType::PTR,
+            QueryClass::IN,
+        );
+        let query = query.build().unwrap();
+        for port in 40000..40003 {
+            let source = SocketAddr::new("169.254.0.1".parse().unwrap(), port);
+            handler.handle_packet(&query, source);
+        }
+
+        #[cfg(feature = "metrics-minimal")]
+        assert_eq!(shared.counters.metrics().responses_dropped_queue_full, 1);
+        assert!(handler.pop_outgoing().is_some());
+        assert!(handler.pop_outgoing().is_some());
+        assert!(handler.pop_outgoing().is_none());
+    }
+
+    #[cfg(feature = "metrics-full")]
+    #[test]
+    fn test_packet_counters() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new(
+                "_test-service-name._tcp",
+                "test-instance",
+                8008,
+                (&[]).into(),
+            ))
+            .unwrap();
+        let shared = Shared::new(&services, ResponderConfig::default());
+        let mut handler = Handler::<Inet>::new(&shared);
+        let source = "169.254.0.1:5353".parse().unwrap();
+
+        for &name in &["_test-service-name._tcp.local", "_other._tcp.local"] {
+            let query = dns_parser::Builder::new_query(0, false).add_question(
+                &Name::from_str(name).unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            );
+            handler.handle_packet(&query.build().unwrap(), source);
+        }
+        handler.handle_packet(b"\x00\x01", source);
+
+        let metrics = shared.counters.metrics();
+        assert_eq!(metrics.packets_answered, 1);
+        assert_eq!(metrics.packets_ignored, 1);
+        assert_eq!(metrics.packets_parse_failed, 1);
+
+        let (answer, _) = handler.pop_outgoing().unwrap();
+        assert!(answer.len() <= 512);
+        let sizes = shared.counters.metrics().packet_sizes;
+        assert_eq!(sizes.buckets, [1, 0, 0, 0, 0, 0]);
+        assert_eq!(sizes.packets, 1);
+        assert_eq!(sizes.bytes, answer.len() as u64);
+        assert_eq!(sizes.largest, answer.len() as u64);
+
+        let types = shared.counters.metrics().question_types;
+        assert_eq!(types.ptr, 2);
+        assert_eq!(types.a + types.aaaa + types.srv + types.txt + types.all, 0);
+        assert_eq!(types.other, 0);
+    }
+
+    #[test]
+    fn test_repeated_questions() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new(
+                "_http._tcp",
+                "test-instance",
+                8008,
+                (&[]).into(),
+            ))
+            .unwrap();
+        let host = FixedHostData::new("test-hostname".into(), vec![]);
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(host)),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        // Captured from a client which repeats its PTR question
+        let packet = b"\x00\x00\x00\x00\x00\x03\x00\x00\x00\x00\x00\x00\
+            \x05_http\x04_tcp\x05local\x00\x00\x0c\x00\x01\
+            \xc0\x0c\x00\x0c\x00\x01\
+            \xc0\x0c\x00\x0c\x00\x01";
+        handler.handle_packet(packet, "169.254.0.1:5353".parse().unwrap());
+
+        let (response, _) = handler.pop_outgoing().unwrap();
+        let parsed = dns_parser::Packet::parse(&response).unwrap();
+        assert_eq!(parsed.answers.len(), 3);
+        assert!(handler.pop_outgoing().is_none());
+        #[cfg(feature = "metrics-minimal")]
+        assert_eq!(shared.counters.metrics().duplicate_questions, 2);
+    }
+
+    /// Answers to ANY, following what avahi sends: every record of the name, the
+    /// addresses the SRV record points at, and an NSEC for names only we own
+    #[test]
+    fn test_any_query() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new(
+                "_http._tcp",
+                "test-instance",
+                8008,
+                (&["path=/"]).into(),
+            ))
+            .unwrap();
+        let host = FixedHostData::new("test-hostname".into(), vec!["192.0.2.9".parse().unwrap()]);
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(host)),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let mut ask = |name: &'static str| {
+            let query = dns_parser::Builder::new_query(0, false).add_question(
+                &Name::from_str(name).unwrap(),
+                QueryType::All,
+                QueryClass::IN,
+            );
+            handler.handle_packet(&query.build().unwrap(), "169.254.0.1:5353".parse().unwrap());
+            let (response, _) = handler.pop_outgoing().unwrap();
+            let parsed = dns_parser::Packet::parse(&response).unwrap();
+            parsed
+                .answers
+                .iter()
+                .map(|answer| match answer.data {
+                    RRData::NSEC { ref types, .. } => {
+                        let mut types = types.clone();
+                        types.sort_unstable();
+                        format!("NSEC {:?}", types)
+                    }
+                    ref data => format!("{:?}", data.typ()),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            ask("test-instance._http._tcp.local"),
+            ["SRV", "A", "TXT", "NSEC [16, 33, 47]"]
+        );
+        assert_eq!(ask("_http._tcp.local"), ["PTR", "SRV", "TXT", "A"]);
+        assert_eq!(ask("test-hostname.local"), ["A", "NSEC [1, 47]"]);
+        assert_eq!(
+            ask("_services._dns-sd._udp.local"),
+            ["PTR"],
+            "the enumeration name is shared"
+        );
+    }
+
+    #[test]
+    fn test_enumerate_instance_records() {
+        let host = FixedHostData::new("test-hostname".into(), vec!["192.0.2.9".parse().unwrap()]);
+        let (services, shared) = handler_with(ResponderConfig {
+            host_data: Some(Arc::new(host)),
+            enumerate_instance_records: true,
+            ..ResponderConfig::default()
+        });
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new("_http._tcp", "web", 80, (&[]).into()))
+            .unwrap();
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new("_ipp._tcp", "printer", 631, (&[]).into()))
+            .unwrap();
+        let handler = Handler::<Inet>::new(&shared);
+
+        let name = &SERVICE_TYPE_ENUMERATION_NAME;
+        let mut answers = ask(&handler, name, QueryType::PTR);
+        answers.sort();
+        assert_eq!(
+            answers,
+            [
+                "PTR _services._dns-sd._udp.local _http._tcp.local",
+                "PTR _services._dns-sd._udp.local _ipp._tcp.local",
+            ]
+        );
+        let mut additional = ask_additional(&handler, name, QueryType::PTR);
+        // The host's addresses follow the instances, sent once for all of them
+        assert_eq!(additional.pop().unwrap(), "A test-hostname.local 192.0.2.9");
+        additional.sort();
+        assert_eq!(
+            additional,
+            [
+                "PTR _http._tcp.local web._http._tcp.local",
+                "PTR _ipp._tcp.local printer._ipp._tcp.local",
+                "SRV printer._ipp._tcp.local",
+                "SRV web._http._tcp.local",
+                "TXT printer._ipp._tcp.local",
+                "TXT web._http._tcp.local",
+            ]
+        );
+    }
+
+    /// What is sent for each type of question at the enumeration name
+    #[test]
+    fn test_enumeration_meta_queries() {
+        let table: &[(bool, bool, QueryType, &[&str])] = &[
+            // (services registered, enumerate instances, question, answers)
+            (true, false, QueryType::PTR, &["PTR _http._tcp.local"]),
+            (true, false, QueryType::All, &["PTR _http._tcp.local"]),
+            // The instances are additional records
+            (true, true, QueryType::All, &["PTR _http._tcp.local"]),
+            (true, false, QueryType::SRV, &["NSEC [12, 47]"]),
+            (true, false, QueryType::TXT, &["NSEC [12, 47]"]),
+            (true, false, QueryType::A, &["NSEC [12, 47]"]),
+            (true, false, QueryType::AAAA, &["NSEC [12, 47]"]),
+            (true, false, QueryType::HINFO, &["NSEC [12, 47]"]),
+            (false, false, QueryType::PTR, &[]),
+            (false, false, QueryType::All, &[]),
+            (false, false, QueryType::SRV, &[]),
+        ];
+
+        for &(registered, enumerate_instances, qtype, expected) in table {
+            let services = Arc::new(RwLock::new(ServicesInner::new(
+                "test-hostname.local".into(),
+            )));
+            if registered {
+                services
+                    .write()
+                    .unwrap()
+                    .register(ServiceData::new(
+                        "_http._tcp",
+                        "test-instance",
+                        8008,
+                        (&[]).into(),
+                    ))
+                    .unwrap();
+            }
+            let config = ResponderConfig {
+                enumerate_instances,
+                ..ResponderConfig::default()
+            };
+            let shared = Shared::new(&services, config);
+            let mut handler = Handler::<Inet>::new(&shared);
+
+            let query = dns_parser::Builder::new_query(0, false).add_question(
+                &Name::from_str(SERVICE_TYPE_ENUMERATION_NAME).unwrap(),
+                qtype,
+                QueryClass::IN,
+            );
+            handler.handle_packet(&query.build().unwrap(), "169.254.0.1:5353".parse().unwrap());
+            let answers: Vec<String> = match handler.pop_outgoing() {
+                Some((response, _)) => dns_parser::Packet::parse(&response)
+                    .unwrap()
+                    .answers
+                    .iter()
+                    .map(|answer| match answer.data {
+                        RRData::PTR(ref name) => format!("PTR {}", name),
+                        RRData::NSEC { ref types, .. } => {
+                            assert!(!answer.cache_flush, "the name is shared");
+                            let mut types = types.clone();
+                            types.sort_unstable();
+                            format!("NSEC {:?}", types)
+                        }
+                        ref data => format!("{:?}", data.typ()),
+                    })
+                    .collect(),
+                None => Vec::new(),
+            };
+            assert_eq!(
+                answers, expected,
+                "{:?} with services registered: {}, enumerating instances: {}",
+                qtype, registered, enumerate_instances
+            );
+        }
+    }
+
+    #[test]
+    fn test_edns_response_size() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        for i in 0..40 {
+            services
+                .write()
+                .unwrap()
+                .register(ServiceData::new(
+                    "_http._tcp",
+                    &format!("instance {}", i),
+                    8000,
+                    (&["path=/"]).into(),
+                ))
+                .unwrap();
+        }
+        let shared = Shared::new(&services, ResponderConfig::default());
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        // Sizes of the responses to a legacy query, and whether each has an OPT record.
+        // Each comes from another port, so as not to be a duplicate.
+        let mut port = 49152;
+        let mut ask = |payload_size: Option<u16>| {
+            port += 1;
+            let query = dns_parser::Builder::new_query(0, false).add_question(
+                &Name::from_str("_http._tcp.local").unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            );
+            let query = match payload_size {
+                Some(udp_payload_size) => query
+                    .add_opt(&dns_parser::Opt {
+                        udp_payload_size,
+                        extended_rcode: 0,
+                        version: 0,
+                        dnssec_ok: false,
+                        options: Vec::new(),
+                    })
+                    .build(),
+                None => query.build(),
+            };
+            handler.handle_packet(&query.unwrap(), SocketAddr::from(([169, 254, 0, 1], port)));
+            std::iter::from_fn(|| handler.pop_outgoing())
+                .map(|(response, _)| {
+                    let (parsed, _) = dns_parser::Packet::parse_lenient(&response).unwrap();
+                    if let Some(ref opt) = parsed.opt {
+                        assert_eq!(opt.udp_payload_size as usize, MAX_EDNS_PACKET_SIZE);
+                    }
+                    (response.len(), parsed.opt.is_some())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let responses = ask(None);
+        assert!(responses.len() > 1);
+        assert!(responses
+            .iter()
+            .all(|&(size, opt)| size <= DEFAULT_MAX_PACKET_SIZE && !opt));
+
+        // Larger sizes are capped
+        let responses = ask(Some(u16::MAX));
+        assert_eq!(responses.len(), 1);
+        let (size, opt) = responses[0];
+        assert!(size > DEFAULT_MAX_PACKET_SIZE && size <= MAX_EDNS_PACKET_SIZE && opt);
+
+        // Smaller sizes stand for the minimum
+        let responses = ask(Some(100));
+        assert!(responses.len() > 2);
+        assert!(responses
+            .iter()
+            .all(|&(size, opt)| size <= MIN_EDNS_PAYLOAD_SIZE && opt));
+    }
+
+    /// A flood of queries and responses from random sources, with random names and
+    /// addresses, stays within the memory budget
+    #[test]
+    fn test_memory_budget_flood() {
+        const BUDGET: usize = 32 * 1024;
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new(
+                "_http._tcp",
+                "test-instance",
+                8008,
+                (&[]).into(),
+            ))
+            .unwrap();
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(FixedHostData::new(
+                "test-hostname".into(),
+                vec!["192.0.2.5".parse().unwrap()],
+            ))),
+            on_address_conflict: Some(Arc::new(|_| ())),
+            on_hostname_conflict: Some(Arc::new(|_| ())),
+            cache_records: true,
+            accept_off_link_queries: true,
+            memory_budget: Some(BUDGET),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let mut rng = thread_rng();
+        for _ in 0..5000 {
+            let source = SocketAddr::from((rng.gen::<[u8; 4]>(), MDNS_PORT));
+            let name = Name::from_str(format!("{:x}.local", rng.gen::<u64>())).unwrap();
+            let packet = match rng.gen_range(0..4) {
+                0 => dns_parser::Builder::new_query(rng.gen(), false)
+                    .add_question(&name, QueryType::A, QueryClass::IN)
+                    .build(),
+                // Our address under another name
+                1 => dns_parser::Builder::new_response(0, false, true)
+                    .add_answer(
+                        &name,
+                        QueryClass::IN,
+                        120,
+                        &RRData::A([192, 0, 2, 5].into()),
+                    )
+                    .build(),
+                // Another address under our name
+                2 => dns_parser::Builder::new_response(0, false, true)
+                    .add_answer_with_cache_flush(
+                        &Name::from_str("test-hostname.local").unwrap(),
+                        QueryClass::IN,
+                        120,
+                        &RRData::A(rng.gen::<[u8; 4]>().into()),
+                        true,
+                    )
+                    .build(),
+                _ => dns_parser::Builder::new_response(0, false, true)
+                    .add_answer(
+                        &name,
+                        QueryClass::IN,
+                        120,
+                        &RRData::TXT(&[3, b'a', b'=', b'b']),
+                    )
+                    .build(),
+            };
+            handler.handle_packet(&packet.unwrap(), source);
+            while handler.pop_outgoing().is_some() {}
+            assert!(shared.budget.used() <= BUDGET);
+        }
+        #[cfg(feature = "metrics-minimal")]
+        assert!(shared.counters.metrics().cache_evictions > 0);
+        assert!(!shared.cache.records(Instant::now()).is_empty());
+
+        // Still answering
+        let query = dns_parser::Builder::new_query(0, false).add_question(
+            &Name::from_str("_http._tcp.local").unwrap(),
+            QueryType::PTR,
+            QueryClass::IN,
+        );
+        handler.handle_packet(&query.build().unwrap(), "192.0.2.9:5353".parse().unwrap());
+        assert!(handler.pop_outgoing().is_some());
+    }
+
+    #[cfg(feature = "responder")]
+    #[test]
+    fn test_announce_pacing() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let svc = ServiceData::new(
+            "_test-service-name._tcp",
+            "test-instance",
+            8008,
+            (&[]).into(),
+        );
+        services.write().unwrap().register(svc.clone()).unwrap();
+        let config = ResponderConfig {
+            announce_rate: Some(1),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let mut moved = svc.clone();
+        moved.port = 8009;
+        handler.send_srv_goodbye(&svc, &moved);
+        handler.send_srv_goodbye(&svc, &moved);
+        let query = dns_parser::Builder::new_query(0, false).add_question(
+            &Name::from_str("_test-service-name._tcp.local").unwrap(),
+            QueryType::PTR,
+            QueryClass::IN,
+        );
+        handler.handle_packet(&query.build().unwrap(), "169.254.0.1:5353".parse().unwrap());
+
+        // The answer jumps the queue, then the budget allows one goodbye
+        let (answer, _) = handler.pop_outgoing().unwrap();
+        assert_ne!(
+            dns_parser::Packet::parse(&answer).unwrap().answers[0].ttl,
+            0
+        );
+        let (goodbye, _) = handler.pop_outgoing().unwrap();
+        assert_eq!(
+            dns_parser::Packet::parse(&goodbye).unwrap().answers[0].ttl,
+            0
+        );
+        assert!(handler.pop_outgoing().is_none());
+        assert!(handler.next_unsolicited_at().unwrap() > Instant::now());
+    }
+
+    #[test]
+    fn test_goodbye_repeats() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let svc = ServiceData::new(
+            "_test-service-name._tcp",
+            "test-instance",
+            8008,
+            (&["path=/"]).into(),
+        );
+        let shared = Shared::new(&services, ResponderConfig::default());
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let start = Instant::now();
+        handler.send_unsolicited(&svc, true, false);
+        let (goodbye, _) = handler.pop_outgoing().unwrap();
+        let parsed = dns_parser::Packet::parse(&goodbye).unwrap();
+        let types: Vec<_> = parsed
+            .answers
+            .iter()
+            .map(|answer| format!("{:?}", answer.data.typ()))
+            .collect();
+        assert_eq!(types, ["PTR", "SRV", "TXT"]);
+        assert!(parsed.answers.iter().all(|answer| answer.ttl == 0));
+
+        // The repeats wait for their delay
+        assert!(handler.pop_outgoing().is_none());
+        let next = handler.next_unsolicited_at().unwrap();
+        assert!(next >= start + Duration::from_millis(GOODBYE_REPEAT_DELAY_MS.0));
+        assert_eq!(handler.scheduled.len(), GOODBYE_REPEATS as usize);
+
+        for (at, _) in &mut handler.scheduled {
+            *at = start;
+        }
+        for _ in 0..GOODBYE_REPEATS {
+            assert_eq!(handler.pop_outgoing().unwrap().0, goodbye);
+        }
+        assert!(handler.pop_outgoing().is_none());
+        assert!(handler.next_unsolicited_at().is_none());
+    }
+
+    #[cfg(feature = "responder")]
+    #[test]
+    fn test_announcement_repeats() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let svc = ServiceData::new(
+            "_test-service-name._tcp",
+            "test-instance",
+            8008,
+            (&["path=/"]).into(),
+        );
+        let config = ResponderConfig {
+            announcements: Some(4),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let start = Instant::now();
+        handler.send_unsolicited(&svc, false, false);
+        let (announcement, _) = handler.pop_outgoing().unwrap();
+        assert!(handler.pop_outgoing().is_none());
+
+        // A second or so apart, then twice as long each time
+        let mut times: Vec<Instant> = handler.scheduled.iter().map(|&(at, _)| at).collect();
+        times.sort();
+        assert_eq!(times.len(), 3);
+        let first = times[0] - start;
+        assert!(first >= Duration::from_millis(ANNOUNCE_INTERVAL_MS.0));
+        assert!(
+            first <= Duration::from_millis(ANNOUNCE_INTERVAL_MS.1) + Duration::from_millis(100)
+        );
+        assert_eq!(times[2] - times[1], (times[1] - times[0]) * 2);
+        assert_eq!(handler.next_unsolicited_at(), Some(times[0]));
+
+        for (at, _) in &mut handler.scheduled {
+            *at = start;
+        }
+        for _ in 0..3 {
+            assert_eq!(handler.pop_outgoing().unwrap().0, announcement);
+        }
+        assert!(handler.pop_outgoing().is_none());
+
+        // A change of port makes the repeats of the old records obsolete
+        handler.send_unsolicited(&svc, false, false);
+        assert_eq!(handler.scheduled.len(), 3);
+        let mut moved = svc.clone();
+        moved.port = 8009;
+        handler.send_srv_goodbye(&svc, &moved);
+        assert_eq!(handler.scheduled.len(), GOODBYE_REPEATS as usize);
+        assert!(handler.scheduled.iter().all(|(_, packet)| packet.goodbye));
+    }
+
+    #[test]
+    fn test_group_repeats() {
+        let (_services, shared) = handler_with(ResponderConfig::default());
+        let mut handler = Handler::<Inet>::new(&shared);
+        let first = ServiceData::new("_http._tcp", "first", 80, (&[]).into());
+        let second = ServiceData::new("_http._tcp", "second", 80, (&[]).into());
+        let group = [first.clone(), second.clone()];
+        handler.send_unsolicited_group(&group, false, false);
+        handler.pop_outgoing().unwrap();
+        assert_eq!(handler.scheduled.len(), 1);
+
+        // Announcing one of them again keeps the repeat for the other
+        handler.send_unsolicited(&first, false, false);
+        handler.pop_outgoing().unwrap();
+        let mut repeats: Vec<Vec<String>> = handler
+            .scheduled
+            .iter()
+            .map(|(_, packet)| {
+                let packet = dns_parser::Packet::parse(&packet.packet).unwrap();
+                packet.answers.iter().map(describe).collect()
+            })
+            .collect();
+        repeats.sort();
+        assert_eq!(
+            repeats,
+            [
+                [
+                    "PTR _http._tcp.local first._http._tcp.local",
+                    "SRV first._http._tcp.local",
+                    "TXT first._http._tcp.local"
+                ],
+                [
+                    "PTR _http._tcp.local second._http._tcp.local",
+                    "SRV second._http._tcp.local",
+                    "TXT second._http._tcp.local"
+                ]
+            ]
+        );
+        let second_key = NameKey::new(&second.name);
+        assert!(handler
+            .scheduled
+            .iter()
+            .any(|(_, packet)| packet.repeats == [second_key.clone()]));
+
+        // And once both have been, the repeat of the group is gone
+        handler.send_unsolicited(&second, false, false);
+        assert_eq!(handler.scheduled.len(), 2);
+    }
+
+    #[test]
+    fn test_reregister_cancels_goodbye_repeats() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let svc = ServiceData::new("_http._tcp", "instance", 80, (&[]).into());
+        let shared = Shared::new(&services, ResponderConfig::default());
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        // Register, unregister and register again
+        handler.send_unsolicited(&svc, false, false);
+        handler.send_unsolicited(&svc, true, false);
+        handler.send_unsolicited(&svc, false, false);
+        while handler.pop_outgoing().is_some() {}
+
+        // A late goodbye would flush the records just announced
+        assert_eq!(handler.scheduled.len(), DEFAULT_ANNOUNCEMENTS as usize - 1);
+        assert!(handler.scheduled.iter().all(|(_, packet)| !packet.goodbye));
+    }
+
+    #[cfg(feature = "responder")]
+    #[test]
+    fn test_shutdown_sends_goodbyes() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let config = ResponderConfig {
+            announce_rate: Some(1),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let first = ServiceData::new("_http._tcp", "first", 80, (&[]).into());
+        let second = ServiceData::new("_http._tcp", "second", 80, (&[]).into());
+        handler.send_unsolicited(&first, true, false);
+        handler.send_unsolicited(&second, false, false);
+        handler.handle_command(Command::Shutdown);
+        assert!(!handler.is_drained());
+
+        // The goodbye goes at once, despite pacing, its repeats collapse into it and
+        // the announcement is dropped
+        let (goodbye, _) = handler.pop_outgoing().unwrap();
+        let parsed = dns_parser::Packet::parse(&goodbye).unwrap();
+        assert!(parsed.answers.iter().all(|answer| answer.ttl == 0));
+        assert!(handler.pop_outgoing().is_none());
+        assert!(handler.is_drained());
+    }
+
+    #[cfg(feature = "responder")]
+    #[test]
+    fn test_resumed() {
+        let (services, shared) = handler_with(ResponderConfig::default());
+        let mut handler = Handler::<Inet>::new(&shared);
+        handler.handle_command(Command::Resumed);
+        assert!(handler.pop_outgoing().is_none());
+
+        for instance in ["first", "second"] {
+            let svc = ServiceData::new("_http._tcp", instance, 80, (&[]).into());
+            services.write().unwrap().register(svc).unwrap();
+        }
+        handler.handle_command(Command::Resumed);
+        let (announcement, _) = handler.pop_outgoing().unwrap();
+        let packet = dns_parser::Packet::parse(&announcement).unwrap();
+        let srv = packet
+            .answers
+            .iter()
+            .filter(|answer| matches!(answer.data, RRData::SRV { .. }))
+            .count();
+        assert_eq!(srv, 2);
+        assert!(packet
+            .answers
+            .iter()
+            .any(|answer| matches!(answer.data, RRData::A(_))));
+        assert!(handler.pop_outgoing().is_none());
+        assert_eq!(handler.scheduled.len(), DEFAULT_ANNOUNCEMENTS as usize - 1);
+    }
+
+    #[test]
+    fn test_group_announcement() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let svc = |instance| ServiceData::new("_http._tcp", instance, 80, (&[]).into());
+        let withdrawn = ServiceData {
+            withdrawn: true,
+            ..svc("withdrawn")
+        };
+        let group = [svc("first"), withdrawn, svc("second")];
+        let shared = Shared::new(&services, ResponderConfig::default());
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        for goodbye in [false, true] {
+            handler.send_unsolicited_group(&group, goodbye, false);
+            let (packet, _) = handler.pop_outgoing().unwrap();
+            let parsed = dns_parser::Packet::parse(&packet).unwrap();
+            let srvs: Vec<_> = parsed
+                .answers
+                .iter()
+                .filter(|answer| answer.data.typ() == Type::SRV)
+                .map(|answer| answer.name.to_string())
+                .collect();
+            assert_eq!(
+                srvs,
+                ["first._http._tcp.local", "second._http._tcp.local"],
+                "one packet for the whole group, without the withdrawn service"
+            );
+            assert!(parsed
+                .answers
+                .iter()
+                .all(|answer| (answer.ttl == 0) == goodbye));
+            assert!(handler.pop_outgoing().is_none());
+        }
+    }
+
+    #[cfg(feature = "responder")]
+    #[test]
+    fn test_announced() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let svc = ServiceData::new("_http._tcp", "instance", 80, (&[]).into());
+        let shared = Shared::new(&services, ResponderConfig::default());
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        // Told once every packet has been sent
+        let (announced, mut sent) = Announced::new();
+        handler.announce_group(
+            std::slice::from_ref(&svc),
+            false,
+            true,
+            Some(announced),
+            None,
+        );
+        assert!(sent.try_recv().is_err());
+        assert!(handler.pop_outgoing().is_some());
+        assert_eq!(sent.try_recv(), Ok(true));
+
+        // And told if it never will be
+        let (announced, mut sent) = Announced::new();
+        handler.announce_group(
+            std::slice::from_ref(&svc),
+            false,
+            true,
+            Some(announced),
+            None,
+        );
+        drop(handler);
+        assert_eq!(sent.try_recv(), Ok(false));
+
+        // Or if the command carrying it never reaches a handler
+        let (announced, mut sent) = Announced::new();
+        drop(announced.clone());
+        drop(announced);
+        assert_eq!(sent.try_recv(), Ok(false));
+    }
+
+    #[test]
+    fn test_multicast_per_family() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let svc = ServiceData::new("_http._tcp", "instance", 80, (&[]).into());
+        services.write().unwrap().register(svc).unwrap();
+        let config = ResponderConfig {
+            accept_off_link_queries: true,
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut v4 = Handler::<Inet>::new(&shared);
+        let mut v6 = Handler::<Inet6>::new(&shared);
+        let query = |id| {
+            dns_parser::Builder::new_query(id, false)
+                .add_question(
+                    &Name::from_str("_http._tcp.local").unwrap(),
+                    QueryType::PTR,
+                    QueryClass::IN,
+                )
+                .build()
+                .unwrap()
+        };
+
+        // Hosts listening on one family only don't see the answers on the other
+        v4.handle_packet(&query(1), "192.0.2.1:5353".parse().unwrap());
+        assert!(v4.pop_outgoing().is_some());
+        v6.handle_packet(&query(2), "[2001:db8::1]:5353".parse().unwrap());
+        assert!(v6.pop_outgoing().is_some());
+
+        // Another querier asking soon after gets no second multicast answer
+        v6.handle_packet(&query(3), "[2001:db8::2]:5353".parse().unwrap());
+        assert!(v6.pop_outgoing().is_none());
+
+        // But direct answers aren't held back
+        v6.handle_packet(&query(3), "[2001:db8::1]:54321".parse().unwrap());
+        assert!(v6.pop_outgoing().is_some());
+    }
+
+    #[test]
+    fn test_on_query() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let on_query = events.clone();
+        let (services, shared) = handler_with(ResponderConfig {
+            accept_off_link_queries: true,
+            on_query: Some(Arc::new(move |event: QueryEvent| {
+                on_query.lock().unwrap().push(event)
+            })),
+            ..ResponderConfig::default()
+        });
+        let svc = ServiceData::new("_http._tcp", "web", 80, (&[]).into());
+        services.write().unwrap().register(svc).unwrap();
+        let mut handler = Handler::<Inet>::new(&shared);
+        let name = |name| Name::from_str(name).unwrap();
+        let source: SocketAddr = "192.0.2.1:5353".parse().unwrap();
+
+        let query = dns_parser::Builder::new_query(1, false)
+            .add_question(&name("_http._tcp.local"), QueryType::PTR, QueryClass::IN)
+            .add_unicast_question(
+                &name("web._http._tcp.local"),
+                QueryType::SRV,
+                QueryClass::IN,
+            )
+            .add_question(&name("_ipp._tcp.local"), QueryType::PTR, QueryClass::IN)
+            .build()
+            .unwrap();
+        handler.handle_packet(&query, source);
+        // Asked again straight away, the multicast answer is held back
+        let again = dns_parser::Builder::new_query(2, false)
+            .add_question(&name("_http._tcp.local"), QueryType::PTR, QueryClass::IN)
+            .build()
+            .unwrap();
+        handler.handle_packet(&again, source);
+
+        let events: Vec<_> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|event| {
+                assert_eq!(event.source, source);
+                (
+                    event.name.clone(),
+                    event.qtype,
+                    event.unicast,
+                    event.answered,
+                )
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("_http._tcp.local".into(), 12, false, true),
+                ("web._http._tcp.local".into(), 33, true, true),
+                ("_ipp._tcp.local".into(), 12, false, false),
+                ("_http._tcp.local".into(), 12, false, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_packet_panic() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new("_http._tcp", "instance", 80, (&[]).into()))
+            .unwrap();
+        let panics = Arc::new(Mutex::new(Vec::new()));
+        let on_packet_panic = {
+            let panics = panics.clone();
+            move |event: PacketPanic| panics.lock().unwrap().push(event)
+        };
+        let config = ResponderConfig {
+            on_query: Some(Arc::new(|event: QueryEvent| {
+                assert!(event.name != "_crash._tcp.local", "bad name");
+            })),
+            on_packet_panic: Some(Arc::new(on_packet_panic)),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+        let query = |name| {
+            dns_parser::Builder::new_query(0, false)
+                .add_question(
+                    &Name::from_str(name).unwrap(),
+                    QueryType::PTR,
+                    QueryClass::IN,
+                )
+                .build()
+                .unwrap()
+        };
+        let source = "169.254.0.1:5353".parse().unwrap();
+
+        handler.handle_packet(&query("_crash._tcp.local"), source);
+        assert!(handler.pop_outgoing().is_none());
+        let panics = std::mem::take(&mut *panics.lock().unwrap());
+        assert_eq!(panics.len(), 1);
+        assert_eq!(panics[0].source, source);
+        assert_eq!(panics[0].message, "bad name");
+        #[cfg(feature = "metrics-minimal")]
+        assert_eq!(shared.counters.metrics().packets_panicked, 1);
+
+        // The next packet is handled as usual
+        handler.handle_packet(&query("_http._tcp.local"), source);
+        assert!(handler.pop_outgoing().is_some());
+    }
+
+    #[cfg(feature = "responder")]
+    #[test]
+    fn test_command_panic() {
+        let decorator = |name: &str, _port: u16, txt: &[u8]| {
+            assert!(!name.starts_with("crash"), "bad service");
+            txt.to_vec()
+        };
+        let (services, shared) = handler_with(ResponderConfig {
+            txt_decorator: Some(Arc::new(decorator)),
+            ..ResponderConfig::default()
+        });
+        let mut handler = Handler::<Inet>::new(&shared);
+        let announce = |handler: &mut Handler<Inet>, name| {
+            handler.handle_command(Command::SendUnsolicited {
+                svc: ServiceData::new("_http._tcp", name, 80, (&[]).into()),
+                goodbye: false,
+                include_ip: false,
+                announced: None,
+            });
+        };
+
+        // Caught rather than ending the task
+        announce(&mut handler, "crash");
+        assert!(handler.pop_outgoing().is_none());
+        announce(&mut handler, "web");
+        assert!(handler.pop_outgoing().is_some());
+
+        // A panic while holding a shared lock leaves it poisoned, which mustn't stop
+        // every later packet
+        let seen = shared.seen.clone();
+        std::thread::spawn(move || {
+            let _seen = seen.lock().unwrap();
+            panic!("poisoning");
+        })
+        .join()
+        .unwrap_err();
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new("_http._tcp", "web", 80, (&[]).into()))
+            .unwrap();
+        let query = dns_parser::Builder::new_query(0, false)
+            .add_question(
+                &Name::from_str("_http._tcp.local").unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        handler.handle_packet(&query, "169.254.0.1:5353".parse().unwrap());
+        assert!(handler.pop_outgoing().is_some());
+    }
+
+    #[test]
+    fn test_mixed_case_queries() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new(
+                "_http._tcp",
+                "My Instance",
+                80,
+                (&["path=/"]).into(),
+            ))
+            .unwrap();
+        let shared = Shared::new(&services, ResponderConfig::default());
+        let mut handler = Handler::<Inet>::new(&shared);
+        let mut ask = |name, qtype| {
+            let query = dns_parser::Builder::new_query(0, false)
+                .add_question(&Name::from_str(name).unwrap(), qtype, QueryClass::IN)
+                .build()
+                .unwrap();
+            handler.handle_packet(&query, "169.254.0.1:54321".parse().unwrap());
+            let (response, _) = handler.pop_outgoing().expect("no answer");
+            let parsed = dns_parser::Packet::parse(&response).unwrap();
+            parsed
+                .answers
+                .iter()
+                .map(|answer| answer.data.typ())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(ask("MY INSTANCE._HTTP._TCP.local", QueryType::SRV).contains(&Type::SRV));
+        assert!(ask("my instance._Http._Tcp.LOCAL", QueryType::TXT).contains(&Type::TXT));
+        assert!(ask("_HTTP._TCP.local", QueryType::PTR).contains(&Type::PTR));
+    }
+
+    /// Every kind of name we answer for, asked for the way resolvers which don't
+    /// lowercase names do, e.g. Windows keeping the case of the hostname
+    #[test]
+    fn test_mixed_case_interop() {
+        let host = FixedHostData::new(
+            "MyHost".into(),
+            vec!["192.0.2.9".parse().unwrap(), "fe80::1".parse().unwrap()],
+        );
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(host)),
+            host_info: Some(("ARM64".into(), "Linux".into())),
+            enumerate_instances: false,
+            ..ResponderConfig::default()
+        };
+        // A hostname in mixed case, unlike the one of `handler_with`
+        let services = Arc::new(RwLock::new(ServicesInner::new("MyHost.local".into())));
+        let shared = Shared::new(&services, config);
+        let svc = ServiceBuilder::new("_http._tcp", "Web", 80)
+            .additional_record("Alias.local", 120, RecordData::A([192, 0, 2, 7].into()))
+            .build()
+            .unwrap();
+        services.write().unwrap().register(svc).unwrap();
+        services.write().unwrap().add_alias("Printer.local".into());
+        let mut handler = Handler::<Inet>::new(&shared);
+        handler.check_aliases();
+        let additional = |name, qtype| ask_additional(&handler, name, qtype);
+        let ask = |name, qtype| ask(&handler, name, qtype);
+
+        // Our own names keep the case they were registered with, and the other family
+        // goes along as an additional record
+        let a = ["A MyHost.local 192.0.2.9"];
+        let aaaa = ["AAAA MyHost.local fe80::1"];
+        assert_eq!(ask("MYHOST.LOCAL", QueryType::A), a);
+        assert_eq!(additional("MYHOST.LOCAL", QueryType::A), aaaa);
+        assert_eq!(ask("myhost.local", QueryType::AAAA), aaaa);
+        assert_eq!(additional("myhost.local", QueryType::AAAA), a);
+        assert_eq!(
+            ask("mYhOsT.lOcAl", QueryType::All),
+            [
+                "A MyHost.local 192.0.2.9",
+                "AAAA MyHost.local fe80::1",
+                "HINFO MyHost.local ARM64 Linux",
+                "NSEC mYhOsT.lOcAl"
+            ]
+        );
+        assert_eq!(
+            ask("MyHost.Local", QueryType::HINFO),
+            ["HINFO MyHost.local ARM64 Linux"]
+        );
+        assert_eq!(
+            ask("PRINTER.local", QueryType::A),
+            ["A Printer.local 192.0.2.9"]
+        );
+        assert_eq!(
+            additional("PRINTER.local", QueryType::A),
+            ["AAAA Printer.local fe80::1"]
+        );
+        assert_eq!(
+            ask("alias.LOCAL", QueryType::A),
+            ["A Alias.local 192.0.2.7"]
+        );
+        assert_eq!(
+            ask("_SERVICES._DNS-SD._UDP.LOCAL", QueryType::PTR),
+            ["PTR _SERVICES._DNS-SD._UDP.LOCAL _http._tcp.local"]
+        );
+        assert_eq!(
+            ask("web._HTTP._TCP.local", QueryType::SRV),
+            [
+                "SRV Web._http._tcp.local",
+                "A MyHost.local 192.0.2.9",
+                "AAAA MyHost.local fe80::1"
+            ]
+        );
+        assert_eq!(
+            ask("9.2.0.192.IN-ADDR.ARPA", QueryType::PTR),
+            [
+                "PTR 9.2.0.192.IN-ADDR.ARPA MyHost.local",
+                "PTR 9.2.0.192.IN-ADDR.ARPA Printer.local"
+            ]
+        );
+        let ip6 = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.E.F.IP6.ARPA";
+        assert_eq!(
+            ask(ip6, QueryType::PTR),
+            [
+                format!("PTR {} MyHost.local", ip6),
+                format!("PTR {} Printer.local", ip6)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_address_order() {
+        let host = FixedHostData::new(
+            "test-hostname".into(),
+            vec![
+                "fe80::1".parse().unwrap(),
+                "192.0.2.9".parse().unwrap(),
+                "fe80::2".parse().unwrap(),
+                "192.0.2.10".parse().unwrap(),
+            ],
+        );
+        let (services, shared) = handler_with(ResponderConfig {
+            host_data: Some(Arc::new(host)),
+            ..ResponderConfig::default()
+        });
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new("_http._tcp", "Web", 80, (&[]).into()))
+            .unwrap();
+
+        fn addresses<AF: AddressFamily>(handler: &Handler<AF>) -> Vec<String> {
+            ask(handler, "Web._http._tcp.local", QueryType::SRV)
+                .into_iter()
+                .filter_map(|record| {
+                    let ip = record.strip_prefix("A test-hostname.local ");
+                    ip.or_else(|| record.strip_prefix("AAAA test-hostname.local "))
+                        .map(str::to_owned)
+                })
+                .collect()
+        }
+
+        assert_eq!(
+            addresses(&Handler::<Inet>::new(&shared)),
+            ["192.0.2.9", "192.0.2.10", "fe80::1", "fe80::2"]
+        );
+        assert_eq!(
+            addresses(&Handler::<Inet6>::new(&shared)),
+            ["fe80::1", "fe80::2", "192.0.2.9", "192.0.2.10"]
+        );
+    }
+
+    #[test]
+    fn test_host_info() {
+        let hinfo = |host_info: Option<(String, String)>, qtype| {
+            let (_, shared) = handler_with(ResponderConfig {
+                host_info,
+                ..ResponderConfig::default()
+            });
+            let handler = Handler::<Inet>::new(&shared);
+            ask(&handler, "test-hostname.local", qtype)
+                .into_iter()
+                .filter(|record| record.starts_with("HINFO"))
+                .collect::<Vec<_>>()
+        };
+
+        assert!(hinfo(None, QueryType::HINFO).is_empty());
+        let host_info = Some(("ARM64".to_owned(), "Linux".to_owned()));
+        let expected = ["HINFO test-hostname.local ARM64 Linux"];
+        assert_eq!(hinfo(host_info.clone(), QueryType::HINFO), expected);
+        assert_eq!(hinfo(host_info.clone(), QueryType::All), expected);
+        assert!(hinfo(host_info, QueryType::A).is_empty());
+    }
+
+    #[derive(Default)]
+    struct AliasHostData {
+        aliases: Mutex<Vec<String>>,
+        address_names: Mutex<Vec<(String, Vec<IpAddr>)>>,
+        watcher: Mutex<Option<HostWatcher>>,
+    }
+
+    impl HostData for AliasHostData {
+        fn hostname(&self) -> io::Result<String> {
+            Ok("test-hostname".into())
+        }
+
+        fn addresses(&self) -> io::Result<Vec<IpAddr>> {
+            Ok(vec!["192.0.2.5".parse().unwrap()])
+        }
+
+        fn aliases(&self) -> io::Result<Vec<String>> {
+            Ok(self.aliases.lock().unwrap().clone())
+        }
+
+        fn address_names(&self) -> io::Result<Vec<(String, Vec<IpAddr>)>> {
+            Ok(self.address_names.lock().unwrap().clone())
+        }
+
+        fn watch(&self, watcher: HostWatcher) {
+            *self.watcher.lock().unwrap() = Some(watcher);
+        }
+    }
+
+    /// Drop the probes scheduled for `name`, returning the records each claimed, and
+    /// make the announcement after them due
+    fn skip_probes(handler: &mut Handler<Inet>, name: &str) -> Vec<String> {
+        handler.scheduled.sort_by_key(|&(at, _)| at);
+        let mut claimed = Vec::new();
+        while let Some((_, probe)) = handler.scheduled.first() {
+            let probe = dns_parser::Packet::parse(&probe.packet).unwrap();
+            if !probe.header.query {
+                break;
+            }
+            assert_eq!(probe.questions[0].qname.to_string(), name);
+            assert_eq!(probe.questions[0].qtype, QueryType::All);
+            let records: Vec<String> = probe.nameservers.iter().map(describe).collect();
+            claimed.push(records.join(", "));
+            handler.scheduled.remove(0);
+        }
+        handler.scheduled[0].0 = clock::now();
+        claimed
+    }
+
+    #[test]
+    fn test_host_aliases() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let host = Arc::new(AliasHostData::default());
+        host.aliases.lock().unwrap().push("printer".into());
+        let config = ResponderConfig {
+            host_data: Some(host.clone()),
+            address_refresh_interval: Some(Duration::from_secs(60)),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        shared.watch_host(None);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        // Probed for before being announced
+        assert!(handler.pop_outgoing().is_none());
+        assert_eq!(
+            skip_probes(&mut handler, "printer.local"),
+            ["A printer.local 192.0.2.5"; PROBES as usize]
+        );
+        let (announcement, _) = handler.pop_outgoing().unwrap();
+        let parsed = dns_parser::Packet::parse(&announcement).unwrap();
+        assert_eq!(parsed.answers[0].name.to_string(), "printer.local");
+        assert_eq!(parsed.answers[0].ttl, DEFAULT_TTL);
+
+        let query = dns_parser::Builder::new_query(0, false).add_question(
+            &Name::from_str("Printer.local").unwrap(),
+            QueryType::A,
+            QueryClass::IN,
+        );
+        handler.handle_packet(&query.build().unwrap(), "169.254.0.1:5353".parse().unwrap());
+        let (answer, _) = handler.pop_outgoing().unwrap();
+        let parsed = dns_parser::Packet::parse(&answer).unwrap();
+        assert_eq!(parsed.answers.len(), 1);
+        assert!(handler.pop_outgoing().is_none());
+
+        // Only noticed before the refresh interval because of the watcher
+        host.aliases.lock().unwrap().clear();
+        host.watcher.lock().unwrap().as_ref().unwrap().changed();
+        let (goodbye, _) = handler.pop_outgoing().unwrap();
+        let parsed = dns_parser::Packet::parse(&goodbye).unwrap();
+        assert_eq!(parsed.answers[0].name.to_string(), "printer.local");
+        assert_eq!(parsed.answers[0].ttl, 0);
+    }
+
+    #[test]
+    fn test_reverse_address_names() {
+        let host = Arc::new(AliasHostData::default());
+        host.aliases.lock().unwrap().push("printer".into());
+        host.address_names.lock().unwrap().push((
+            "test-hostname-wlan".into(),
+            vec!["192.0.2.6".parse().unwrap()],
+        ));
+        let (_, shared) = handler_with(ResponderConfig {
+            host_data: Some(host),
+            ..ResponderConfig::default()
+        });
+        let mut handler = Handler::<Inet>::new(&shared);
+        handler.check_aliases();
+        let ask = |name, qtype| ask(&handler, name, qtype);
+
+        assert_eq!(
+            ask("5.2.0.192.in-addr.arpa", QueryType::PTR),
+            [
+                "PTR 5.2.0.192.in-addr.arpa test-hostname.local",
+                "PTR 5.2.0.192.in-addr.arpa printer.local"
+            ]
+        );
+        assert_eq!(
+            ask("6.2.0.192.in-addr.arpa", QueryType::PTR),
+            ["PTR 6.2.0.192.in-addr.arpa test-hostname-wlan.local"]
+        );
+        assert!(ask("7.2.0.192.in-addr.arpa", QueryType::PTR).is_empty());
+        assert_eq!(
+            ask("test-hostname-wlan.local", QueryType::A),
+            ["A test-hostname-wlan.local 192.0.2.6"]
+        );
+        assert_eq!(
+            ask("printer.local", QueryType::A),
+            ["A printer.local 192.0.2.5"]
+        );
+    }
+
+    #[test]
+    fn test_allowed_prefix() {
+        let ips = ["192.0.2.9", "198.51.100.4", "192.0.2.200"];
+        let host = FixedHostData::new(
+            "test-hostname".into(),
+            ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+        );
+        let (_, shared) = handler_with(ResponderConfig {
+            host_data: Some(Arc::new(host)),
+            allowed_ips: vec!["192.0.2.0/24".parse().unwrap()],
+            ..ResponderConfig::default()
+        });
+        let handler = Handler::<Inet>::new(&shared);
+        let ask = |name, qtype| ask(&handler, name, qtype);
+
+        assert_eq!(
+            ask("test-hostname.local", QueryType::A),
+            [
+                "A test-hostname.local 192.0.2.9",
+                "A test-hostname.local 192.0.2.200"
+            ]
+        );
+        assert_eq!(
+            ask("200.2.0.192.in-addr.arpa", QueryType::PTR),
+            ["PTR 200.2.0.192.in-addr.arpa test-hostname.local"]
+        );
+        assert!(ask("4.100.51.198.in-addr.arpa", QueryType::PTR).is_empty());
+    }
+
+    #[test]
+    fn test_network_conflicts() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let register = |name: &str, strategy| {
+            let svc = ServiceBuilder::new("_http._tcp", name, 80)
+                .conflict_strategy(strategy)
+                .build()
+                .unwrap();
+            services.write().unwrap().register(svc).unwrap()
+        };
+        let renamed = register("renamed", ConflictStrategy::Rename);
+        let failed = register("failed", ConflictStrategy::Fail);
+        let ignored = register("ignored", ConflictStrategy::Ignore);
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let on_event = events.clone();
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(FixedHostData::new(
+                "test-hostname".into(),
+                vec!["192.0.2.5".parse().unwrap()],
+            ))),
+            on_service_event: Some(Arc::new(move |event| {
+                on_event.lock().unwrap().push(format!("{:?}", event))
+            })),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let srv = |name: &str, port, cache_flush| {
+            let mut builder =
+                dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+            builder = builder.add_answer_with_cache_flush(
+                &Name::from_str(format!("{}._http._tcp.local", name)).unwrap(),
+                QueryClass::IN,
+                DEFAULT_TTL,
+                &RRData::SRV {
+                    priority: 0,
+                    weight: 0,
+                    port,
+                    target: Name::from_str("test-hostname.local").unwrap(),
+                },
+                cache_flush,
+            );
+            builder.build().unwrap()
+        };
+
+        // The same data is no conflict, and neither are our own packets or shared
+        // records
+        let other: SocketAddr = "192.0.2.9:5353".parse().unwrap();
+        handler.handle_packet(&srv("renamed", 80, true), other);
+        handler.handle_packet(&srv("renamed", 81, true), "192.0.2.5:5353".parse().unwrap());
+        handler.handle_packet(&srv("renamed", 81, false), other);
+        assert!(events.lock().unwrap().is_empty());
+
+        handler.handle_packet(&srv("renamed", 81, true), other);
+        handler.handle_packet(&srv("failed", 81, true), other);
+        handler.handle_packet(&srv("ignored", 81, true), other);
+        assert_eq!(events.lock().unwrap().len(), 4);
+
+        let services = services.read().unwrap();
+        assert_eq!(services.get(renamed).unwrap().instance(), "renamed (2)");
+        assert!(services.get(failed).unwrap().withdrawn);
+        assert!(!services.get(ignored).unwrap().withdrawn);
+        drop(services);
+
+        // The new name is probed for before being announced
+        handler.check_renamed();
+        assert!(handler.unsolicited.is_empty());
+        handler.scheduled.sort_by_key(|&(at, _)| at);
+        let scheduled = handler.scheduled.clone();
+        let packets: Vec<dns_parser::Packet> = scheduled
+            .iter()
+            .map(|(_, packet)| dns_parser::Packet::parse(&packet.packet).unwrap())
+            .collect();
         assert_eq!(
-            parsed.answers[0].name,
-            Name::from_str(SERVICE_TYPE_ENUMERATION_NAME).unwrap()
+            packets.len(),
+            PROBES as usize + DEFAULT_ANNOUNCEMENTS as usize
         );
-        assert_eq!(parsed.answers[0].cls, dns_parser::Class::IN);
-        assert_eq!(parsed.answers[0].ttl, 60);
-        let ptr = match &parsed.answers[0].data {
-            RRData::PTR(ptr) => ptr,
-            other => panic!("Unexpected answer RR data type: {:?}", other),
+        for (i, probe) in packets[..PROBES as usize].iter().enumerate() {
+            assert!(probe.header.query);
+            assert_eq!(
+                probe.questions[0].qname.to_string(),
+                "renamed (2)._http._tcp.local"
+            );
+            assert_eq!(probe.questions[0].qtype, QueryType::All);
+            assert_eq!(probe.questions[0].qu, i == 0);
+            let authority: Vec<String> = probe.nameservers.iter().map(describe).collect();
+            assert_eq!(
+                authority,
+                [
+                    "SRV renamed (2)._http._tcp.local",
+                    "TXT renamed (2)._http._tcp.local"
+                ]
+            );
+        }
+        let announcement = &packets[PROBES as usize];
+        assert!(!announcement.header.query);
+        assert_eq!(
+            announcement.answers[1].name.to_string(),
+            "renamed (2)._http._tcp.local"
+        );
+        let probed_at = handler.scheduled[PROBES as usize - 1].0;
+        assert!(handler.scheduled[PROBES as usize].0 >= probed_at);
+
+        // Another conflict meanwhile drops them, for probes of the next name
+        handler.handle_packet(&srv("renamed (2)", 81, true), other);
+        handler.check_renamed();
+        assert_eq!(handler.scheduled.len(), packets.len());
+        assert!(handler.scheduled.iter().all(|(_, packet)| {
+            let packet = dns_parser::Packet::parse_lenient(&packet.packet).unwrap().0;
+            let name = match packet.questions.first() {
+                Some(probe) => &probe.qname,
+                None => &packet.answers[1].name,
+            };
+            name.to_string() == "renamed (2) (2)._http._tcp.local"
+        }));
+    }
+
+    #[test]
+    fn test_record_validator() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let svc = ServiceData::new("_http._tcp", "web", 80, (&[]).into());
+        let id = services.write().unwrap().register(svc).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let on_event = events.clone();
+        let config = ResponderConfig {
+            cache_records: true,
+            record_validator: Some(Arc::new(|_: &str, _: Option<u16>, txt: &[u8]| {
+                txt == b"\x07sig=yes"
+            })),
+            on_service_event: Some(Arc::new(move |event| {
+                on_event.lock().unwrap().push(format!("{:?}", event))
+            })),
+            ..ResponderConfig::default()
         };
-        assert_eq!(*ptr, Name::from_str("_test-service-name._tcp").unwrap());
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let response = |instance: &str, txt: &[u8]| {
+            let name = Name::from_str(format!("{}._http._tcp.local", instance)).unwrap();
+            dns_parser::Builder::new_response(0, false, true)
+                .move_to::<dns_parser::Answers>()
+                .add_answer(
+                    &Name::from_str("_http._tcp.local").unwrap(),
+                    QueryClass::IN,
+                    DEFAULT_TTL,
+                    &RRData::PTR(name.clone()),
+                )
+                .add_answer_with_cache_flush(
+                    &name,
+                    QueryClass::IN,
+                    DEFAULT_TTL,
+                    &RRData::SRV {
+                        priority: 0,
+                        weight: 0,
+                        port: 81,
+                        target: Name::from_str("other-host.local").unwrap(),
+                    },
+                    true,
+                )
+                .add_answer_with_cache_flush(
+                    &name,
+                    QueryClass::IN,
+                    DEFAULT_TTL,
+                    &RRData::TXT(txt),
+                    true,
+                )
+                .add_answer_with_cache_flush(
+                    &Name::from_str("other-host.local").unwrap(),
+                    QueryClass::IN,
+                    DEFAULT_TTL,
+                    &RRData::A("192.0.2.9".parse().unwrap()),
+                    true,
+                )
+                .build()
+                .unwrap()
+        };
+        let cached = |handler: &Handler<Inet>| {
+            let mut names: Vec<String> = handler
+                .cache
+                .records(clock::now())
+                .into_iter()
+                .map(|record| format!("{} {}", record.rtype, record.name))
+                .collect();
+            names.sort();
+            names
+        };
+        let other: SocketAddr = "192.0.2.9:5353".parse().unwrap();
+
+        // Unsigned records claiming our name neither rename the service nor are
+        // cached, the others in the packet are
+        handler.handle_packet(&response("web", b"\x06sig=no"), other);
+        assert!(events.lock().unwrap().is_empty());
+        assert_eq!(services.read().unwrap().get(id).unwrap().instance(), "web");
+        assert_eq!(cached(&handler), ["1 other-host.local"]);
+
+        // Signed ones are taken in
+        handler.handle_packet(&response("printer", b"\x07sig=yes"), other);
+        assert_eq!(
+            cached(&handler),
+            [
+                "1 other-host.local",
+                "12 _http._tcp.local",
+                "16 printer._http._tcp.local",
+                "33 printer._http._tcp.local"
+            ]
+        );
+        handler.handle_packet(&response("web", b"\x07sig=yes"), other);
+        assert_eq!(events.lock().unwrap().len(), 2);
+    }
+
+    /// A `RenameStrategy` which panics poisons the services lock, which mustn't keep
+    /// the responder from answering
+    #[test]
+    fn test_poisoned_services() {
+        let panics = Arc::new(AtomicUsize::new(0));
+        let on_panic = panics.clone();
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(FixedHostData::new(
+                "test-hostname".into(),
+                vec!["192.0.2.5".parse().unwrap()],
+            ))),
+            rename_strategy: Some(Arc::new(|_: &str, _: u32| -> String {
+                panic!("no names left")
+            })),
+            on_packet_panic: Some(Arc::new(move |_| {
+                on_panic.fetch_add(1, Ordering::SeqCst);
+            })),
+            ..ResponderConfig::default()
+        };
+        let (services, shared) = handler_with(config);
+        let svc = ServiceBuilder::new("_http._tcp", "web", 80)
+            .conflict_strategy(ConflictStrategy::Rename)
+            .build()
+            .unwrap();
+        services.write().unwrap().register(svc).unwrap();
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let mut claim =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        claim = claim.add_answer_with_cache_flush(
+            &Name::from_str("web._http._tcp.local").unwrap(),
+            QueryClass::IN,
+            DEFAULT_TTL,
+            &RRData::SRV {
+                priority: 0,
+                weight: 0,
+                port: 81,
+                target: Name::from_str("other-host.local").unwrap(),
+            },
+            true,
+        );
+        let other: SocketAddr = "192.0.2.9:5353".parse().unwrap();
+        handler.handle_packet(&claim.build().unwrap(), other);
+        assert!(services.is_poisoned());
+        assert_eq!(panics.load(Ordering::SeqCst), 1);
+
+        // Later packets are handled as usual
+        let query = dns_parser::Builder::new_query(0, false)
+            .add_question(
+                &Name::from_str("_http._tcp.local").unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        handler.handle_packet(&query, other);
+        assert_eq!(panics.load(Ordering::SeqCst), 1);
+        let answers = ask(&handler, "_http._tcp.local", QueryType::PTR);
+        assert_eq!(answers[0], "PTR _http._tcp.local web._http._tcp.local");
+    }
+
+    #[test]
+    fn test_address_conflicts() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let conflicts = Arc::new(Mutex::new(Vec::new()));
+        let on_conflict = conflicts.clone();
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(FixedHostData::new(
+                "test-hostname".into(),
+                vec!["192.0.2.5".parse().unwrap()],
+            ))),
+            on_address_conflict: Some(Arc::new(move |conflict: AddressConflict| {
+                on_conflict.lock().unwrap().push(conflict)
+            })),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let mut builder =
+            dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+        for &(name, ip) in &[
+            ("other-host.local", [192, 0, 2, 5]),
+            ("Test-Hostname.local", [192, 0, 2, 5]),
+            ("third-host.local", [192, 0, 2, 7]),
+        ] {
+            builder = builder.add_answer(
+                &Name::from_str(name).unwrap(),
+                QueryClass::IN,
+                DEFAULT_TTL,
+                &RRData::A(ip.into()),
+            );
+        }
+        let packet = builder.build().unwrap();
+        let source: SocketAddr = "192.0.2.9:5353".parse().unwrap();
+        handler.handle_packet(&packet, source);
+        handler.handle_packet(&packet, source);
+
+        let conflicts = conflicts.lock().unwrap();
+        assert_eq!(conflicts.len(), 1, "reported once");
+        assert_eq!(conflicts[0].name, "other-host.local");
+        assert_eq!(conflicts[0].address, IpAddr::from([192, 0, 2, 5]));
+        assert_eq!(conflicts[0].source, source);
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_restored_hostname_announced() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let snapshot = services.read().unwrap().snapshot();
+        services.write().unwrap().restore(&snapshot);
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(FixedHostData::new(
+                "test-hostname".into(),
+                vec!["192.0.2.5".parse().unwrap()],
+            ))),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+
+        // Announced at once by the task's handlers, without waiting for a query
+        let mut handler = Handler::<Inet>::new(&shared);
+        let (packet, _) = handler.pop_outgoing().unwrap();
+        let packet = dns_parser::Packet::parse(&packet).unwrap();
+        let answers: Vec<String> = packet.answers.iter().map(describe).collect();
+        assert_eq!(answers, ["A test-hostname.local 192.0.2.5"]);
+        assert!(handler.pop_outgoing().is_none());
+
+        #[cfg(feature = "responder")]
+        {
+            let mut worker = Handler::<Inet>::answer_only(&shared);
+            assert!(worker.pop_outgoing().is_none());
+        }
+    }
+
+    #[test]
+    fn test_hostname_conflicts() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let conflicts = Arc::new(Mutex::new(Vec::new()));
+        let on_conflict = conflicts.clone();
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(FixedHostData::new(
+                "test-hostname".into(),
+                vec!["192.0.2.5".parse().unwrap()],
+            ))),
+            on_hostname_conflict: Some(Arc::new(move |conflict: HostnameConflict| {
+                on_conflict.lock().unwrap().push(conflict)
+            })),
+            cache_records: true,
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let packet = dns_parser::Builder::new_response(0, false, true)
+            .add_answer_with_cache_flush(
+                &Name::from_str("test-hostname.local").unwrap(),
+                QueryClass::IN,
+                DEFAULT_TTL,
+                &RRData::A([192, 0, 2, 5].into()),
+                true,
+            )
+            .add_answer_with_cache_flush(
+                &Name::from_str("other-host.local").unwrap(),
+                QueryClass::IN,
+                DEFAULT_TTL,
+                &RRData::A([192, 0, 2, 7].into()),
+                true,
+            )
+            // A shared record doesn't claim the name
+            .add_answer(
+                &Name::from_str("test-hostname.local").unwrap(),
+                QueryClass::IN,
+                DEFAULT_TTL,
+                &RRData::A([192, 0, 2, 6].into()),
+            )
+            // Records in the additional section count too
+            .add_additional_with_cache_flush(
+                &Name::from_str("Test-Hostname.local").unwrap(),
+                QueryClass::IN,
+                DEFAULT_TTL,
+                &RRData::A([192, 0, 2, 8].into()),
+                true,
+            )
+            .build()
+            .unwrap();
+        let source: SocketAddr = "192.0.2.9:5353".parse().unwrap();
+        handler.handle_packet(&packet, source);
+        handler.handle_packet(&packet, source);
+
+        let conflicts = conflicts.lock().unwrap();
+        assert_eq!(conflicts.len(), 1, "reported once");
+        assert_eq!(conflicts[0].name, "Test-Hostname.local");
+        assert_eq!(conflicts[0].address, IpAddr::from([192, 0, 2, 8]));
+        assert_eq!(conflicts[0].source, source);
+
+        // All of the records are cached for browsing
+        assert_eq!(shared.cache.records(Instant::now()).len(), 4);
+
+        // The host moves to another name, and announces it
+        let hostname = services.read().unwrap().get_hostname().to_string();
+        assert_eq!(hostname, "test-hostname (2).local");
+        handler.check_renamed();
+        let (announcement, _) = handler.pop_outgoing().unwrap();
+        let parsed = dns_parser::Packet::parse(&announcement).unwrap();
+        let described: Vec<String> = parsed.answers.iter().map(describe).collect();
+        assert_eq!(described, ["A test-hostname (2).local 192.0.2.5"]);
+    }
+
+    #[test]
+    fn test_alias_conflicts() {
+        let host = Arc::new(AliasHostData::default());
+        host.aliases.lock().unwrap().push("printer".into());
+        let (services, shared) = handler_with(ResponderConfig {
+            host_data: Some(host),
+            ..ResponderConfig::default()
+        });
+        services.write().unwrap().add_alias("myapp.local".into());
+        let mut handler = Handler::<Inet>::new(&shared);
+        handler.check_aliases();
+        assert_eq!(
+            services.read().unwrap().aliases().len(),
+            2,
+            "those of the host data are kept along with those added"
+        );
+        assert_eq!(handler.scheduled.len(), 2 * (PROBES + DEFAULT_ANNOUNCEMENTS) as usize);
+
+        let claim = |name: &str| {
+            dns_parser::Builder::new_response(0, false, true)
+                .add_answer_with_cache_flush(
+                    &Name::from_str(name.to_owned()).unwrap(),
+                    QueryClass::IN,
+                    DEFAULT_TTL,
+                    &RRData::A([192, 0, 2, 7].into()),
+                    true,
+                )
+                .build()
+                .unwrap()
+        };
+        let other: SocketAddr = "192.0.2.9:5353".parse().unwrap();
+
+        // An added alias claimed while being probed for is given up, without a goodbye
+        handler.handle_packet(&claim("MyApp.local"), other);
+        let services_aliases = || -> Vec<String> {
+            let services = services.read().unwrap();
+            services.aliases().iter().map(Name::to_string).collect()
+        };
+        assert_eq!(services_aliases(), ["printer.local"]);
+        assert_eq!(
+            skip_probes(&mut handler, "printer.local"),
+            ["A printer.local 192.0.2.5"; PROBES as usize]
+        );
+        let (announcement, _) = handler.pop_outgoing().unwrap();
+        let parsed = dns_parser::Packet::parse(&announcement).unwrap();
+        let described: Vec<String> = parsed.answers.iter().map(describe).collect();
+        assert_eq!(described, ["A printer.local 192.0.2.5"]);
+        handler.scheduled.clear();
+        assert!(handler.pop_outgoing().is_none());
+
+        // One of the host data is only reported
+        handler.handle_packet(&claim("printer.local"), other);
+        assert_eq!(services_aliases(), ["printer.local"]);
+
+        // Added again, it is probed for again
+        services.write().unwrap().add_alias("myapp.local".into());
+        handler.check_aliases();
+        assert_eq!(
+            skip_probes(&mut handler, "myapp.local"),
+            ["A myapp.local 192.0.2.5"; PROBES as usize]
+        );
+    }
+
+    #[test]
+    fn test_hostname_conflict_reannounces_services() {
+        let (services, shared) = handler_with(ResponderConfig {
+            host_data: Some(Arc::new(FixedHostData::new(
+                "test-hostname".into(),
+                vec!["192.0.2.5".parse().unwrap()],
+            ))),
+            ..ResponderConfig::default()
+        });
+        services
+            .write()
+            .unwrap()
+            .register(ServiceData::new("_http._tcp", "web", 80, (&[]).into()))
+            .unwrap();
+        let mut handler = Handler::<Inet>::new(&shared);
+        let packet = dns_parser::Builder::new_response(0, false, true)
+            .add_answer_with_cache_flush(
+                &Name::from_str("test-hostname.local").unwrap(),
+                QueryClass::IN,
+                DEFAULT_TTL,
+                &RRData::A([192, 0, 2, 8].into()),
+                true,
+            )
+            .build()
+            .unwrap();
+        handler.handle_packet(&packet, "192.0.2.9:5353".parse().unwrap());
+        handler.check_renamed();
+
+        // Probed for, then announced with the SRV record pointing at the new name
+        let mut scheduled = handler.scheduled.clone();
+        scheduled.sort_by_key(|&(at, _)| at);
+        let (_, announcement) = scheduled.last().unwrap();
+        let parsed = dns_parser::Packet::parse(&announcement.packet).unwrap();
+        let described: Vec<String> = parsed.answers.iter().map(describe).collect();
+        assert!(described.contains(&"SRV web._http._tcp.local".to_owned()));
+        assert!(described.contains(&"A test-hostname (2).local 192.0.2.5".to_owned()));
+        let srv = parsed.answers.iter().find_map(|record| match record.data {
+            RRData::SRV { ref target, .. } => Some(target.to_string()),
+            _ => None,
+        });
+        assert_eq!(srv.unwrap(), "test-hostname (2).local");
+    }
+
+    #[test]
+    fn test_ignore_own_packets() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let svc = ServiceData::new("_http._tcp", "test-instance", 8008, (&[]).into());
+        services.write().unwrap().register(svc.clone()).unwrap();
+        let config = || ResponderConfig {
+            host_data: Some(Arc::new(FixedHostData::new(
+                "test-hostname".into(),
+                vec!["192.0.2.5".parse().unwrap()],
+            ))),
+            cache_records: true,
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config());
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        handler.send_unsolicited(&svc, false, true);
+        let (announcement, _) = handler.pop_outgoing().unwrap();
+        shared
+            .browsers
+            .start(BrowseBuilder::new("_http._tcp").unwrap());
+
+        // Looped back from an address we don't know as ours, such as a temporary one
+        let source: SocketAddr = "192.0.2.9:5353".parse().unwrap();
+        handler.handle_packet(&announcement, source);
+        assert!(shared.cache.records(Instant::now()).is_empty());
+        // Browses find our own services all the same
+        let found = shared.browsers.instances();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "test-instance._http._tcp.local");
+        handler.handle_packet(&announcement, "192.0.2.5:5353".parse().unwrap());
+        assert!(shared.cache.records(Instant::now()).is_empty());
+
+        // Another responder in this process takes it for a foreign one
+        let other = Shared::new(&services, config());
+        Handler::<Inet>::new(&other).handle_packet(&announcement, source);
+        assert!(!other.cache.records(Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn test_additional_record() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let svc = ServiceBuilder::new("_test-service-name._tcp", "test-instance", 8008)
+            .additional_record("alias.local", 120, RecordData::A([192, 0, 2, 7].into()))
+            .build()
+            .unwrap();
+        services.write().unwrap().register(svc).unwrap();
+        let shared = Shared::new(&services, ResponderConfig::default());
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let query = dns_parser::Builder::new_query(0, false).add_question(
+            &Name::from_str("Alias.local").unwrap(),
+            QueryType::A,
+            QueryClass::IN,
+        );
+        handler.handle_packet(&query.build().unwrap(), "169.254.0.1:5353".parse().unwrap());
+
+        let (response, _) = handler.pop_outgoing().unwrap();
+        let parsed = dns_parser::Packet::parse(&response).unwrap();
+        assert_eq!(parsed.answers.len(), 1);
+        assert_eq!(parsed.answers[0].ttl, 120);
+        match parsed.answers[0].data {
+            RRData::A(ip) => assert_eq!(ip, std::net::Ipv4Addr::new(192, 0, 2, 7)),
+            ref other => panic!("Unexpected answer RR data type: {:?}", other),
+        }
+        assert!(parsed.answers[0].cache_flush);
+    }
+
+    #[test]
+    fn test_raw_record() {
+        let (services, shared) = handler_with(ResponderConfig::default());
+        // An SVCB record with priority 1 and the root as target, given as bytes
+        let svc = ServiceBuilder::new("_test-service-name._tcp", "test-instance", 8008)
+            .additional_record(
+                "raw.local",
+                120,
+                RecordData::Raw {
+                    typ: Type::SVCB as u16,
+                    data: vec![0, 1, 0],
+                },
+            )
+            .build()
+            .unwrap();
+        services.write().unwrap().register(svc).unwrap();
+        let handler = Handler::<Inet>::new(&shared);
+
+        assert_eq!(
+            ask(&handler, "raw.local", QueryType::SVCB),
+            ["SVCB raw.local"]
+        );
+        let response = respond(&handler, "raw.local", QueryType::SVCB);
+        let parsed = dns_parser::Packet::parse(&response).unwrap();
+        assert_eq!(parsed.answers.len(), 1);
+        match parsed.answers[0].data {
+            RRData::SVCB(ref binding) => {
+                assert_eq!(binding.priority, 1);
+                assert_eq!(binding.target.to_string(), "");
+            }
+            ref other => panic!("Unexpected answer RR data type: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_private_use_type() {
+        let (services, shared) = handler_with(ResponderConfig::default());
+        let svc = ServiceBuilder::new("_test-service-name._tcp", "test-instance", 8008)
+            .additional_record(
+                "private.local",
+                120,
+                RecordData::Raw {
+                    typ: 65280,
+                    data: vec![1, 2, 3],
+                },
+            )
+            .build()
+            .unwrap();
+        services.write().unwrap().register(svc).unwrap();
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let query = dns_parser::Builder::new_query(0, false).add_question(
+            &Name::from_str("private.local").unwrap(),
+            QueryType::Unknown(65280),
+            QueryClass::IN,
+        );
+        handler.handle_packet(&query.build().unwrap(), "169.254.0.1:5353".parse().unwrap());
+
+        // The parser has no type for the answer either, and skips it
+        let (response, _) = handler.pop_outgoing().unwrap();
+        let (parsed, errors) = dns_parser::Packet::parse_lenient(&response).unwrap();
+        assert_eq!(parsed.header.answers, 1);
+        assert!(matches!(errors[..], [ref error] if error.section == dns_parser::Section::Answer));
+        assert!(handler.pop_outgoing().is_none());
+
+        // Other private use types have no answer
+        let query = dns_parser::Builder::new_query(1, false).add_question(
+            &Name::from_str("private.local").unwrap(),
+            QueryType::Unknown(65281),
+            QueryClass::IN,
+        );
+        handler.handle_packet(&query.build().unwrap(), "169.254.0.1:5353".parse().unwrap());
+        assert!(handler.pop_outgoing().is_none());
+    }
+
+    #[test]
+    fn test_shared_record() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let svc = ServiceBuilder::new("_test-service-name._tcp", "test-instance", 8008)
+            .shared_record("shared.local", 120, RecordData::A([192, 0, 2, 7].into()))
+            .additional_record("shared.local", 120, RecordData::TXT((&["a=1"]).into()))
+            .build()
+            .unwrap();
+        services.write().unwrap().register(svc.clone()).unwrap();
+        let shared = Shared::new(&services, ResponderConfig::default());
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        // Neither announced nor answered with the cache-flush bit, nor listed in NSEC
+        handler.send_unsolicited(&svc, false, false);
+        let query = dns_parser::Builder::new_query(0, false).add_question(
+            &Name::from_str("shared.local").unwrap(),
+            QueryType::All,
+            QueryClass::IN,
+        );
+        handler.handle_packet(&query.build().unwrap(), "169.254.0.1:5353".parse().unwrap());
+        let mut seen = 0;
+        while let Some((packet, _)) = handler.pop_outgoing() {
+            let parsed = dns_parser::Packet::parse(&packet).unwrap();
+            for answer in parsed
+                .answers
+                .iter()
+                .filter(|a| a.name.to_string() == "shared.local")
+            {
+                match answer.data {
+                    RRData::A(_) => {
+                        assert!(!answer.cache_flush);
+                        seen += 1;
+                    }
+                    RRData::TXT(_) => assert!(answer.cache_flush),
+                    RRData::NSEC { ref types, .. } => {
+                        assert_eq!(types, &[Type::TXT as u16, Type::NSEC as u16])
+                    }
+                    ref other => panic!("unexpected record {:?}", other),
+                }
+            }
+        }
+        assert_eq!(seen, 2);
+    }
+
+    #[test]
+    fn test_record_ttls() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let svc = ServiceBuilder::new("_test-service-name._tcp", "test-instance", 8008)
+            .ptr_ttl(4500)
+            .ttl(120)
+            .build()
+            .unwrap();
+        services.write().unwrap().register(svc.clone()).unwrap();
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(FixedHostData::new(
+                "test-hostname".into(),
+                vec!["192.0.2.5".parse().unwrap()],
+            ))),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+        let ttls = |packet: &[u8]| -> Vec<(Type, u32)> {
+            dns_parser::Packet::parse(packet)
+                .unwrap()
+                .answers
+                .iter()
+                .map(|answer| (answer.data.typ(), answer.ttl))
+                .collect()
+        };
+        let expected = vec![
+            (Type::PTR, 4500),
+            (Type::SRV, 120),
+            (Type::TXT, 120),
+            // The host's own, the same whichever service they come with
+            (Type::A, DEFAULT_TTL),
+        ];
+
+        handler.send_unsolicited(&svc, false, true);
+        let (announcement, _) = handler.pop_outgoing().unwrap();
+        assert_eq!(ttls(&announcement), expected);
+
+        let browse = dns_parser::Builder::new_query(0, false)
+            .add_question(
+                &Name::from_str("_test-service-name._tcp.local").unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        handler.handle_packet(&browse, "169.254.0.1:5353".parse().unwrap());
+        let (answer, _) = handler.pop_outgoing().unwrap();
+        assert_eq!(ttls(&answer), expected);
+        let address = respond(&handler, "test-hostname.local", QueryType::A);
+        assert_eq!(ttls(&address), [(Type::A, DEFAULT_TTL)]);
+
+        // Legacy resolvers still get short TTLs
+        handler.handle_packet(&browse, "169.254.0.1:40000".parse().unwrap());
+        let (answer, _) = handler.pop_outgoing().unwrap();
+        assert!(ttls(&answer)
+            .iter()
+            .all(|&(_, ttl)| ttl == LEGACY_UNICAST_TTL));
+
+        handler.send_unsolicited(&svc, true, true);
+        let (goodbye, _) = handler.pop_outgoing().unwrap();
+        assert!(ttls(&goodbye).iter().all(|&(_, ttl)| ttl == 0));
+    }
+
+    #[test]
+    fn test_host_data_addresses() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let host = FixedHostData::new(
+            "test-hostname".into(),
+            vec![
+                "127.0.0.1".parse().unwrap(),
+                "192.0.2.9".parse().unwrap(),
+                "2001:db8::9".parse().unwrap(),
+            ],
+        );
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(host)),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let query = dns_parser::Builder::new_query(0, false).add_question(
+            &Name::from_str("test-hostname.local").unwrap(),
+            QueryType::A,
+            QueryClass::IN,
+        );
+        handler.handle_packet(&query.build().unwrap(), "169.254.0.1:5353".parse().unwrap());
+
+        let (response, _) = handler.pop_outgoing().unwrap();
+        let (parsed, _) = dns_parser::Packet::parse_lenient(&response).unwrap();
+        assert_eq!(parsed.answers.len(), 1);
+        match parsed.answers[0].data {
+            RRData::A(ip) => assert_eq!(ip, std::net::Ipv4Addr::new(192, 0, 2, 9)),
+            ref other => panic!("Unexpected answer RR data type: {:?}", other),
+        }
+        assert_eq!(parsed.additional.len(), 1);
+        match parsed.additional[0].data {
+            RRData::AAAA(ip) => {
+                assert_eq!(ip, "2001:db8::9".parse::<std::net::Ipv6Addr>().unwrap())
+            }
+            ref other => panic!("Unexpected answer RR data type: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_off_link_queries() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let host = FixedHostData::new("test-hostname".into(), vec!["192.0.2.9".parse().unwrap()]);
+        let query = dns_parser::Builder::new_query(0, false)
+            .add_question(
+                &Name::from_str("test-hostname.local").unwrap(),
+                QueryType::A,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        // A documentation address, which no interface should be on
+        let off_link: SocketAddr = "203.0.113.7:5353".parse().unwrap();
+
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(host.clone())),
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+        handler.handle_packet(&query, off_link);
+        assert!(handler.pop_outgoing().is_none());
+        #[cfg(feature = "metrics-minimal")]
+        assert_eq!(shared.counters.metrics().off_link_queries, 1);
+
+        handler.handle_packet(&query, "169.254.0.1:5353".parse().unwrap());
+        assert!(handler.pop_outgoing().is_some());
+
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(host)),
+            accept_off_link_queries: true,
+            ..ResponderConfig::default()
+        };
+        let shared = Shared::new(&services, config);
+        let mut handler = Handler::<Inet>::new(&shared);
+        handler.handle_packet(&query, off_link);
+        assert!(handler.pop_outgoing().is_some());
+        #[cfg(feature = "metrics-minimal")]
+        assert_eq!(shared.counters.metrics().off_link_queries, 0);
+    }
+
+    #[test]
+    fn test_other_interface() {
+        let host = FixedHostData::new("test-hostname".into(), vec!["192.0.2.9".parse().unwrap()]);
+        let (_, shared) = handler_with(ResponderConfig {
+            host_data: Some(Arc::new(host)),
+            interfaces: vec!["no-such-iface0".into()],
+            accept_off_link_queries: true,
+            ..ResponderConfig::default()
+        });
+        let mut handler = Handler::<Inet6>::new(&shared);
+        let query = dns_parser::Builder::new_query(0, false)
+            .add_question(
+                &Name::from_str("test-hostname.local").unwrap(),
+                QueryType::A,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+
+        // Dropped even with off-link queries accepted, as no interface is selected
+        handler.handle_packet(&query, "[fe80::7%1]:5353".parse().unwrap());
+        assert!(handler.pop_outgoing().is_none());
+        #[cfg(feature = "metrics-minimal")]
+        assert_eq!(shared.counters.metrics().other_interface_packets, 1);
+    }
+
+    #[test]
+    fn test_packet_dropped() {
+        let host = FixedHostData::new("test-hostname".into(), vec!["192.0.2.9".parse().unwrap()]);
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let on_packet_dropped = {
+            let dropped = dropped.clone();
+            move |event: PacketDropped| dropped.lock().unwrap().push((event.reason, event.source))
+        };
+        let (_, shared) = handler_with(ResponderConfig {
+            host_data: Some(Arc::new(host)),
+            on_packet_dropped: Some(Arc::new(on_packet_dropped)),
+            ..ResponderConfig::default()
+        });
+        let mut handler = Handler::<Inet>::new(&shared);
+
+        let query = dns_parser::Builder::new_query(0, false)
+            .add_question(
+                &Name::from_str("test-hostname.local").unwrap(),
+                QueryType::A,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        let mut truncated = query.clone();
+        truncated[2] |= 0x02;
+        let response = dns_parser::Builder::new_response(0, false, true)
+            .build()
+            .unwrap();
+        let on_link: SocketAddr = "169.254.0.1:5353".parse().unwrap();
+        let off_link: SocketAddr = "203.0.113.7:5353".parse().unwrap();
+        let own: SocketAddr = "192.0.2.9:5353".parse().unwrap();
+
+        handler.handle_packet(b"\x00\x01", on_link);
+        handler.handle_packet(&response, own);
+        handler.handle_packet(&truncated, on_link);
+        handler.handle_packet(&query, off_link);
+        handler.handle_packet(&query, on_link);
+        handler.handle_packet(&query, on_link);
+        assert_eq!(
+            *dropped.lock().unwrap(),
+            [
+                (DropReason::Unparseable, on_link),
+                (DropReason::OwnPacket, own),
+                (DropReason::Truncated, on_link),
+                (DropReason::OffLink, off_link),
+                (DropReason::Duplicate, on_link),
+            ]
+        );
+
+        #[cfg(feature = "metrics-minimal")]
+        {
+            let metrics = shared.counters.metrics();
+            assert_eq!(metrics.packets_parse_failed, 1);
+            assert_eq!(metrics.own_packets, 1);
+            assert_eq!(metrics.truncated_queries, 1);
+            assert_eq!(metrics.off_link_queries, 1);
+            assert_eq!(metrics.duplicate_queries, 1);
+            assert_eq!(metrics.packets_ignored, 3);
+            assert_eq!(metrics.packets_answered, 1);
+        }
+    }
+
+    #[test]
+    fn test_echo_question() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let host = FixedHostData::new("test-hostname".into(), vec!["192.0.2.9".parse().unwrap()]);
+        let mut query = dns_parser::Builder::new_query(0, false)
+            .add_question(
+                &Name::from_str("test-hostname.local").unwrap(),
+                QueryType::A,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        // Set the QU bit
+        let len = query.len();
+        query[len - 2] |= 0x80;
+        let source: SocketAddr = "169.254.0.1:5353".parse().unwrap();
+
+        for echo in [false, true] {
+            let config = ResponderConfig {
+                host_data: Some(Arc::new(host.clone())),
+                echo_question: echo,
+                ..ResponderConfig::default()
+            };
+            let shared = Shared::new(&services, config);
+            let mut handler = Handler::<Inet>::new(&shared);
+            handler.handle_packet(&query, source);
+
+            let (response, addr) = handler.pop_outgoing().unwrap();
+            assert_eq!(addr, source);
+            let parsed = dns_parser::Packet::parse(&response).unwrap();
+            assert_eq!(parsed.questions.len(), usize::from(echo));
+            assert_eq!(parsed.answers.len(), 1);
+        }
+    }
+
+    /// A transport receiving the packets queued in it, so that tasks are driven
+    /// without any traffic on real sockets
+    #[cfg(all(feature = "metrics-minimal", feature = "responder"))]
+    struct Inbox {
+        /// lends its handle only, nothing is sent or received on it
+        socket: std::net::UdpSocket,
+        queue: std::sync::Mutex<VecDeque<(Vec<u8>, SocketAddr)>>,
+    }
+
+    #[cfg(all(feature = "metrics-minimal", feature = "responder"))]
+    impl Inbox {
+        fn push(&self, packet: &[u8], from: SocketAddr) {
+            self.queue
+                .lock()
+                .unwrap()
+                .push_back((packet.to_vec(), from));
+        }
+    }
+
+    #[cfg(all(unix, feature = "metrics-minimal", feature = "responder"))]
+    impl std::os::fd::AsFd for Inbox {
+        fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+            self.socket.as_fd()
+        }
+    }
+
+    #[cfg(all(windows, feature = "metrics-minimal", feature = "responder"))]
+    impl std::os::windows::io::AsSocket for Inbox {
+        fn as_socket(&self) -> std::os::windows::io::BorrowedSocket<'_> {
+            self.socket.as_socket()
+        }
+    }
+
+    #[cfg(all(feature = "metrics-minimal", feature = "responder"))]
+    impl Transport for Inbox {
+        type Sleep = std::future::Pending<()>;
+
+        fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+            Ok(Inbox {
+                socket,
+                queue: Default::default(),
+            })
+        }
+
+        fn poll_recv_from(
+            &self,
+            _cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<(usize, SocketAddr)>> {
+            match self.queue.lock().unwrap().pop_front() {
+                Some((packet, from)) => {
+                    buf[..packet.len()].copy_from_slice(&packet);
+                    Poll::Ready(Ok((packet.len(), from)))
+                }
+                None => Poll::Pending,
+            }
+        }
+
+        fn poll_send_to(
+            &self,
+            _cx: &mut Context,
+            buf: &[u8],
+            _addr: SocketAddr,
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn sleep_until(_deadline: Instant) -> Self::Sleep {
+            std::future::pending()
+        }
+    }
+
+    #[cfg(all(feature = "metrics-minimal", feature = "responder"))]
+    #[test]
+    fn test_storm_does_not_starve_other_family() {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        let stormed = Shared::new(&services, ResponderConfig::default());
+        let quiet = Shared::new(&services, ResponderConfig::default());
+        let socket = || std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (stormed_fsm, _stormed_tx) =
+            FSM::<Inet, Inbox>::with_socket(&stormed, socket(), Vec::new()).unwrap();
+        let (quiet_fsm, _quiet_tx) =
+            FSM::<Inet, Inbox>::with_socket(&quiet, socket(), Vec::new()).unwrap();
+
+        let from = "192.0.2.1:5353".parse().unwrap();
+        for _ in 0..POLL_BUDGET * 4 {
+            stormed_fsm.socket.push(&[0xff; 3], from);
+        }
+        quiet_fsm.socket.push(&[0xff; 3], from);
+
+        // Polled in the same task, like the two families of a responder
+        let mut tasks = futures_util::future::join(stormed_fsm, quiet_fsm);
+        let wakes = Arc::new(CountingWaker::default());
+        let waker = std::task::Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+        let parse_failed = |shared: &Shared| shared.counters.metrics().packets_parse_failed;
+        for polls in 1..=2 {
+            assert!(Pin::new(&mut tasks).poll(&mut cx).is_pending());
+            assert_eq!(parse_failed(&stormed), (POLL_BUDGET * polls) as u64);
+            assert_eq!(parse_failed(&quiet), 1);
+        }
+
+        // Once the storm is drained, the task waits for the socket
+        for _ in 0..3 {
+            assert!(Pin::new(&mut tasks).poll(&mut cx).is_pending());
+        }
+        assert_eq!(parse_failed(&stormed), (POLL_BUDGET * 4) as u64);
+        wakes.0.store(0, Ordering::SeqCst);
+        assert!(Pin::new(&mut tasks).poll(&mut cx).is_pending());
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+    }
+
+    /// Counts how often a task wakes itself
+    #[cfg(all(feature = "metrics-minimal", feature = "responder"))]
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    #[cfg(all(feature = "metrics-minimal", feature = "responder"))]
+    impl std::task::Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
     }
 }
@@ -0,0 +1,183 @@
+//! Packets the responder sends for a fixed set of services and questions, compared
+//! byte for byte with those recorded in `testdata/golden`. Devices on the network see
+//! exactly these bytes, so a change here has to be deliberate.
+//!
+//! A change to the encoding comes in behind a flag of `ResponderBuilder`, such as
+//! `compress_names` and `cache_flush`, with the packets recorded both with and without
+//! it. After an intended change, run the tests with `LIBMDNS_UPDATE_GOLDEN=1` to
+//! record the new packets, and review the difference in the files.
+
+use crate::address_family::Inet;
+use crate::config::ResponderConfig;
+use crate::dns_parser::{Builder, Name, QueryClass, QueryType};
+use crate::fsm::{Handler, Shared};
+use crate::host::FixedHostData;
+use crate::service_builder::ServiceBuilder;
+use crate::services::{ServiceData, ServicesInner};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::{env, fs};
+
+fn service() -> ServiceData {
+    ServiceBuilder::new("_http._tcp", "Golden Server", 8080)
+        .txt(&["path=/", "version=1"])
+        .build()
+        .unwrap()
+}
+
+fn handler() -> Handler<Inet> {
+    handler_with(ResponderConfig::default())
+}
+
+fn handler_with(config: ResponderConfig) -> Handler<Inet> {
+    let services = Arc::new(RwLock::new(ServicesInner::new("golden-host.local".into())));
+    services.write().unwrap().register(service()).unwrap();
+    let host = FixedHostData::new(
+        "golden-host".into(),
+        vec![
+            "192.0.2.10".parse().unwrap(),
+            "2001:db8::10".parse().unwrap(),
+        ],
+    );
+    let config = ResponderConfig {
+        host_data: Some(Arc::new(host)),
+        ..config
+    };
+    Handler::new(&Shared::new(&services, config))
+}
+
+/// The packets sent in response to a query for `name` from `from`
+fn ask(name: &str, qtype: QueryType, id: u16, from: &str) -> Vec<Vec<u8>> {
+    let mut handler = handler();
+    let query = Builder::new_query(id, false)
+        .add_question(
+            &Name::from_str(name.to_owned()).unwrap(),
+            qtype,
+            QueryClass::IN,
+        )
+        .build()
+        .unwrap();
+    handler.handle_packet(&query, from.parse::<SocketAddr>().unwrap());
+    drain(&mut handler)
+}
+
+fn drain(handler: &mut Handler<Inet>) -> Vec<Vec<u8>> {
+    std::iter::from_fn(|| handler.pop_outgoing())
+        .map(|(packet, _)| packet)
+        .collect()
+}
+
+fn hex(packets: &[Vec<u8>]) -> String {
+    let mut out = String::new();
+    for packet in packets {
+        for line in packet.chunks(16) {
+            let bytes: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(out, "{}", bytes.join(" ")).unwrap();
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn check(name: &str, packets: Vec<Vec<u8>>) {
+    assert!(!packets.is_empty(), "{}: nothing was sent", name);
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testdata", "golden", name]
+        .iter()
+        .collect();
+    let actual = hex(&packets);
+
+    if env::var_os("LIBMDNS_UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("can't read {}: {}", path.display(), err));
+    assert!(
+        actual == expected,
+        "{} changed, expected:\n{}\nactual:\n{}",
+        name,
+        expected,
+        actual
+    );
+}
+
+#[test]
+fn golden_announcement() {
+    let mut handler = handler();
+    handler.send_unsolicited(&service(), false, true);
+    check("announcement.hex", drain(&mut handler));
+}
+
+#[test]
+fn golden_announcement_uncompressed() {
+    let mut handler = handler_with(ResponderConfig {
+        disable_name_compression: true,
+        ..ResponderConfig::default()
+    });
+    handler.send_unsolicited(&service(), false, true);
+    check("announcement_uncompressed.hex", drain(&mut handler));
+}
+
+#[test]
+fn golden_announcement_without_cache_flush() {
+    let mut handler = handler_with(ResponderConfig {
+        disable_cache_flush: true,
+        ..ResponderConfig::default()
+    });
+    handler.send_unsolicited(&service(), false, true);
+    check("announcement_without_cache_flush.hex", drain(&mut handler));
+}
+
+#[test]
+fn golden_goodbye() {
+    let mut handler = handler();
+    handler.send_unsolicited(&service(), true, false);
+    check("goodbye.hex", drain(&mut handler));
+}
+
+#[test]
+fn golden_browse() {
+    let packets = ask("_http._tcp.local", QueryType::PTR, 0, "169.254.0.1:5353");
+    check("browse.hex", packets);
+}
+
+#[test]
+fn golden_legacy_browse() {
+    let packets = ask(
+        "_http._tcp.local",
+        QueryType::PTR,
+        0x1234,
+        "169.254.0.1:40000",
+    );
+    check("legacy_browse.hex", packets);
+}
+
+#[test]
+fn golden_srv() {
+    let name = "Golden Server._http._tcp.local";
+    check("srv.hex", ask(name, QueryType::SRV, 0, "169.254.0.1:5353"));
+}
+
+#[test]
+fn golden_any() {
+    let name = "Golden Server._http._tcp.local";
+    check("any.hex", ask(name, QueryType::All, 0, "169.254.0.1:5353"));
+}
+
+#[test]
+fn golden_hostname() {
+    let packets = ask("golden-host.local", QueryType::A, 0, "169.254.0.1:5353");
+    check("hostname.hex", packets);
+}
+
+#[test]
+fn golden_enumeration() {
+    let name = "_services._dns-sd._udp.local";
+    check(
+        "enumeration.hex",
+        ask(name, QueryType::PTR, 0, "169.254.0.1:5353"),
+    );
+}
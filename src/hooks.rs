@@ -0,0 +1,43 @@
+//! Hook points for closed ecosystems which authenticate their advertisements.
+//!
+//! No cryptography is implemented here, a fleet would typically append an HMAC
+//! over the instance name, port and TXT data with a [`TxtDecorator`] and check
+//! it with a matching [`RecordValidator`].
+
+/// Rewrites the TXT data of a service each time it is sent.
+pub trait TxtDecorator: Send + Sync {
+    /// Returns the TXT RDATA to send for the service instance `name` on `port`.
+    ///
+    /// `txt` is the registered data as a sequence of length-prefixed strings, the
+    /// returned value must use the same encoding.
+    fn decorate(&self, name: &str, port: u16, txt: &[u8]) -> Vec<u8>;
+}
+
+impl<F> TxtDecorator for F
+where
+    F: Fn(&str, u16, &[u8]) -> Vec<u8> + Send + Sync,
+{
+    fn decorate(&self, name: &str, port: u16, txt: &[u8]) -> Vec<u8> {
+        self(name, port, txt)
+    }
+}
+
+/// Decides whether a service record seen from another responder is trusted.
+pub trait RecordValidator: Send + Sync {
+    /// Returns `true` if the TXT data received for instance `name` is accepted.
+    /// Otherwise the records of the instance in that packet are ignored: they are
+    /// neither cached nor browsed, and don't count as conflicts with our services.
+    ///
+    /// `port` is taken from an SRV record for the same name in the same packet,
+    /// if there is one.
+    fn validate(&self, name: &str, port: Option<u16>, txt: &[u8]) -> bool;
+}
+
+impl<F> RecordValidator for F
+where
+    F: Fn(&str, Option<u16>, &[u8]) -> bool + Send + Sync,
+{
+    fn validate(&self, name: &str, port: Option<u16>, txt: &[u8]) -> bool {
+        self(name, port, txt)
+    }
+}
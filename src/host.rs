@@ -0,0 +1,636 @@
+use crate::netns::Interfaces;
+use if_addrs::{get_if_addrs, IfAddr, Interface};
+use log::warn;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::{Duration, Instant};
+
+/// Source of the hostname and addresses published by a responder, set with
+/// [`ResponderBuilder::host_data`](crate::ResponderBuilder::host_data).
+pub trait HostData: Send + Sync {
+    /// The hostname, with or without the `.local` suffix. Only read at startup.
+    fn hostname(&self) -> io::Result<String>;
+
+    /// The addresses to publish for the hostname. Loopback addresses are never published.
+    ///
+    /// The result is reused for up to
+    /// [`address_refresh_interval`](crate::ResponderBuilder::address_refresh_interval).
+    fn addresses(&self) -> io::Result<Vec<IpAddr>>;
+
+    /// Further names to publish with the same addresses, with or without the `.local`
+    /// suffix. Names which appear are probed for and announced, and names which
+    /// disappear get a goodbye. Another host publishing one of them is only reported to
+    /// [`on_hostname_conflict`](crate::ResponderBuilder::on_hostname_conflict).
+    ///
+    /// Read along with `addresses`, and straight away after
+    /// [`HostWatcher::changed`].
+    fn aliases(&self) -> io::Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Names which only some addresses belong to, such as a different hostname on
+    /// each network, with or without the `.local` suffix. Each is published with its
+    /// own addresses, and reverse lookups of those addresses answer with it too.
+    /// Names are announced and withdrawn like aliases.
+    ///
+    /// Read along with `addresses`.
+    fn address_names(&self) -> io::Result<Vec<(String, Vec<IpAddr>)>> {
+        Ok(Vec::new())
+    }
+
+    /// The subnets of the local link, each as an address and prefix length, such as
+    /// `(192.168.1.0, 24)`. Queries from elsewhere are dropped unless
+    /// [`accept_off_link_queries`](crate::ResponderBuilder::accept_off_link_queries)
+    /// is set, loopback and link-local sources are always accepted. `None`, the
+    /// default, takes the subnets of the system's network interfaces.
+    ///
+    /// Read along with `addresses`.
+    fn subnets(&self) -> io::Result<Option<Vec<(IpAddr, u8)>>> {
+        Ok(None)
+    }
+
+    /// Called when a responder starts using this `HostData`. Implementations which
+    /// know when their data changes can keep the `watcher` and call
+    /// [`changed`](HostWatcher::changed), rather than waiting for the next refresh.
+    /// If called again, e.g. after [`Responder::replace`](crate::Responder::replace),
+    /// only the latest watcher is of use.
+    fn watch(&self, _watcher: HostWatcher) {}
+}
+
+/// Tells a responder that its [`HostData`] has changed, see [`HostData::watch`].
+#[derive(Clone)]
+pub struct HostWatcher {
+    cache: Weak<AddressCache>,
+    wake: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+impl HostWatcher {
+    pub(crate) fn new(
+        cache: &Arc<AddressCache>,
+        wake: Option<Arc<dyn Fn() + Send + Sync>>,
+    ) -> Self {
+        HostWatcher {
+            cache: Arc::downgrade(cache),
+            wake,
+        }
+    }
+
+    /// Have the addresses and aliases read again, and any new aliases announced.
+    /// Does nothing once the responder has stopped.
+    pub fn changed(&self) {
+        if let Some(cache) = self.cache.upgrade() {
+            cache.invalidate();
+            if let Some(ref wake) = self.wake {
+                wake();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for HostWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HostWatcher").finish_non_exhaustive()
+    }
+}
+
+/// The system hostname and the addresses of all network interfaces.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultHostData;
+
+impl HostData for DefaultHostData {
+    fn hostname(&self) -> io::Result<String> {
+        hostname::get()?
+            .into_string()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Hostname not valid unicode"))
+    }
+
+    fn addresses(&self) -> io::Result<Vec<IpAddr>> {
+        Ok(get_if_addrs()?.iter().map(|iface| iface.ip()).collect())
+    }
+}
+
+/// The system hostname and the addresses of the network interfaces of the namespace
+/// set with [`ResponderBuilder::network_namespace`](crate::ResponderBuilder::network_namespace),
+/// in place of `DefaultHostData`.
+pub(crate) struct NamespaceHostData(pub Arc<Interfaces>);
+
+impl HostData for NamespaceHostData {
+    fn hostname(&self) -> io::Result<String> {
+        DefaultHostData.hostname()
+    }
+
+    fn addresses(&self) -> io::Result<Vec<IpAddr>> {
+        Ok(self.0.list()?.iter().map(|iface| iface.ip()).collect())
+    }
+}
+
+/// Where the network interfaces are listed: in the network namespace of the process,
+/// or in that of the responder's sockets
+#[derive(Clone, Debug, Default)]
+pub enum InterfaceSource {
+    #[default]
+    Process,
+    Namespace(Arc<Interfaces>),
+    /// none, for a responder sending through a sink, whose link isn't that of the
+    /// host's interfaces
+    Sink,
+}
+
+impl InterfaceSource {
+    pub fn new(interfaces: Option<&Arc<Interfaces>>) -> InterfaceSource {
+        match interfaces {
+            Some(interfaces) => InterfaceSource::Namespace(interfaces.clone()),
+            None => InterfaceSource::Process,
+        }
+    }
+
+    fn is_sink(&self) -> bool {
+        matches!(*self, InterfaceSource::Sink)
+    }
+
+    fn list(&self) -> io::Result<Vec<Interface>> {
+        match *self {
+            InterfaceSource::Process => get_if_addrs(),
+            InterfaceSource::Namespace(ref interfaces) => interfaces.list(),
+            InterfaceSource::Sink => Ok(Vec::new()),
+        }
+    }
+}
+
+/// A hostname and addresses which never change.
+///
+/// The local link is taken to be the subnets of the addresses, a /24 for IPv4 and a
+/// /64 for IPv6, unless set with [`with_subnets`](FixedHostData::with_subnets). The
+/// system's network interfaces aren't consulted.
+#[derive(Clone, Debug)]
+pub struct FixedHostData {
+    hostname: String,
+    addresses: Vec<IpAddr>,
+    subnets: Option<Vec<(IpAddr, u8)>>,
+}
+
+impl FixedHostData {
+    pub fn new(hostname: String, addresses: Vec<IpAddr>) -> FixedHostData {
+        FixedHostData {
+            hostname,
+            addresses,
+            subnets: None,
+        }
+    }
+
+    /// Take the local link to be `subnets`, see [`HostData::subnets`].
+    pub fn with_subnets(mut self, subnets: Vec<(IpAddr, u8)>) -> FixedHostData {
+        self.subnets = Some(subnets);
+        self
+    }
+}
+
+impl HostData for FixedHostData {
+    fn hostname(&self) -> io::Result<String> {
+        Ok(self.hostname.clone())
+    }
+
+    fn addresses(&self) -> io::Result<Vec<IpAddr>> {
+        Ok(self.addresses.clone())
+    }
+
+    fn subnets(&self) -> io::Result<Option<Vec<(IpAddr, u8)>>> {
+        let subnets = match self.subnets {
+            Some(ref subnets) => subnets.clone(),
+            None => self
+                .addresses
+                .iter()
+                .map(|&ip| (ip, if ip.is_ipv4() { 24 } else { 64 }))
+                .collect(),
+        };
+        Ok(Some(subnets))
+    }
+}
+
+/// Remembers the addresses and aliases of a `HostData` for a while, as listing the
+/// network interfaces takes several syscalls and is needed for almost every response.
+pub struct AddressCache {
+    host: Arc<dyn HostData>,
+    refresh_interval: Duration,
+    /// names of the interfaces whose addresses are kept, all if empty
+    interfaces: Vec<String>,
+    source: InterfaceSource,
+    cached: Mutex<Option<Cached>>,
+    stale: AtomicBool,
+    /// counts changes to the aliases and address names
+    generation: AtomicU64,
+}
+
+struct Cached {
+    fetched_at: Instant,
+    addresses: Vec<IpAddr>,
+    aliases: Vec<String>,
+    address_names: Vec<(String, Vec<IpAddr>)>,
+    /// the subnets of the network interfaces with their names and indices, `None` if
+    /// they couldn't be listed
+    subnets: Option<Vec<(String, Option<u32>, Subnet)>>,
+    /// the subnets of the local link given by the `HostData`, if any
+    link_subnets: Option<Vec<Subnet>>,
+}
+
+/// An interface address with its netmask
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    ip: IpAddr,
+    netmask: IpAddr,
+}
+
+impl Subnet {
+    pub fn new(addr: &IfAddr) -> Subnet {
+        match *addr {
+            IfAddr::V4(ref addr) => Subnet {
+                ip: addr.ip.into(),
+                netmask: addr.netmask.into(),
+            },
+            IfAddr::V6(ref addr) => Subnet {
+                ip: addr.ip.into(),
+                netmask: addr.netmask.into(),
+            },
+        }
+    }
+
+    /// The subnet of `ip` with a prefix of `len` bits
+    pub fn from_prefix(ip: IpAddr, len: u8) -> Subnet {
+        let netmask = match ip {
+            IpAddr::V4(_) => {
+                let bits = u32::MAX
+                    .checked_shl(32 - u32::from(len.min(32)))
+                    .unwrap_or(0);
+                IpAddr::V4(bits.into())
+            }
+            IpAddr::V6(_) => {
+                let bits = u128::MAX
+                    .checked_shl(128 - u32::from(len.min(128)))
+                    .unwrap_or(0);
+                IpAddr::V6(bits.into())
+            }
+        };
+        Subnet { ip, netmask }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.ip, self.netmask, ip) {
+            (IpAddr::V4(own), IpAddr::V4(mask), IpAddr::V4(ip)) => {
+                u32::from(own) & u32::from(mask) == u32::from(ip) & u32::from(mask)
+            }
+            (IpAddr::V6(own), IpAddr::V6(mask), IpAddr::V6(ip)) => {
+                u128::from(own) & u128::from(mask) == u128::from(ip) & u128::from(mask)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl AddressCache {
+    pub fn new(
+        host: Arc<dyn HostData>,
+        refresh_interval: Duration,
+        interfaces: Vec<String>,
+        source: InterfaceSource,
+    ) -> AddressCache {
+        AddressCache {
+            host,
+            refresh_interval,
+            interfaces,
+            source,
+            cached: Mutex::new(None),
+            stale: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    pub fn addresses(&self) -> io::Result<Vec<IpAddr>> {
+        self.with_cached(|cached| cached.addresses.clone())
+    }
+
+    /// The aliases, each in the `.local` domain
+    pub fn aliases(&self) -> Vec<String> {
+        self.with_cached(|cached| cached.aliases.clone())
+            .unwrap_or_default()
+    }
+
+    /// Names with addresses of their own, each in the `.local` domain
+    pub fn address_names(&self) -> Vec<(String, Vec<IpAddr>)> {
+        self.with_cached(|cached| cached.address_names.clone())
+            .unwrap_or_default()
+    }
+
+    /// Whether `ip` is on the local link: a loopback or link-local address, or one
+    /// within the subnets given by the `HostData`, or else those of the network
+    /// interfaces. If the interfaces can't be listed, every address is taken to be
+    /// on the link.
+    pub fn is_on_link(&self, ip: IpAddr) -> bool {
+        if is_link_local(ip) {
+            return true;
+        }
+        self.with_cached(|cached| match (&cached.link_subnets, &cached.subnets) {
+            (Some(subnets), _) => subnets.iter().any(|subnet| subnet.contains(ip)),
+            (None, Some(subnets)) => subnets.iter().any(|(_, _, subnet)| subnet.contains(ip)),
+            (None, None) => true,
+        })
+        .unwrap_or(true)
+    }
+
+    /// Whether a packet from `addr` can have arrived on one of the selected network
+    /// interfaces: it comes from within the subnet of one of them, or for IPv6
+    /// link-local sources, from the scope of one of them. The sockets receive from
+    /// every interface, whichever ones they joined the mDNS group on. True if no
+    /// interfaces were selected, or they can't be listed.
+    pub fn is_from_interfaces(&self, addr: SocketAddr) -> bool {
+        if self.interfaces.is_empty() || addr.ip().is_loopback() {
+            return true;
+        }
+        let scope = match addr {
+            SocketAddr::V6(addr) if addr.scope_id() != 0 && is_link_local((*addr.ip()).into()) => {
+                Some(addr.scope_id())
+            }
+            _ => None,
+        };
+        self.with_cached(|cached| match (&cached.subnets, scope) {
+            (Some(subnets), Some(scope)) => {
+                subnets.iter().any(|(_, index, _)| *index == Some(scope))
+            }
+            (Some(subnets), None) => subnets
+                .iter()
+                .any(|(_, _, subnet)| subnet.contains(addr.ip())),
+            (None, _) => true,
+        })
+        .unwrap_or(true)
+    }
+
+    /// Whether `ip` is an address of the network interface named `name`, as last
+    /// listed. False if the interfaces couldn't be listed.
+    pub fn is_on_interface(&self, ip: IpAddr, name: &str) -> bool {
+        self.with_cached(|cached| match cached.subnets {
+            Some(ref subnets) => subnets
+                .iter()
+                .any(|(iface, _, subnet)| iface == name && subnet.ip == ip),
+            None => false,
+        })
+        .unwrap_or(false)
+    }
+
+    /// Changes whenever the aliases or address names do
+    pub fn generation(&self) -> u64 {
+        // A failed refresh leaves the aliases as they were
+        let _ = self.with_cached(|_| ());
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Have the addresses and aliases read again on next use
+    pub fn invalidate(&self) {
+        self.stale.store(true, Ordering::SeqCst);
+    }
+
+    fn with_cached<T>(&self, f: impl FnOnce(&Cached) -> T) -> io::Result<T> {
+        let mut cached = self.cached.lock().unwrap_or_else(PoisonError::into_inner);
+        let stale = self.stale.swap(false, Ordering::SeqCst);
+        if let Some(ref cached) = *cached {
+            if !stale && cached.fetched_at.elapsed() < self.refresh_interval {
+                return Ok(f(cached));
+            }
+        }
+
+        let mut addresses = self.host.addresses()?;
+        if !self.interfaces.is_empty() && !self.source.is_sink() {
+            let selected = interface_addresses(&self.source, &self.interfaces)?;
+            addresses.retain(|ip| selected.contains(ip));
+        }
+        let previous = cached.take();
+        let aliases = match self.host.aliases() {
+            Ok(aliases) => aliases.into_iter().map(in_local_domain).collect(),
+            Err(err) => {
+                warn!("could not get hostname aliases: {}", err);
+                previous
+                    .as_ref()
+                    .map(|cached| cached.aliases.clone())
+                    .unwrap_or_default()
+            }
+        };
+        let address_names = match self.host.address_names() {
+            Ok(names) => names
+                .into_iter()
+                .map(|(name, ips)| (in_local_domain(name), ips))
+                .collect(),
+            Err(err) => {
+                warn!("could not get address names: {}", err);
+                previous
+                    .as_ref()
+                    .map(|cached| cached.address_names.clone())
+                    .unwrap_or_default()
+            }
+        };
+        let link_subnets = match self.host.subnets() {
+            Ok(subnets) => subnets.map(|subnets| {
+                let subnet = |(ip, len)| Subnet::from_prefix(ip, len);
+                subnets.into_iter().map(subnet).collect()
+            }),
+            Err(err) => {
+                warn!("could not get the subnets of the host: {}", err);
+                previous
+                    .as_ref()
+                    .and_then(|cached| cached.link_subnets.clone())
+            }
+        };
+        let subnets = match interface_subnets(&self.source, &self.interfaces) {
+            // Without interfaces of its own, any address may be on the link
+            Ok(_) if self.source.is_sink() => None,
+            Ok(subnets) => Some(subnets),
+            Err(err) => {
+                warn!("could not list interface subnets: {}", err);
+                previous.as_ref().and_then(|cached| cached.subnets.clone())
+            }
+        };
+        let changed = match previous {
+            Some(previous) => {
+                previous.aliases != aliases || previous.address_names != address_names
+            }
+            None => true,
+        };
+        if changed {
+            self.generation.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let fresh = cached.insert(Cached {
+            fetched_at: Instant::now(),
+            addresses,
+            aliases,
+            address_names,
+            subnets,
+            link_subnets,
+        });
+        Ok(f(fresh))
+    }
+}
+
+fn in_local_domain(mut name: String) -> String {
+    if !name.ends_with(".local") {
+        name.push_str(".local");
+    }
+    name
+}
+
+/// The addresses of the network interfaces named in `interfaces`
+fn interface_addresses(source: &InterfaceSource, interfaces: &[String]) -> io::Result<Vec<IpAddr>> {
+    Ok(source
+        .list()?
+        .into_iter()
+        .filter(|iface| interfaces.contains(&iface.name))
+        .map(|iface| iface.ip())
+        .collect())
+}
+
+/// The subnets of the network interfaces named in `interfaces`, or of all of them,
+/// with the name and index of their interface
+fn interface_subnets(
+    source: &InterfaceSource,
+    interfaces: &[String],
+) -> io::Result<Vec<(String, Option<u32>, Subnet)>> {
+    Ok(source
+        .list()?
+        .into_iter()
+        .filter(|iface| interfaces.is_empty() || interfaces.contains(&iface.name))
+        .map(|iface| {
+            let subnet = Subnet::new(&iface.addr);
+            (iface.name, iface.index, subnet)
+        })
+        .collect())
+}
+
+/// Loopback and link-local addresses, which can't have come from off the link
+fn is_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountingHostData(AtomicUsize);
+
+    impl HostData for CountingHostData {
+        fn hostname(&self) -> io::Result<String> {
+            Ok("counting".into())
+        }
+
+        fn addresses(&self) -> io::Result<Vec<IpAddr>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(vec!["192.0.2.1".parse().unwrap()])
+        }
+    }
+
+    #[test]
+    fn test_address_cache() {
+        let host = Arc::new(CountingHostData::default());
+        let cache = AddressCache::new(
+            host.clone(),
+            Duration::from_secs(60),
+            vec![],
+            InterfaceSource::Process,
+        );
+        assert_eq!(cache.addresses().unwrap().len(), 1);
+        assert_eq!(cache.addresses().unwrap().len(), 1);
+        assert_eq!(host.0.load(Ordering::SeqCst), 1);
+
+        let uncached = AddressCache::new(
+            host.clone(),
+            Duration::ZERO,
+            vec![],
+            InterfaceSource::Process,
+        );
+        uncached.addresses().unwrap();
+        uncached.addresses().unwrap();
+        assert_eq!(host.0.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_subnets() {
+        let subnet = Subnet {
+            ip: "192.0.2.10".parse().unwrap(),
+            netmask: "255.255.255.0".parse().unwrap(),
+        };
+        assert!(subnet.contains("192.0.2.200".parse().unwrap()));
+        assert!(!subnet.contains("198.51.100.1".parse().unwrap()));
+        assert!(!subnet.contains("2001:db8::1".parse().unwrap()));
+
+        let subnet = Subnet {
+            ip: "2001:db8::10".parse().unwrap(),
+            netmask: "ffff:ffff:ffff:ffff::".parse().unwrap(),
+        };
+        assert!(subnet.contains("2001:db8::abcd:1".parse().unwrap()));
+        assert!(!subnet.contains("2001:db8:0:1::1".parse().unwrap()));
+
+        assert!(is_link_local("169.254.3.4".parse().unwrap()));
+        assert!(is_link_local("fe80::1".parse().unwrap()));
+        assert!(is_link_local("::1".parse().unwrap()));
+        assert!(!is_link_local("192.0.2.1".parse().unwrap()));
+        assert!(!is_link_local("fec0::1".parse().unwrap()));
+
+        let subnet = Subnet::from_prefix("192.0.2.10".parse().unwrap(), 24);
+        assert_eq!(subnet.netmask, "255.255.255.0".parse::<IpAddr>().unwrap());
+        let subnet = Subnet::from_prefix("2001:db8::10".parse().unwrap(), 64);
+        assert_eq!(
+            subnet.netmask,
+            "ffff:ffff:ffff:ffff::".parse::<IpAddr>().unwrap()
+        );
+        let subnet = Subnet::from_prefix("192.0.2.10".parse().unwrap(), 0);
+        assert!(subnet.contains("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_host_subnets() {
+        let host = FixedHostData::new("fixed".into(), vec!["192.0.2.5".parse().unwrap()]);
+        let cache = AddressCache::new(
+            Arc::new(host.clone()),
+            Duration::ZERO,
+            vec![],
+            InterfaceSource::Process,
+        );
+        assert!(cache.is_on_link("192.0.2.200".parse().unwrap()));
+        assert!(cache.is_on_link("169.254.3.4".parse().unwrap()));
+        assert!(!cache.is_on_link("198.51.100.1".parse().unwrap()));
+
+        let host = host.with_subnets(vec![("198.51.100.0".parse().unwrap(), 24)]);
+        let cache = AddressCache::new(
+            Arc::new(host),
+            Duration::ZERO,
+            vec![],
+            InterfaceSource::Process,
+        );
+        assert!(!cache.is_on_link("192.0.2.200".parse().unwrap()));
+        assert!(cache.is_on_link("198.51.100.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_address_cache_interfaces() {
+        let host = Arc::new(CountingHostData::default());
+        let cache = AddressCache::new(
+            host.clone(),
+            Duration::ZERO,
+            vec!["no-such-iface0".into()],
+            InterfaceSource::Process,
+        );
+        assert!(cache.addresses().unwrap().is_empty());
+
+        // Nothing but loopback arrives on an interface which doesn't exist
+        assert!(cache.is_from_interfaces("127.0.0.1:5353".parse().unwrap()));
+        assert!(!cache.is_from_interfaces("192.0.2.7:5353".parse().unwrap()));
+        assert!(!cache.is_from_interfaces("[fe80::7%1]:5353".parse().unwrap()));
+
+        let all = AddressCache::new(host, Duration::ZERO, vec![], InterfaceSource::Process);
+        assert!(all.is_from_interfaces("192.0.2.7:5353".parse().unwrap()));
+    }
+}
@@ -0,0 +1,155 @@
+//! Service instance names, which unlike the rest of a DNS-SD name may be any text,
+//! dots and spaces included (RFC 6763 section 4.1.1).
+
+use crate::dns_parser::{escape_label, Name};
+use std::fmt;
+
+/// The instance part of a service name, such as `Living Room. Printer` in
+/// `Living Room\. Printer._ipp._tcp.local`.
+///
+/// The instance is a single label, whatever it contains. Names of services are built
+/// from its [`escaped`](InstanceName::escaped) form, so that a dot in it isn't taken
+/// for a label separator.
+///
+/// ```
+/// use libmdns::InstanceName;
+///
+/// let name = InstanceName::new("Living Room. Printer");
+/// assert_eq!(name.escaped(), "Living Room\\. Printer");
+/// assert_eq!(InstanceName::from_escaped(&name.escaped()), Some(name));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct InstanceName(String);
+
+impl InstanceName {
+    pub fn new<S: Into<String>>(name: S) -> InstanceName {
+        InstanceName(name.into())
+    }
+
+    /// The instance as given, without escapes
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The instance in DNS presentation format, with dots, backslashes and control
+    /// characters escaped
+    pub fn escaped(&self) -> String {
+        let mut escaped = String::with_capacity(self.0.len());
+        escape_label(self.0.as_bytes(), &mut escaped).unwrap();
+        escaped
+    }
+
+    /// Decode an instance in presentation format, such as the first label of a name
+    /// printed with `to_string`. `None` unless it is a single label of UTF-8 text.
+    pub fn from_escaped(escaped: &str) -> Option<InstanceName> {
+        let name = Name::from_str(escaped.to_owned()).ok()?;
+        let mut labels = name.labels();
+        let label = labels.next()?;
+        if labels.next().is_some() {
+            return None;
+        }
+        String::from_utf8(label.to_vec()).ok().map(InstanceName)
+    }
+
+    /// The instance of the service name `name`, i.e. its first label
+    pub(crate) fn of(name: &Name) -> InstanceName {
+        let label = name.labels().next().unwrap_or_default();
+        InstanceName(String::from_utf8_lossy(label).into_owned())
+    }
+}
+
+impl fmt::Display for InstanceName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<&str> for InstanceName {
+    fn from(name: &str) -> InstanceName {
+        InstanceName::new(name)
+    }
+}
+
+impl From<String> for InstanceName {
+    fn from(name: String) -> InstanceName {
+        InstanceName(name)
+    }
+}
+
+impl From<InstanceName> for String {
+    fn from(name: InstanceName) -> String {
+        name.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_parser::{self, NameKey};
+
+    #[test]
+    fn test_instance_name_round_trip() {
+        for instance in &[
+            "printer",
+            "Living Room. Printer",
+            "back\\slash",
+            "...",
+            "Caf\u{e9} \u{1f5a8}",
+            "tab\there",
+        ] {
+            let escaped = InstanceName::new(*instance).escaped();
+            let name = Name::from_str(format!("{}._ipp._tcp.local", escaped)).unwrap();
+            assert_eq!(name.labels().count(), 4, "{}", escaped);
+            assert_eq!(InstanceName::of(&name).as_str(), *instance);
+            assert_eq!(
+                name.to_string(),
+                format!("{}._ipp._tcp.local", escaped),
+                "{}",
+                instance
+            );
+
+            // The same name as received in a packet
+            let mut wire = Vec::new();
+            name.write_to(&mut wire).unwrap();
+            let (parsed, _) = Name::scan(&wire, &wire).unwrap();
+            assert_eq!(parsed, name);
+            assert_eq!(parsed.to_string(), name.to_string());
+            assert_eq!(
+                InstanceName::from_escaped(&escaped).unwrap().as_str(),
+                *instance
+            );
+        }
+        assert_eq!(InstanceName::new("tab\there").escaped(), "tab\\009here");
+    }
+
+    #[test]
+    fn test_decimal_escapes() {
+        let name = Name::from_str("a\\046b\\\\.local").unwrap();
+        assert_eq!(
+            name.labels().collect::<Vec<_>>(),
+            vec![&b"a.b\\"[..], &b"local"[..]]
+        );
+        assert_eq!(
+            NameKey::new(&name),
+            NameKey::new(&Name::from_str("A\\.B\\\\.local").unwrap())
+        );
+        // Not an escape
+        assert_eq!(
+            Name::from_str("trailing\\").unwrap().labels().next(),
+            Some(&b"trailing\\"[..])
+        );
+        assert_eq!(InstanceName::from_escaped("two.labels"), None);
+
+        // Escapes can't make a label longer than DNS allows
+        let long = format!("{}.local", "a\\.".repeat(32));
+        assert!(matches!(
+            Name::from_str(long),
+            Err(dns_parser::Error::LabelTooLong(label)) if label.len() == 3 * 32
+        ));
+
+        // Bytes which aren't UTF-8 are escaped rather than replaced
+        let name = Name::from_str("a\\255b.local").unwrap();
+        assert_eq!(name.labels().next(), Some(&b"a\xffb"[..]));
+        assert_eq!(name.to_string(), "a\\255b.local");
+    }
+}
@@ -0,0 +1,308 @@
+//! What a responder counts. Nothing without the `metrics-minimal` feature, the
+//! counters of `Metrics` with it, and with `metrics-full` the packet size histogram and
+//! the types of the questions asked as well.
+
+#[cfg(feature = "metrics-full")]
+use crate::dns_parser::QueryType;
+use crate::events::DropReason;
+#[cfg(feature = "metrics-minimal")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters describing what a responder has been doing, see [`Responder::metrics`].
+/// Needs the `metrics-minimal` feature, and `metrics-full` for
+/// [`packet_sizes`](Metrics::packet_sizes) and
+/// [`question_types`](Metrics::question_types).
+///
+/// [`Responder::metrics`]: crate::Responder::metrics
+#[cfg(feature = "metrics-minimal")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct Metrics {
+    /// Responses dropped because they waited in the send queue for too long
+    pub stale_responses_dropped: u64,
+    /// Responses dropped because the send queue was full, see
+    /// [`ResponderBuilder::max_queued_responses`]
+    ///
+    /// [`ResponderBuilder::max_queued_responses`]: crate::ResponderBuilder::max_queued_responses
+    pub responses_dropped_queue_full: u64,
+    /// Queries which got at least one answer
+    pub packets_answered: u64,
+    /// Queries which were dropped or had nothing to answer
+    pub packets_ignored: u64,
+    /// Packets which could not be parsed
+    pub packets_parse_failed: u64,
+    /// Questions ignored because they repeated another in the same packet
+    pub duplicate_questions: u64,
+    /// Queries refused because they came from off the local link
+    pub off_link_queries: u64,
+    /// Queries dropped because they had the TC bit set
+    pub truncated_queries: u64,
+    /// Queries dropped because they arrived on another socket already
+    pub duplicate_queries: u64,
+    /// Our own responses, brought back by multicast loopback
+    pub own_packets: u64,
+    /// Sends which failed with an error that paused sending, such as ENOBUFS
+    pub send_errors: u64,
+    /// Packets dropped after failing to send several times
+    pub send_dropped: u64,
+    /// Entries dropped from the caches filled from network traffic, or not kept in
+    /// them, to stay within [`ResponderBuilder::memory_budget`]
+    ///
+    /// [`ResponderBuilder::memory_budget`]: crate::ResponderBuilder::memory_budget
+    pub cache_evictions: u64,
+    /// Packets dropped because handling them panicked, see
+    /// [`ResponderBuilder::on_packet_panic`]
+    ///
+    /// [`ResponderBuilder::on_packet_panic`]: crate::ResponderBuilder::on_packet_panic
+    pub packets_panicked: u64,
+    /// Packets dropped for arriving from a network interface other than those
+    /// selected with [`ResponderBuilder::interfaces`]
+    ///
+    /// [`ResponderBuilder::interfaces`]: crate::ResponderBuilder::interfaces
+    pub other_interface_packets: u64,
+    /// Sizes of the packets handed out to be sent
+    #[cfg(feature = "metrics-full")]
+    pub packet_sizes: PacketSizes,
+    /// Questions received, by type
+    #[cfg(feature = "metrics-full")]
+    pub question_types: QuestionTypes,
+}
+
+/// Upper bounds, in bytes, of the buckets of [`PacketSizes::buckets`]: the limit of
+/// DNS over UDP without EDNS, 1 KiB, the usual EDNS buffer size, and the largest
+/// payload of a 1500 byte Ethernet frame over IPv6 and over IPv4. Larger packets are
+/// fragmented on most networks.
+#[cfg(feature = "metrics-full")]
+pub const PACKET_SIZE_BOUNDS: [u64; 5] = [512, 1024, 1232, 1452, 1472];
+
+/// A histogram of the sizes of outgoing packets, see [`Metrics::packet_sizes`].
+#[cfg(feature = "metrics-full")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct PacketSizes {
+    /// Packets of at most each of [`PACKET_SIZE_BOUNDS`] bytes, but more than the
+    /// bound before, with the last bucket counting those larger than all of them
+    pub buckets: [u64; PACKET_SIZE_BOUNDS.len() + 1],
+    pub packets: u64,
+    pub bytes: u64,
+    /// Size of the largest packet
+    pub largest: u64,
+}
+
+/// The questions received of each type the responder answers, and of all others,
+/// see [`Metrics::question_types`].
+#[cfg(feature = "metrics-full")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct QuestionTypes {
+    pub a: u64,
+    pub aaaa: u64,
+    pub ptr: u64,
+    pub srv: u64,
+    pub txt: u64,
+    /// Questions of type ANY
+    pub all: u64,
+    pub other: u64,
+}
+
+/// The live counters behind `Metrics`, shared between the tasks of a responder.
+/// Without the metrics features it is empty and counting compiles to nothing.
+#[derive(Default)]
+pub struct Counters {
+    #[cfg(feature = "metrics-minimal")]
+    minimal: MinimalCounters,
+    #[cfg(feature = "metrics-full")]
+    full: FullCounters,
+}
+
+#[cfg(feature = "metrics-minimal")]
+#[derive(Default)]
+struct MinimalCounters {
+    stale_responses_dropped: AtomicU64,
+    responses_dropped_queue_full: AtomicU64,
+    packets_answered: AtomicU64,
+    packets_ignored: AtomicU64,
+    packets_parse_failed: AtomicU64,
+    duplicate_questions: AtomicU64,
+    off_link_queries: AtomicU64,
+    truncated_queries: AtomicU64,
+    duplicate_queries: AtomicU64,
+    own_packets: AtomicU64,
+    send_errors: AtomicU64,
+    send_dropped: AtomicU64,
+    cache_evictions: AtomicU64,
+    packets_panicked: AtomicU64,
+    other_interface_packets: AtomicU64,
+}
+
+#[cfg(feature = "metrics-full")]
+#[derive(Default)]
+struct FullCounters {
+    packet_sizes: [AtomicU64; PACKET_SIZE_BOUNDS.len() + 1],
+    packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    largest_packet: AtomicU64,
+    /// indexed like the fields of `QuestionTypes`
+    question_types: [AtomicU64; 7],
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Counters::default()
+    }
+
+    pub fn stale_response_dropped(&self) {
+        #[cfg(feature = "metrics-minimal")]
+        self.minimal
+            .stale_responses_dropped
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn response_queue_full(&self) {
+        #[cfg(feature = "metrics-minimal")]
+        self.minimal
+            .responses_dropped_queue_full
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn packet_answered(&self) {
+        #[cfg(feature = "metrics-minimal")]
+        self.minimal
+            .packets_answered
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn packet_ignored(&self) {
+        #[cfg(feature = "metrics-minimal")]
+        self.minimal.packets_ignored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a packet dropped for `reason`. Dropped queries count as ignored as well.
+    #[cfg_attr(not(feature = "metrics-minimal"), allow(unused_variables))]
+    pub fn packet_dropped(&self, reason: DropReason) {
+        #[cfg(feature = "metrics-minimal")]
+        {
+            let minimal = &self.minimal;
+            let (counter, query) = match reason {
+                DropReason::Unparseable => (&minimal.packets_parse_failed, false),
+                DropReason::OwnPacket => (&minimal.own_packets, false),
+                DropReason::Truncated => (&minimal.truncated_queries, true),
+                DropReason::OffLink => (&minimal.off_link_queries, true),
+                DropReason::Duplicate => (&minimal.duplicate_queries, true),
+                DropReason::Panicked => (&minimal.packets_panicked, false),
+                DropReason::OtherInterface => (&minimal.other_interface_packets, false),
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            if query {
+                self.packet_ignored();
+            }
+        }
+    }
+
+    #[cfg_attr(not(feature = "metrics-minimal"), allow(unused_variables))]
+    pub fn duplicate_questions(&self, count: u64) {
+        #[cfg(feature = "metrics-minimal")]
+        self.minimal
+            .duplicate_questions
+            .fetch_add(count, Ordering::Relaxed);
+    }
+
+    #[cfg(any(feature = "responder", feature = "sync"))]
+    pub fn send_error(&self) {
+        #[cfg(feature = "metrics-minimal")]
+        self.minimal.send_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(any(feature = "responder", feature = "sync"))]
+    pub fn send_dropped(&self) {
+        #[cfg(feature = "metrics-minimal")]
+        self.minimal.send_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache_evicted(&self) {
+        #[cfg(feature = "metrics-minimal")]
+        self.minimal.cache_evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg_attr(not(feature = "metrics-full"), allow(unused_variables))]
+    pub fn packet_sent(&self, size: usize) {
+        #[cfg(feature = "metrics-full")]
+        {
+            let size = size as u64;
+            let bucket = PACKET_SIZE_BOUNDS
+                .iter()
+                .position(|&bound| size <= bound)
+                .unwrap_or(PACKET_SIZE_BOUNDS.len());
+            let full = &self.full;
+            full.packet_sizes[bucket].fetch_add(1, Ordering::Relaxed);
+            full.packets_sent.fetch_add(1, Ordering::Relaxed);
+            full.bytes_sent.fetch_add(size, Ordering::Relaxed);
+            full.largest_packet.fetch_max(size, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(feature = "metrics-full")]
+    pub fn question(&self, qtype: QueryType) {
+        let index = match qtype {
+            QueryType::A => 0,
+            QueryType::AAAA => 1,
+            QueryType::PTR => 2,
+            QueryType::SRV => 3,
+            QueryType::TXT => 4,
+            QueryType::All => 5,
+            _ => 6,
+        };
+        self.full.question_types[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(not(feature = "metrics-full"))]
+    pub fn question(&self, _qtype: crate::dns_parser::QueryType) {}
+
+    #[cfg(feature = "metrics-minimal")]
+    pub fn metrics(&self) -> Metrics {
+        let minimal = &self.minimal;
+        Metrics {
+            stale_responses_dropped: minimal.stale_responses_dropped.load(Ordering::Relaxed),
+            responses_dropped_queue_full: minimal
+                .responses_dropped_queue_full
+                .load(Ordering::Relaxed),
+            packets_answered: minimal.packets_answered.load(Ordering::Relaxed),
+            packets_ignored: minimal.packets_ignored.load(Ordering::Relaxed),
+            packets_parse_failed: minimal.packets_parse_failed.load(Ordering::Relaxed),
+            duplicate_questions: minimal.duplicate_questions.load(Ordering::Relaxed),
+            off_link_queries: minimal.off_link_queries.load(Ordering::Relaxed),
+            truncated_queries: minimal.truncated_queries.load(Ordering::Relaxed),
+            duplicate_queries: minimal.duplicate_queries.load(Ordering::Relaxed),
+            own_packets: minimal.own_packets.load(Ordering::Relaxed),
+            send_errors: minimal.send_errors.load(Ordering::Relaxed),
+            send_dropped: minimal.send_dropped.load(Ordering::Relaxed),
+            cache_evictions: minimal.cache_evictions.load(Ordering::Relaxed),
+            packets_panicked: minimal.packets_panicked.load(Ordering::Relaxed),
+            other_interface_packets: minimal.other_interface_packets.load(Ordering::Relaxed),
+            #[cfg(feature = "metrics-full")]
+            packet_sizes: PacketSizes {
+                buckets: std::array::from_fn(|i| self.full.packet_sizes[i].load(Ordering::Relaxed)),
+                packets: self.full.packets_sent.load(Ordering::Relaxed),
+                bytes: self.full.bytes_sent.load(Ordering::Relaxed),
+                largest: self.full.largest_packet.load(Ordering::Relaxed),
+            },
+            #[cfg(feature = "metrics-full")]
+            question_types: {
+                let [a, aaaa, ptr, srv, txt, all, other]: [u64; 7] =
+                    std::array::from_fn(|i| self.full.question_types[i].load(Ordering::Relaxed));
+                QuestionTypes {
+                    a,
+                    aaaa,
+                    ptr,
+                    srv,
+                    txt,
+                    all,
+                    other,
+                }
+            },
+        }
+    }
+}
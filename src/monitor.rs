@@ -0,0 +1,172 @@
+//! Capturing the mDNS traffic on the network, for debugging discovery problems.
+
+use crate::address_family::{AddressFamily, Inet, Inet6};
+#[cfg(feature = "parser")]
+use crate::dns_parser::{self, Packet, RecordError};
+use crate::error::Error;
+use log::{debug, warn};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often the receiving threads check whether the `Monitor` is still there
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A packet received on the mDNS port, see [`monitor`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Captured {
+    pub from: SocketAddr,
+    pub at: SystemTime,
+    pub data: Vec<u8>,
+}
+
+impl Captured {
+    /// Parse the packet, skipping the records which can't be parsed and returning
+    /// why with it, see [`Packet::parse_lenient`].
+    #[cfg(feature = "parser")]
+    pub fn parse(&self) -> Result<(Packet<'_>, Vec<RecordError>), dns_parser::Error> {
+        Packet::parse_lenient(&self.data)
+    }
+}
+
+/// Every mDNS packet on the network, in the order received, see [`monitor`].
+/// Iterating blocks until the next packet arrives.
+pub struct Monitor {
+    packets: mpsc::Receiver<Captured>,
+    /// tells the receiving threads to stop, even when no packet arrives to find the
+    /// channel closed
+    stopped: Arc<AtomicBool>,
+}
+
+impl Iterator for Monitor {
+    type Item = Captured;
+
+    fn next(&mut self) -> Option<Captured> {
+        self.packets.recv().ok()
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Listen to the mDNS traffic of every interface, queries and responses alike, from
+/// this host and others, e.g. to see what a device which can't be found sends.
+///
+/// Joins the mDNS groups of IPv4 and IPv6 on port 5353, alongside any responders on
+/// the host, and only fails if neither family can be listened to. Packets are read on
+/// a thread per family until the `Monitor` is dropped.
+///
+/// ```no_run
+/// for packet in libmdns::monitor()? {
+///     println!("{} bytes from {}", packet.data.len(), packet.from);
+/// }
+/// # Ok::<(), libmdns::Error>(())
+/// ```
+pub fn monitor() -> Result<Monitor, Error> {
+    let (tx, rx) = mpsc::channel();
+    let stopped = Arc::new(AtomicBool::new(false));
+    let v4 = Inet::bind(&[]).and_then(|socket| listen(socket, "IPv4", tx.clone(), &stopped));
+    let v6 = Inet6::bind(&[]).and_then(|socket| listen(socket, "IPv6", tx, &stopped));
+    match (v4, v6) {
+        (Err(err), Err(_)) => Err(err),
+        (v4, v6) => {
+            if let Some(err) = v4.err().or_else(|| v6.err()) {
+                warn!("monitoring one address family only: {}", err);
+            }
+            Ok(Monitor {
+                packets: rx,
+                stopped,
+            })
+        }
+    }
+}
+
+fn listen(
+    socket: UdpSocket,
+    family: &str,
+    tx: mpsc::Sender<Captured>,
+    stopped: &Arc<AtomicBool>,
+) -> Result<(), Error> {
+    socket.set_nonblocking(false)?;
+    socket.set_read_timeout(Some(STOP_CHECK_INTERVAL))?;
+    let stopped = stopped.clone();
+    thread::Builder::new()
+        .name(format!("mdns-monitor-{}", family))
+        .spawn(move || receive(&socket, &tx, &stopped))?;
+    Ok(())
+}
+
+fn receive(socket: &UdpSocket, tx: &mpsc::Sender<Captured>, stopped: &AtomicBool) {
+    let mut buf = vec![0; 9000];
+    while !stopped.load(Ordering::Relaxed) {
+        let captured = match socket.recv_from(&mut buf) {
+            Ok((len, from)) => Captured {
+                from,
+                at: SystemTime::now(),
+                data: buf[..len].to_vec(),
+            },
+            Err(ref err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                continue;
+            }
+            Err(err) => {
+                warn!("stopped monitoring: {}", err);
+                return;
+            }
+        };
+        if tx.send(captured).is_err() {
+            debug!("monitor dropped");
+            return;
+        }
+    }
+    debug!("monitor dropped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "parser")]
+    use crate::dns_parser::QueryType;
+
+    #[test]
+    fn test_stops_when_dropped() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let (tx, rx) = mpsc::channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let monitor = Monitor {
+            packets: rx,
+            stopped: stopped.clone(),
+        };
+        let receiver = thread::spawn(move || receive(&socket, &tx, &stopped));
+
+        // Without any packet to find the channel closed by
+        drop(monitor);
+        receiver.join().unwrap();
+    }
+
+    #[cfg(feature = "parser")]
+    #[test]
+    fn test_parse_captured() {
+        let mut data = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(b"\x04test\x05local\x00\x00\x01\x80\x01");
+        let captured = Captured {
+            from: "192.168.1.5:5353".parse().unwrap(),
+            at: SystemTime::now(),
+            data,
+        };
+        let (packet, errors) = captured.parse().unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(packet.questions[0].qname.to_string(), "test.local");
+        assert_eq!(packet.questions[0].qtype, QueryType::A);
+        assert!(packet.questions[0].qu);
+    }
+}
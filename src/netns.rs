@@ -0,0 +1,240 @@
+//! Creation of sockets in another network namespace than the process runs in, such
+//! as that of a container, on Linux.
+//!
+//! A namespace is entered by a short-lived thread, which creates the sockets and
+//! exits. Sockets stay in the namespace they were created in, so the rest of the
+//! responder can run on any thread. The network interfaces of the namespace are
+//! listed by a thread which stays in it, see [`Interfaces`].
+
+use crate::error::Error;
+use if_addrs::Interface;
+use std::fmt;
+use std::io;
+#[cfg(unix)]
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// The network namespace set with
+/// [`ResponderBuilder::network_namespace`](crate::ResponderBuilder::network_namespace)
+#[derive(Clone, Debug)]
+pub enum NetworkNamespace {
+    /// A namespace file, such as `/var/run/netns/<name>` or `/proc/<pid>/ns/net`
+    Path(PathBuf),
+    /// An open namespace file
+    #[cfg(unix)]
+    Fd(Arc<OwnedFd>),
+}
+
+impl fmt::Display for NetworkNamespace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NetworkNamespace::Path(ref path) => write!(f, "{}", path.display()),
+            #[cfg(unix)]
+            NetworkNamespace::Fd(ref fd) => {
+                use std::os::fd::AsRawFd;
+                write!(f, "fd {}", fd.as_raw_fd())
+            }
+        }
+    }
+}
+
+/// A request for the network interfaces, answered on the sender
+type Request = mpsc::Sender<io::Result<Vec<Interface>>>;
+
+/// Lists the network interfaces of a namespace from a thread which entered it once,
+/// so that they can be listed again after the privilege to enter it is given up.
+/// The thread exits once this is dropped.
+pub struct Interfaces {
+    requests: Mutex<mpsc::Sender<Request>>,
+}
+
+impl Interfaces {
+    /// Start the thread listing the interfaces of `netns`
+    pub fn start(netns: &NetworkNamespace) -> Result<Arc<Interfaces>, Error> {
+        let requests = imp::serve(netns)?;
+        Ok(Arc::new(Interfaces {
+            requests: Mutex::new(requests),
+        }))
+    }
+
+    pub fn list(&self) -> io::Result<Vec<Interface>> {
+        let gone = || io::Error::new(io::ErrorKind::BrokenPipe, "namespace thread exited");
+        let (tx, rx) = mpsc::channel();
+        self.requests.lock().unwrap_or_else(PoisonError::into_inner).send(tx).map_err(|_| gone())?;
+        rx.recv().map_err(|_| gone())?
+    }
+}
+
+impl fmt::Debug for Interfaces {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Interfaces").finish_non_exhaustive()
+    }
+}
+
+/// Run `f` in the network namespace `netns`, or right away if there is none
+pub fn within<T, F>(netns: Option<&NetworkNamespace>, f: F) -> Result<T, Error>
+where
+    T: Send,
+    F: FnOnce() -> Result<T, Error> + Send,
+{
+    match netns {
+        Some(netns) => imp::within(netns, f),
+        None => f(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::{NetworkNamespace, Request};
+    use crate::error::Error;
+    use if_addrs::get_if_addrs;
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::panic::resume_unwind;
+    use std::sync::mpsc;
+    use std::thread;
+
+    pub fn within<T, F>(netns: &NetworkNamespace, f: F) -> Result<T, Error>
+    where
+        T: Send,
+        F: FnOnce() -> Result<T, Error> + Send,
+    {
+        thread::scope(|scope| {
+            let entered = thread::Builder::new()
+                .name("mdns-netns".to_owned())
+                .spawn_scoped(scope, || {
+                    enter(netns).map_err(|source| Error::NetworkNamespace {
+                        namespace: netns.to_string(),
+                        source,
+                    })?;
+                    f()
+                })?;
+            entered.join().unwrap_or_else(|panic| resume_unwind(panic))
+        })
+    }
+
+    /// Spawn a thread which enters `netns` and lists its interfaces for each request
+    pub fn serve(netns: &NetworkNamespace) -> Result<mpsc::Sender<Request>, Error> {
+        let (requests, received) = mpsc::channel::<Request>();
+        let (entered_tx, entered) = mpsc::channel();
+        let namespace = netns.clone();
+        thread::Builder::new()
+            .name("mdns-netns".to_owned())
+            .spawn(move || {
+                let result = enter(&namespace);
+                let ok = result.is_ok();
+                let _ = entered_tx.send(result);
+                if ok {
+                    for reply in received {
+                        let _ = reply.send(get_if_addrs());
+                    }
+                }
+            })?;
+        match entered.recv() {
+            Ok(Ok(())) => Ok(requests),
+            Ok(Err(source)) => Err(Error::NetworkNamespace {
+                namespace: netns.to_string(),
+                source,
+            }),
+            Err(_) => {
+                let gone = io::Error::new(io::ErrorKind::BrokenPipe, "namespace thread exited");
+                Err(gone.into())
+            }
+        }
+    }
+
+    /// Move the calling thread into `netns`
+    fn enter(netns: &NetworkNamespace) -> io::Result<()> {
+        match *netns {
+            NetworkNamespace::Path(ref path) => setns(File::open(path)?.as_raw_fd()),
+            NetworkNamespace::Fd(ref fd) => setns(fd.as_raw_fd()),
+        }
+    }
+
+    fn setns(fd: RawFd) -> io::Result<()> {
+        // Safety: setns only reads the descriptor, which outlives the call
+        match unsafe { libc::setns(fd, libc::CLONE_NEWNET) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::{NetworkNamespace, Request};
+    use crate::error::Error;
+    use std::sync::mpsc;
+
+    pub fn within<T, F>(_netns: &NetworkNamespace, _f: F) -> Result<T, Error> {
+        Err(Error::NetworkNamespaceUnsupported)
+    }
+
+    pub fn serve(_netns: &NetworkNamespace) -> Result<mpsc::Sender<Request>, Error> {
+        Err(Error::NetworkNamespaceUnsupported)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io;
+
+    #[test]
+    fn test_within() {
+        // Our own namespace can always be entered again, given the privilege to
+        let current = NetworkNamespace::Path("/proc/self/ns/net".into());
+        match within(Some(&current), || Ok(thread_netns())) {
+            Ok(netns) => assert_eq!(netns, thread_netns()),
+            Err(Error::NetworkNamespace { ref source, .. })
+                if source.kind() == io::ErrorKind::PermissionDenied => {}
+            Err(err) => panic!("{}", err),
+        }
+
+        let missing = NetworkNamespace::Path("/nonexistent/netns".into());
+        match within(Some(&missing), || Ok(())) {
+            Err(Error::NetworkNamespace { namespace, source }) => {
+                assert_eq!(namespace, "/nonexistent/netns");
+                assert_eq!(source.kind(), io::ErrorKind::NotFound);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_interfaces() {
+        let current = NetworkNamespace::Path("/proc/self/ns/net".into());
+        let interfaces = match Interfaces::start(&current) {
+            Ok(interfaces) => interfaces,
+            Err(Error::NetworkNamespace { ref source, .. })
+                if source.kind() == io::ErrorKind::PermissionDenied =>
+            {
+                return
+            }
+            Err(err) => panic!("{}", err),
+        };
+        // Listed again and again by the same thread
+        let names = |listed: Vec<Interface>| {
+            let mut names: Vec<String> = listed.into_iter().map(|iface| iface.name).collect();
+            names.sort();
+            names
+        };
+        let expected = names(if_addrs::get_if_addrs().unwrap());
+        assert_eq!(names(interfaces.list().unwrap()), expected);
+        assert_eq!(names(interfaces.list().unwrap()), expected);
+
+        let missing = NetworkNamespace::Path("/nonexistent/netns".into());
+        assert!(matches!(
+            Interfaces::start(&missing),
+            Err(Error::NetworkNamespace { .. })
+        ));
+    }
+
+    fn thread_netns() -> PathBuf {
+        fs::read_link("/proc/thread-self/ns/net").unwrap()
+    }
+}
@@ -0,0 +1,78 @@
+use std::time::{Duration, Instant};
+
+/// Limits announcements and goodbyes to `rate` packets per second, allowing a burst
+/// of up to one second's worth after a quiet period.
+#[derive(Debug)]
+pub struct Pacer {
+    rate: u32,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Pacer {
+    pub fn new(rate: u32, now: Instant) -> Pacer {
+        assert!(
+            rate > 0,
+            "announce rate must be at least one packet per second"
+        );
+        Pacer {
+            rate,
+            tokens: rate as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.updated {
+            let elapsed = (now - self.updated).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+            self.updated = now;
+        }
+    }
+
+    /// When the next packet may be sent, `now` if it may be sent straight away
+    pub fn ready_at(&mut self, now: Instant) -> Instant {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            now
+        } else {
+            now + Duration::from_secs_f64((1.0 - self.tokens) / self.rate as f64)
+        }
+    }
+
+    /// Account for a packet being sent
+    pub fn take(&mut self, now: Instant) {
+        self.refill(now);
+        self.tokens -= 1.0;
+    }
+
+    /// Whether the pacer has recovered fully, and so is no different from a new one
+    pub fn is_idle(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.rate as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pacer() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(2, start);
+        assert!(pacer.is_idle(start));
+
+        pacer.take(start);
+        pacer.take(start);
+        assert_eq!(pacer.ready_at(start), start + Duration::from_millis(500));
+        assert!(!pacer.is_idle(start));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(pacer.ready_at(later), later);
+        pacer.take(later);
+        assert!(pacer.ready_at(later) > later);
+
+        assert!(pacer.is_idle(later + Duration::from_secs(5)));
+    }
+}
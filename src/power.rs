@@ -0,0 +1,131 @@
+//! Announcing the services again when the host wakes from sleep, on the
+//! `PrepareForSleep` signal of systemd-logind on Linux. Elsewhere, applications which
+//! learn of resumes themselves call [`Responder::notify_resumed`].
+
+use crate::responder::WeakResponder;
+use crate::Responder;
+use log::debug;
+use std::io;
+
+/// Watches for the host resuming from sleep, started with
+/// [`Responder::watch_power_events`]. Calls
+/// [`notify_resumed`](Responder::notify_resumed) on each resume, until dropped.
+pub struct PowerWatcher {
+    _platform: platform::Watcher,
+}
+
+impl PowerWatcher {
+    pub(crate) fn start(responder: &Responder) -> io::Result<PowerWatcher> {
+        let responder = WeakResponder::new(responder);
+        let watcher = platform::Watcher::start(Box::new(move || {
+            debug!("host resumed from sleep");
+            if let Some(responder) = responder.upgrade() {
+                responder.notify_resumed();
+            }
+        }))?;
+        Ok(PowerWatcher { _platform: watcher })
+    }
+}
+
+type OnResume = Box<dyn Fn() + Send + Sync>;
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::OnResume;
+    use futures_util::future::{self, Either};
+    use futures_util::StreamExt;
+    use log::warn;
+    use std::io;
+    use std::sync::mpsc;
+    use std::thread::{self, JoinHandle};
+    use tokio::sync::oneshot;
+
+    pub struct Watcher {
+        stop: Option<oneshot::Sender<()>>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Watcher {
+        pub fn start(on_resume: OnResume) -> io::Result<Watcher> {
+            let (started_tx, started_rx) = mpsc::sync_channel(0);
+            let (stop, stopped) = oneshot::channel();
+            let thread = thread::Builder::new()
+                .name("mdns-power".into())
+                .spawn(move || {
+                    let rt = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .unwrap();
+                    rt.block_on(watch(on_resume, started_tx, stopped));
+                })?;
+            // Without a system bus, or logind on it, resumes can't be told of
+            match started_rx.recv() {
+                Ok(Ok(())) => Ok(Watcher {
+                    stop: Some(stop),
+                    thread: Some(thread),
+                }),
+                Ok(Err(err)) => Err(io::Error::new(io::ErrorKind::NotConnected, err)),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "power event thread exited",
+                )),
+            }
+        }
+    }
+
+    async fn watch(
+        on_resume: OnResume,
+        started: mpsc::SyncSender<zbus::Result<()>>,
+        mut stopped: oneshot::Receiver<()>,
+    ) {
+        let subscribed = async {
+            let connection = zbus::Connection::system().await?;
+            let proxy = zbus::Proxy::new(
+                &connection,
+                "org.freedesktop.login1",
+                "/org/freedesktop/login1",
+                "org.freedesktop.login1.Manager",
+            )
+            .await?;
+            proxy.receive_signal("PrepareForSleep").await
+        };
+        let mut signals = match subscribed.await {
+            Ok(signals) => {
+                let _ = started.send(Ok(()));
+                signals
+            }
+            Err(err) => {
+                let _ = started.send(Err(err));
+                return;
+            }
+        };
+
+        loop {
+            let signal = match future::select(signals.next(), &mut stopped).await {
+                Either::Left((Some(signal), _)) => signal,
+                Either::Left((None, _)) => {
+                    warn!("lost the connection to systemd-logind");
+                    return;
+                }
+                Either::Right(_) => return,
+            };
+            // The argument is true before sleeping, and false once awake again
+            match signal.body::<bool>() {
+                Ok(false) => on_resume(),
+                Ok(true) => (),
+                Err(err) => warn!("unexpected PrepareForSleep signal: {}", err),
+            }
+        }
+    }
+
+    impl Drop for Watcher {
+        fn drop(&mut self) {
+            if let Some(stop) = self.stop.take() {
+                let _ = stop.send(());
+            }
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Checks whether mDNS can work on this machine, before starting a responder.
+
+use crate::address_family::{AddressFamily, Inet, Inet6};
+use crate::dns_parser::{Builder, Name, Packet, QueryClass, QueryType};
+use crate::error::Error;
+use crate::MDNS_PORT;
+use log::debug;
+use rand::{thread_rng, Rng};
+use socket2::{SockAddr, Socket};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The outcome of [`Responder::preflight`](crate::Responder::preflight).
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct PreflightReport {
+    pub ipv4: FamilyReport,
+    pub ipv6: FamilyReport,
+}
+
+impl PreflightReport {
+    /// Whether a responder can send and receive multicast on at least one family.
+    pub fn mdns_works(&self) -> bool {
+        self.ipv4.works() || self.ipv6.works()
+    }
+}
+
+/// The checks for one IP family.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct FamilyReport {
+    /// Why port 5353 couldn't be bound, in which case nothing else was checked
+    pub bind_error: Option<Error>,
+    /// Joining the mDNS group, per interface
+    pub interfaces: Vec<InterfaceReport>,
+    /// How long our own query took to come back to us over multicast, or `None`
+    /// if it didn't within the timeout
+    pub loopback: Option<Duration>,
+}
+
+impl FamilyReport {
+    /// Whether the port was bound, a group joined and our query looped back.
+    pub fn works(&self) -> bool {
+        self.bind_error.is_none()
+            && self.interfaces.iter().any(|iface| iface.error.is_none())
+            && self.loopback.is_some()
+    }
+}
+
+/// Whether the mDNS group could be joined on an interface.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct InterfaceReport {
+    pub name: String,
+    pub error: Option<io::Error>,
+}
+
+pub fn run(interfaces: &[String], timeout: Duration) -> PreflightReport {
+    PreflightReport {
+        ipv4: check::<Inet>(interfaces, timeout),
+        ipv6: check::<Inet6>(interfaces, timeout),
+    }
+}
+
+fn check<AF: AddressFamily>(interfaces: &[String], timeout: Duration) -> FamilyReport {
+    let socket = match AF::bind_unjoined() {
+        Ok(socket) => socket,
+        Err(err) => return family_report(Err(err), || Ok(None)),
+    };
+    let joined = AF::join_each(&socket, &AF::MDNS_GROUP, interfaces);
+    family_report(joined, || loopback::<AF>(&socket, timeout))
+}
+
+/// The report for a family whose socket was bound and `joined` to the group on each
+/// interface, or `Err` with why either failed. `loopback` is only tried once the
+/// group has been joined on some interface.
+fn family_report(
+    joined: Result<Vec<(String, io::Result<()>)>, Error>,
+    loopback: impl FnOnce() -> io::Result<Option<Duration>>,
+) -> FamilyReport {
+    let mut report = FamilyReport {
+        bind_error: None,
+        interfaces: Vec::new(),
+        loopback: None,
+    };
+
+    match joined {
+        Ok(results) => {
+            report.interfaces = results
+                .into_iter()
+                .map(|(name, joined)| InterfaceReport {
+                    name,
+                    error: joined.err(),
+                })
+                .collect();
+        }
+        Err(err) => {
+            // Not bound, or no interface to try at all
+            report.bind_error = Some(err);
+            return report;
+        }
+    }
+
+    if report.interfaces.iter().any(|iface| iface.error.is_none()) {
+        report.loopback = loopback().unwrap_or_else(|err| {
+            debug!("preflight loopback check failed: {}", err);
+            None
+        });
+    }
+    report
+}
+
+/// Send a query for a name nobody should answer, and wait for the copy which
+/// multicast loopback delivers back to us
+fn loopback<AF: AddressFamily>(socket: &Socket, timeout: Duration) -> io::Result<Option<Duration>> {
+    let id = thread_rng().gen();
+    let name = format!("libmdns-preflight-{:08x}.local", thread_rng().gen::<u32>());
+    let name = Name::from_str(name).expect("valid preflight name");
+    let query = Builder::new_query(id, false)
+        .add_question(&name, QueryType::TXT, QueryClass::IN)
+        .build()
+        .unwrap_or_else(|x| x);
+
+    socket.set_nonblocking(false)?;
+    let group = SockAddr::from(SocketAddr::new(AF::MDNS_GROUP.into(), MDNS_PORT));
+    let sent = Instant::now();
+    socket.send_to(&query, &group)?;
+
+    let socket: std::net::UdpSocket = socket.try_clone()?.into();
+    let mut buf = [0u8; 4096];
+    loop {
+        let elapsed = sent.elapsed();
+        if elapsed >= timeout {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(timeout - elapsed))?;
+        let len = match socket.recv_from(&mut buf) {
+            Ok((len, _)) => len,
+            Err(err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Ok(None)
+            }
+            Err(err) => return Err(err),
+        };
+        // Other traffic on the group is skipped
+        if let Ok(packet) = Packet::parse(&buf[..len]) {
+            if packet.header.query && packet.header.id == id {
+                return Ok(Some(sent.elapsed()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::IpFamily;
+
+    fn joined(results: &[(&str, bool)]) -> Result<Vec<(String, io::Result<()>)>, Error> {
+        Ok(results
+            .iter()
+            .map(|&(name, joined)| {
+                let result = match joined {
+                    true => Ok(()),
+                    false => Err(io::Error::from(io::ErrorKind::AddrNotAvailable)),
+                };
+                (name.to_owned(), result)
+            })
+            .collect())
+    }
+
+    #[test]
+    fn test_family_report() {
+        let looped_back = || Ok(Some(Duration::from_millis(3)));
+
+        let report = family_report(joined(&[("eth0", false), ("wlan0", true)]), looped_back);
+        let names: Vec<_> = report.interfaces.iter().map(|iface| &iface.name).collect();
+        assert_eq!(names, ["eth0", "wlan0"]);
+        assert!(report.interfaces[0].error.is_some());
+        assert!(report.interfaces[1].error.is_none());
+        assert_eq!(report.loopback, Some(Duration::from_millis(3)));
+        assert!(report.works());
+
+        // Without a group joined there is nothing to loop back
+        let report = family_report(joined(&[("eth0", false)]), || {
+            panic!("loopback tried without a group joined")
+        });
+        assert!(report.loopback.is_none());
+        assert!(!report.works());
+
+        // Nor when the query doesn't come back, or sending it fails
+        let silent = family_report(joined(&[("eth0", true)]), || Ok(None));
+        assert!(!silent.works());
+        let failed = family_report(joined(&[("eth0", true)]), || {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+        assert!(failed.loopback.is_none());
+
+        let unbound = Err(Error::NoMatchingInterface {
+            family: IpFamily::V6,
+            interfaces: vec!["eth7".into()],
+        });
+        let report = family_report(unbound, looped_back);
+        assert!(report.bind_error.is_some());
+        assert!(report.interfaces.is_empty());
+        assert!(!report.works());
+
+        let ipv4 = family_report(joined(&[("eth0", true)]), looped_back);
+        let ipv6 = family_report(joined(&[("eth0", true)]), || Ok(None));
+        assert!(PreflightReport { ipv4, ipv6 }.mdns_works());
+        let ipv4 = family_report(joined(&[]), looped_back);
+        let ipv6 = family_report(joined(&[("eth0", false)]), looped_back);
+        assert!(!PreflightReport { ipv4, ipv6 }.mdns_works());
+    }
+}
@@ -0,0 +1,64 @@
+//! Ready-made descriptions of services whose TXT records other software expects in
+//! an exact form.
+
+use crate::ServiceBuilder;
+
+/// The service type of Spotify Connect receivers
+pub const SPOTIFY_CONNECT_SERVICE_TYPE: &str = "_spotify-connect._tcp";
+
+/// The TXT record of a Spotify Connect receiver, as librespot publishes it: the
+/// version of the ZeroConf API, the path of its `getInfo`/`addUser` endpoint on
+/// `port`, and the stack it is built on.
+pub const SPOTIFY_CONNECT_TXT: [&str; 3] = ["VERSION=1.0", "CPath=/", "Stack=SP"];
+
+/// The TXT key under which [`spotify_connect`] publishes the device id
+pub const SPOTIFY_CONNECT_DEVICE_ID_KEY: &str = "deviceid";
+
+/// A Spotify Connect receiver named `name` with the id `device_id`, whose ZeroConf
+/// HTTP server listens on `port`, for librespot-style discovery.
+///
+/// The device id is published in the TXT record besides [`SPOTIFY_CONNECT_TXT`], so
+/// that clients can tell devices apart before asking their `getInfo` endpoint,
+/// which reports the same id as `deviceID`.
+///
+/// ```no_run
+/// # #[cfg(feature = "responder")]
+/// use libmdns::{profiles, Responder};
+///
+/// # use std::io;
+/// # #[cfg(feature = "responder")]
+/// # fn main() -> io::Result<()> {
+/// let responder = Responder::new()?;
+/// let device_id = "4a3b2c1d4a3b2c1d4a3b2c1d4a3b2c1d4a3b2c1d";
+/// let service = profiles::spotify_connect(device_id, "Living Room", 4070);
+/// let _svc = responder.register_service(service);
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "responder"))]
+/// # fn main() {}
+/// ```
+pub fn spotify_connect(device_id: &str, name: &str, port: u16) -> ServiceBuilder {
+    let device_id = format!("{}={}", SPOTIFY_CONNECT_DEVICE_ID_KEY, device_id);
+    let mut txt = SPOTIFY_CONNECT_TXT.to_vec();
+    txt.push(&device_id);
+    ServiceBuilder::new(SPOTIFY_CONNECT_SERVICE_TYPE, name, port).txt(&txt[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spotify_connect() {
+        let svc = spotify_connect("0123abcd", "Living Room", 4070)
+            .build()
+            .unwrap();
+        assert_eq!(svc.typ.to_string(), "_spotify-connect._tcp.local");
+        assert_eq!(svc.instance(), "Living Room");
+        assert_eq!(svc.port, 4070);
+        assert_eq!(
+            svc.txt,
+            b"\x0bVERSION=1.0\x07CPath=/\x08Stack=SP\x11deviceid=0123abcd"
+        );
+    }
+}
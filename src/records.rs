@@ -0,0 +1,352 @@
+//! The records of a service, written into packets the same way whether they are
+//! sent by the responder or by firmware with a transport of its own.
+//!
+//! ```
+//! use libmdns::dns_parser::{Builder, Name};
+//! use libmdns::records::ServiceRecords;
+//! use libmdns::TxtRecord;
+//!
+//! let mut txt = TxtRecord::new();
+//! txt.insert("path", "/").unwrap();
+//! let records = ServiceRecords::new("_http._tcp", "web", 80, txt);
+//! let hostname = Name::from_str("device.local").unwrap();
+//! let builder = Builder::new_response(0, false, true).move_to();
+//! let builder = records.add_ptr_rr(builder, records.ptr_ttl);
+//! let builder = records.add_srv_rr(&hostname, builder, records.ttl, true);
+//! let announcement = records.add_txt_rr(builder, records.ttl, true).build().unwrap();
+//! # let _ = announcement;
+//! ```
+
+use crate::dns_parser::{self, Canonical, Name, QueryClass, RRData, MAX_LABEL_LEN};
+use crate::instance_name::InstanceName;
+use crate::txt::TxtRecord;
+use crate::DEFAULT_TTL;
+
+pub type AnswerBuilder = dns_parser::Builder<dns_parser::Answers>;
+pub type ProbeBuilder = dns_parser::Builder<dns_parser::Nameservers>;
+
+/// The names, SRV, TXT and additional records of a service instance
+#[derive(Clone, Debug)]
+pub struct ServiceRecords {
+    /// The instance name, e.g. `web._http._tcp.local`
+    pub name: Name<'static>,
+    /// The service type, e.g. `_http._tcp.local`
+    pub typ: Name<'static>,
+    pub port: u16,
+    /// SRV records besides the one for `port`, sorted by priority
+    pub extra_srv: Vec<ExtraSrv>,
+    /// The TXT RDATA, as a sequence of length-prefixed strings
+    pub txt: Vec<u8>,
+    pub additional: Vec<AdditionalRecord>,
+    /// TTL of the PTR record
+    pub ptr_ttl: u32,
+    /// TTL of the SRV and TXT records, and the address records sent along with them
+    pub ttl: u32,
+}
+
+/// Another SRV record of a service, see `ServiceBuilder::srv_record`
+#[derive(Clone, Debug)]
+pub struct ExtraSrv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// `None` for the responder's hostname
+    pub target: Option<Name<'static>>,
+}
+
+/// One SRV record of a service, with the target resolved
+struct Srv<'a> {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: &'a Name<'a>,
+}
+
+impl<'a> Srv<'a> {
+    fn same_as(&self, other: &Srv) -> bool {
+        self.priority == other.priority
+            && self.weight == other.weight
+            && self.port == other.port
+            && self.target as &dyn Canonical == other.target as &dyn Canonical
+    }
+}
+
+/// A record published along with a service, see `ServiceBuilder::additional_record`
+#[derive(Clone, Debug)]
+pub struct AdditionalRecord {
+    pub name: Name<'static>,
+    pub ttl: u32,
+    typ: u16,
+    rdata: Vec<u8>,
+    unique: bool,
+}
+
+impl AdditionalRecord {
+    /// A record of `data` under `name`. A `unique` record is sent with the
+    /// cache-flush bit, RFC 6762 section 10.2.
+    pub fn new(name: String, ttl: u32, data: &RRData, unique: bool) -> AdditionalRecord {
+        let mut rdata = Vec::new();
+        data.write_to(&mut rdata).unwrap();
+        AdditionalRecord {
+            name: Name::from_str(name).unwrap(),
+            ttl,
+            typ: data.type_code(),
+            rdata,
+            unique,
+        }
+    }
+
+    /// The numeric type, which may be one `Type` doesn't know
+    pub fn typ(&self) -> u16 {
+        self.typ
+    }
+
+    /// Whether the record is ours alone, unlike shared records such as PTR records,
+    /// which other responders may publish under the same name
+    pub fn is_unique(&self) -> bool {
+        self.unique
+    }
+
+    pub fn add_rr(&self, builder: AnswerBuilder, ttl: u32, cache_flush: bool) -> AnswerBuilder {
+        let data = RRData::Raw {
+            typ: self.typ,
+            data: self.rdata.clone(),
+        };
+        let cache_flush = cache_flush && self.is_unique();
+        builder.add_answer_with_cache_flush(&self.name, QueryClass::IN, ttl, &data, cache_flush)
+    }
+}
+
+impl ServiceRecords {
+    /// The records of instance `svc_name` of `svc_type`, e.g. `_http._tcp`, in the
+    /// `.local` domain, with the default TTLs.
+    pub fn new(svc_type: &str, svc_name: &str, port: u16, txt: TxtRecord) -> ServiceRecords {
+        ServiceRecords {
+            typ: Name::from_str(format!("{}.local", svc_type)).unwrap(),
+            name: Name::from_str(format!(
+                "{}.{}.local",
+                InstanceName::new(svc_name).escaped(),
+                svc_type
+            ))
+            .unwrap(),
+            port,
+            extra_srv: Vec::new(),
+            txt: txt.into_bytes(),
+            additional: Vec::new(),
+            ptr_ttl: DEFAULT_TTL,
+            ttl: DEFAULT_TTL,
+        }
+    }
+
+    /// The instance part of the name, without the service type or escapes
+    pub fn instance(&self) -> String {
+        InstanceName::of(&self.name).into()
+    }
+
+    /// Change the instance part of the name, cut to the longest label DNS allows
+    pub fn set_instance(&mut self, mut instance: &str) {
+        if instance.len() > MAX_LABEL_LEN {
+            let end = (0..=MAX_LABEL_LEN)
+                .rev()
+                .find(|&end| instance.is_char_boundary(end))
+                .unwrap_or(0);
+            instance = &instance[..end];
+        }
+        let escaped = InstanceName::new(instance).escaped();
+        self.name = Name::from_str(format!("{}.{}", escaped, self.typ)).unwrap();
+    }
+
+    /// Replace the extra SRV records, keeping them ordered by priority
+    pub fn set_extra_srv(&mut self, mut extra_srv: Vec<ExtraSrv>) {
+        extra_srv.sort_by_key(|srv| srv.priority);
+        self.extra_srv = extra_srv;
+    }
+
+    /// Whether one of the SRV records of the service has this data
+    pub fn has_srv(
+        &self,
+        hostname: &Name,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: &Name,
+    ) -> bool {
+        let srv = Srv {
+            priority,
+            weight,
+            port,
+            target,
+        };
+        self.srv_records(hostname).any(|other| other.same_as(&srv))
+    }
+
+    /// All SRV records, the one for `port` first
+    fn srv_records<'a>(&'a self, hostname: &'a Name<'a>) -> impl Iterator<Item = Srv<'a>> {
+        let main = Srv {
+            priority: 0,
+            weight: 0,
+            port: self.port,
+            target: hostname,
+        };
+        let extra = self.extra_srv.iter().map(move |srv| Srv {
+            priority: srv.priority,
+            weight: srv.weight,
+            port: srv.port,
+            target: srv.target.as_ref().unwrap_or(hostname),
+        });
+        std::iter::once(main).chain(extra)
+    }
+}
+
+/// Packet building helpers, used by `fsm` to respond with a service
+impl ServiceRecords {
+    pub fn add_ptr_rr(&self, builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
+        builder.add_answer(
+            &self.typ,
+            QueryClass::IN,
+            ttl,
+            &RRData::PTR(self.name.clone()),
+        )
+    }
+
+    /// The SRV records, their targets defaulting to `hostname`
+    pub fn add_srv_rr(
+        &self,
+        hostname: &Name,
+        builder: AnswerBuilder,
+        ttl: u32,
+        cache_flush: bool,
+    ) -> AnswerBuilder {
+        self.srv_records(hostname).fold(builder, |builder, srv| {
+            add_srv(&self.name, &srv, builder, ttl, cache_flush)
+        })
+    }
+
+    /// The SRV records the service claims, for the authority section of a probe for
+    /// its name (RFC 6762 section 8.2)
+    pub fn add_probe_rr(&self, hostname: &Name, builder: ProbeBuilder) -> ProbeBuilder {
+        self.srv_records(hostname).fold(builder, |builder, srv| {
+            builder.add_nameserver(
+                &self.name,
+                QueryClass::IN,
+                self.ttl,
+                &RRData::SRV {
+                    priority: srv.priority,
+                    weight: srv.weight,
+                    port: srv.port,
+                    target: srv.target.clone(),
+                },
+            )
+        })
+    }
+
+    /// Goodbyes for the SRV records which `current`, the updated service, no
+    /// longer has
+    pub fn add_srv_goodbye_rr(
+        &self,
+        current: &ServiceRecords,
+        hostname: &Name,
+        builder: AnswerBuilder,
+        cache_flush: bool,
+    ) -> AnswerBuilder {
+        let kept: Vec<_> = current.srv_records(hostname).collect();
+        self.srv_records(hostname)
+            .filter(|srv| !kept.iter().any(|other| other.same_as(srv)))
+            .fold(builder, |builder, srv| {
+                add_srv(&self.name, &srv, builder, 0, cache_flush)
+            })
+    }
+
+    pub fn add_txt_rr(&self, builder: AnswerBuilder, ttl: u32, cache_flush: bool) -> AnswerBuilder {
+        builder.add_answer_with_cache_flush(
+            &self.name,
+            QueryClass::IN,
+            ttl,
+            &RRData::TXT(&self.txt),
+            cache_flush,
+        )
+    }
+}
+
+fn add_srv(
+    name: &Name,
+    srv: &Srv,
+    builder: AnswerBuilder,
+    ttl: u32,
+    cache_flush: bool,
+) -> AnswerBuilder {
+    builder.add_answer_with_cache_flush(
+        name,
+        QueryClass::IN,
+        ttl,
+        &RRData::SRV {
+            priority: srv.priority,
+            weight: srv.weight,
+            port: srv.port,
+            target: srv.target.clone(),
+        },
+        cache_flush,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn srv_answers(builder: AnswerBuilder) -> Vec<(u16, u16, u16, String, u32)> {
+        let packet = builder.build().unwrap();
+        dns_parser::Packet::parse(&packet)
+            .unwrap()
+            .answers
+            .iter()
+            .map(|answer| match answer.data {
+                RRData::SRV {
+                    priority,
+                    weight,
+                    port,
+                    ref target,
+                } => (priority, weight, port, target.to_string(), answer.ttl),
+                ref other => panic!("unexpected answer {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_extra_srv() {
+        let hostname = Name::from_str("test-hostname.local").unwrap();
+        let extra = |priority, port, target: Option<&str>| ExtraSrv {
+            priority,
+            weight: 1,
+            port,
+            target: target.map(|target| Name::from_str(target.to_owned()).unwrap()),
+        };
+        let mut svc = ServiceRecords::new("_http._tcp", "multi", 80, TxtRecord::new());
+        svc.set_extra_srv(vec![extra(10, 81, Some("other.local")), extra(5, 82, None)]);
+
+        let new_builder = || {
+            let mut builder =
+                dns_parser::Builder::new_response(0, false, true).move_to::<dns_parser::Answers>();
+            builder.set_max_size(None);
+            builder
+        };
+        assert_eq!(
+            srv_answers(svc.add_srv_rr(&hostname, new_builder(), 60, true)),
+            [
+                (0, 0, 80, "test-hostname.local".to_owned(), 60),
+                (5, 1, 82, "test-hostname.local".to_owned(), 60),
+                (10, 1, 81, "other.local".to_owned(), 60),
+            ]
+        );
+
+        // Only the records which are gone get a goodbye
+        let mut current = svc.clone();
+        current.set_extra_srv(vec![extra(5, 82, None), extra(20, 83, None)]);
+        assert_eq!(
+            srv_answers(svc.add_srv_goodbye_rr(&current, &hostname, new_builder(), true)),
+            [(10, 1, 81, "other.local".to_owned(), 0)]
+        );
+        assert!(current
+            .add_srv_goodbye_rr(&current, &hostname, new_builder(), true)
+            .is_empty());
+    }
+}
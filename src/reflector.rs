@@ -0,0 +1,267 @@
+//! Reflection of mDNS traffic between network interfaces, for networks split into
+//! several links such as VLANs, in the manner of avahi's reflector.
+//!
+//! Queries and responses received from one of the reflected interfaces are multicast
+//! again on the others, from the same socket and so with one of our addresses as the
+//! source. Packets from our own addresses are never reflected, which keeps copies from
+//! going round in loops.
+
+#[cfg(any(feature = "responder", feature = "sync"))]
+use super::MDNS_PORT;
+#[cfg(any(feature = "responder", feature = "sync"))]
+use crate::address_family::{AddressFamily, Inet, Inet6};
+use crate::host::Subnet;
+use if_addrs::{get_if_addrs, IfAddr};
+#[cfg(any(feature = "responder", feature = "sync"))]
+use log::trace;
+use log::warn;
+#[cfg(any(feature = "responder", feature = "sync"))]
+use socket2::SockRef;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+#[cfg(any(feature = "responder", feature = "sync"))]
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+
+/// Reflected packets waiting to be sent beyond this many are dropped
+#[cfg(any(feature = "responder", feature = "sync"))]
+pub const MAX_QUEUED: usize = 256;
+
+/// How to send multicast out of one interface
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Via {
+    /// An IPv4 address of the interface, which selects it as the outgoing interface
+    V4(Ipv4Addr),
+    /// The index of the interface, which scopes the destination
+    V6(u32),
+}
+
+#[cfg(any(feature = "responder", feature = "sync"))]
+impl Via {
+    /// The mDNS group, on this interface for IPv6
+    pub fn destination(&self) -> SocketAddr {
+        match *self {
+            Via::V4(_) => SocketAddrV4::new(Inet::MDNS_GROUP, MDNS_PORT).into(),
+            Via::V6(index) => SocketAddrV6::new(Inet6::MDNS_GROUP, MDNS_PORT, 0, index).into(),
+        }
+    }
+
+    /// Make multicast sent on `socket` go out of this interface, until `reset`
+    pub fn select(&self, socket: &SockRef) -> io::Result<()> {
+        match *self {
+            Via::V4(ip) => socket.set_multicast_if_v4(&ip),
+            Via::V6(_) => Ok(()),
+        }
+    }
+
+    /// Let the system pick the interface for multicast on `socket` again
+    pub fn reset(&self, socket: &SockRef) -> io::Result<()> {
+        match *self {
+            Via::V4(_) => socket.set_multicast_if_v4(&Ipv4Addr::UNSPECIFIED),
+            Via::V6(_) => Ok(()),
+        }
+    }
+}
+
+/// A reflected interface, of one address family
+struct Link {
+    name: String,
+    via: Via,
+    subnets: Vec<Subnet>,
+    /// our addresses on it
+    addresses: Vec<IpAddr>,
+}
+
+impl Link {
+    fn is_v4(&self) -> bool {
+        matches!(self.via, Via::V4(_))
+    }
+}
+
+/// The interfaces to reflect between, as they were when the responder started
+pub struct Reflector {
+    links: Vec<Link>,
+}
+
+impl Reflector {
+    /// Reflect between the network interfaces named in `interfaces`
+    pub fn new(interfaces: &[String]) -> io::Result<Reflector> {
+        let addrs = get_if_addrs()?
+            .into_iter()
+            .map(|iface| (iface.name, iface.index, iface.addr));
+        let reflector = Reflector::with_addrs(interfaces, addrs);
+        for name in interfaces {
+            if !reflector.links.iter().any(|link| link.name == *name) {
+                warn!("not reflecting on {}, which has no usable address", name);
+            }
+        }
+        Ok(reflector)
+    }
+
+    fn with_addrs(
+        interfaces: &[String],
+        addrs: impl IntoIterator<Item = (String, Option<u32>, IfAddr)>,
+    ) -> Reflector {
+        let mut links: Vec<Link> = Vec::new();
+        for (name, index, addr) in addrs {
+            if addr.is_loopback() || !interfaces.contains(&name) {
+                continue;
+            }
+            let via = match (&addr, index) {
+                (IfAddr::V4(ref addr), _) => Via::V4(addr.ip),
+                (IfAddr::V6(_), Some(index)) => Via::V6(index),
+                (IfAddr::V6(_), None) => continue,
+            };
+            let is_v4 = matches!(via, Via::V4(_));
+            let link = match links
+                .iter_mut()
+                .find(|link| link.name == name && link.is_v4() == is_v4)
+            {
+                Some(link) => link,
+                None => {
+                    links.push(Link {
+                        name,
+                        via,
+                        subnets: Vec::new(),
+                        addresses: Vec::new(),
+                    });
+                    links.last_mut().unwrap()
+                }
+            };
+            link.subnets.push(Subnet::new(&addr));
+            link.addresses.push(addr.ip());
+        }
+        Reflector { links }
+    }
+
+    /// The interfaces to send copies of a packet from `source` on
+    #[cfg(any(feature = "responder", feature = "sync"))]
+    pub fn reflect(&self, source: SocketAddr) -> Vec<Via> {
+        // Legacy unicast queries expect their answers from us directly
+        if source.port() != MDNS_PORT {
+            return Vec::new();
+        }
+        // Including the copies we reflected ourselves
+        if self
+            .links
+            .iter()
+            .any(|link| link.addresses.contains(&source.ip()))
+        {
+            return Vec::new();
+        }
+
+        let from = match self.origin(source) {
+            Some(from) => from,
+            None => {
+                trace!("not reflecting packet from {:?} of unknown origin", source);
+                return Vec::new();
+            }
+        };
+        let is_v4 = source.is_ipv4();
+        self.links
+            .iter()
+            .enumerate()
+            .filter(|&(index, link)| index != from && link.is_v4() == is_v4)
+            .map(|(_, link)| link.via)
+            .collect()
+    }
+
+    /// The link `source` is on, if it is on exactly one of them
+    #[cfg(any(feature = "responder", feature = "sync"))]
+    fn origin(&self, source: SocketAddr) -> Option<usize> {
+        if let SocketAddr::V6(source) = source {
+            // Link-local sources come with the interface they were received on
+            if source.scope_id() != 0 {
+                return self
+                    .links
+                    .iter()
+                    .position(|link| link.via == Via::V6(source.scope_id()));
+            }
+        }
+        let mut on = self.links.iter().enumerate().filter(|(_, link)| {
+            link.subnets
+                .iter()
+                .any(|subnet| subnet.contains(source.ip()))
+        });
+        match (on.next(), on.next()) {
+            (Some((index, _)), None) => Some(index),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "responder", feature = "sync")))]
+mod tests {
+    use super::*;
+    use if_addrs::{Ifv4Addr, Ifv6Addr};
+
+    fn v4(name: &str, ip: [u8; 4]) -> (String, Option<u32>, IfAddr) {
+        let addr = Ifv4Addr {
+            ip: ip.into(),
+            netmask: [255, 255, 255, 0].into(),
+            broadcast: None,
+        };
+        (name.to_owned(), Some(1), IfAddr::V4(addr))
+    }
+
+    fn v6(name: &str, index: u32, ip: &str) -> (String, Option<u32>, IfAddr) {
+        let addr = Ifv6Addr {
+            ip: ip.parse().unwrap(),
+            netmask: "ffff:ffff:ffff:ffff::".parse().unwrap(),
+            broadcast: None,
+        };
+        (name.to_owned(), Some(index), IfAddr::V6(addr))
+    }
+
+    #[test]
+    fn test_reflect() {
+        let interfaces = vec![
+            "vlan10".to_owned(),
+            "vlan20".to_owned(),
+            "vlan30".to_owned(),
+        ];
+        let reflector = Reflector::with_addrs(
+            &interfaces,
+            vec![
+                v4("vlan10", [192, 168, 10, 1]),
+                v4("vlan20", [192, 168, 20, 1]),
+                v4("vlan30", [192, 168, 30, 1]),
+                v4("eth0", [192, 0, 2, 1]),
+                v6("vlan10", 10, "fe80::1"),
+                v6("vlan20", 20, "fe80::2"),
+            ],
+        );
+        let source = |addr: &str| addr.parse::<SocketAddr>().unwrap();
+        let vias = |ips: &[[u8; 4]]| ips.iter().map(|&ip| Via::V4(ip.into())).collect::<Vec<_>>();
+
+        assert_eq!(
+            reflector.reflect(source("192.168.10.7:5353")),
+            vias(&[[192, 168, 20, 1], [192, 168, 30, 1]])
+        );
+        assert_eq!(
+            reflector.reflect(source("192.168.30.7:5353")),
+            vias(&[[192, 168, 10, 1], [192, 168, 20, 1]])
+        );
+        assert_eq!(
+            reflector.reflect(source("[fe80::7%20]:5353")),
+            vec![Via::V6(10)]
+        );
+
+        // Not our own, including copies we reflected, legacy queries, or from elsewhere
+        assert!(reflector.reflect(source("192.168.20.1:5353")).is_empty());
+        assert!(reflector.reflect(source("192.168.10.7:49152")).is_empty());
+        assert!(reflector.reflect(source("192.0.2.7:5353")).is_empty());
+        assert!(reflector.reflect(source("[fe80::7%3]:5353")).is_empty());
+    }
+
+    #[test]
+    fn test_via() {
+        assert_eq!(
+            Via::V4([192, 168, 10, 1].into()).destination(),
+            "224.0.0.251:5353".parse().unwrap()
+        );
+        assert_eq!(
+            Via::V6(10).destination(),
+            "[ff02::fb%10]:5353".parse().unwrap()
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// What to do when another device on the network publishes the instance name of a
+/// service, set with [`ServiceBuilder::conflict_strategy`].
+///
+/// [`ServiceBuilder::conflict_strategy`]: crate::ServiceBuilder::conflict_strategy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum ConflictStrategy {
+    /// Move to a name picked by the [`RenameStrategy`] and announce it, see RFC 6762
+    /// section 9.
+    #[default]
+    Rename,
+    /// Stop advertising the service. Registering it fails if another service of the
+    /// same responder has the name.
+    Fail,
+    /// Keep advertising the name. Services of the same responder still can't share a
+    /// name, so one registered under a taken name is renamed.
+    Ignore,
+}
+
+/// Picks a new instance name when the requested one is already taken.
+pub trait RenameStrategy: Send + Sync {
+    /// Returns the name to try after `attempt` conflicts (starting at 1) for the
+    /// requested instance `name`.
+    fn rename(&self, name: &str, attempt: u32) -> String;
+
+    /// What the strategy is called in the [`ServiceEvent`](crate::ServiceEvent)s of
+    /// the services it renames
+    fn describe(&self) -> String {
+        "custom".into()
+    }
+}
+
+impl<F> RenameStrategy for F
+where
+    F: Fn(&str, u32) -> String + Send + Sync,
+{
+    fn rename(&self, name: &str, attempt: u32) -> String {
+        self(name, attempt)
+    }
+}
+
+/// `name (2)`, `name (3)`, ... as suggested by RFC 6762 section 9.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NumericSuffix;
+
+impl RenameStrategy for NumericSuffix {
+    fn rename(&self, name: &str, attempt: u32) -> String {
+        format!("{} ({})", name, attempt + 1)
+    }
+
+    fn describe(&self) -> String {
+        "numeric suffix".into()
+    }
+}
+
+/// `name-a1b2`, with four hex digits derived from an identifier of the device such as
+/// its MAC address, so the chosen name is stable across restarts.
+#[derive(Clone, Debug)]
+pub struct HexSuffix {
+    id: Vec<u8>,
+}
+
+impl HexSuffix {
+    pub fn new(id: &[u8]) -> HexSuffix {
+        HexSuffix { id: id.to_vec() }
+    }
+}
+
+impl RenameStrategy for HexSuffix {
+    fn rename(&self, name: &str, attempt: u32) -> String {
+        let suffix = match *self.id {
+            // The end of a MAC address is the part which differs between devices
+            [.., a, b] if attempt == 1 => u16::from_be_bytes([a, b]),
+            _ => {
+                let mut hasher = DefaultHasher::new();
+                self.id.hash(&mut hasher);
+                attempt.hash(&mut hasher);
+                hasher.finish() as u16
+            }
+        };
+        format!("{}-{:04x}", name, suffix)
+    }
+
+    fn describe(&self) -> String {
+        "hex suffix".into()
+    }
+}
+
+/// `name-blue`, `name-otter`, ... with a randomly chosen word.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WordSuffix;
+
+const WORDS: &[&str] = &[
+    "amber", "aspen", "birch", "blue", "cedar", "coral", "crane", "delta", "ember", "fern",
+    "finch", "gold", "green", "hazel", "heron", "indigo", "iris", "jade", "lark", "lime", "maple",
+    "mint", "moss", "navy", "oak", "olive", "onyx", "otter", "pearl", "pine", "plum", "raven",
+    "red", "river", "robin", "rose", "ruby", "sage", "silver", "slate", "sky", "stone", "swift",
+    "teal", "tiger", "violet", "willow", "wren",
+];
+
+impl RenameStrategy for WordSuffix {
+    fn rename(&self, name: &str, _attempt: u32) -> String {
+        format!("{}-{}", name, WORDS.choose(&mut thread_rng()).unwrap())
+    }
+
+    fn describe(&self) -> String {
+        "word suffix".into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suffixes() {
+        assert_eq!(NumericSuffix.rename("printer", 1), "printer (2)");
+        assert_eq!(NumericSuffix.rename("printer", 2), "printer (3)");
+
+        let hex = HexSuffix::new(&[0x00, 0x11, 0x22, 0x33, 0xa1, 0xb2]);
+        assert_eq!(hex.rename("printer", 1), "printer-a1b2");
+        assert_ne!(hex.rename("printer", 2), "printer-a1b2");
+        assert_eq!(hex.rename("printer", 2), hex.rename("printer", 2));
+
+        let word = WordSuffix.rename("printer", 1);
+        assert!(WORDS.contains(&word.strip_prefix("printer-").unwrap()));
+    }
+}
@@ -0,0 +1,418 @@
+//! Replaying recorded mDNS traffic through an [`Engine`], to see offline what a
+//! responder would have sent in reply.
+//!
+//! Time stands still on the replaying thread between the packets of the trace, so a
+//! replay gives the same responses at the same offsets however often, and however
+//! fast, it is run. Only the responses depend on the configuration of the engine and
+//! the services registered with it, which should match those of the responder the
+//! trace was captured from.
+//!
+//! The `mdns-replay` binary replays a capture file made with e.g.
+//! `tcpdump -i eth0 -w trace.pcap udp port 5353`.
+
+use crate::clock;
+use crate::{Engine, MDNS_PORT};
+use std::convert::TryInto;
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// A packet of a trace: when it was received relative to the start of the trace, and
+/// from where
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Received {
+    pub at: Duration,
+    pub from: SocketAddr,
+    pub packet: Vec<u8>,
+}
+
+/// A packet the engine sent during a replay, and when relative to the start of the
+/// trace
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sent {
+    pub at: Duration,
+    pub to: SocketAddr,
+    pub packet: Vec<u8>,
+}
+
+/// The time of the thread which replays a trace, from creation until dropped.
+///
+/// Create it before building the engine and registering its services, so that they
+/// are announced at the start of the trace rather than before it.
+///
+/// ```no_run
+/// use libmdns::replay::Replay;
+/// use libmdns::{FixedHostData, ResponderBuilder};
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// # fn main() -> std::io::Result<()> {
+/// let replay = Replay::new();
+/// let host = FixedHostData::new("host".into(), vec!["192.0.2.2".parse().unwrap()]);
+/// let mut engine = ResponderBuilder::new()
+///     .host_data(Arc::new(host))
+///     .accept_off_link_queries(true)
+///     .build_engine()?;
+/// engine.register("_http._tcp", "web", 80, &["path=/"]);
+///
+/// let trace = libmdns::replay::read_pcap(std::fs::File::open("trace.pcap")?)?;
+/// for sent in replay.run(&mut engine, &trace, Duration::from_secs(10)) {
+///     println!("{:?}: {} bytes to {}", sent.at, sent.packet.len(), sent.to);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Replay {
+    origin: Instant,
+    /// the replayed time is that of the creating thread
+    _thread: PhantomData<*const ()>,
+}
+
+impl Replay {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Replay {
+        let origin = Instant::now();
+        clock::set_replayed(Some(origin));
+        Replay {
+            origin,
+            _thread: PhantomData,
+        }
+    }
+
+    /// Feed `trace`, ordered by time, to `engine`, and keep time going for `settle`
+    /// after its last packet so that scheduled packets such as repeated goodbyes are
+    /// sent too
+    pub fn run(&self, engine: &mut Engine, trace: &[Received], settle: Duration) -> Vec<Sent> {
+        let mut sent = Vec::new();
+        for received in trace {
+            self.advance(engine, received.at, &mut sent);
+            engine.handle_packet(&received.packet, received.from);
+            self.transmit(engine, &mut sent);
+        }
+        let end = trace.last().map_or(Duration::ZERO, |last| last.at) + settle;
+        self.advance(engine, end, &mut sent);
+        sent
+    }
+
+    /// Move time on to `until`, sending what becomes due on the way
+    fn advance(&self, engine: &mut Engine, until: Duration, sent: &mut Vec<Sent>) {
+        let until = self.origin + until;
+        loop {
+            self.transmit(engine, sent);
+            match engine.next_timeout() {
+                Some(next) if next > clock::now() && next <= until => {
+                    clock::set_replayed(Some(next));
+                }
+                _ => break,
+            }
+        }
+        if until > clock::now() {
+            clock::set_replayed(Some(until));
+            self.transmit(engine, sent);
+        }
+    }
+
+    fn transmit(&self, engine: &mut Engine, sent: &mut Vec<Sent>) {
+        let at = clock::now() - self.origin;
+        while let Some((packet, to)) = engine.poll_transmit() {
+            sent.push(Sent { at, to, packet });
+        }
+    }
+}
+
+impl Drop for Replay {
+    fn drop(&mut self) {
+        clock::set_replayed(None);
+    }
+}
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+/// Largest frame which can carry an IP packet, with room for the link layer header
+const MAX_FRAME_SIZE: u32 = 65535 + 64;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Read the mDNS packets received on port 5353 from a capture in the classic pcap
+/// format, as tcpdump writes it. Captures in pcapng, the default of Wireshark, can
+/// be converted with `editcap -F pcap`.
+///
+/// Times are relative to the first packet of the capture. Packets which aren't UDP
+/// over IPv4 or IPv6, or are fragments, are left out, as are frames too long to
+/// carry an IP packet.
+pub fn read_pcap<R: Read>(mut reader: R) -> io::Result<Vec<Received>> {
+    let mut header = [0; 24];
+    reader.read_exact(&mut header)?;
+    let (big_endian, nanos) = match header[..4] {
+        [0xa1, 0xb2, 0xc3, 0xd4] => (true, false),
+        [0xd4, 0xc3, 0xb2, 0xa1] => (false, false),
+        [0xa1, 0xb2, 0x3c, 0x4d] => (true, true),
+        [0x4d, 0x3c, 0xb2, 0xa1] => (false, true),
+        [0x0a, 0x0d, 0x0d, 0x0a] => {
+            return Err(invalid("pcapng capture, convert it with `editcap -F pcap`"))
+        }
+        _ => return Err(invalid("not a pcap capture")),
+    };
+    let u32_at = |bytes: &[u8], at: usize| {
+        let word = [bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]];
+        match big_endian {
+            true => u32::from_be_bytes(word),
+            false => u32::from_le_bytes(word),
+        }
+    };
+    let snaplen = u32_at(&header, 16);
+    let linktype = u32_at(&header, 20) & 0x0fff_ffff;
+
+    let mut received = Vec::new();
+    let mut start = None;
+    let mut record = [0; 16];
+    loop {
+        match reader.read_exact(&mut record) {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+        let frac = u64::from(u32_at(&record, 4));
+        let at = Duration::from_secs(u64::from(u32_at(&record, 0)))
+            + match nanos {
+                true => Duration::from_nanos(frac),
+                false => Duration::from_micros(frac),
+            };
+        // The length comes from the file, so it is checked before allocating for it
+        let caplen = u32_at(&record, 8);
+        if caplen > snaplen {
+            return Err(invalid("frame longer than the snapshot length"));
+        }
+        if caplen > MAX_FRAME_SIZE {
+            let skipped = io::copy(&mut (&mut reader).take(caplen.into()), &mut io::sink())?;
+            if skipped < caplen.into() {
+                break;
+            }
+            continue;
+        }
+        let mut frame = vec![0; caplen as usize];
+        reader.read_exact(&mut frame)?;
+
+        let start = *start.get_or_insert(at);
+        if let Some((from, packet)) = link_payload(linktype, big_endian, &frame).and_then(udp) {
+            received.push(Received {
+                at: at.saturating_sub(start),
+                from,
+                packet: packet.to_vec(),
+            });
+        }
+    }
+    Ok(received)
+}
+
+/// The IP packet in a frame of `linktype`
+fn link_payload(linktype: u32, big_endian: bool, frame: &[u8]) -> Option<&[u8]> {
+    match linktype {
+        LINKTYPE_NULL => {
+            // The address family, in the byte order of the capturing host
+            let family = frame.get(..4)?;
+            let family = match big_endian {
+                true => u32::from_be_bytes(family.try_into().ok()?),
+                false => u32::from_le_bytes(family.try_into().ok()?),
+            };
+            matches!(family, 2 | 24 | 28 | 30).then(|| &frame[4..])
+        }
+        LINKTYPE_ETHERNET => {
+            let mut at = 12;
+            // 802.1Q VLAN tags
+            while frame.get(at..at + 2)? == [0x81, 0x00] {
+                at += 4;
+            }
+            ethertype_payload(frame.get(at..at + 2)?, &frame[at + 2..])
+        }
+        LINKTYPE_RAW => Some(frame),
+        LINKTYPE_LINUX_SLL => ethertype_payload(frame.get(14..16)?, frame.get(16..)?),
+        LINKTYPE_LINUX_SLL2 => ethertype_payload(frame.get(..2)?, frame.get(20..)?),
+        _ => None,
+    }
+}
+
+fn ethertype_payload<'a>(ethertype: &[u8], payload: &'a [u8]) -> Option<&'a [u8]> {
+    match ethertype {
+        [0x08, 0x00] | [0x86, 0xdd] => Some(payload),
+        _ => None,
+    }
+}
+
+fn be16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+/// The source and payload of an IP packet carrying UDP for the mDNS port
+fn udp(ip: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    let (source, datagram): (IpAddr, _) = match *ip.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(ip[0] & 0x0f) * 4;
+            let total_len = usize::from(be16(ip, 2)?);
+            let fragment = be16(ip, 6)? & 0x3fff;
+            if *ip.get(9)? != 17 || fragment != 0 {
+                return None;
+            }
+            let source: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let datagram = ip.get(header_len..total_len.min(ip.len()))?;
+            (Ipv4Addr::from(source).into(), datagram)
+        }
+        6 => {
+            let payload_len = usize::from(be16(ip, 4)?);
+            if *ip.get(6)? != 17 {
+                return None;
+            }
+            let source: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let datagram = ip.get(40..(40 + payload_len).min(ip.len()))?;
+            (Ipv6Addr::from(source).into(), datagram)
+        }
+        _ => return None,
+    };
+
+    let source_port = be16(datagram, 0)?;
+    let dest_port = be16(datagram, 2)?;
+    let len = usize::from(be16(datagram, 4)?);
+    if dest_port != MDNS_PORT {
+        return None;
+    }
+    let payload = datagram.get(8..len.clamp(8, datagram.len()))?;
+    Some((SocketAddr::new(source, source_port), payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_parser::{Builder, Name, Packet, QueryClass, QueryType};
+    use crate::{FixedHostData, ResponderBuilder};
+    use std::sync::Arc;
+
+    /// A little-endian pcap of Ethernet frames carrying `packets` from 192.0.2.10
+    fn pcap(packets: &[(Duration, &[u8])]) -> Vec<u8> {
+        let mut pcap = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        pcap.extend_from_slice(&[0; 8]);
+        pcap.extend_from_slice(&65535u32.to_le_bytes());
+        pcap.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        for &(at, packet) in packets {
+            let mut frame = vec![0; 12];
+            frame.extend_from_slice(&[0x08, 0x00]);
+            frame.extend_from_slice(&[0x45, 0]);
+            frame.extend_from_slice(&(28 + packet.len() as u16).to_be_bytes());
+            frame.extend_from_slice(&[0, 0, 0, 0, 255, 17, 0, 0]);
+            frame.extend_from_slice(&[192, 0, 2, 10, 224, 0, 0, 251]);
+            frame.extend_from_slice(&MDNS_PORT.to_be_bytes());
+            frame.extend_from_slice(&MDNS_PORT.to_be_bytes());
+            frame.extend_from_slice(&(8 + packet.len() as u16).to_be_bytes());
+            frame.extend_from_slice(&[0, 0]);
+            frame.extend_from_slice(packet);
+
+            // Times from the epoch, to be made relative to the first packet
+            let at = at + Duration::from_secs(1_700_000_000);
+            pcap.extend_from_slice(&(at.as_secs() as u32).to_le_bytes());
+            pcap.extend_from_slice(&at.subsec_micros().to_le_bytes());
+            pcap.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(&frame);
+        }
+        pcap
+    }
+
+    fn replay(trace: &[Received]) -> Vec<Sent> {
+        let replay = Replay::new();
+        let host = FixedHostData::new("replay-host".into(), vec!["192.0.2.2".parse().unwrap()]);
+        let mut engine = ResponderBuilder::new()
+            .host_data(Arc::new(host))
+            .accept_off_link_queries(true)
+            .enable_ipv6(false)
+            .build_engine()
+            .unwrap();
+        engine.register("_http._tcp", "web", 80, &["path=/"]);
+        replay.run(&mut engine, trace, Duration::from_secs(10))
+    }
+
+    #[test]
+    fn test_replay() {
+        let query = Builder::new_query(0, false)
+            .add_question(
+                &Name::from_str("_http._tcp.local").unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        let start = Duration::from_millis(250);
+        let capture = pcap(&[
+            (start, &query),
+            (start + Duration::from_millis(1500), &query),
+        ]);
+        let trace = read_pcap(&capture[..]).unwrap();
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].at, Duration::ZERO);
+        assert_eq!(trace[1].at, Duration::from_millis(1500));
+        assert_eq!(trace[1].from, "192.0.2.10:5353".parse().unwrap());
+        assert_eq!(trace[1].packet, query);
+
+        let sent = replay(&trace);
+        // The announcement at the start and its repeat a jittered second later, and an
+        // answer to each query when it arrived
+        let times: Vec<Duration> = sent.iter().map(|sent| sent.at).collect();
+        assert_eq!(
+            times,
+            vec![
+                Duration::ZERO,
+                Duration::ZERO,
+                Duration::from_millis(1050),
+                Duration::from_millis(1500)
+            ]
+        );
+        for sent in &sent {
+            let packet = Packet::parse(&sent.packet).unwrap();
+            assert_eq!(packet.answers[0].name.to_string(), "_http._tcp.local");
+        }
+
+        // However often it is run
+        assert_eq!(replay(&trace), sent);
+        let mut pcapng = vec![0x0a, 0x0d, 0x0d, 0x0a];
+        pcapng.resize(24, 0);
+        let err = read_pcap(&pcapng[..]).unwrap_err();
+        assert!(err.to_string().contains("editcap"));
+    }
+
+    #[test]
+    fn test_frame_lengths() {
+        let query = Builder::new_query(0, false)
+            .add_question(
+                &Name::from_str("_http._tcp.local").unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        let capture = pcap(&[(Duration::ZERO, &query)]);
+
+        // A corrupt length beyond the snapshot length fails without allocating it
+        let mut corrupt = capture.clone();
+        corrupt[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = read_pcap(&corrupt[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A jumbo frame under a large snapshot length is skipped
+        let mut jumbo = capture[..24].to_vec();
+        jumbo[16..20].copy_from_slice(&262_144u32.to_le_bytes());
+        let size = MAX_FRAME_SIZE + 1;
+        jumbo.extend_from_slice(&[0; 8]);
+        jumbo.extend_from_slice(&size.to_le_bytes());
+        jumbo.extend_from_slice(&size.to_le_bytes());
+        jumbo.resize(jumbo.len() + size as usize, 0);
+        jumbo.extend_from_slice(&capture[24..]);
+        let trace = read_pcap(&jumbo[..]).unwrap();
+        assert_eq!(trace.len(), 1);
+        assert_eq!(trace[0].packet, query);
+    }
+}
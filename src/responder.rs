@@ -0,0 +1,1603 @@
+//! The tokio `Responder` and the handles of the services registered with it.
+
+use futures_util::{future, future::FutureExt};
+use log::{debug, warn};
+use std::cell::RefCell;
+use std::future::Future;
+use std::marker::Unpin;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, oneshot},
+};
+
+use crate::address_family::{Inet, Inet6};
+use crate::browse::{Browse, BrowseBuilder, Browsers};
+use crate::cache::{CachedRecord, RecordCache};
+use crate::config::{NetworkProfile, ResponderBuilder, ResponderConfig};
+#[cfg(all(feature = "control", unix))]
+use crate::control::ControlServer;
+use crate::dns_parser::{Canonical, Name};
+use crate::engine::{self, local_hostname, resolve_hostname};
+use crate::error::Error;
+use crate::fsm::{Announced, Command, Shared, FSM};
+use crate::host::HostData;
+use crate::metrics::Counters;
+#[cfg(feature = "metrics-minimal")]
+use crate::metrics::Metrics;
+#[cfg(all(feature = "power-events", target_os = "linux"))]
+use crate::power::PowerWatcher;
+use crate::preflight::PreflightReport;
+use crate::service_builder::{ServiceBuilder, SrvRecord};
+use crate::services::{check_instance, ServiceData, Services};
+#[cfg(feature = "snapshot")]
+use crate::snapshot::Snapshot;
+use crate::sockets::PreparedSockets;
+use crate::tethered::{SharedSink, SinkLink};
+use crate::transport::Transport;
+use crate::txt::TxtRecord;
+
+pub struct Responder {
+    pub(crate) services: Services,
+    pub(crate) config: Arc<ResponderConfig>,
+    host: Arc<dyn HostData>,
+    counters: Arc<Counters>,
+    cache: Arc<RecordCache>,
+    browsers: Arc<Browsers>,
+    pub(crate) commands: RefCell<CommandSender>,
+    shutdown: Arc<Shutdown>,
+    runner: Runner,
+}
+
+/// Where the responder task runs, so a replacement can be started the same way
+#[derive(Clone)]
+pub(crate) enum Runner {
+    Thread,
+    Handle(Handle),
+    /// on an executor of the caller's, which a replacement can't be spawned on
+    Caller,
+    /// like `Caller`, without sockets, see `ResponderBuilder::build_with_sink`
+    Tethered(SharedSink),
+}
+
+/// A registered service. Clones share the registration, which is withdrawn once the
+/// last of them is dropped, or by [`withdraw`](Service::withdraw).
+#[derive(Clone)]
+pub struct Service(Arc<Registration>);
+
+/// A registered service detached from its [`Service`] handles by
+/// [`Service::detach`], which stays registered until passed to
+/// [`Responder::unregister`]. Dropping it leaves the service advertised for as long
+/// as the responder runs.
+pub struct ServiceToken(Arc<Registration>);
+
+struct Registration {
+    id: usize,
+    services: Services,
+    commands: CommandSender,
+    /// resolves once the first announcement has been sent, to whether it was
+    announced: future::Shared<oneshot::Receiver<bool>>,
+    /// the last data of the service, once withdrawn
+    withdrawn: Mutex<Option<ServiceData>>,
+    /// set by `Service::detach`, after which dropping doesn't withdraw the service
+    detached: AtomicBool,
+    _shutdown: Arc<Shutdown>,
+}
+
+/// Services registered and withdrawn together, like an avahi entry group: the
+/// services added are registered by [`commit`](ServiceGroup::commit) with a single
+/// announcement, and dropping the group withdraws them all with a single goodbye.
+pub struct ServiceGroup {
+    services: Services,
+    config: Arc<ResponderConfig>,
+    commands: CommandSender,
+    shutdown: Arc<Shutdown>,
+    staged: Vec<ServiceBuilder>,
+    committed: Vec<Service>,
+}
+
+pub(crate) type ResponderTask = Box<dyn Future<Output = ()> + Send + Unpin>;
+
+/// The responder behind [`Responder::shared`], without keeping its task alive
+pub(crate) struct WeakResponder {
+    services: Services,
+    config: Arc<ResponderConfig>,
+    host: Arc<dyn HostData>,
+    counters: Arc<Counters>,
+    cache: Arc<RecordCache>,
+    browsers: Arc<Browsers>,
+    commands: CommandSender,
+    shutdown: Weak<Shutdown>,
+    runner: Runner,
+}
+
+impl WeakResponder {
+    pub(crate) fn new(responder: &Responder) -> WeakResponder {
+        WeakResponder {
+            services: responder.services.clone(),
+            config: responder.config.clone(),
+            host: responder.host.clone(),
+            counters: responder.counters.clone(),
+            cache: responder.cache.clone(),
+            browsers: responder.browsers.clone(),
+            commands: responder.commands.borrow().clone(),
+            shutdown: Arc::downgrade(&responder.shutdown),
+            runner: responder.runner.clone(),
+        }
+    }
+
+    /// Another handle to the responder, if it is still running
+    pub(crate) fn upgrade(&self) -> Option<Responder> {
+        Some(Responder {
+            services: self.services.clone(),
+            config: self.config.clone(),
+            host: self.host.clone(),
+            counters: self.counters.clone(),
+            cache: self.cache.clone(),
+            browsers: self.browsers.clone(),
+            commands: RefCell::new(self.commands.clone()),
+            shutdown: self.shutdown.upgrade()?,
+            runner: self.runner.clone(),
+        })
+    }
+}
+
+static SHARED: Mutex<Option<WeakResponder>> = Mutex::new(None);
+
+impl Responder {
+    /// Configure a `Responder` with a `ResponderBuilder`.
+    pub fn builder() -> ResponderBuilder {
+        ResponderBuilder::new()
+    }
+
+    /// A handle to the responder shared by the whole process, which is started on a
+    /// new os thread with the default configuration on first use.
+    ///
+    /// Libraries which each want to advertise services can use this instead of binding
+    /// sockets of their own. Services registered through any handle are answered by
+    /// the same task, which keeps running until every handle and service is dropped.
+    /// [`replace`](Responder::replace) on any handle reconfigures it for all of them.
+    pub fn shared() -> Result<Responder, Error> {
+        let mut shared = SHARED.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(responder) = shared.as_ref().and_then(WeakResponder::upgrade) {
+            return Ok(responder);
+        }
+
+        let responder = Self::new()?;
+        *shared = Some(WeakResponder::new(&responder));
+        Ok(responder)
+    }
+
+    /// Spawn a `Responder` task on an new os thread.
+    pub fn new() -> Result<Responder, Error> {
+        Self::new_with_ip_list(Vec::new())
+    }
+    /// Spawn a `Responder` task on an new os thread.
+    /// DNS response records will have the reported IPs limited to those passed in here.
+    /// This can be particularly useful on machines with lots of networks created by tools such as docker.
+    pub fn new_with_ip_list(allowed_ips: Vec<IpAddr>) -> Result<Responder, Error> {
+        ResponderBuilder::new()
+            .allowed_ips(allowed_ips)
+            .spawn_thread()
+    }
+
+    /// Spawn a `Responder` with the provided tokio `Handle`, whose runtime needs both
+    /// the IO and time drivers, see [`ResponderBuilder::spawn`].
+    ///
+    /// # Example
+    /// ```no_run
+    /// use libmdns::Responder;
+    ///
+    /// # use std::io;
+    /// # fn main() -> io::Result<()> {
+    /// let rt = tokio::runtime::Builder::new_current_thread()
+    ///     .enable_all()
+    ///     .build()
+    ///     .unwrap();
+    /// let handle = rt.handle().clone();
+    /// let responder = Responder::spawn(&handle)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn spawn(handle: &Handle) -> Result<Responder, Error> {
+        Self::spawn_with_ip_list(handle, Vec::new())
+    }
+
+    /// Spawn a `Responder` task  with the provided tokio `Handle`.
+    /// DNS response records will have the reported IPs limited to those passed in here.
+    /// This can be particularly useful on machines with lots of networks created by tools such as docker.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use libmdns::Responder;
+    ///
+    /// # use std::io;
+    /// # fn main() -> io::Result<()> {
+    /// let rt = tokio::runtime::Builder::new_current_thread()
+    ///     .enable_all()
+    ///     .build()
+    ///     .unwrap();
+    /// let handle = rt.handle().clone();
+    /// let vec: Vec<std::net::IpAddr> = vec![
+    ///     "192.168.1.10".parse::<std::net::Ipv4Addr>().unwrap().into(),
+    ///     std::net::Ipv6Addr::new(0, 0, 0, 0xfe80, 0x1ff, 0xfe23, 0x4567, 0x890a).into(),
+    /// ];
+    /// let responder = Responder::spawn_with_ip_list(&handle, vec)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn spawn_with_ip_list(
+        handle: &Handle,
+        allowed_ips: Vec<IpAddr>,
+    ) -> Result<Responder, Error> {
+        ResponderBuilder::new()
+            .allowed_ips(allowed_ips)
+            .spawn(handle)
+    }
+
+    /// Spawn a `Responder` task  with the provided tokio `Handle`.
+    /// DNS response records will have the reported IPs limited to those passed in here.
+    /// This can be particularly useful on machines with lots of networks created by tools such as docker.
+    /// And SRV field will have specified hostname instead of system hostname.
+    /// This can be particularly useful if the platform has the fixed hostname and the application
+    /// should make hostname unique for its purpose.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use libmdns::Responder;
+    ///
+    /// # use std::io;
+    /// # fn main() -> io::Result<()> {
+    /// let rt = tokio::runtime::Builder::new_current_thread()
+    ///     .enable_all()
+    ///     .build()
+    ///     .unwrap();
+    /// let handle = rt.handle().clone();
+    /// let responder = Responder::spawn_with_ip_list_and_hostname(&handle, Vec::new(), "myUniqueName".to_owned())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn spawn_with_ip_list_and_hostname(
+        handle: &Handle,
+        allowed_ips: Vec<IpAddr>,
+        hostname: String,
+    ) -> Result<Responder, Error> {
+        ResponderBuilder::new()
+            .allowed_ips(allowed_ips)
+            .hostname(hostname)
+            .spawn(handle)
+    }
+
+    /// Spawn a `Responder` on the default tokio handle, whose runtime needs both the IO
+    /// and time drivers, see [`ResponderBuilder::spawn`].
+    pub fn with_default_handle() -> Result<(Responder, ResponderTask), Error> {
+        Self::with_default_handle_and_ip_list(Vec::new())
+    }
+
+    /// Spawn a `Responder` on the default tokio handle.
+    /// DNS response records will have the reported IPs limited to those passed in here.
+    /// This can be particularly useful on machines with lots of networks created by tools such as docker.
+    pub fn with_default_handle_and_ip_list(
+        allowed_ips: Vec<IpAddr>,
+    ) -> Result<(Responder, ResponderTask), Error> {
+        ResponderBuilder::new().allowed_ips(allowed_ips).build()
+    }
+
+    /// Spawn a `Responder` on the default tokio handle.
+    /// DNS response records will have the reported IPs limited to those passed in here.
+    /// This can be particularly useful on machines with lots of networks created by tools such as docker.
+    /// And SRV field will have specified hostname instead of system hostname.
+    /// This can be particularly useful if the platform has the fixed hostname and the application
+    /// should make hostname unique for its purpose.
+    pub fn with_default_handle_and_ip_list_and_hostname(
+        allowed_ips: Vec<IpAddr>,
+        hostname: String,
+    ) -> Result<(Responder, ResponderTask), Error> {
+        ResponderBuilder::new()
+            .allowed_ips(allowed_ips)
+            .hostname(hostname)
+            .build()
+    }
+
+    pub(crate) fn spawn_thread_with_config(
+        config: ResponderConfig,
+        sockets: Option<PreparedSockets>,
+    ) -> Result<Responder, Error> {
+        Self::spawn_thread_with(move || {
+            Self::with_config::<tokio::net::UdpSocket>(config, sockets, Runner::Thread)
+        })
+    }
+
+    /// Run the task returned by `start` on a new os thread.
+    fn spawn_thread_with<T, F>(start: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<(T, ResponderTask), Error> + Send + 'static,
+    {
+        let (tx, rx) = std::sync::mpsc::sync_channel(0);
+        thread::Builder::new()
+            .name("mdns-responder".to_owned())
+            .spawn(move || {
+                let rt = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap();
+                rt.block_on(async {
+                    match start() {
+                        Ok((value, task)) => {
+                            tx.send(Ok(value)).expect("tx responder channel closed");
+                            task.await;
+                        }
+                        Err(e) => tx.send(Err(e)).expect("tx responder channel closed"),
+                    }
+                })
+            })?;
+        rx.recv().expect("rx responder channel closed")
+    }
+
+    pub(crate) fn with_config<T: Transport>(
+        config: ResponderConfig,
+        sockets: Option<PreparedSockets>,
+        runner: Runner,
+    ) -> Result<(Responder, ResponderTask), Error> {
+        Self::with_start(config, runner, |services, carried, config| {
+            Self::start::<T>(services, carried, config, sockets)
+        })
+    }
+
+    /// Like `with_config`, sending through `sink` instead of sockets
+    pub(crate) fn with_sink(
+        config: ResponderConfig,
+        sink: SharedSink,
+    ) -> Result<(Responder, ResponderTask), Error> {
+        let runner = Runner::Tethered(sink.clone());
+        Self::with_start(config, runner, |services, carried, config| {
+            Self::start_tethered(services, carried, config, sink)
+        })
+    }
+
+    fn with_start<F>(
+        config: ResponderConfig,
+        runner: Runner,
+        start: F,
+    ) -> Result<(Responder, ResponderTask), Error>
+    where
+        F: FnOnce(&Services, &Carried, ResponderConfig) -> Result<(Started, ResponderTask), Error>,
+    {
+        let services = engine::new_services(&config)?;
+        let carried = Carried::default();
+        let (started, task) = start(&services, &carried, config)?;
+
+        let commands = CommandSender::new(started.commands);
+        started.shared.watch_host(Some(commands.waker()));
+        let responder = Responder {
+            services,
+            config: started.shared.config,
+            host: started.shared.host,
+            counters: started.shared.counters,
+            cache: started.shared.cache,
+            browsers: started.shared.browsers.clone(),
+            commands: RefCell::new(commands.clone()),
+            shutdown: Arc::new(Shutdown(commands)),
+            runner,
+        };
+
+        Ok((responder, task))
+    }
+
+    /// Bind the sockets for `services`, unless they were prepared already, must be
+    /// called from within a runtime.
+    fn start<T: Transport>(
+        services: &Services,
+        carried: &Carried,
+        mut config: ResponderConfig,
+        sockets: Option<PreparedSockets>,
+    ) -> Result<(Started, ResponderTask), Error> {
+        if let Some(ref sockets) = sockets {
+            sockets.configure(&mut config);
+        }
+        config.check_address_families()?;
+        let shared = carried.shared(services, config);
+
+        let (v4, v6) = match sockets {
+            Some(PreparedSockets { v4, v6, .. }) => (
+                v4.map(|(socket, scopes)| FSM::<Inet, T>::with_socket(&shared, socket, scopes)),
+                v6.map(|(socket, scopes)| FSM::<Inet6, T>::with_socket(&shared, socket, scopes)),
+            ),
+            None => (
+                (!shared.config.disable_ipv4).then(|| FSM::<Inet, T>::new(&shared)),
+                (!shared.config.disable_ipv6).then(|| FSM::<Inet6, T>::new(&shared)),
+            ),
+        };
+
+        let (task, commands): (ResponderTask, _) = match (v4, v6) {
+            (Some(Ok((v4_task, v4_command))), Some(Ok((v6_task, v6_command)))) => {
+                let tasks = future::join(v4_task, v6_task).map(|((), ())| ());
+                (Box::new(tasks), vec![v4_command, v6_command])
+            }
+
+            (Some(Ok((v4_task, v4_command))), Some(Err(err))) => {
+                warn!("Failed to register IPv6 receiver: {:?}", err);
+                (Box::new(v4_task), vec![v4_command])
+            }
+
+            (Some(Ok((v4_task, v4_command))), None) => (Box::new(v4_task), vec![v4_command]),
+            (None, Some(Ok((v6_task, v6_command)))) => (Box::new(v6_task), vec![v6_command]),
+
+            (Some(Err(err)), _) | (None, Some(Err(err))) => return Err(err),
+            (None, None) => unreachable!("checked above, or when the sockets were prepared"),
+        };
+
+        Ok((Started { shared, commands }, task))
+    }
+
+    /// Like `start`, with a task per enabled family sending through `sink`
+    fn start_tethered(
+        services: &Services,
+        carried: &Carried,
+        mut config: ResponderConfig,
+        sink: SharedSink,
+    ) -> Result<(Started, ResponderTask), Error> {
+        config.check_address_families()?;
+        config.sink = true;
+        let shared = carried.shared(services, config);
+
+        let link = || SinkLink(sink.clone());
+        let v4 = (!shared.config.disable_ipv4)
+            .then(|| FSM::<Inet, SinkLink>::with_link(&shared, link(), Vec::new()))
+            .transpose()?;
+        let v6 = (!shared.config.disable_ipv6)
+            .then(|| FSM::<Inet6, SinkLink>::with_link(&shared, link(), Vec::new()))
+            .transpose()?;
+        let (task, commands): (ResponderTask, _) = match (v4, v6) {
+            (Some((v4_task, v4_command)), Some((v6_task, v6_command))) => {
+                let tasks = future::join(v4_task, v6_task).map(|((), ())| ());
+                (Box::new(tasks), vec![v4_command, v6_command])
+            }
+            (Some((v4_task, v4_command)), None) => (Box::new(v4_task), vec![v4_command]),
+            (None, Some((v6_task, v6_command))) => (Box::new(v6_task), vec![v6_command]),
+            (None, None) => unreachable!("checked above"),
+        };
+
+        Ok((Started { shared, commands }, task))
+    }
+
+    /// Hand all registered services over to a new responder configured by `builder`,
+    /// without the goodbyes and fresh announcements that dropping this one and
+    /// registering them again would cause.
+    ///
+    /// The new sockets are bound before the old ones are closed, and the new task
+    /// runs where this one did: on its own thread or on the same tokio runtime. That of
+    /// a responder built with
+    /// [`build_with_transport`](ResponderBuilder::build_with_transport) runs on a new
+    /// thread, as there is no telling how to spawn it on the caller's executor. That of
+    /// one built with [`build_with_sink`](ResponderBuilder::build_with_sink) is
+    /// spawned on the tokio runtime `replace` is called on, or else on a new thread;
+    /// use [`replace_with_task`](Responder::replace_with_task) to poll it yourself.
+    /// Existing [`Service`] handles keep working and now belong to the new responder.
+    ///
+    /// If the hostname changes, the old task sends goodbyes for the addresses and SRV
+    /// records published under the old one as it ends. [`metrics`](Responder::metrics)
+    /// go on counting from those of this responder.
+    ///
+    /// The new sockets are bound like those of a new responder, so this fails once the
+    /// privileges binding needed have been given up, see [`PreparedSockets`]. Use
+    /// [`replace_with_sockets`](Responder::replace_with_sockets) then.
+    pub fn replace(self, builder: ResponderBuilder) -> Result<Responder, Error> {
+        self.replace_inner(builder, None, false)
+            .map(|(responder, _)| responder)
+    }
+
+    /// Like [`replace`](Responder::replace), leaving the new task to the caller as
+    /// [`build`](ResponderBuilder::build) and
+    /// [`build_with_sink`](ResponderBuilder::build_with_sink) do. The new task of a
+    /// responder built with `build_with_sink` sends through the same sink; that of any
+    /// other uses tokio's sockets, and this must be called from within a tokio runtime.
+    /// Either uses tokio's timers, so must be polled on a tokio runtime with time
+    /// enabled.
+    pub fn replace_with_task(
+        self,
+        builder: ResponderBuilder,
+    ) -> Result<(Responder, ResponderTask), Error> {
+        self.replace_inner(builder, None, true)
+            .map(|(responder, task)| (responder, task.expect("asked for the task")))
+    }
+
+    /// Like [`replace`](Responder::replace), with sockets from
+    /// [`prepare_sockets`](ResponderBuilder::prepare_sockets), e.g. prepared for the
+    /// new configuration before giving up privileges. A responder built with
+    /// [`build_with_sink`](ResponderBuilder::build_with_sink) has no sockets, and
+    /// drops them.
+    pub fn replace_with_sockets(
+        self,
+        builder: ResponderBuilder,
+        sockets: PreparedSockets,
+    ) -> Result<Responder, Error> {
+        self.replace_inner(builder, Some(sockets), false)
+            .map(|(responder, _)| responder)
+    }
+
+    /// Replace this responder, returning the new task if `return_task` is set, and
+    /// otherwise running it where this one ran
+    fn replace_inner(
+        self,
+        builder: ResponderBuilder,
+        sockets: Option<PreparedSockets>,
+        return_task: bool,
+    ) -> Result<(Responder, Option<ResponderTask>), Error> {
+        let config = builder.config;
+        let hostname = resolve_hostname(&config)?;
+
+        let services = self.services.clone();
+        let carried = Carried {
+            browsers: Some(self.browsers.clone()),
+            counters: self.counters.clone(),
+        };
+        let (started, task) = match self.runner {
+            Runner::Tethered(ref sink) if return_task => {
+                let (started, task) =
+                    Self::start_tethered(&services, &carried, config, sink.clone())?;
+                (started, Some(task))
+            }
+            _ if return_task => {
+                let (started, task) =
+                    Self::start::<tokio::net::UdpSocket>(&services, &carried, config, sockets)?;
+                (started, Some(task))
+            }
+            Runner::Thread | Runner::Caller => {
+                let started = Self::spawn_thread_with(move || {
+                    Self::start::<tokio::net::UdpSocket>(&services, &carried, config, sockets)
+                })?;
+                (started, None)
+            }
+            Runner::Tethered(ref sink) => {
+                let sink = sink.clone();
+                let started = match Handle::try_current() {
+                    Ok(handle) => {
+                        let (started, task) =
+                            Self::start_tethered(&services, &carried, config, sink)?;
+                        handle.spawn(task);
+                        started
+                    }
+                    Err(_) => Self::spawn_thread_with(move || {
+                        Self::start_tethered(&services, &carried, config, sink)
+                    })?,
+                };
+                (started, None)
+            }
+            Runner::Handle(ref handle) => {
+                let _guard = handle.enter();
+                let (started, task) =
+                    Self::start::<tokio::net::UdpSocket>(&services, &carried, config, sockets)?;
+                handle.spawn(task);
+                (started, None)
+            }
+        };
+
+        let commands = self.commands.into_inner();
+        let migrated: Vec<ServiceData> = {
+            let mut services = self.services.write().unwrap_or_else(PoisonError::into_inner);
+            // The old tasks withdraw what they published under the old hostname, before
+            // the new tasks announce the new one
+            let old_hostname = services.get_hostname().clone();
+            services.set_hostname(hostname);
+            if &old_hostname as &dyn Canonical != services.get_hostname() as &dyn Canonical {
+                commands.withdraw_hostname(old_hostname);
+            }
+            services.into_iter().cloned().collect()
+        };
+
+        let old = commands.replace(started.commands);
+        started.shared.watch_host(Some(commands.waker()));
+        old.send_shutdown();
+        for svc in migrated {
+            commands.clone().send_unsolicited(svc, false, true);
+        }
+
+        let responder = Responder {
+            services: self.services,
+            config: started.shared.config,
+            host: started.shared.host,
+            counters: started.shared.counters,
+            cache: started.shared.cache,
+            browsers: self.browsers,
+            commands: RefCell::new(commands),
+            shutdown: self.shutdown,
+            runner: match self.runner {
+                Runner::Tethered(sink) => Runner::Tethered(sink),
+                _ if return_task => Runner::Caller,
+                Runner::Caller => Runner::Thread,
+                runner => runner,
+            },
+        };
+
+        // Later calls to `shared` should see the new configuration
+        let mut shared = SHARED.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(ref mut weak) = *shared {
+            if weak.shutdown.ptr_eq(&Arc::downgrade(&responder.shutdown)) {
+                *weak = WeakResponder::new(&responder);
+            }
+        }
+        drop(shared);
+
+        Ok((responder, task))
+    }
+}
+
+/// What a responder started by `replace` takes over from the one it replaces
+#[derive(Default)]
+struct Carried {
+    browsers: Option<Arc<Browsers>>,
+    counters: Arc<Counters>,
+}
+
+impl Carried {
+    /// The state of the handlers of the new responder, the browses carried over
+    /// charged to its memory budget
+    fn shared(&self, services: &Services, config: ResponderConfig) -> Shared {
+        let mut shared = Shared::with_counters(services, config, self.counters.clone());
+        if let Some(ref browsers) = self.browsers {
+            browsers.use_budget(&shared.budget);
+            shared.browsers = browsers.clone();
+        }
+        shared
+    }
+}
+
+/// A newly started responder task and the means of controlling it
+struct Started {
+    shared: Shared,
+    commands: Vec<mpsc::UnboundedSender<Command>>,
+}
+
+impl Responder {
+    /// Register a service to be advertised by the `Responder`. The service is unregistered
+    /// once the returned handle and all its clones are dropped.
+    ///
+    /// The instance name `svc_name` may be any text, see [`InstanceName`](crate::InstanceName).
+    ///
+    /// # example
+    ///
+    /// ```no_run
+    /// use libmdns::Responder;
+    ///
+    /// # use std::io;
+    /// # fn main() -> io::Result<()> {
+    /// let responder = Responder::new()?;
+    /// // bind service
+    /// let _http_svc = responder.register(
+    ///          "_http._tcp".into(),
+    ///          "my http server".into(),
+    ///          80,
+    ///          &["path=/"]
+    ///      );
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn register(&self, svc_type: String, svc_name: String, port: u16, txt: &[&str]) -> Service {
+        self.register_service(ServiceBuilder::new(&svc_type, &svc_name, port).txt(txt))
+    }
+
+    /// Like [`register`](Responder::register), with the TXT data given as a
+    /// [`TxtRecord`] whose entries have been validated.
+    #[must_use]
+    pub fn register_txt(
+        &self,
+        svc_type: String,
+        svc_name: String,
+        port: u16,
+        txt: TxtRecord,
+    ) -> Service {
+        self.register_service(ServiceBuilder::new(&svc_type, &svc_name, port).txt(txt))
+    }
+
+    /// Register a service described by a [`ServiceBuilder`]. The service is
+    /// unregistered once the returned handle and all its clones are dropped.
+    ///
+    /// # Panics
+    ///
+    /// If the service uses [`ConflictStrategy::Fail`](crate::ConflictStrategy::Fail) and its name is taken, or one of
+    /// its names is too long for DNS, see
+    /// [`try_register_service`](Responder::try_register_service).
+    #[must_use]
+    pub fn register_service(&self, service: ServiceBuilder) -> Service {
+        self.try_register_service(service)
+            .expect("couldn't register service")
+    }
+
+    /// Like [`register_service`](Responder::register_service), but failing with
+    /// [`Error::NameConflict`] when a service using [`ConflictStrategy::Fail`](crate::ConflictStrategy::Fail) has
+    /// the name of one already registered, and with [`Error::LabelTooLong`] or
+    /// [`Error::NameTooLong`] when one of its names can't be encoded in DNS: the
+    /// instance name may take up to 63 bytes, and each name up to 255. Fails with
+    /// [`Error::StoreFull`] when a fixed service storage has no room left.
+    pub fn try_register_service(&self, service: ServiceBuilder) -> Result<Service, Error> {
+        let registered = engine::register_services(&self.services, &self.config, Some(service))?;
+        let engine::Registered { id, svc, .. } = registered.into_iter().next().unwrap();
+
+        let (announced, sent) = Announced::new();
+        self.commands.borrow().send_announcement(svc, announced);
+
+        Ok(Service(Arc::new(Registration {
+            id,
+            services: self.services.clone(),
+            commands: self.commands.borrow().clone(),
+            announced: sent.shared(),
+            withdrawn: Mutex::new(None),
+            detached: AtomicBool::new(false),
+            _shutdown: self.shutdown.clone(),
+        })))
+    }
+
+    /// Register a service like [`try_register_service`](Responder::try_register_service),
+    /// resolving once it has been announced on every address family, so that others
+    /// can find it, e.g. to report being ready only then.
+    ///
+    /// The service is registered straight away. If the future is dropped before it
+    /// resolves, the service is unregistered again, as when its handle is dropped.
+    /// Fails with [`Error::ShutDown`] if the responder stops before the announcement
+    /// goes out.
+    pub fn register_async(
+        &self,
+        service: ServiceBuilder,
+    ) -> impl Future<Output = Result<Service, Error>> {
+        let registered = self.try_register_service(service);
+        async move {
+            let service = registered?;
+            service.announced().await?;
+            Ok(service)
+        }
+    }
+
+    /// Stop advertising a service detached with [`Service::detach`], sending goodbyes
+    /// for it. Returns false if it was withdrawn already, or registered with another
+    /// responder.
+    pub fn unregister(&self, token: ServiceToken) -> bool {
+        token.withdraw(&self.services)
+    }
+
+    /// Start a group of services to register together, see [`ServiceGroup`].
+    pub fn service_group(&self) -> ServiceGroup {
+        ServiceGroup {
+            services: self.services.clone(),
+            config: self.config.clone(),
+            commands: self.commands.borrow().clone(),
+            shutdown: self.shutdown.clone(),
+            staged: Vec::new(),
+            committed: Vec::new(),
+        }
+    }
+}
+
+impl Service {
+    /// The instance name the service is registered under, which differs from the
+    /// requested one if that was taken.
+    pub fn name(&self) -> String {
+        self.0.read(|svc| svc.instance())
+    }
+
+    /// Resolves once the first announcement of the service has been sent on every
+    /// address family, straight away if it has been already. Services committed in a
+    /// [`ServiceGroup`] are announced, and resolve, together.
+    ///
+    /// Fails with [`Error::ShutDown`] if the responder stops, or is
+    /// [replaced](Responder::replace), before the announcement goes out.
+    pub fn announced(&self) -> impl Future<Output = Result<(), Error>> + Send + 'static {
+        self.0.announced.clone().map(|sent| match sent {
+            Ok(true) => Ok(()),
+            _ => Err(Error::ShutDown),
+        })
+    }
+
+    /// The port currently advertised for this service.
+    pub fn port(&self) -> u16 {
+        self.0.read(|svc| svc.port)
+    }
+
+    /// The TXT RDATA currently advertised for this service, as a sequence of
+    /// length-prefixed strings. A service registered without TXT data has a single
+    /// empty string. Changes made by a [`TxtDecorator`](crate::TxtDecorator) as the record is sent are
+    /// not included.
+    pub fn txt(&self) -> Vec<u8> {
+        self.0.read(|svc| svc.txt.clone())
+    }
+
+    /// Change the port advertised for this service.
+    ///
+    /// A goodbye is sent for the old SRV record before the updated records are announced.
+    pub fn update_port(&mut self, port: u16) {
+        let updated = self.0.update(|svc| {
+            svc.port = port;
+            Ok(())
+        });
+        updated.expect("the name is unchanged");
+    }
+
+    /// The SRV records published besides the one for [`port`](Service::port).
+    pub fn srv_records(&self) -> Vec<SrvRecord> {
+        self.0
+            .read(|svc| svc.extra_srv.iter().map(SrvRecord::from_extra).collect())
+    }
+
+    /// Replace the SRV records published besides the one for the port.
+    ///
+    /// Goodbyes are sent for the records which are gone before the new set is announced.
+    pub fn update_srv_records(&mut self, records: &[SrvRecord]) {
+        let updated = self
+            .0
+            .update(|svc| {
+                svc.set_extra_srv(records.iter().map(SrvRecord::to_extra).collect());
+                Ok(())
+            });
+        updated.expect("the name is unchanged");
+    }
+
+    /// Change the instance name of this service, keeping its type. Fails, leaving the
+    /// service as it was, with [`Error::NameConflict`] if another service of this
+    /// responder has the name, and like [`register`](Responder::register) with
+    /// [`Error::LabelTooLong`] for names longer than the 63 bytes DNS allows.
+    ///
+    /// A goodbye is sent for the records under the old name before the new ones are announced.
+    pub fn update_name(&mut self, svc_name: String) -> Result<(), Error> {
+        self.0.update(|svc| {
+            check_instance(&svc_name, &svc.typ)?;
+            svc.set_instance(&svc_name);
+            Ok(())
+        })
+    }
+
+    /// Stop advertising the service and send goodbyes for it, even while other clones
+    /// of this handle are alive. They keep returning the service's last data, and
+    /// ignore updates.
+    pub fn withdraw(&self) {
+        self.0.withdraw();
+    }
+
+    /// Whether the service was withdrawn with [`withdraw`](Service::withdraw).
+    pub fn is_withdrawn(&self) -> bool {
+        self.0.withdrawn.lock().unwrap_or_else(PoisonError::into_inner).is_some()
+    }
+
+    /// Keep the service registered without holding on to a handle, e.g. where it
+    /// is owned by foreign code, until it is unregistered with the returned token by
+    /// [`Responder::unregister`]. No handle sends goodbyes when dropped any more,
+    /// though clones of this one can still update or withdraw the service.
+    pub fn detach(self) -> ServiceToken {
+        self.0.detached.store(true, Ordering::SeqCst);
+        ServiceToken(self.0)
+    }
+}
+
+impl ServiceToken {
+    /// Withdraw the service if it belongs to `services` and hasn't been withdrawn yet
+    fn withdraw(self, services: &Services) -> bool {
+        if !Arc::ptr_eq(&self.0.services, services) {
+            return false;
+        }
+        match self.0.unregister() {
+            Some(svc) => {
+                self.0.commands.send_unsolicited(svc, true, false);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Registration {
+    fn read<T>(&self, f: impl FnOnce(&ServiceData) -> T) -> T {
+        let withdrawn = self.withdrawn.lock().unwrap_or_else(PoisonError::into_inner);
+        match *withdrawn {
+            Some(ref svc) => f(svc),
+            None => f(self
+                .services
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(self.id)
+                .expect("unknown service")),
+        }
+    }
+
+    /// Change the service with `f`, failing if it was given the name of another
+    fn update(&self, f: impl FnOnce(&mut ServiceData) -> Result<(), Error>) -> Result<(), Error> {
+        // Held so that the service isn't withdrawn meanwhile
+        let withdrawn = self.withdrawn.lock().unwrap_or_else(PoisonError::into_inner);
+        if withdrawn.is_some() {
+            debug!("ignoring update of withdrawn service {}", self.id);
+            return Ok(());
+        }
+        let (old, new) = {
+            let mut services = self.services.write().unwrap_or_else(PoisonError::into_inner);
+            let mut svc = services.get(self.id).expect("unknown service").clone();
+            f(&mut svc)?;
+            (services.update(self.id, svc.clone())?, svc)
+        };
+        drop(withdrawn);
+
+        if old.name == new.name {
+            self.commands.send_srv_goodbye(old, new.clone());
+        } else {
+            self.commands.send_unsolicited(old, true, false);
+        }
+        self.commands.send_unsolicited(new, false, true);
+        Ok(())
+    }
+
+    fn withdraw(&self) {
+        if let Some(svc) = self.unregister() {
+            self.commands.send_unsolicited(svc, true, false);
+        }
+    }
+
+    /// Stop advertising the service without sending goodbyes, returning its last
+    /// data unless it was withdrawn already
+    fn unregister(&self) -> Option<ServiceData> {
+        let mut withdrawn = self.withdrawn.lock().unwrap_or_else(PoisonError::into_inner);
+        if withdrawn.is_some() {
+            return None;
+        }
+        let svc = self.services.write().unwrap_or_else(PoisonError::into_inner).unregister(self.id);
+        *withdrawn = Some(svc.clone());
+        Some(svc)
+    }
+}
+
+impl ServiceGroup {
+    /// Stage a service, to be registered by the next [`commit`](ServiceGroup::commit).
+    pub fn add(&mut self, service: ServiceBuilder) -> &mut ServiceGroup {
+        self.staged.push(service);
+        self
+    }
+
+    /// Register the staged services, probing for their names together (RFC 6762
+    /// section 8.1) and then announcing them all together. A service renamed after a
+    /// conflict meanwhile is left out, and probed for and announced on its own. If all
+    /// of the names were restored from a recent snapshot, they are still ours and are
+    /// announced straight away.
+    ///
+    /// Either all of them are registered or none are: if one uses
+    /// [`ConflictStrategy::Fail`](crate::ConflictStrategy::Fail) and its name is
+    /// taken, [`Error::NameConflict`] is returned, and if one has a name too long for
+    /// DNS, [`Error::LabelTooLong`] or [`Error::NameTooLong`]. The staged services are
+    /// discarded either way. Returns handles for the services registered, which are
+    /// withdrawn with the group even while the handles are alive.
+    pub fn commit(&mut self) -> Result<Vec<Service>, Error> {
+        let staged = self.staged.drain(..);
+        let registered = engine::register_services(&self.services, &self.config, staged)?;
+        let defended = registered.iter().all(|registered| registered.defended);
+        let (ids, svcs): (Vec<usize>, Vec<ServiceData>) = registered
+            .into_iter()
+            .map(|registered| (registered.id, registered.svc))
+            .unzip();
+
+        // Sent together, the services are announced together
+        let (announced, sent) = Announced::new();
+        if !svcs.is_empty() {
+            let commands = &self.commands;
+            match defended {
+                true => commands.send_unsolicited_group(svcs, false, true, Some(announced)),
+                false => commands.send_probe_group(svcs, announced),
+            }
+        }
+        let sent = sent.shared();
+
+        let committed: Vec<Service> = ids
+            .into_iter()
+            .map(|id| {
+                Service(Arc::new(Registration {
+                    id,
+                    services: self.services.clone(),
+                    commands: self.commands.clone(),
+                    announced: sent.clone(),
+                    withdrawn: Mutex::new(None),
+                    detached: AtomicBool::new(false),
+                    _shutdown: self.shutdown.clone(),
+                }))
+            })
+            .collect();
+        self.committed.extend(committed.iter().cloned());
+        Ok(committed)
+    }
+
+    /// The services committed so far, including any withdrawn since.
+    pub fn services(&self) -> &[Service] {
+        &self.committed
+    }
+}
+
+impl Drop for ServiceGroup {
+    fn drop(&mut self) {
+        let svcs: Vec<ServiceData> = self
+            .committed
+            .iter()
+            .filter(|service| !service.0.detached.load(Ordering::SeqCst))
+            .filter_map(|service| service.0.unregister())
+            .collect();
+        if !svcs.is_empty() {
+            self.commands
+                .send_unsolicited_group(svcs, true, false, None);
+        }
+    }
+}
+
+impl Responder {
+    /// The source of the hostname and addresses this responder publishes.
+    pub fn host(&self) -> Arc<dyn HostData> {
+        self.host.clone()
+    }
+
+    /// Counters describing what the responder has been doing.
+    #[cfg(feature = "metrics-minimal")]
+    pub fn metrics(&self) -> Metrics {
+        self.counters.metrics()
+    }
+
+    /// The records other responders have sent, if enabled with
+    /// [`ResponderBuilder::cache_records`]. A replaced responder starts with an empty
+    /// cache.
+    pub fn cached_records(&self) -> Vec<CachedRecord> {
+        self.cache.records(Instant::now())
+    }
+
+    /// Browse for the instances of a service type on the network, this responder's
+    /// own included, see [`Browse`]. Queries are sent until the `Browse` is dropped,
+    /// starting right away, and carry on on a replacement responder.
+    ///
+    /// ```no_run
+    /// use futures_util::StreamExt;
+    /// use libmdns::{BrowseBuilder, Responder};
+    ///
+    /// # async fn browse(responder: Responder) {
+    /// let mut browse = responder.browse(BrowseBuilder::new("_http._tcp").unwrap());
+    /// while let Some(service) = browse.next().await {
+    ///     println!("{:?} {} at {:?}", service.discovery, service.name, service.addresses);
+    /// }
+    /// # }
+    /// ```
+    pub fn browse(&self, browse: BrowseBuilder) -> Browse {
+        let browse = Browse::start(browse, &self.browsers, self.shutdown.clone());
+        self.commands.borrow().send(Command::Browse);
+        browse
+    }
+
+    /// Check whether mDNS can work here, without starting a responder: that port 5353
+    /// can be bound, which interfaces can join the multicast group, and whether a query
+    /// we send comes back to us within `timeout`. Blocks for up to `timeout` per family.
+    ///
+    /// Use [`ResponderBuilder::preflight`] to check only the configured interfaces.
+    pub fn preflight(timeout: Duration) -> PreflightReport {
+        crate::preflight::run(&[], timeout)
+    }
+
+    /// Switch how eagerly queries are answered, e.g. when the application detects poor
+    /// connectivity.
+    pub fn set_network_profile(&self, profile: NetworkProfile) {
+        self.commands
+            .borrow_mut()
+            .send(Command::SetNetworkProfile(profile));
+    }
+
+    /// Tell the responder the host has woken from sleep, while which others may have
+    /// forgotten its services and its addresses may have changed. The addresses are
+    /// read again, and every service is announced as when registered.
+    ///
+    /// With the `power-events` feature,
+    /// [`watch_power_events`](Responder::watch_power_events) calls this on Linux.
+    /// Elsewhere, applications which learn of resumes call it themselves: there is
+    /// no watcher yet for the suspend/resume notifications of Windows, nor for the
+    /// IOKit power notifications of macOS, which need a `CFRunLoop` to be delivered on.
+    pub fn notify_resumed(&self) {
+        self.commands.borrow().send(Command::Resumed);
+    }
+
+    /// Handle `packet` as if received from `from` on the mDNS port. This is how a
+    /// responder built with [`build_with_sink`](ResponderBuilder::build_with_sink)
+    /// receives anything. A responder with sockets answers through them as usual,
+    /// which lets tests inject queries.
+    pub fn feed_packet(&self, packet: &[u8], from: SocketAddr) {
+        self.commands.borrow().send(Command::Received {
+            packet: packet.to_vec(),
+            from,
+        });
+    }
+
+    /// Publish the host's addresses under `alias` as well as the hostname, so that
+    /// e.g. `myapp.local` resolves to this machine. `.local` is appended if missing.
+    /// The alias is probed for and then announced, and answered like the hostname.
+    /// If another host publishes it, it is given up and reported to
+    /// [`on_hostname_conflict`](ResponderBuilder::on_hostname_conflict).
+    pub fn add_hostname_alias(&self, alias: &str) -> Result<(), Error> {
+        let alias = local_hostname(alias.to_owned())?;
+        if self.services.write().unwrap_or_else(PoisonError::into_inner).add_alias(alias) {
+            self.commands.borrow_mut().send(Command::HostChanged);
+        }
+        Ok(())
+    }
+
+    /// Stop publishing `alias`, sending goodbyes for it. Returns whether it was added
+    /// with [`add_hostname_alias`](Responder::add_hostname_alias).
+    pub fn remove_hostname_alias(&self, alias: &str) -> bool {
+        let alias = match local_hostname(alias.to_owned()) {
+            Ok(alias) => alias,
+            Err(_) => return false,
+        };
+        let removed = self.services.write().unwrap_or_else(PoisonError::into_inner).remove_alias(&alias);
+        if removed {
+            self.commands.borrow_mut().send(Command::HostChanged);
+        }
+        removed
+    }
+}
+
+#[cfg(all(feature = "control", unix))]
+impl Responder {
+    /// Serve requests from debugging tools on a unix socket at `path`, until the
+    /// returned server is dropped, see [`ControlServer`] for the protocol. A socket
+    /// left at `path` by a process which has exited is replaced.
+    ///
+    /// Anyone who can connect can list the services and change the log level, so
+    /// `path` should be somewhere only trusted users can reach.
+    pub fn serve_control<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+    ) -> std::io::Result<ControlServer> {
+        ControlServer::start(self, path.as_ref())
+    }
+}
+
+#[cfg(all(feature = "power-events", target_os = "linux"))]
+impl Responder {
+    /// Announce the services again whenever the host resumes from sleep, until the
+    /// returned watcher is dropped, see [`notify_resumed`](Responder::notify_resumed).
+    /// Fails without a system bus to reach systemd-logind on. Only on Linux for now,
+    /// see `notify_resumed` for other platforms.
+    pub fn watch_power_events(&self) -> std::io::Result<PowerWatcher> {
+        PowerWatcher::start(self)
+    }
+}
+
+#[cfg(feature = "snapshot")]
+impl Responder {
+    /// Capture the registered services and hostname, to be saved and passed to
+    /// [`ResponderBuilder::restore`] after a restart.
+    pub fn snapshot(&self) -> Snapshot {
+        self.services.read().unwrap_or_else(PoisonError::into_inner).snapshot()
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if !self.detached.load(Ordering::SeqCst) {
+            self.withdraw();
+        }
+    }
+}
+
+pub(crate) struct Shutdown(CommandSender);
+
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        self.0.send_shutdown();
+        // TODO wait for tasks to shutdown
+    }
+}
+
+/// Senders for the tasks of every address family. Clones share the senders, so
+/// that `Responder::replace` can redirect them all to a new set of tasks.
+#[derive(Clone)]
+pub(crate) struct CommandSender(Arc<RwLock<Vec<mpsc::UnboundedSender<Command>>>>);
+impl CommandSender {
+    fn new(senders: Vec<mpsc::UnboundedSender<Command>>) -> CommandSender {
+        CommandSender(Arc::new(RwLock::new(senders)))
+    }
+
+    /// Send to `senders` from now on, returning the old ones.
+    fn replace(&self, senders: Vec<mpsc::UnboundedSender<Command>>) -> CommandSender {
+        let old = std::mem::replace(&mut *self.0.write().unwrap_or_else(PoisonError::into_inner), senders);
+        CommandSender::new(old)
+    }
+
+    fn send(&self, cmd: Command) {
+        let senders = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        // The last family takes the command itself, so that an `Announced` in it
+        // isn't counted once more than there are families to send it
+        if let Some((last, others)) = senders.split_last() {
+            for tx in others {
+                tx.send(cmd.clone()).expect("responder died");
+            }
+            last.send(cmd).expect("responder died");
+        }
+    }
+
+    fn send_unsolicited(&self, svc: ServiceData, goodbye: bool, include_ip: bool) {
+        self.send(Command::SendUnsolicited {
+            svc,
+            goodbye,
+            include_ip,
+            announced: None,
+        });
+    }
+
+    /// Announce `svc`, telling `announced` once every address family has sent it
+    fn send_announcement(&self, svc: ServiceData, announced: Announced) {
+        self.send(Command::SendUnsolicited {
+            svc,
+            goodbye: false,
+            include_ip: true,
+            announced: Some(announced),
+        });
+    }
+
+    pub(crate) fn send_unsolicited_group(
+        &self,
+        svcs: Vec<ServiceData>,
+        goodbye: bool,
+        include_ip: bool,
+        announced: Option<Announced>,
+    ) {
+        self.send(Command::SendUnsolicitedGroup {
+            svcs,
+            goodbye,
+            include_ip,
+            announced,
+        });
+    }
+
+    /// Probe for the names of `svcs` and then announce them together, telling
+    /// `announced` once every address family has sent the announcement
+    fn send_probe_group(&self, svcs: Vec<ServiceData>, announced: Announced) {
+        self.send(Command::ProbeGroup { svcs, announced });
+    }
+
+    fn send_srv_goodbye(&self, svc: ServiceData, current: ServiceData) {
+        self.send(Command::SendSrvGoodbye { svc, current });
+    }
+
+    /// Wakes the tasks to check for changes to the `HostData`. Unlike the other
+    /// commands this may be sent after the tasks have stopped.
+    fn waker(&self) -> Arc<dyn Fn() + Send + Sync> {
+        let senders = self.0.clone();
+        Arc::new(move || {
+            for tx in senders.read().unwrap_or_else(PoisonError::into_inner).iter() {
+                let _ = tx.send(Command::HostChanged);
+            }
+        })
+    }
+
+    fn withdraw_hostname(&self, hostname: Name<'static>) {
+        self.send(Command::WithdrawHostname(hostname));
+    }
+
+    fn send_shutdown(&self) {
+        self.send(Command::Shutdown);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::ServicesInner;
+    use crate::host::FixedHostData;
+    use crate::rename::ConflictStrategy;
+    use crate::{address_family, dns_parser, fsm};
+
+    /// Goodbyes sent so far
+    fn goodbyes(rx: &mut mpsc::UnboundedReceiver<Command>) -> usize {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .filter(|cmd| matches!(cmd, Command::SendUnsolicited { goodbye: true, .. }))
+            .count()
+    }
+
+    #[test]
+    fn test_shared_service() {
+        let services = Arc::new(RwLock::new(ServicesInner::new("test-host.local".into())));
+        let svc = ServiceBuilder::new("_http._tcp", "instance", 80)
+            .build()
+            .unwrap();
+        let id = services.write().unwrap().register(svc).unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let commands = CommandSender::new(vec![tx]);
+        let service = Service(Arc::new(Registration {
+            id,
+            services: services.clone(),
+            commands: commands.clone(),
+            announced: Announced::new().1.shared(),
+            withdrawn: Mutex::new(None),
+            detached: AtomicBool::new(false),
+            _shutdown: Arc::new(Shutdown(commands)),
+        }));
+
+        let mut clone = service.clone();
+        drop(service);
+        assert_eq!(
+            goodbyes(&mut rx),
+            0,
+            "still advertised while a clone is alive"
+        );
+        assert!(services.read().unwrap().get(id).is_some());
+
+        let other = clone.clone();
+        other.withdraw();
+        assert_eq!(goodbyes(&mut rx), 1);
+        assert!(clone.is_withdrawn());
+        assert!(services.read().unwrap().get(id).is_none());
+
+        // The last data is kept, and updates go nowhere
+        clone.update_port(81);
+        assert_eq!(clone.name(), "instance");
+        assert_eq!(clone.port(), 80);
+        drop(other);
+        drop(clone);
+        assert_eq!(goodbyes(&mut rx), 0);
+    }
+
+    #[test]
+    fn test_service_accessors() {
+        let services = Arc::new(RwLock::new(ServicesInner::new("test-host.local".into())));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let commands = CommandSender::new(vec![tx]);
+        let register = |service: ServiceBuilder| {
+            let id = services
+                .write()
+                .unwrap()
+                .register(service.build().unwrap())
+                .unwrap();
+            Service(Arc::new(Registration {
+                id,
+                services: services.clone(),
+                commands: commands.clone(),
+                announced: Announced::new().1.shared(),
+                withdrawn: Mutex::new(None),
+                detached: AtomicBool::new(false),
+                _shutdown: Arc::new(Shutdown(commands.clone())),
+            }))
+        };
+
+        let mut service = register(ServiceBuilder::new("_http._tcp", "web", 80).txt(&["a=b"]));
+        assert_eq!(service.port(), 80);
+        assert_eq!(service.txt(), b"\x03a=b");
+        let bare = register(ServiceBuilder::new("_http._tcp", "bare", 80));
+        assert_eq!(bare.txt(), b"\x00");
+
+        // They follow updates, whichever handle makes them
+        service.clone().update_port(81);
+        assert_eq!(service.port(), 81);
+        service.update_port(82);
+        assert_eq!(service.port(), 82);
+        let id = service.0.id;
+        let mut svc = services.read().unwrap().get(id).unwrap().clone();
+        svc.txt = b"\x03c=d".to_vec();
+        services.write().unwrap().update(id, svc).unwrap();
+        assert_eq!(service.txt(), b"\x03c=d");
+
+        // A name in use by another service is refused
+        assert!(matches!(
+            service.update_name("bare".into()),
+            Err(Error::NameConflict(ref name)) if name == "bare"
+        ));
+        // As is one too long for DNS, rather than being cut short
+        let long = "x".repeat(64);
+        assert!(matches!(
+            service.update_name(long.clone()),
+            Err(Error::LabelTooLong { ref label, .. }) if *label == long
+        ));
+        service.update_name("site".into()).unwrap();
+        let name = services.read().unwrap().get(id).unwrap().name.to_string();
+        assert_eq!(name, "site._http._tcp.local");
+    }
+
+    #[test]
+    fn test_detached_service() {
+        let services = Arc::new(RwLock::new(ServicesInner::new("test-host.local".into())));
+        let svc = ServiceBuilder::new("_http._tcp", "instance", 80)
+            .build()
+            .unwrap();
+        let id = services.write().unwrap().register(svc).unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let commands = CommandSender::new(vec![tx]);
+        let service = Service(Arc::new(Registration {
+            id,
+            services: services.clone(),
+            commands: commands.clone(),
+            announced: Announced::new().1.shared(),
+            withdrawn: Mutex::new(None),
+            detached: AtomicBool::new(false),
+            _shutdown: Arc::new(Shutdown(commands)),
+        }));
+
+        let clone = service.clone();
+        let token = service.detach();
+        drop(clone);
+        assert_eq!(goodbyes(&mut rx), 0);
+        assert!(services.read().unwrap().get(id).is_some());
+
+        // Only the responder the service belongs to unregisters it
+        let other = Arc::new(RwLock::new(ServicesInner::new("test-host.local".into())));
+        let ServiceToken(registration) = token;
+        assert!(!ServiceToken(registration.clone()).withdraw(&other));
+        assert!(ServiceToken(registration.clone()).withdraw(&services));
+        assert_eq!(goodbyes(&mut rx), 1);
+        assert!(services.read().unwrap().get(id).is_none());
+        assert!(!ServiceToken(registration).withdraw(&services));
+        assert_eq!(goodbyes(&mut rx), 0);
+    }
+
+    #[test]
+    fn test_service_group() {
+        let services = Arc::new(RwLock::new(ServicesInner::new("test-host.local".into())));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let commands = CommandSender::new(vec![tx]);
+        let mut group = ServiceGroup {
+            services: services.clone(),
+            config: Arc::new(ResponderConfig::default()),
+            commands: commands.clone(),
+            shutdown: Arc::new(Shutdown(commands)),
+            staged: Vec::new(),
+            committed: Vec::new(),
+        };
+        let sent = |rx: &mut mpsc::UnboundedReceiver<Command>| -> Vec<(usize, bool)> {
+            std::iter::from_fn(|| rx.try_recv().ok())
+                .filter_map(|cmd| match cmd {
+                    Command::SendUnsolicitedGroup { svcs, goodbye, .. } => {
+                        Some((svcs.len(), goodbye))
+                    }
+                    Command::ProbeGroup { svcs, .. } => Some((svcs.len(), false)),
+                    _ => None,
+                })
+                .collect()
+        };
+        let taken = services
+            .write()
+            .unwrap()
+            .register(
+                ServiceBuilder::new("_http._tcp", "taken", 80)
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+
+        // A conflict registers none of them
+        group.add(ServiceBuilder::new("_http._tcp", "web", 80)).add(
+            ServiceBuilder::new("_http._tcp", "taken", 80)
+                .conflict_strategy(ConflictStrategy::Fail),
+        );
+        assert!(matches!(group.commit(), Err(Error::NameConflict(_))));
+        assert_eq!(services.read().unwrap().into_iter().count(), 1);
+        assert!(sent(&mut rx).is_empty());
+
+        group
+            .add(ServiceBuilder::new("_http._tcp", "web", 80))
+            .add(ServiceBuilder::new("_ipp._tcp", "printer", 631));
+        let committed = group.commit().unwrap();
+        assert_eq!(committed.len(), 2);
+        assert_eq!(services.read().unwrap().into_iter().count(), 3);
+        assert_eq!(sent(&mut rx), [(2, false)]);
+
+        // Handles don't keep the services from going with the group
+        committed[0].withdraw();
+        assert_eq!(goodbyes(&mut rx), 1);
+        drop(group);
+        assert_eq!(sent(&mut rx), [(1, true)]);
+        assert!(committed[1].is_withdrawn());
+        let services = services.read().unwrap();
+        assert_eq!(services.into_iter().count(), 1);
+        assert!(services.get(taken).is_some());
+    }
+
+    #[test]
+    fn test_replace() {
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let sent = packets.clone();
+        let sink = move |packet: &[u8], _: SocketAddr| sent.lock().unwrap().push(packet.to_vec());
+        let host = |hostname: &str| -> Arc<dyn HostData> {
+            let addresses = vec!["192.0.2.5".parse().unwrap()];
+            Arc::new(FixedHostData::new(hostname.into(), addresses))
+        };
+        let (responder, task) = ResponderBuilder::new()
+            .host_data(host("old-host"))
+            .enable_ipv6(false)
+            .build_with_sink(sink)
+            .unwrap();
+        let _service = responder.register("_http._tcp".into(), "web".into(), 80, &[]);
+        let counters = responder.counters.clone();
+
+        let replaced = responder
+            .replace(
+                ResponderBuilder::new()
+                    .host_data(host("new-host"))
+                    .enable_ipv6(false),
+            )
+            .unwrap();
+        // The counters go on from those of the old responder
+        assert!(Arc::ptr_eq(&counters, &replaced.counters));
+
+        // The old task sends goodbyes for what was published under the old hostname
+        // only, before it ends
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(task);
+        let packets = packets.lock().unwrap();
+        let withdrawn: Vec<String> = packets
+            .iter()
+            .flat_map(|packet| dns_parser::Packet::parse(packet).unwrap().answers)
+            .filter(|record| record.ttl == 0)
+            .map(|record| match record.data {
+                dns_parser::RRData::A(ip) => format!("A {} {}", record.name, ip),
+                dns_parser::RRData::SRV { ref target, .. } => {
+                    format!("SRV {} {}", record.name, target)
+                }
+                _ => format!("other {}", record.name),
+            })
+            .collect();
+        let expected = [
+            "A old-host.local 192.0.2.5",
+            "SRV web._http._tcp.local old-host.local",
+        ];
+        for record in &withdrawn {
+            assert!(expected.contains(&record.as_str()), "{}", record);
+        }
+        for record in &expected {
+            assert!(withdrawn.iter().any(|withdrawn| withdrawn == record));
+        }
+    }
+
+    #[test]
+    fn test_service_announced() {
+        let services = Arc::new(RwLock::new(ServicesInner::new("test-host.local".into())));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let commands = CommandSender::new(vec![tx]);
+        let mut group = ServiceGroup {
+            services: services.clone(),
+            config: Arc::new(ResponderConfig::default()),
+            commands: commands.clone(),
+            shutdown: Arc::new(Shutdown(commands)),
+            staged: Vec::new(),
+            committed: Vec::new(),
+        };
+        let shared = fsm::Shared::new(&services, ResponderConfig::default());
+        let mut handler = fsm::Handler::<address_family::Inet>::new(&shared);
+
+        // Services of a group are announced together
+        group
+            .add(ServiceBuilder::new("_http._tcp", "web", 80))
+            .add(ServiceBuilder::new("_ipp._tcp", "printer", 631));
+        let committed = group.commit().unwrap();
+        handler.handle_command(rx.try_recv().unwrap());
+
+        // Once probed for
+        let mut probes = 0;
+        loop {
+            assert!(committed[0].announced().now_or_never().is_none());
+            match handler.pop_outgoing() {
+                Some((packet, _)) if dns_parser::Packet::parse(&packet).unwrap().header.query => {
+                    probes += 1
+                }
+                Some(_) => break,
+                None => {
+                    let at = handler.next_unsolicited_at().unwrap();
+                    thread::sleep(at.saturating_duration_since(Instant::now()));
+                }
+            }
+        }
+        assert_eq!(probes, 3);
+        for service in &committed {
+            assert!(matches!(service.announced().now_or_never(), Some(Ok(()))));
+        }
+
+        // A command the responder never got to fails
+        group.add(ServiceBuilder::new("_http._tcp", "late", 80));
+        let committed = group.commit().unwrap();
+        drop(rx.try_recv().unwrap());
+        assert!(matches!(
+            committed[0].announced().now_or_never(),
+            Some(Err(Error::ShutDown))
+        ));
+    }
+}
@@ -0,0 +1,295 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::dns_parser::{self, Name, RRData, MAX_LABEL_LEN};
+use crate::error::Error;
+use crate::instance_name::InstanceName;
+use crate::rename::ConflictStrategy;
+use crate::records::{AdditionalRecord, ExtraSrv};
+use crate::services::{check_name, ServiceData};
+use crate::txt::TxtRecord;
+
+/// The data of an extra record attached to a service with
+/// [`ServiceBuilder::additional_record`]. Names are fully qualified, e.g. `alias.local`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RecordData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    CNAME(String),
+    PTR(String),
+    TXT(TxtRecord),
+    /// Data of any type code, e.g. SVCB (64) or one assigned after this crate was
+    /// written, published as given. Anything the data names must be uncompressed.
+    Raw {
+        typ: u16,
+        data: Vec<u8>,
+    },
+}
+
+/// An SRV record published besides the one for the service's port, with
+/// [`ServiceBuilder::srv_record`]. Clients try targets with lower `priority` first,
+/// and pick among equal priorities in proportion to `weight`, see RFC 2782.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// The fully qualified host serving this port, or `None` for the responder's
+    /// own hostname
+    pub target: Option<String>,
+}
+
+impl SrvRecord {
+    /// A record for `port` on the responder's own hostname.
+    pub fn new(priority: u16, weight: u16, port: u16) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port,
+            target: None,
+        }
+    }
+
+    #[cfg(feature = "responder")]
+    pub(crate) fn from_extra(srv: &ExtraSrv) -> SrvRecord {
+        SrvRecord {
+            priority: srv.priority,
+            weight: srv.weight,
+            port: srv.port,
+            target: srv.target.as_ref().map(ToString::to_string),
+        }
+    }
+
+    pub(crate) fn to_extra(&self) -> ExtraSrv {
+        ExtraSrv {
+            priority: self.priority,
+            weight: self.weight,
+            port: self.port,
+            target: self
+                .target
+                .as_ref()
+                .map(|target| Name::from_str(target.clone()).unwrap()),
+        }
+    }
+}
+
+/// A record added with `ServiceBuilder::additional_record` or `shared_record`
+#[derive(Clone, Debug)]
+struct ExtraRecord {
+    name: String,
+    ttl: u32,
+    data: RecordData,
+    shared: bool,
+}
+
+/// Describes a service to be registered with `Responder::register_service`.
+///
+/// # Example
+/// ```no_run
+/// # #[cfg(feature = "responder")]
+/// use libmdns::{RecordData, Responder, ServiceBuilder};
+///
+/// # use std::io;
+/// # #[cfg(feature = "responder")]
+/// # fn main() -> io::Result<()> {
+/// let responder = Responder::new()?;
+/// let _svc = responder.register_service(
+///     ServiceBuilder::new("_airplay._tcp", "Living Room", 7000)
+///         .txt(&["model=speaker"])
+///         .additional_record("living-room.local", 120, RecordData::A([192, 0, 2, 1].into())),
+/// );
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "responder"))]
+/// # fn main() {}
+/// ```
+#[derive(Clone, Debug)]
+pub struct ServiceBuilder {
+    svc_type: String,
+    svc_name: String,
+    port: u16,
+    srv: Vec<SrvRecord>,
+    txt: TxtRecord,
+    additional: Vec<ExtraRecord>,
+    ptr_ttl: Option<u32>,
+    ttl: Option<u32>,
+    announce_rate: Option<u32>,
+    conflict: ConflictStrategy,
+}
+
+impl ServiceBuilder {
+    pub fn new(svc_type: &str, svc_name: &str, port: u16) -> ServiceBuilder {
+        ServiceBuilder {
+            svc_type: svc_type.to_owned(),
+            svc_name: svc_name.to_owned(),
+            port,
+            srv: Vec::new(),
+            txt: TxtRecord::new(),
+            additional: Vec::new(),
+            ptr_ttl: None,
+            ttl: None,
+            announce_rate: None,
+            conflict: ConflictStrategy::default(),
+        }
+    }
+
+    /// The TXT data, as raw `key=value` strings or a [`TxtRecord`].
+    pub fn txt<T: Into<TxtRecord>>(mut self, txt: T) -> Self {
+        self.txt = txt.into();
+        self
+    }
+
+    /// Publish another SRV record for the instance, e.g. for a protocol offered on
+    /// several ports. The record for the service's own port has priority and weight
+    /// 0, and is listed first.
+    pub fn srv_record(mut self, record: SrvRecord) -> Self {
+        self.srv.push(record);
+        self
+    }
+
+    /// Publish another record along with the service. It is announced, answered and
+    /// withdrawn together with the service's own records.
+    ///
+    /// The record is unique, i.e. only this responder publishes it under its name, so
+    /// it is sent with the cache-flush bit. PTR records are the exception, as other
+    /// responders list their own under the same name, see
+    /// [`shared_record`](Self::shared_record).
+    pub fn additional_record(mut self, name: &str, ttl: u32, data: RecordData) -> Self {
+        let shared = matches!(data, RecordData::PTR(_));
+        self.additional.push(ExtraRecord {
+            name: name.to_owned(),
+            ttl,
+            data,
+            shared,
+        });
+        self
+    }
+
+    /// Like [`additional_record`](Self::additional_record), for a record which other
+    /// responders may publish under the same name too, such as a subtype PTR record.
+    /// It is never sent with the cache-flush bit, so that it doesn't flush theirs
+    /// from caches (RFC 6762 section 10.2), and isn't listed as ours alone in NSEC
+    /// records.
+    pub fn shared_record(mut self, name: &str, ttl: u32, data: RecordData) -> Self {
+        self.additional.push(ExtraRecord {
+            name: name.to_owned(),
+            ttl,
+            data,
+            shared: true,
+        });
+        self
+    }
+
+    /// The TTL, in seconds, of the PTR record which lists the instance under its
+    /// service type. RFC 6762 section 10 suggests 4500 (75 minutes) for records which
+    /// don't contain host names. Defaults to 60.
+    pub fn ptr_ttl(mut self, ttl: u32) -> Self {
+        self.ptr_ttl = Some(ttl);
+        self
+    }
+
+    /// The TTL, in seconds, of the SRV and TXT records and of the address records
+    /// sent along with them. RFC 6762 section 10 suggests 120 for records which
+    /// contain or depend on host names. Defaults to 60.
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Send at most `packets_per_second` announcements and goodbyes for this service,
+    /// in addition to the limit set by [`ResponderBuilder::announce_rate`].
+    ///
+    /// [`ResponderBuilder::announce_rate`]: crate::ResponderBuilder::announce_rate
+    ///
+    /// # Panics
+    ///
+    /// If `packets_per_second` is zero.
+    pub fn announce_rate(mut self, packets_per_second: u32) -> Self {
+        assert!(
+            packets_per_second > 0,
+            "announce rate must be at least one packet per second"
+        );
+        self.announce_rate = Some(packets_per_second);
+        self
+    }
+
+    /// What to do when the instance name turns out to be taken, by another service of
+    /// the responder or another device on the network. Defaults to
+    /// [`ConflictStrategy::Rename`].
+    pub fn conflict_strategy(mut self, strategy: ConflictStrategy) -> Self {
+        self.conflict = strategy;
+        self
+    }
+
+    /// The data of the service, failing if one of its names is too long for DNS
+    pub(crate) fn build(self) -> Result<ServiceData, Error> {
+        self.check_names()?;
+        let mut svc = ServiceData::new(&self.svc_type, &self.svc_name, self.port, self.txt);
+        if let Some(ptr_ttl) = self.ptr_ttl {
+            svc.ptr_ttl = ptr_ttl;
+        }
+        if let Some(ttl) = self.ttl {
+            svc.ttl = ttl;
+        }
+        svc.announce_rate = self.announce_rate;
+        svc.conflict = self.conflict;
+        svc.set_extra_srv(self.srv.iter().map(SrvRecord::to_extra).collect());
+        svc.additional = self
+            .additional
+            .into_iter()
+            .map(|record| {
+                let txt;
+                let data = match record.data {
+                    RecordData::A(ip) => RRData::A(ip),
+                    RecordData::AAAA(ip) => RRData::AAAA(ip),
+                    RecordData::CNAME(name) => RRData::CNAME(Name::from_str(name).unwrap()),
+                    RecordData::PTR(name) => RRData::PTR(Name::from_str(name).unwrap()),
+                    RecordData::TXT(data) => {
+                        txt = data.into_bytes();
+                        RRData::TXT(&txt)
+                    }
+                    RecordData::Raw { typ, data } => RRData::Raw { typ, data },
+                };
+                AdditionalRecord::new(record.name, record.ttl, &data, !record.shared)
+            })
+            .collect();
+        Ok(svc)
+    }
+
+    fn check_names(&self) -> Result<(), Error> {
+        let instance = InstanceName::new(self.svc_name.as_str());
+        let name = format!("{}.{}.local", instance.escaped(), self.svc_type);
+        // The instance is checked as given, for the error to show it without escapes
+        if self.svc_name.len() > MAX_LABEL_LEN {
+            return Err(Error::LabelTooLong {
+                name,
+                label: self.svc_name.clone(),
+            });
+        }
+
+        let targets = self
+            .additional
+            .iter()
+            .filter_map(|record| match record.data {
+                RecordData::CNAME(ref target) | RecordData::PTR(ref target) => Some(target),
+                _ => None,
+            });
+        let others = self
+            .srv
+            .iter()
+            .filter_map(|srv| srv.target.as_ref())
+            .chain(self.additional.iter().map(|record| &record.name))
+            .chain(targets);
+        for name in Some(&name).into_iter().chain(others) {
+            let parsed = Name::from_str(name.clone()).map_err(|err| match err {
+                dns_parser::Error::LabelTooLong(label) => Error::LabelTooLong {
+                    name: name.clone(),
+                    label,
+                },
+                err => unreachable!("{}", err),
+            })?;
+            check_name(&parsed)?;
+        }
+        Ok(())
+    }
+}
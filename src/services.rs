@@ -1,148 +1,797 @@
-use crate::dns_parser::{self, Name, QueryClass, RRData};
-use multimap::MultiMap;
+use crate::clock;
+use crate::dns_parser::{Canonical, Name, NameKey, MAX_LABEL_LEN, MAX_NAME_LEN};
+use crate::error::Error;
+use crate::events::ServiceEvent;
+#[cfg(feature = "responder")]
+use crate::instance_name::InstanceName;
+use crate::rename::{ConflictStrategy, RenameStrategy};
+#[cfg(feature = "snapshot")]
+use crate::snapshot::{ServiceSnapshot, Snapshot, SNAPSHOT_MAX_AGE};
+use crate::store::{ServiceIter, ServiceStorage, ServicesStore};
+use crate::records::ServiceRecords;
+use crate::txt::TxtRecord;
+use log::debug;
 use rand::{thread_rng, Rng};
 use std::collections::HashMap;
-use std::slice;
+use std::ops::{Deref, DerefMut};
 use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
-pub type AnswerBuilder = dns_parser::Builder<dns_parser::Answers>;
+/// Fails if `name` is too long to be encoded in DNS
+pub fn check_name(name: &Name) -> Result<(), Error> {
+    if let Some(label) = name.labels().find(|label| label.len() > MAX_LABEL_LEN) {
+        return Err(Error::LabelTooLong {
+            name: name.to_string(),
+            label: String::from_utf8_lossy(label).into_owned(),
+        });
+    }
+    if name.wire_len() > MAX_NAME_LEN {
+        return Err(Error::NameTooLong(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Fails if `instance` is too long to be the instance part of a name of type `typ`,
+/// where `set_instance` would cut it short. Like `ServiceBuilder`, the label is
+/// reported as given, without escapes.
+#[cfg(feature = "responder")]
+pub fn check_instance(instance: &str, typ: &Name) -> Result<(), Error> {
+    let name = format!("{}.{}", InstanceName::new(instance).escaped(), typ);
+    if instance.len() > MAX_LABEL_LEN {
+        return Err(Error::LabelTooLong {
+            name,
+            label: instance.to_owned(),
+        });
+    }
+    check_name(&Name::from_str(name).unwrap())
+}
+
+/// Gives up on a `RenameStrategy` which keeps returning names in use
+const MAX_RENAME_ATTEMPTS: u32 = 1000;
+
+/// How many renames are remembered for handlers which have yet to announce them
+const MAX_PENDING_RENAMES: usize = 32;
 
 /// A collection of registered services is shared between threads.
 pub type Services = Arc<RwLock<ServicesInner>>;
 
 pub struct ServicesInner {
     hostname: Name<'static>,
-    /// main index
-    by_id: HashMap<usize, ServiceData>,
-    /// maps to id
-    by_type: MultiMap<Name<'static>, usize>,
-    /// maps to id
-    by_name: HashMap<Name<'static>, usize>,
+    /// the hostname asked for, and how many times it has been renamed after conflicts
+    requested_hostname: String,
+    hostname_renames: u32,
+    store: Box<dyn ServicesStore>,
+    /// ids of services restored from a snapshot which have not been registered again
+    /// yet, and the hostname it was taken with, forgotten at `defended_until`
+    defended: HashMap<NameKey, usize>,
+    defended_hostname: Option<NameKey>,
+    defended_until: Option<Instant>,
+    /// services renamed after a conflict on the network, by sequence number, so that
+    /// the handler of every address family announces them
+    renamed: Vec<(u64, usize)>,
+    renamed_seq: u64,
+    /// counts renames of the hostname after conflicts, which every handler announces
+    hostname_seq: u64,
+    /// names published with the host's addresses besides the hostname: those added
+    /// through the responder, those of the `HostData`, and a count of changes to them
+    aliases: Vec<Name<'static>>,
+    host_aliases: Vec<Name<'static>>,
+    aliases_seq: u64,
+    /// aliases given up after a conflict, which mustn't get goodbyes as they are
+    /// someone else's now
+    lost_aliases: Vec<Name<'static>>,
+    /// the id of the next service, if ids are sequential rather than random
+    next_id: Option<usize>,
+    /// whether instance names are kept in Unicode NFC
+    #[cfg(feature = "unicode")]
+    normalize: bool,
 }
 
 impl ServicesInner {
     pub fn new(hostname: String) -> Self {
+        Self::with_storage(hostname, ServiceStorage::default())
+    }
+
+    pub fn with_storage(hostname: String, storage: ServiceStorage) -> Self {
         ServicesInner {
-            hostname: Name::from_str(hostname).unwrap(),
-            by_id: HashMap::new(),
-            by_type: MultiMap::new(),
-            by_name: HashMap::new(),
+            hostname: Name::from_str(hostname.clone()).unwrap(),
+            requested_hostname: hostname,
+            hostname_renames: 0,
+            store: storage.create(),
+            defended: HashMap::new(),
+            defended_hostname: None,
+            defended_until: None,
+            renamed: Vec::new(),
+            renamed_seq: 0,
+            hostname_seq: 0,
+            aliases: Vec::new(),
+            host_aliases: Vec::new(),
+            aliases_seq: 0,
+            lost_aliases: Vec::new(),
+            next_id: None,
+            #[cfg(feature = "unicode")]
+            normalize: true,
         }
     }
 
+    /// Whether instance names are registered and looked up in Unicode NFC
+    #[cfg(feature = "unicode")]
+    pub fn normalize_names(&mut self, normalize: bool) {
+        self.normalize = normalize;
+    }
+
+    /// Number services from 1 rather than giving them random ids, which are easier
+    /// to follow in logs
+    pub fn sequential_ids(&mut self, sequential: bool) {
+        self.next_id = match self.next_id {
+            Some(next) if sequential => Some(next),
+            _ => sequential.then_some(1),
+        };
+    }
+
     pub fn get_hostname(&self) -> &Name<'static> {
         &self.hostname
     }
 
-    pub fn find_by_name<'a>(&'a self, name: &'a Name<'a>) -> Option<&ServiceData> {
-        self.by_name.get(name).and_then(|id| self.by_id.get(id))
+    pub fn set_hostname(&mut self, hostname: String) {
+        self.hostname = Name::from_str(hostname.clone()).unwrap();
+        self.requested_hostname = hostname;
+        self.hostname_renames = 0;
     }
 
-    pub fn find_by_type<'a>(&'a self, ty: &'a Name<'a>) -> FindByType<'a> {
-        let ids = self.by_type.get_vec(ty).map(|ids| ids.iter());
+    /// Move to a hostname picked by `strategy` after another device published ours
+    /// with addresses of its own (RFC 6762 section 9), to have every service
+    /// announced again with it
+    pub fn rename_hostname(&mut self, strategy: &dyn RenameStrategy) -> &Name<'static> {
+        self.hostname_renames += 1;
+        let requested = &self.requested_hostname;
+        let label = requested.strip_suffix(".local").unwrap_or(requested);
+        let renamed = strategy.rename(label, self.hostname_renames);
+        debug!("renamed host {} to {}.local", self.hostname, renamed);
+        self.hostname = Name::from_str(format!("{}.local", renamed)).unwrap();
+        self.hostname_seq += 1;
+        &self.hostname
+    }
+
+    /// Counts renames of the hostname after conflicts
+    pub fn hostname_seq(&self) -> u64 {
+        self.hostname_seq
+    }
 
-        FindByType {
-            services: self,
-            ids: ids,
+    /// Publish the host's addresses under `alias` too, returns false if it already was
+    pub fn add_alias(&mut self, alias: String) -> bool {
+        let alias = Name::from_str(alias).unwrap();
+        let known = Some(&self.hostname)
+            .into_iter()
+            .chain(&self.aliases)
+            .any(|name| name as &dyn Canonical == &alias as &dyn Canonical);
+        if known {
+            return false;
         }
+        self.lost_aliases
+            .retain(|name| name as &dyn Canonical != &alias as &dyn Canonical);
+        self.aliases.push(alias);
+        self.aliases_seq += 1;
+        true
     }
 
-    pub fn register(&mut self, svc: ServiceData) -> usize {
-        let mut id = thread_rng().gen::<usize>();
-        while self.by_id.contains_key(&id) {
-            id = thread_rng().gen::<usize>();
+    /// Stop publishing `alias`, returns false if it wasn't added with `add_alias`
+    pub fn remove_alias(&mut self, alias: &str) -> bool {
+        let alias = Name::from_str(alias.to_owned()).unwrap();
+        let len = self.aliases.len();
+        self.aliases
+            .retain(|name| name as &dyn Canonical != &alias as &dyn Canonical);
+        if self.aliases.len() == len {
+            return false;
         }
+        self.aliases_seq += 1;
+        true
+    }
 
-        self.by_type.insert(svc.typ.clone(), id);
-        self.by_name.insert(svc.name.clone(), id);
-        self.by_id.insert(id, svc);
+    /// Give up `alias` after another host claimed it, if it was added with
+    /// `add_alias`. Unlike `remove_alias`, no goodbyes are sent for it.
+    pub fn lose_alias(&mut self, alias: &Name) -> bool {
+        let index = self
+            .aliases
+            .iter()
+            .position(|name| name as &dyn Canonical == alias as &dyn Canonical);
+        let index = match index {
+            Some(index) => index,
+            None => return false,
+        };
+        let alias = self.aliases.remove(index);
+        self.lost_aliases.push(alias);
+        self.aliases_seq += 1;
+        true
+    }
 
-        id
+    /// Whether `alias` was given up with `lose_alias`
+    pub fn alias_lost(&self, alias: &Name) -> bool {
+        self.lost_aliases
+            .iter()
+            .any(|name| name as &dyn Canonical == alias as &dyn Canonical)
     }
 
-    pub fn unregister(&mut self, id: usize) -> ServiceData {
-        use std::collections::hash_map::Entry;
+    /// Replace the aliases read from the `HostData`
+    pub fn set_host_aliases(&mut self, aliases: Vec<Name<'static>>) {
+        let same = aliases.len() == self.host_aliases.len()
+            && aliases
+                .iter()
+                .zip(&self.host_aliases)
+                .all(|(a, b)| a as &dyn Canonical == b as &dyn Canonical);
+        if !same {
+            self.host_aliases = aliases;
+            self.aliases_seq += 1;
+        }
+    }
+
+    /// Every alias: those of the `HostData` and then those added with `add_alias`
+    pub fn aliases(&self) -> Vec<Name<'static>> {
+        let mut aliases = self.host_aliases.clone();
+        for alias in &self.aliases {
+            let known = aliases
+                .iter()
+                .any(|name| name as &dyn Canonical == alias as &dyn Canonical);
+            if !known {
+                aliases.push(alias.clone());
+            }
+        }
+        aliases
+    }
+
+    /// Changes whenever the aliases do
+    pub fn aliases_seq(&self) -> u64 {
+        self.aliases_seq
+    }
 
-        let svc = self.by_id.remove(&id).expect("unknown service");
+    /// The advertised service named `name`
+    pub fn find_by_name<'a>(&'a self, name: &'a Name<'a>) -> Option<&'a ServiceData> {
+        self.id_by_name(name)
+            .and_then(|id| self.store.get(id))
+            .filter(|svc| !svc.withdrawn)
+    }
 
-        if let Some(entries) = self.by_type.get_vec_mut(&svc.typ) {
-            entries.retain(|&e| e != id);
+    /// The advertised services of type `ty`
+    pub fn find_by_type<'a>(&'a self, ty: &'a Name<'a>) -> ServiceIter<'a> {
+        Box::new(self.store.by_type(ty).filter(|svc| !svc.withdrawn))
+    }
+
+    pub fn id_by_name(&self, name: &Name) -> Option<usize> {
+        #[cfg(feature = "unicode")]
+        if self.normalize {
+            if let Some(name) = crate::unicode::nfc_name(name) {
+                return self.store.id_by_name(&name);
+            }
         }
+        self.store.id_by_name(name)
+    }
 
-        match self.by_name.entry(svc.name.clone()) {
-            Entry::Occupied(entry) => {
-                assert_eq!(*entry.get(), id);
-                entry.remove();
+    /// Rename `svc` with `strategy` until its name isn't used by another service,
+    /// unless it is registered with `ConflictStrategy::Fail`. With the `unicode`
+    /// feature, the name is first normalized.
+    pub fn make_unique(
+        &self,
+        svc: &mut ServiceData,
+        strategy: &dyn RenameStrategy,
+    ) -> Result<Option<ServiceEvent>, Error> {
+        #[cfg(feature = "unicode")]
+        if self.normalize {
+            if let Some(instance) = crate::unicode::nfc(&svc.instance()) {
+                svc.set_instance(&instance);
             }
-            _ => {
-                panic!("unknown/wrong service for id {}", id);
+        }
+        if self.store.id_by_name(&svc.name).is_none() {
+            return Ok(None);
+        }
+        if svc.conflict == ConflictStrategy::Fail {
+            return Err(Error::NameConflict(svc.instance()));
+        }
+        match self.rename(svc, strategy) {
+            Ok(event) => Ok(Some(event)),
+            Err(_) => Err(Error::NameConflict(svc.instance())),
+        }
+    }
+
+    /// A `Renamed` event, or a `RenameFailed` one leaving `svc` as it was if
+    /// `strategy` gives no free name
+    fn rename(
+        &self,
+        svc: &mut ServiceData,
+        strategy: &dyn RenameStrategy,
+    ) -> Result<ServiceEvent, ServiceEvent> {
+        let requested = svc.instance();
+        for attempt in 1..=MAX_RENAME_ATTEMPTS {
+            // The requested name is shortened as needed to keep the suffix in the label
+            let mut base = requested.as_str();
+            let mut name = strategy.rename(base, attempt);
+            while name.len() > MAX_LABEL_LEN && !base.is_empty() {
+                let end = base.char_indices().last().map_or(0, |(end, _)| end);
+                base = &base[..end];
+                name = strategy.rename(base, attempt);
+            }
+            svc.set_instance(&name);
+            if self.store.id_by_name(&svc.name).is_none() {
+                return Ok(ServiceEvent::Renamed {
+                    requested,
+                    name,
+                    attempts: attempt,
+                    strategy: strategy.describe(),
+                });
+            }
+        }
+        debug!(
+            "no free name for {:?} in {} attempts",
+            requested, MAX_RENAME_ATTEMPTS
+        );
+        svc.set_instance(&requested);
+        Err(ServiceEvent::RenameFailed {
+            requested,
+            attempts: MAX_RENAME_ATTEMPTS,
+            strategy: strategy.describe(),
+        })
+    }
+
+    /// Fails with [`Error::StoreFull`] if the store has no room for the service
+    pub fn register(&mut self, svc: ServiceData) -> Result<usize, Error> {
+        self.prune_defended();
+        let mut id = match self.defended.remove(&svc.name as &dyn Canonical) {
+            Some(id) => id,
+            None => self.new_id(),
+        };
+        while self.store.get(id).is_some() {
+            id = self.new_id();
+        }
+
+        debug!("registered service {} as {}", id, svc.name);
+        self.store.insert(id, svc)?;
+        Ok(id)
+    }
+
+    /// Whether `name` was restored from a recent snapshot and hasn't been registered
+    /// again yet, so that others still have it cached as ours
+    pub fn is_defended(&mut self, name: &Name) -> bool {
+        self.prune_defended();
+        self.defended.contains_key(name as &dyn Canonical)
+    }
+
+    /// Forget the names restored from a snapshot once it would be too old to restore
+    fn prune_defended(&mut self) {
+        if self
+            .defended_until
+            .is_some_and(|until| clock::now() >= until)
+        {
+            debug!(
+                "forgetting {} services of the snapshot",
+                self.defended.len()
+            );
+            self.defended.clear();
+            self.defended_hostname = None;
+            self.defended_until = None;
+        }
+    }
+
+    /// Whether the hostname is the one of a restored snapshot, still recent enough for
+    /// others to have it cached, so that it is announced again straight away
+    pub fn hostname_defended(&self) -> bool {
+        let recent = self
+            .defended_until
+            .is_some_and(|until| clock::now() < until);
+        let hostname = &self.hostname as &dyn Canonical;
+        let defended = |key: &NameKey| key as &dyn Canonical == hostname;
+        recent && self.defended_hostname.as_ref().is_some_and(defended)
+    }
+
+    fn new_id(&mut self) -> usize {
+        match self.next_id {
+            Some(ref mut next) => {
+                let id = *next;
+                *next = next.wrapping_add(1);
+                id
             }
+            None => thread_rng().gen::<usize>(),
         }
+    }
+
+    pub fn get(&self, id: usize) -> Option<&ServiceData> {
+        self.store.get(id)
+    }
 
+    /// Replaces the data of an already registered service, keeping its id.
+    /// Returns the previous data, or [`Error::NameConflict`] leaving the service as it
+    /// was if another one has the new name.
+    pub fn update(&mut self, id: usize, svc: ServiceData) -> Result<ServiceData, Error> {
+        match self.store.id_by_name(&svc.name) {
+            Some(other) if other != id => return Err(Error::NameConflict(svc.instance())),
+            _ => (),
+        }
+        let old = self.store.remove(id).expect("unknown service");
+        self.store
+            .insert(id, svc)
+            .expect("the replaced service left room");
+        Ok(old)
+    }
+
+    pub fn unregister(&mut self, id: usize) -> ServiceData {
+        let svc = self.store.remove(id).expect("unknown service");
+        debug!("unregistered service {} ({})", id, svc.name);
         svc
     }
-}
 
-impl<'a> IntoIterator for &'a ServicesInner {
-    type Item = &'a crate::ServiceData;
-    type IntoIter = std::collections::hash_map::Values<'a, usize, crate::ServiceData>;
+    /// Move a service whose name another device uses to one picked by `strategy`,
+    /// and have it announced, or withdraw it if `strategy` gives no free name.
+    pub fn rename_conflicting(&mut self, id: usize, strategy: &dyn RenameStrategy) -> ServiceEvent {
+        let mut svc = self.get(id).expect("unknown service").clone();
+        let event = match self.rename(&mut svc, strategy) {
+            Ok(event) => event,
+            Err(event) => {
+                self.withdraw(id);
+                return event;
+            }
+        };
+        debug!("renamed service {} to {}", id, svc.name);
+        self.update(id, svc).expect("renamed to a free name");
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.by_id.values()
+        self.renamed_seq += 1;
+        self.renamed.push((self.renamed_seq, id));
+        if self.renamed.len() > MAX_PENDING_RENAMES {
+            self.renamed.remove(0);
+        }
+        event
+    }
+
+    /// Stop advertising a service, leaving it registered until it is dropped.
+    pub fn withdraw(&mut self, id: usize) {
+        let mut svc = self.get(id).expect("unknown service").clone();
+        svc.withdrawn = true;
+        debug!("withdrew service {} ({})", id, svc.name);
+        self.update(id, svc).expect("the name is unchanged");
+    }
+
+    /// Counts renames after conflicts
+    pub fn renamed_seq(&self) -> u64 {
+        self.renamed_seq
+    }
+
+    /// The latest rename sequence number, and the services renamed after `seen`
+    pub fn renamed_since(&self, seen: u64) -> (u64, Vec<&ServiceData>) {
+        let renamed = self
+            .renamed
+            .iter()
+            .filter(|&&(seq, _)| seq > seen)
+            .filter_map(|&(_, id)| self.get(id))
+            .filter(|svc| !svc.withdrawn)
+            .collect();
+        (self.renamed_seq, renamed)
     }
 }
 
-/// Returned by [`ServicesInner.find_by_type`](struct.ServicesInner.html#method.find_by_type)
-pub struct FindByType<'a> {
-    services: &'a ServicesInner,
-    ids: Option<slice::Iter<'a, usize>>,
+#[cfg(feature = "snapshot")]
+impl ServicesInner {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            hostname: self.hostname.to_string(),
+            saved_at: Snapshot::now(),
+            services: self
+                .store
+                .iter()
+                .map(|svc| ServiceSnapshot {
+                    id: self.store.id_by_name(&svc.name).unwrap(),
+                    typ: svc.typ.to_string(),
+                    name: svc.name.to_string(),
+                    port: svc.port,
+                    txt: svc.txt.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Remember the ids of services in `snapshot`, so that registering them again
+    /// brings them back with the same identity, until the snapshot would be too old
+    /// to restore.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        for svc in &snapshot.services {
+            let name = Name::from_str(svc.name.clone()).unwrap();
+            self.defended.insert(NameKey::new(&name), svc.id);
+        }
+        let hostname = Name::from_str(snapshot.hostname.clone()).unwrap();
+        self.defended_hostname = Some(NameKey::new(&hostname));
+        self.defended_until = Some(clock::now() + SNAPSHOT_MAX_AGE.saturating_sub(snapshot.age()));
+    }
 }
 
-impl<'a> Iterator for FindByType<'a> {
+impl<'a> IntoIterator for &'a ServicesInner {
     type Item = &'a ServiceData;
+    type IntoIter = ServiceIter<'a>;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.ids.as_mut().and_then(Iterator::next).map(|id| {
-            let svc = self.services.by_id.get(id);
-            svc.expect("missing service")
-        })
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.store.iter().filter(|svc| !svc.withdrawn))
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct ServiceData {
-    pub name: Name<'static>,
-    pub typ: Name<'static>,
-    pub port: u16,
-    pub txt: Vec<u8>,
+    /// what is published, which the fields of `ServiceData` are reached through
+    pub records: ServiceRecords,
+    /// announcements and goodbyes per second, on top of the responder's own limit
+    pub announce_rate: Option<u32>,
+    pub conflict: ConflictStrategy,
+    /// no longer advertised after a conflict, see `ConflictStrategy::Fail`
+    pub withdrawn: bool,
 }
 
-/// Packet building helpers for `fsm` to respond with `ServiceData`
 impl ServiceData {
-    pub fn add_ptr_rr(&self, builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        builder.add_answer(
-            &self.typ,
-            QueryClass::IN,
-            ttl,
-            &RRData::PTR(self.name.clone()),
-        )
-    }
-
-    pub fn add_srv_rr(&self, hostname: &Name, builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        builder.add_answer(
-            &self.name,
-            QueryClass::IN,
-            ttl,
-            &RRData::SRV {
-                priority: 0,
-                weight: 0,
-                port: self.port,
-                target: hostname.clone(),
-            },
-        )
-    }
-
-    pub fn add_txt_rr(&self, builder: AnswerBuilder, ttl: u32) -> AnswerBuilder {
-        builder.add_answer(&self.name, QueryClass::IN, ttl, &RRData::TXT(&self.txt))
+    pub fn new(svc_type: &str, svc_name: &str, port: u16, txt: TxtRecord) -> ServiceData {
+        ServiceData::with_records(ServiceRecords::new(svc_type, svc_name, port, txt))
+    }
+
+    pub fn with_records(records: ServiceRecords) -> ServiceData {
+        ServiceData {
+            records,
+            announce_rate: None,
+            conflict: ConflictStrategy::default(),
+            withdrawn: false,
+        }
+    }
+}
+
+impl Deref for ServiceData {
+    type Target = ServiceRecords;
+
+    fn deref(&self) -> &ServiceRecords {
+        &self.records
+    }
+}
+
+impl DerefMut for ServiceData {
+    fn deref_mut(&mut self) -> &mut ServiceRecords {
+        &mut self.records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rename::NumericSuffix;
+
+    fn service_data(name: &str, port: u16) -> ServiceData {
+        ServiceData::new("_http._tcp", name, port, TxtRecord::new())
+    }
+
+    #[test]
+    fn test_update_keeps_id() {
+        let mut services = ServicesInner::new("test-hostname.local".into());
+        let id = services.register(service_data("old", 80)).unwrap();
+
+        let old = services.update(id, service_data("new", 8080)).unwrap();
+        assert_eq!(old.port, 80);
+        assert_eq!(services.get(id).unwrap().port, 8080);
+
+        let old_name = Name::from_str("old._http._tcp.local").unwrap();
+        let new_name = Name::from_str("new._http._tcp.local").unwrap();
+        assert!(services.find_by_name(&old_name).is_none());
+        assert_eq!(services.find_by_name(&new_name).unwrap().port, 8080);
+
+        let typ = Name::from_str("_http._tcp.local").unwrap();
+        assert_eq!(services.find_by_type(&typ).count(), 1);
+
+        // Not to the name of another service
+        let other = services.register(service_data("other", 80)).unwrap();
+        match services.update(id, service_data("other", 8081)) {
+            Err(Error::NameConflict(name)) => assert_eq!(name, "other"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(services.get(id).unwrap().port, 8080);
+        assert_eq!(services.get(other).unwrap().port, 80);
+    }
+
+    #[test]
+    fn test_sequential_ids() {
+        let mut services = ServicesInner::new("test-hostname.local".into());
+        services.sequential_ids(true);
+        let first = services.register(service_data("first", 80)).unwrap();
+        let second = services.register(service_data("second", 80)).unwrap();
+        assert_eq!((first, second), (1, 2));
+
+        // Ids aren't reused
+        services.unregister(second);
+        assert_eq!(services.register(service_data("third", 80)).unwrap(), 3);
+        services.sequential_ids(true);
+        assert_eq!(services.register(service_data("fourth", 80)).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_make_unique() {
+        let mut services = ServicesInner::new("test-hostname.local".into());
+        services.register(service_data("printer", 80)).unwrap();
+
+        let mut svc = service_data("printer", 81);
+        match services.make_unique(&mut svc, &NumericSuffix).unwrap() {
+            Some(ServiceEvent::Renamed {
+                name,
+                attempts,
+                strategy,
+                ..
+            }) => {
+                assert_eq!(name, "printer (2)");
+                assert_eq!(attempts, 1);
+                assert_eq!(strategy, "numeric suffix");
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+        assert_eq!(svc.name.to_string(), "printer (2)._http._tcp.local");
+        assert_eq!(svc.instance(), "printer (2)");
+        services.register(svc).unwrap();
+
+        let mut svc = service_data("printer", 82);
+        services.make_unique(&mut svc, &NumericSuffix).unwrap();
+        assert_eq!(svc.instance(), "printer (3)");
+
+        let mut svc = service_data("scanner", 83);
+        assert!(services
+            .make_unique(&mut svc, &NumericSuffix)
+            .unwrap()
+            .is_none());
+
+        let mut svc = ServiceData {
+            conflict: ConflictStrategy::Fail,
+            ..service_data("printer", 84)
+        };
+        match services.make_unique(&mut svc, &NumericSuffix) {
+            Err(Error::NameConflict(name)) => assert_eq!(name, "printer"),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Nor when the strategy runs out of names
+        let mut svc = service_data("printer", 85);
+        let taken = |_: &str, _| "printer".to_owned();
+        match services.make_unique(&mut svc, &taken) {
+            Err(Error::NameConflict(name)) => assert_eq!(name, "printer"),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // A dot in the instance stays part of it through renames
+        services
+            .register(service_data("Living Room. Printer", 85))
+            .unwrap();
+        let mut svc = service_data("Living Room. Printer", 86);
+        services.make_unique(&mut svc, &NumericSuffix).unwrap();
+        assert_eq!(svc.instance(), "Living Room. Printer (2)");
+        assert_eq!(
+            svc.name.to_string(),
+            "Living Room\\. Printer (2)._http._tcp.local"
+        );
+        assert_eq!(svc.name.labels().count(), 4);
+    }
+
+    #[test]
+    #[cfg(feature = "unicode")]
+    fn test_normalized_names() {
+        let mut services = ServicesInner::new("test-hostname.local".into());
+        let mut svc = service_data("Cafe\u{301}", 80);
+        assert!(services
+            .make_unique(&mut svc, &NumericSuffix)
+            .unwrap()
+            .is_none());
+        assert_eq!(svc.instance(), "Caf\u{e9}");
+        services.register(svc).unwrap();
+
+        let composed = Name::from_str("Caf\u{e9}._http._tcp.local").unwrap();
+        let decomposed = Name::from_str("Cafe\u{301}._http._tcp.local").unwrap();
+        assert!(services.find_by_name(&composed).is_some());
+        assert!(services.find_by_name(&decomposed).is_some());
+
+        let mut svc = service_data("Cafe\u{301}", 81);
+        services.make_unique(&mut svc, &NumericSuffix).unwrap();
+        assert_eq!(svc.instance(), "Caf\u{e9} (2)");
+
+        services.normalize_names(false);
+        assert!(services.find_by_name(&decomposed).is_none());
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let mut services = ServicesInner::new("test-hostname.local".into());
+        let printer = services.register(service_data("printer", 80)).unwrap();
+        let scanner = services.register(service_data("scanner", 81)).unwrap();
+        let (seen, renamed) = services.renamed_since(0);
+        assert!(renamed.is_empty());
+
+        services.rename_conflicting(printer, &NumericSuffix);
+        assert_eq!(services.get(printer).unwrap().instance(), "printer (2)");
+        let (latest, renamed) = services.renamed_since(seen);
+        assert_eq!(renamed.len(), 1);
+        assert!(services.renamed_since(latest).1.is_empty());
+
+        // A strategy which runs out of names withdraws the service
+        let taken = |_: &str, _| "scanner".to_owned();
+        match services.rename_conflicting(printer, &taken) {
+            ServiceEvent::RenameFailed {
+                requested,
+                strategy,
+                ..
+            } => {
+                assert_eq!(requested, "printer (2)");
+                assert_eq!(strategy, "custom");
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+        let printer = services.get(printer).unwrap();
+        assert!(printer.withdrawn);
+        assert_eq!(printer.instance(), "printer (2)");
+
+        services.withdraw(scanner);
+        let typ = Name::from_str("_http._tcp.local").unwrap();
+        assert_eq!(services.find_by_type(&typ).count(), 0);
+        let name = Name::from_str("scanner._http._tcp.local").unwrap();
+        assert!(services.find_by_name(&name).is_none());
+        assert_eq!(services.get(scanner).unwrap().instance(), "scanner");
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_restore_keeps_id() {
+        let mut services = ServicesInner::new("test-hostname.local".into());
+        let id = services.register(service_data("restored", 80)).unwrap();
+        let snapshot = services.snapshot();
+        assert_eq!(snapshot.hostname, "test-hostname.local");
+
+        let mut restarted = ServicesInner::new("test-hostname.local".into());
+        restarted.restore(&snapshot);
+        let restored = service_data("restored", 80);
+        assert!(restarted.is_defended(&restored.name));
+        assert_eq!(restarted.register(restored.clone()).unwrap(), id);
+        assert!(!restarted.is_defended(&restored.name));
+        assert_ne!(restarted.register(service_data("fresh", 80)).unwrap(), id);
+        assert!(restarted.hostname_defended());
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_restore_expires() {
+        let mut services = ServicesInner::new("test-hostname.local".into());
+        let id = services.register(service_data("restored", 80)).unwrap();
+        let mut snapshot = services.snapshot();
+
+        // Forgotten once the snapshot would be too old to restore
+        snapshot.saved_at -= SNAPSHOT_MAX_AGE.as_secs();
+        let mut restarted = ServicesInner::new("test-hostname.local".into());
+        restarted.restore(&snapshot);
+        assert!(!restarted.hostname_defended());
+        assert_ne!(
+            restarted.register(service_data("restored", 80)).unwrap(),
+            id
+        );
+        assert!(restarted.defended.is_empty());
+
+        // A hostname other than the snapshot's was never defended
+        let mut renamed = ServicesInner::new("other-hostname.local".into());
+        renamed.restore(&services.snapshot());
+        assert!(!renamed.hostname_defended());
+    }
+
+    /// Micro-benchmark for lookups with names borrowed from a parsed packet.
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_find_by_name() {
+        use std::time::Instant;
+
+        let mut services = ServicesInner::new("test-hostname.local".into());
+        for i in 0..100 {
+            services
+                .register(service_data(&format!("instance-{}", i), 80))
+                .unwrap();
+        }
+
+        let packet = b"\x0binstance-42\x05_http\x04_tcp\x05local\x00";
+        let (name, _) = Name::scan(&packet[..], &packet[..]).unwrap();
+
+        const ITERATIONS: u32 = 1_000_000;
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            assert!(services.find_by_name(&name).is_some());
+        }
+        println!("find_by_name: {:?}/iter", start.elapsed() / ITERATIONS);
     }
 }
@@ -0,0 +1,60 @@
+//! On-disk snapshot of the registered services, so a restarted process can
+//! come back with the same identity.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Snapshots older than this are ignored when restoring.
+pub const SNAPSHOT_MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// The service table and hostname of a `Responder` at some point in time.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub hostname: String,
+    /// Seconds since the unix epoch
+    pub saved_at: u64,
+    pub services: Vec<ServiceSnapshot>,
+}
+
+/// A single registered service within a `Snapshot`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ServiceSnapshot {
+    pub id: usize,
+    pub typ: String,
+    pub name: String,
+    pub port: u16,
+    pub txt: Vec<u8>,
+}
+
+impl Snapshot {
+    pub(crate) fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    /// How long ago the snapshot was taken.
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(Self::now().saturating_sub(self.saved_at))
+    }
+
+    /// Write the snapshot as JSON. The file is replaced atomically so a crash
+    /// while saving leaves the previous snapshot intact.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let json = serde_json::to_vec(self).map_err(io::Error::from)?;
+        fs::write(&tmp, json)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Read a snapshot written by [`save`](Self::save).
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Snapshot> {
+        let json = fs::read(path)?;
+        serde_json::from_slice(&json).map_err(io::Error::from)
+    }
+}
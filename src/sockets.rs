@@ -0,0 +1,179 @@
+//! Sockets bound ahead of the rest of a responder, so that a process can give up the
+//! privileges binding needed before it starts answering anything.
+
+use crate::address_family::{AddressFamily, Inet, Inet6};
+use crate::config::ResponderConfig;
+use crate::error::Error;
+use crate::netns::Interfaces;
+use log::warn;
+use std::fmt;
+use std::net::UdpSocket;
+use std::sync::Arc;
+
+/// The mDNS sockets of a responder, bound and joined to the multicast groups by
+/// [`ResponderBuilder::prepare_sockets`](crate::ResponderBuilder::prepare_sockets)
+/// but not yet driven by anything.
+///
+/// The port 5353 isn't privileged, yet binding it may still need more than the rest
+/// of a responder does: entering a
+/// [network namespace](crate::ResponderBuilder::network_namespace) takes
+/// `CAP_SYS_ADMIN`, and sandboxes such as seccomp filters or `pledge` may forbid
+/// creating sockets at all after setup. Preparing the sockets first lets a process
+/// drop those capabilities, or switch to an unprivileged user, before the responder
+/// is built from them:
+///
+/// ```no_run
+/// # #[cfg(feature = "responder")]
+/// use libmdns::ResponderBuilder;
+///
+/// # use std::io;
+/// # #[cfg(feature = "responder")]
+/// # fn main() -> io::Result<()> {
+/// let builder = ResponderBuilder::new().network_namespace("/var/run/netns/media");
+/// let sockets = builder.prepare_sockets()?;
+/// // drop privileges here, e.g. with setuid or capset
+/// let responder = builder.spawn_thread_with_sockets(sockets)?;
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "responder"))]
+/// # fn main() {}
+/// ```
+///
+/// [`Responder::replace`](crate::Responder::replace) binds sockets again, so a
+/// responder replaced after privileges are given up needs sockets prepared for it
+/// too, see [`Responder::replace_with_sockets`](crate::Responder::replace_with_sockets).
+///
+/// Nothing is received until the responder is built, so the sockets should not be
+/// kept waiting for long: queries arriving meanwhile queue up in the socket buffers,
+/// and are answered late or dropped once those are full.
+pub struct PreparedSockets {
+    pub(crate) v4: Option<(UdpSocket, Vec<u32>)>,
+    pub(crate) v6: Option<(UdpSocket, Vec<u32>)>,
+    /// lists the interfaces of the network namespace, entered while it still can be
+    #[cfg_attr(not(any(feature = "responder", feature = "sync")), allow(dead_code))]
+    pub(crate) interfaces: Option<Arc<Interfaces>>,
+}
+
+impl PreparedSockets {
+    /// Bind the sockets of the address families `config` enables. Like starting a
+    /// responder, only failing for IPv6 if IPv4 is disabled or fails as well.
+    pub(crate) fn bind(config: &ResponderConfig) -> Result<PreparedSockets, Error> {
+        config.check_address_families()?;
+        let listed = match config.network_namespace {
+            Some(ref netns) => Some(Interfaces::start(netns)?),
+            None => None,
+        };
+        let netns = config.network_namespace.as_ref();
+        let (interfaces, allowed) = (&config.interfaces, config.join_allow_list());
+        let v4 = (!config.disable_ipv4).then(|| Inet::bind_in(netns, interfaces, allowed));
+        let v6 = (!config.disable_ipv6).then(|| Inet6::bind_in(netns, interfaces, allowed));
+
+        let (v4, v6) = match (v4, v6) {
+            (Some(Ok(v4)), Some(Err(err))) => {
+                warn!("Failed to bind IPv6 socket: {:?}", err);
+                (Some(v4), None)
+            }
+            (Some(Err(err)), _) | (None, Some(Err(err))) => return Err(err),
+            (v4, v6) => (v4.transpose()?, v6.transpose()?),
+        };
+        Ok(PreparedSockets {
+            v4,
+            v6,
+            interfaces: listed,
+        })
+    }
+
+    /// Have `config` use these sockets: nothing is announced for a family without
+    /// one, and the interfaces of the namespace are listed by the thread entered to
+    /// bind them
+    #[cfg(any(feature = "responder", feature = "sync"))]
+    pub(crate) fn configure(&self, config: &mut ResponderConfig) {
+        config.disable_ipv4 = !self.has_ipv4();
+        config.disable_ipv6 = !self.has_ipv6();
+        if self.interfaces.is_some() {
+            config.netns_interfaces.clone_from(&self.interfaces);
+        }
+    }
+
+    /// Whether an IPv4 socket was bound
+    pub fn has_ipv4(&self) -> bool {
+        self.v4.is_some()
+    }
+
+    /// Whether an IPv6 socket was bound
+    pub fn has_ipv6(&self) -> bool {
+        self.v6.is_some()
+    }
+}
+
+impl fmt::Debug for PreparedSockets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PreparedSockets")
+            .field("v4", &self.v4.as_ref().map(|(socket, _)| socket))
+            .field("v6", &self.v6.as_ref().map(|(socket, _)| socket))
+            .finish()
+    }
+}
+
+// The test sees the socket being read through the metrics
+#[cfg(all(test, feature = "metrics-minimal", feature = "responder"))]
+mod tests {
+    use super::*;
+    use crate::ResponderBuilder;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_prepared_sockets() {
+        let builder = ResponderBuilder::new()
+            .enable_ipv4(false)
+            .enable_ipv6(false);
+        assert!(matches!(
+            builder.prepare_sockets(),
+            Err(Error::NoAddressFamily)
+        ));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let sockets = PreparedSockets {
+            v4: Some((socket, Vec::new())),
+            v6: None,
+            interfaces: None,
+        };
+        let responder = ResponderBuilder::new()
+            .hostname("test-host".into())
+            .spawn_thread_with_sockets(sockets)
+            .unwrap();
+
+        // Nothing is announced on the family without a socket
+        assert!(responder.config.disable_ipv6);
+
+        // The responder reads from the socket it was given
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let received = |responder: &crate::Responder, count| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while responder.metrics().packets_parse_failed < count {
+                assert!(Instant::now() < deadline, "packet not received");
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+        sender.send_to(&[0xff; 3], addr).unwrap();
+        received(&responder, 1);
+
+        // And a replacement from the sockets prepared for it
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let sockets = PreparedSockets {
+            v4: Some((socket, Vec::new())),
+            v6: None,
+            interfaces: None,
+        };
+        let builder = ResponderBuilder::new().hostname("test-host".into());
+        let responder = responder.replace_with_sockets(builder, sockets).unwrap();
+        assert!(responder.config.disable_ipv6);
+        sender.send_to(&[0xff; 3], addr).unwrap();
+        received(&responder, 2);
+    }
+}
@@ -0,0 +1,305 @@
+//! Storage of the registered services, indexed by id, instance name and service type.
+
+use crate::dns_parser::{Canonical, NameKey};
+use crate::error::Error;
+use crate::services::ServiceData;
+use multimap::MultiMap;
+use std::collections::{BTreeMap, HashMap};
+
+/// How a responder stores its registered services, set with
+/// [`ResponderBuilder::service_storage`](crate::ResponderBuilder::service_storage).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum ServiceStorage {
+    /// Hash maps, the fastest choice for many services.
+    #[default]
+    Hashed,
+    /// Sorted maps, so services are always listed in the order of their names,
+    /// e.g. in answers to browse queries.
+    Ordered,
+    /// Room for at most `capacity` services, allocated up front and never grown.
+    /// Lookups search through every service, which is fine for the handful a
+    /// small device advertises.
+    #[cfg(feature = "fixed-store")]
+    Fixed { capacity: usize },
+}
+
+impl ServiceStorage {
+    pub(crate) fn create(self) -> Box<dyn ServicesStore> {
+        match self {
+            ServiceStorage::Hashed => Box::<HashStore>::default(),
+            ServiceStorage::Ordered => Box::<OrderedStore>::default(),
+            #[cfg(feature = "fixed-store")]
+            ServiceStorage::Fixed { capacity } => Box::new(FixedStore::new(capacity)),
+        }
+    }
+}
+
+/// The indexes behind `ServicesInner`. Ids are unique, and so are instance names,
+/// which `ServicesInner` checks before inserting.
+pub trait ServicesStore: Send + Sync {
+    /// Fails with [`Error::StoreFull`] if there is no room for another service
+    fn insert(&mut self, id: usize, svc: ServiceData) -> Result<(), Error>;
+
+    fn remove(&mut self, id: usize) -> Option<ServiceData>;
+
+    fn get(&self, id: usize) -> Option<&ServiceData>;
+
+    /// The id of the service with instance name `name`
+    fn id_by_name(&self, name: &dyn Canonical) -> Option<usize>;
+
+    /// All services of type `typ`
+    fn by_type<'a>(&'a self, typ: &'a dyn Canonical) -> ServiceIter<'a>;
+
+    fn iter(&self) -> ServiceIter<'_>;
+}
+
+pub type ServiceIter<'a> = Box<dyn Iterator<Item = &'a ServiceData> + 'a>;
+
+/// The default store, keyed by hash maps
+#[derive(Default)]
+pub struct HashStore {
+    /// main index
+    by_id: HashMap<usize, ServiceData>,
+    /// maps to id
+    by_type: MultiMap<NameKey, usize>,
+    /// maps to id
+    by_name: HashMap<NameKey, usize>,
+}
+
+impl ServicesStore for HashStore {
+    fn insert(&mut self, id: usize, svc: ServiceData) -> Result<(), Error> {
+        self.by_type.insert(NameKey::new(&svc.typ), id);
+        self.by_name.insert(NameKey::new(&svc.name), id);
+        self.by_id.insert(id, svc);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: usize) -> Option<ServiceData> {
+        let svc = self.by_id.remove(&id)?;
+
+        if let Some(entries) = self.by_type.get_vec_mut(&svc.typ as &dyn Canonical) {
+            entries.retain(|&e| e != id);
+        }
+        let named = self.by_name.remove(&svc.name as &dyn Canonical);
+        assert_eq!(named, Some(id), "unknown/wrong service for id {}", id);
+
+        Some(svc)
+    }
+
+    fn get(&self, id: usize) -> Option<&ServiceData> {
+        self.by_id.get(&id)
+    }
+
+    fn id_by_name(&self, name: &dyn Canonical) -> Option<usize> {
+        self.by_name.get(name).copied()
+    }
+
+    fn by_type<'a>(&'a self, typ: &'a dyn Canonical) -> ServiceIter<'a> {
+        let ids = self.by_type.get_vec(typ).map(|ids| ids.iter());
+        Box::new(
+            ids.into_iter()
+                .flatten()
+                .map(move |id| self.by_id.get(id).expect("missing service")),
+        )
+    }
+
+    fn iter(&self) -> ServiceIter<'_> {
+        Box::new(self.by_id.values())
+    }
+}
+
+/// Keeps services sorted by instance name
+#[derive(Default)]
+pub struct OrderedStore {
+    by_id: BTreeMap<usize, ServiceData>,
+    /// maps to id
+    by_name: BTreeMap<NameKey, usize>,
+    /// maps to the ids of the type, by instance name
+    by_type: BTreeMap<NameKey, BTreeMap<NameKey, usize>>,
+}
+
+impl ServicesStore for OrderedStore {
+    fn insert(&mut self, id: usize, svc: ServiceData) -> Result<(), Error> {
+        self.by_type
+            .entry(NameKey::new(&svc.typ))
+            .or_default()
+            .insert(NameKey::new(&svc.name), id);
+        self.by_name.insert(NameKey::new(&svc.name), id);
+        self.by_id.insert(id, svc);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: usize) -> Option<ServiceData> {
+        let svc = self.by_id.remove(&id)?;
+
+        if let Some(entries) = self.by_type.get_mut(&svc.typ as &dyn Canonical) {
+            entries.remove(&svc.name as &dyn Canonical);
+            if entries.is_empty() {
+                self.by_type.remove(&svc.typ as &dyn Canonical);
+            }
+        }
+        let named = self.by_name.remove(&svc.name as &dyn Canonical);
+        assert_eq!(named, Some(id), "unknown/wrong service for id {}", id);
+
+        Some(svc)
+    }
+
+    fn get(&self, id: usize) -> Option<&ServiceData> {
+        self.by_id.get(&id)
+    }
+
+    fn id_by_name(&self, name: &dyn Canonical) -> Option<usize> {
+        self.by_name.get(name).copied()
+    }
+
+    fn by_type<'a>(&'a self, typ: &'a dyn Canonical) -> ServiceIter<'a> {
+        let ids = self.by_type.get(typ).map(BTreeMap::values);
+        Box::new(
+            ids.into_iter()
+                .flatten()
+                .map(move |id| self.by_id.get(id).expect("missing service")),
+        )
+    }
+
+    fn iter(&self) -> ServiceIter<'_> {
+        Box::new(
+            self.by_name
+                .values()
+                .map(move |id| self.by_id.get(id).expect("missing service")),
+        )
+    }
+}
+
+/// Services in a list which is allocated once, searched instead of indexed
+#[cfg(feature = "fixed-store")]
+pub struct FixedStore {
+    services: Vec<(usize, ServiceData)>,
+}
+
+#[cfg(feature = "fixed-store")]
+impl FixedStore {
+    pub fn new(capacity: usize) -> FixedStore {
+        FixedStore {
+            services: Vec::with_capacity(capacity),
+        }
+    }
+}
+
+#[cfg(feature = "fixed-store")]
+impl ServicesStore for FixedStore {
+    fn insert(&mut self, id: usize, svc: ServiceData) -> Result<(), Error> {
+        if self.services.len() == self.services.capacity() {
+            return Err(Error::StoreFull(self.services.capacity()));
+        }
+        self.services.push((id, svc));
+        Ok(())
+    }
+
+    fn remove(&mut self, id: usize) -> Option<ServiceData> {
+        let index = self.services.iter().position(|&(other, _)| other == id)?;
+        Some(self.services.swap_remove(index).1)
+    }
+
+    fn get(&self, id: usize) -> Option<&ServiceData> {
+        self.services
+            .iter()
+            .find(|&&(other, _)| other == id)
+            .map(|(_, svc)| svc)
+    }
+
+    fn id_by_name(&self, name: &dyn Canonical) -> Option<usize> {
+        self.services
+            .iter()
+            .find(|(_, svc)| &svc.name as &dyn Canonical == name)
+            .map(|&(id, _)| id)
+    }
+
+    fn by_type<'a>(&'a self, typ: &'a dyn Canonical) -> ServiceIter<'a> {
+        Box::new(
+            self.services
+                .iter()
+                .filter(move |(_, svc)| &svc.typ as &dyn Canonical == typ)
+                .map(|(_, svc)| svc),
+        )
+    }
+
+    fn iter(&self) -> ServiceIter<'_> {
+        Box::new(self.services.iter().map(|(_, svc)| svc))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_parser::Name;
+    use crate::txt::TxtRecord;
+
+    fn check_store(mut store: Box<dyn ServicesStore>) {
+        let names = ["charlie", "alpha", "bravo"];
+        for (id, name) in names.iter().enumerate() {
+            store
+                .insert(
+                    id,
+                    ServiceData::new("_http._tcp", name, 80, TxtRecord::new()),
+                )
+                .unwrap();
+        }
+        store
+            .insert(
+                3,
+                ServiceData::new("_ipp._tcp", "delta", 631, TxtRecord::new()),
+            )
+            .unwrap();
+
+        let typ = Name::from_str("_HTTP._tcp.local").unwrap();
+        assert_eq!(store.by_type(&typ).count(), 3);
+        let name = Name::from_str("Bravo._http._tcp.local").unwrap();
+        assert_eq!(store.id_by_name(&name), Some(2));
+
+        assert_eq!(store.remove(2).unwrap().instance(), "bravo");
+        assert!(store.remove(2).is_none());
+        assert_eq!(store.id_by_name(&name), None);
+        assert_eq!(store.by_type(&typ).count(), 2);
+        assert_eq!(store.iter().count(), 3);
+    }
+
+    #[test]
+    fn test_hash_store() {
+        check_store(ServiceStorage::Hashed.create());
+    }
+
+    #[test]
+    fn test_ordered_store() {
+        let store = ServiceStorage::Ordered.create();
+        check_store(store);
+
+        let mut store = ServiceStorage::Ordered.create();
+        for (id, name) in ["zed", "Alpha", "bravo"].iter().enumerate() {
+            store
+                .insert(
+                    id,
+                    ServiceData::new("_http._tcp", name, 80, TxtRecord::new()),
+                )
+                .unwrap();
+        }
+        let typ = Name::from_str("_http._tcp.local").unwrap();
+        let listed: Vec<String> = store.by_type(&typ).map(|svc| svc.instance()).collect();
+        assert_eq!(listed, ["Alpha", "bravo", "zed"]);
+    }
+
+    #[cfg(feature = "fixed-store")]
+    #[test]
+    fn test_fixed_store() {
+        check_store(ServiceStorage::Fixed { capacity: 4 }.create());
+
+        let mut store = ServiceStorage::Fixed { capacity: 1 }.create();
+        let svc = |name| ServiceData::new("_http._tcp", name, 80, TxtRecord::new());
+        store.insert(0, svc("alpha")).unwrap();
+        assert!(matches!(
+            store.insert(1, svc("bravo")),
+            Err(Error::StoreFull(1))
+        ));
+        assert_eq!(store.iter().count(), 1);
+    }
+}
@@ -0,0 +1,338 @@
+//! A responder driven by a plain blocking loop, for programs without an async runtime.
+
+use log::{debug, error, trace, warn};
+use socket2::SockRef;
+use std::collections::VecDeque;
+use std::io::ErrorKind::WouldBlock;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::address_family::destinations;
+use crate::backoff::SendBackoff;
+use crate::config::{NetworkProfile, ResponderConfig};
+use crate::engine::{Engine, ServiceId};
+use crate::error::Error;
+use crate::host::HostData;
+#[cfg(feature = "metrics-minimal")]
+use crate::metrics::Metrics;
+use crate::reflector::{self, Reflector, Via};
+use crate::service_builder::ServiceBuilder;
+use crate::sockets::PreparedSockets;
+use crate::txt::TxtRecord;
+
+/// How long to sleep between checks of the sockets when nothing was received.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A `Responder` which does not need an async runtime.
+///
+/// Nothing happens unless [`poll`](SyncResponder::poll) or [`run`](SyncResponder::run)
+/// is called. It uses plain `std::net` sockets and starts no runtime, and with
+/// `default-features = false, features = ["sync"]` tokio isn't built at all.
+///
+/// # Example
+/// ```no_run
+/// use libmdns::ResponderBuilder;
+///
+/// # use std::io;
+/// # fn main() -> io::Result<()> {
+/// let mut responder = ResponderBuilder::new().build_sync()?;
+/// let _svc = responder.register("_http._tcp", "my http server", 80, &["path=/"]);
+/// responder.run()
+/// # }
+/// ```
+pub struct SyncResponder {
+    engine: Engine,
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+    backoff: SendBackoff,
+    /// a packet to send again once the backoff allows
+    retry: Option<(Vec<u8>, SocketAddr)>,
+    /// interfaces to send a copy of each IPv6 multicast packet on
+    v6_scopes: Vec<u32>,
+    /// packets taken from the engine but not sent yet: copies of a packet for further
+    /// scopes, or one the socket wasn't ready for
+    pending: VecDeque<(Vec<u8>, SocketAddr)>,
+    reflector: Option<Arc<Reflector>>,
+    /// copies of received packets to multicast on other interfaces
+    reflected: VecDeque<(Vec<u8>, Via)>,
+}
+
+impl SyncResponder {
+    pub(crate) fn with_config(
+        mut config: ResponderConfig,
+        sockets: Option<PreparedSockets>,
+    ) -> Result<SyncResponder, Error> {
+        let sockets = match sockets {
+            Some(sockets) => sockets,
+            None => PreparedSockets::bind(&config)?,
+        };
+        sockets.configure(&mut config);
+        let v4 = sockets.v4.map(|(v4, _)| v4);
+        let (v6, v6_scopes) = match sockets.v6 {
+            Some((v6, scopes)) => (Some(v6), scopes),
+            None => (None, Vec::new()),
+        };
+
+        let engine = Engine::with_config(config)?;
+        Ok(SyncResponder {
+            backoff: engine.send_backoff(),
+            retry: None,
+            v6_scopes,
+            pending: VecDeque::new(),
+            reflector: engine.reflector(),
+            reflected: VecDeque::new(),
+            engine,
+            v4,
+            v6,
+        })
+    }
+
+    /// Register a service to be advertised. It is announced on the next call to `poll`.
+    pub fn register(
+        &mut self,
+        svc_type: &str,
+        svc_name: &str,
+        port: u16,
+        txt: &[&str],
+    ) -> ServiceId {
+        self.engine.register(svc_type, svc_name, port, txt)
+    }
+
+    /// Like [`register`](SyncResponder::register), with the TXT data given as a
+    /// [`TxtRecord`].
+    pub fn register_txt(
+        &mut self,
+        svc_type: &str,
+        svc_name: &str,
+        port: u16,
+        txt: TxtRecord,
+    ) -> ServiceId {
+        self.engine.register_txt(svc_type, svc_name, port, txt)
+    }
+
+    /// Register a service described by a [`ServiceBuilder`].
+    pub fn register_service(&mut self, service: ServiceBuilder) -> ServiceId {
+        self.engine.register_service(service)
+    }
+
+    /// Register a service, failing if its name is taken and it may not be renamed, or
+    /// if one of its names is too long for DNS.
+    pub fn try_register_service(&mut self, service: ServiceBuilder) -> Result<ServiceId, Error> {
+        self.engine.try_register_service(service)
+    }
+
+    /// The instance name a service is registered under, which differs from the
+    /// requested one if that was taken.
+    pub fn name(&self, id: ServiceId) -> String {
+        self.engine.name(id)
+    }
+
+    /// Stop advertising a service, sending a goodbye on the next call to `poll`.
+    pub fn unregister(&mut self, id: ServiceId) {
+        self.engine.unregister(id)
+    }
+
+    /// Switch how eagerly queries are answered.
+    pub fn set_network_profile(&mut self, profile: NetworkProfile) {
+        self.engine.set_network_profile(profile)
+    }
+
+    /// The source of the hostname and addresses this responder publishes.
+    pub fn host(&self) -> Arc<dyn HostData> {
+        self.engine.host()
+    }
+
+    /// Counters describing what the responder has been doing.
+    #[cfg(feature = "metrics-minimal")]
+    pub fn metrics(&self) -> Metrics {
+        self.engine.metrics()
+    }
+
+    /// Handle all packets waiting on the sockets, returns whether there were any.
+    fn recv_packets(&mut self) -> bool {
+        // Buffer size discussed in: https://github.com/librespot-org/libmdns/pull/40
+        let mut buf = [0u8; 65536];
+        let mut received = false;
+        for socket in self.v4.iter().chain(self.v6.iter()) {
+            loop {
+                match socket.recv_from(&mut buf) {
+                    Ok((len, addr)) => {
+                        received = true;
+                        self.engine.handle_packet(&buf[..len], addr);
+                        if let Some(ref reflector) = self.reflector {
+                            for via in reflector.reflect(addr) {
+                                if self.reflected.len() >= reflector::MAX_QUEUED {
+                                    debug!("dropping reflected packet from {:?}", addr);
+                                    break;
+                                }
+                                self.reflected.push_back((buf[..len].to_vec(), via));
+                            }
+                        }
+                    }
+                    Err(ref err) if err.kind() == WouldBlock => break,
+                    Err(err) => {
+                        error!("ResponderRecvPacket Error: {:?}", err);
+                        break;
+                    }
+                }
+            }
+        }
+        received
+    }
+
+    fn send_packets(&mut self) {
+        loop {
+            let now = Instant::now();
+            if self.backoff.paused_until(now).is_some() {
+                return;
+            }
+            let (response, addr) = match self.retry.take().or_else(|| self.pending.pop_front()) {
+                Some(packet) => packet,
+                None => match self.engine.poll_transmit() {
+                    Some((packet, addr)) => {
+                        for dest in destinations(addr, &self.v6_scopes) {
+                            self.pending.push_back((packet.clone(), dest));
+                        }
+                        continue;
+                    }
+                    None => match self.reflected.pop_front() {
+                        Some((packet, via)) => {
+                            if !self.send_reflected(packet, via) {
+                                return;
+                            }
+                            continue;
+                        }
+                        None => return,
+                    },
+                },
+            };
+            trace!("sending packet to {:?}", addr);
+
+            let socket = match addr {
+                SocketAddr::V4(_) => self.v4.as_ref(),
+                SocketAddr::V6(_) => self.v6.as_ref(),
+            };
+            let socket = socket.expect("packet queued for a disabled address family");
+            match socket.send_to(&response, addr) {
+                Ok(bytes_sent) if bytes_sent == response.len() => self.backoff.sent(),
+                Ok(_) => warn!("failed to send entire packet"),
+                Err(ref ioerr) if ioerr.kind() == WouldBlock => {
+                    self.pending.push_front((response, addr));
+                    return;
+                }
+                Err(err) => self.retry = self.backoff.failed(err, response, addr, now),
+            }
+        }
+    }
+
+    /// Send a reflected packet, best effort. Returns false if the socket isn't ready.
+    fn send_reflected(&mut self, packet: Vec<u8>, via: Via) -> bool {
+        let socket = match via {
+            Via::V4(_) => self.v4.as_ref(),
+            Via::V6(_) => self.v6.as_ref(),
+        };
+        let socket = match socket {
+            Some(socket) => socket,
+            None => return true,
+        };
+        let sent = via
+            .select(&SockRef::from(socket))
+            .and_then(|()| socket.send_to(&packet, via.destination()));
+        if let Err(err) = via.reset(&SockRef::from(socket)) {
+            warn!("failed to reset the multicast interface: {}", err);
+        }
+        match sent {
+            Ok(_) => true,
+            Err(ref err) if err.kind() == WouldBlock => {
+                self.reflected.push_front((packet, via));
+                false
+            }
+            Err(err) => {
+                debug!("failed to reflect packet via {:?}: {}", via, err);
+                true
+            }
+        }
+    }
+
+    /// Answer queries and send pending packets until `timeout` has passed.
+    pub fn poll(&mut self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let received = self.recv_packets();
+            self.send_packets();
+
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            if !received {
+                thread::sleep(POLL_INTERVAL.min(deadline - now));
+            }
+        }
+    }
+
+    /// Answer queries forever.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.poll(Duration::from_secs(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_parser::{self, Name, QueryClass, QueryType, RRData};
+    use crate::host::FixedHostData;
+    use crate::ResponderBuilder;
+
+    #[test]
+    fn test_register_and_answer() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let sockets = PreparedSockets {
+            v4: Some((socket, Vec::new())),
+            v6: None,
+            interfaces: None,
+        };
+        let host = FixedHostData::new("sync-host".into(), vec!["127.0.0.1".parse().unwrap()]);
+        let mut responder = ResponderBuilder::new()
+            .host_data(Arc::new(host))
+            .build_sync_with_sockets(sockets)
+            .unwrap();
+        let id = responder.register("_sync._tcp", "instance", 80, &[]);
+        assert_eq!(responder.name(id), "instance");
+
+        // A legacy querier, answered on its own port rather than the group
+        let querier = UdpSocket::bind("127.0.0.1:0").unwrap();
+        querier
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let query = dns_parser::Builder::new_query(7, false)
+            .add_question(
+                &Name::from_str("_sync._tcp.local").unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        let mut buf = [0u8; 9000];
+        for _ in 0..40 {
+            querier.send_to(&query, addr).unwrap();
+            responder.poll(Duration::from_millis(50));
+            if let Ok((len, _)) = querier.recv_from(&mut buf) {
+                let response = dns_parser::Packet::parse(&buf[..len]).unwrap();
+                assert_eq!(response.header.id, 7);
+                assert!(response
+                    .answers
+                    .iter()
+                    .any(|answer| matches!(answer.data, RRData::PTR(_))));
+                return;
+            }
+        }
+        panic!("not answered");
+    }
+}
@@ -0,0 +1,263 @@
+//! Responders without sockets of their own, for environments where the caller moves
+//! the packets: simulators, userspace network stacks, tunnels and tests.
+
+use socket2::SockRef;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use crate::transport::Link;
+
+/// Where a responder built with
+/// [`ResponderBuilder::build_with_sink`](crate::ResponderBuilder::build_with_sink)
+/// sends its packets. Packets for it are passed to
+/// [`Responder::feed_packet`](crate::Responder::feed_packet).
+///
+/// Implemented for closures taking the packet and its destination.
+pub trait PacketSink: Send + 'static {
+    /// Send `packet` to `to`: the mDNS group of its family on port 5353, or the
+    /// address of a querier asking for a unicast answer
+    fn send(&mut self, packet: &[u8], to: SocketAddr);
+}
+
+impl<F: FnMut(&[u8], SocketAddr) + Send + 'static> PacketSink for F {
+    fn send(&mut self, packet: &[u8], to: SocketAddr) {
+        self(packet, to)
+    }
+}
+
+/// The sink shared by the tasks of both address families
+pub(crate) type SharedSink = Arc<Mutex<dyn PacketSink>>;
+
+/// What the tasks of a responder without sockets send through, in place of a socket.
+/// Nothing is received through it: packets passed to `Responder::feed_packet` come
+/// in as commands.
+pub(crate) struct SinkLink(pub SharedSink);
+
+impl Link for SinkLink {
+    type Sleep = tokio::time::Sleep;
+
+    fn poll_recv_from(
+        &self,
+        _cx: &mut Context,
+        _buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        Poll::Pending
+    }
+
+    /// The sink never blocks
+    fn poll_send_to(
+        &self,
+        _cx: &mut Context,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).send(buf, addr);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn sleep_until(deadline: Instant) -> Self::Sleep {
+        tokio::time::sleep_until(deadline.into())
+    }
+
+    fn socket(&self) -> Option<SockRef<'_>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns_parser::{self, Canonical, Name, QueryClass, QueryType, RRData};
+    use crate::host::{FixedHostData, HostData};
+    use crate::{BrowseBuilder, Responder, ResponderBuilder};
+    use std::io;
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    type Sent = Arc<Mutex<Vec<(Vec<u8>, SocketAddr)>>>;
+
+    /// Wait for the task to send something, and take it
+    async fn take_sent(sent: &Sent) -> Vec<(Vec<u8>, SocketAddr)> {
+        for _ in 0..100 {
+            let packets = std::mem::take(&mut *sent.lock().unwrap());
+            if !packets.is_empty() {
+                return packets;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("nothing sent");
+    }
+
+    #[test]
+    fn test_tethered_responder() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let sent = Sent::default();
+            let sink = sent.clone();
+            let host = FixedHostData::new("tethered".into(), vec!["192.0.2.3".parse().unwrap()]);
+            let (responder, task) = ResponderBuilder::new()
+                .host_data(Arc::new(host))
+                .enable_ipv6(false)
+                .build_with_sink(move |packet: &[u8], to| {
+                    sink.lock().unwrap().push((packet.to_vec(), to))
+                })
+                .unwrap();
+            tokio::spawn(task);
+
+            let _svc = responder.register("_tethered._tcp".into(), "instance".into(), 80, &[]);
+            let (announcement, to) = take_sent(&sent).await.remove(0);
+            assert_eq!(to, "224.0.0.251:5353".parse().unwrap());
+            assert!(!dns_parser::Packet::parse(&announcement)
+                .unwrap()
+                .answers
+                .is_empty());
+
+            // Further announcements follow a second apart, leaving time to ask
+            sent.lock().unwrap().clear();
+            let query = dns_parser::Builder::new_query(0, false)
+                .add_question(
+                    &Name::from_str("_tethered._tcp.local").unwrap(),
+                    QueryType::PTR,
+                    QueryClass::IN,
+                )
+                .build()
+                .unwrap();
+            responder.feed_packet(&query, "[2001:db8::1]:5353".parse().unwrap());
+            responder.feed_packet(&query, "169.254.0.1:5353".parse().unwrap());
+            let (response, _) = take_sent(&sent).await.remove(0);
+            let response = dns_parser::Packet::parse(&response).unwrap();
+            assert!(response
+                .answers
+                .iter()
+                .any(|answer| matches!(answer.data, RRData::PTR(_))));
+        });
+    }
+
+    /// A simulated host, on a link that isn't that of any interface of this one
+    struct SimulatedHost;
+
+    impl HostData for SimulatedHost {
+        fn hostname(&self) -> io::Result<String> {
+            Ok("simulated".into())
+        }
+
+        fn addresses(&self) -> io::Result<Vec<IpAddr>> {
+            Ok(vec!["10.0.0.2".parse().unwrap()])
+        }
+    }
+
+    /// Ask for the PTR records of `_tethered._tcp` from `from`, a legacy querier, and
+    /// wait for the answer sent back to it
+    async fn ask(responder: &Responder, sent: &Sent, from: &str) {
+        let from: SocketAddr = from.parse().unwrap();
+        let query = dns_parser::Builder::new_query(0, false)
+            .add_question(
+                &Name::from_str("_tethered._tcp.local").unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap();
+        responder.feed_packet(&query, from);
+        for _ in 0..100 {
+            let packets = std::mem::take(&mut *sent.lock().unwrap());
+            let answered = packets.iter().any(|(packet, to)| {
+                let packet = dns_parser::Packet::parse(packet).unwrap();
+                *to == from
+                    && packet
+                        .answers
+                        .iter()
+                        .any(|answer| matches!(answer.data, RRData::PTR(_)))
+            });
+            if answered {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("not answered");
+    }
+
+    /// Queriers are on the simulated link, not on that of the host's interfaces, and
+    /// a replacement's task is left to the caller too
+    #[test]
+    fn test_simulated_link() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let sent = Sent::default();
+            let sink = sent.clone();
+            let (responder, task) = ResponderBuilder::new()
+                .host_data(Arc::new(SimulatedHost))
+                .enable_ipv6(false)
+                .build_with_sink(move |packet: &[u8], to| {
+                    sink.lock().unwrap().push((packet.to_vec(), to))
+                })
+                .unwrap();
+            tokio::spawn(task);
+            let _svc = responder.register("_tethered._tcp".into(), "instance".into(), 80, &[]);
+            take_sent(&sent).await;
+            ask(&responder, &sent, "10.0.0.5:40000").await;
+
+            let (responder, task) = responder
+                .replace_with_task(
+                    ResponderBuilder::new()
+                        .host_data(Arc::new(SimulatedHost))
+                        .enable_ipv6(false),
+                )
+                .unwrap();
+            tokio::spawn(task);
+            ask(&responder, &sent, "10.0.0.6:40000").await;
+        });
+    }
+
+    /// Browsing wakes a task with nothing else to do, to send the first query
+    #[test]
+    fn test_browse_wakes_task() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let sent = Sent::default();
+            let sink = sent.clone();
+            let host = FixedHostData::new("tethered".into(), vec!["192.0.2.3".parse().unwrap()]);
+            let (responder, task) = ResponderBuilder::new()
+                .host_data(Arc::new(host))
+                .enable_ipv6(false)
+                .build_with_sink(move |packet: &[u8], to| {
+                    sink.lock().unwrap().push((packet.to_vec(), to))
+                })
+                .unwrap();
+            tokio::spawn(task);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            sent.lock().unwrap().clear();
+
+            let _browse = responder.browse(BrowseBuilder::new("_http._tcp").unwrap());
+            let browsed = Name::from_str("_http._tcp.local").unwrap();
+            // Well before anything else the task would wake up for
+            for _ in 0..50 {
+                let packets = std::mem::take(&mut *sent.lock().unwrap());
+                let queried = packets.iter().any(|(packet, _)| {
+                    let packet = dns_parser::Packet::parse(packet).unwrap();
+                    packet.questions.iter().any(|question| {
+                        question.qtype == QueryType::PTR
+                            && &question.qname as &dyn Canonical == &browsed as &dyn Canonical
+                    })
+                });
+                if queried {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("no query sent");
+        });
+    }
+}
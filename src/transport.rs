@@ -0,0 +1,281 @@
+//! The sockets and timers a responder task is driven by, so that it can run on
+//! other executors than tokio.
+
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use socket2::SockRef;
+
+#[cfg(unix)]
+use std::os::fd::AsFd as AsSocket;
+#[cfg(windows)]
+use std::os::windows::io::AsSocket;
+
+/// The socket of one address family of a responder, registered with the reactor of
+/// the executor its task runs on, and that executor's timers.
+///
+/// Implemented for tokio's `UdpSocket`, which [`ResponderBuilder::build`] and the other
+/// finishers use, and with the `async-io` feature for `async_io::Async<UdpSocket>`,
+/// which suits async-std, smol and any other executor. Pass either to
+/// [`ResponderBuilder::build_with_transport`] and poll the returned task wherever
+/// convenient.
+///
+/// [`ResponderBuilder::build`]: crate::ResponderBuilder::build
+/// [`ResponderBuilder::build_with_transport`]: crate::ResponderBuilder::build_with_transport
+pub trait Transport: AsSocket + Send + Sized + Unpin + 'static {
+    /// A timer, as returned by [`sleep_until`](Transport::sleep_until)
+    type Sleep: Future<Output = ()> + Send;
+
+    /// Register a non-blocking socket, bound and joined to the mDNS group already.
+    /// Called within the task's executor where that matters, such as on a tokio
+    /// runtime.
+    fn from_std(socket: UdpSocket) -> io::Result<Self>;
+
+    /// Receive a packet into `buf`, returning its length and where it came from
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>>;
+
+    /// Send `buf` to `addr`, returning the bytes sent
+    fn poll_send_to(
+        &self,
+        cx: &mut Context,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> Poll<io::Result<usize>>;
+
+    /// A timer completing at `deadline`
+    fn sleep_until(deadline: Instant) -> Self::Sleep;
+}
+
+impl Transport for tokio::net::UdpSocket {
+    type Sleep = tokio::time::Sleep;
+
+    fn from_std(socket: UdpSocket) -> io::Result<Self> {
+        tokio::net::UdpSocket::from_std(socket)
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        let mut buf = tokio::io::ReadBuf::new(buf);
+        tokio::net::UdpSocket::poll_recv_from(self, cx, &mut buf)
+            .map_ok(|addr| (buf.filled().len(), addr))
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        tokio::net::UdpSocket::poll_send_to(self, cx, buf, addr)
+    }
+
+    fn sleep_until(deadline: Instant) -> Self::Sleep {
+        tokio::time::sleep_until(deadline.into())
+    }
+}
+
+#[cfg(feature = "async-io")]
+impl Transport for async_io::Async<UdpSocket> {
+    type Sleep = TimerSleep;
+
+    fn from_std(socket: UdpSocket) -> io::Result<Self> {
+        async_io::Async::new_nonblocking(socket)
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        loop {
+            match self.get_ref().recv_from(buf) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                received => return Poll::Ready(received),
+            }
+            futures_util::ready!(self.poll_readable(cx))?;
+        }
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            match self.get_ref().send_to(buf, addr) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                sent => return Poll::Ready(sent),
+            }
+            futures_util::ready!(self.poll_writable(cx))?;
+        }
+    }
+
+    fn sleep_until(deadline: Instant) -> Self::Sleep {
+        TimerSleep(async_io::Timer::at(deadline))
+    }
+}
+
+/// An `async_io::Timer` as a future of `()`
+#[cfg(feature = "async-io")]
+pub struct TimerSleep(async_io::Timer);
+
+#[cfg(feature = "async-io")]
+impl Future for TimerSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        Pin::new(&mut self.0).poll(cx).map(|_| ())
+    }
+}
+
+/// What the task of one address family sends and receives through: the socket of a
+/// [`Transport`], or the sink of a responder without sockets
+pub trait Link: Send + Unpin + 'static {
+    type Sleep: Future<Output = ()> + Send;
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>>;
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> Poll<io::Result<usize>>;
+
+    fn sleep_until(deadline: Instant) -> Self::Sleep;
+
+    /// The socket, to pick the interface reflected packets go out on
+    fn socket(&self) -> Option<SockRef<'_>>;
+}
+
+impl<T: Transport> Link for T {
+    type Sleep = T::Sleep;
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        Transport::poll_recv_from(self, cx, buf)
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context,
+        buf: &[u8],
+        addr: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        Transport::poll_send_to(self, cx, buf, addr)
+    }
+
+    fn sleep_until(deadline: Instant) -> Self::Sleep {
+        <T as Transport>::sleep_until(deadline)
+    }
+
+    fn socket(&self) -> Option<SockRef<'_>> {
+        Some(SockRef::from(self))
+    }
+}
+
+/// A timer of `T` which can be moved to another deadline
+pub(crate) struct Timer<T: Link> {
+    deadline: Instant,
+    sleep: Pin<Box<T::Sleep>>,
+}
+
+impl<T: Link> Timer<T> {
+    pub fn new(deadline: Instant) -> Timer<T> {
+        Timer {
+            deadline,
+            sleep: Box::pin(T::sleep_until(deadline)),
+        }
+    }
+
+    /// Complete at `deadline` instead
+    pub fn reset(&mut self, deadline: Instant) {
+        if deadline != self.deadline {
+            *self = Timer::new(deadline);
+        }
+    }
+
+    pub fn poll(&mut self, cx: &mut Context) -> Poll<()> {
+        self.sleep.as_mut().poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn bind<T: Transport>() -> T {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        T::from_std(socket).unwrap()
+    }
+
+    async fn round_trip<T: Transport>() {
+        let (sender, receiver) = (bind::<T>(), bind::<T>());
+        let to = receiver.local_addr();
+        let sent = std::future::poll_fn(|cx| sender.poll_send_to(cx, b"ping", to)).await;
+        assert_eq!(sent.unwrap(), 4);
+
+        let mut buf = [0; 16];
+        let (len, from) = std::future::poll_fn(|cx| receiver.poll_recv_from(cx, &mut buf))
+            .await
+            .unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from, sender.local_addr());
+
+        let deadline = Instant::now() + Duration::from_millis(20);
+        let mut timer = Timer::<T>::new(Instant::now() + Duration::from_secs(60));
+        timer.reset(deadline);
+        std::future::poll_fn(|cx| timer.poll(cx)).await;
+        assert!(Instant::now() >= deadline);
+    }
+
+    trait LocalAddr {
+        fn local_addr(&self) -> SocketAddr;
+    }
+
+    impl<T: AsSocket> LocalAddr for T {
+        fn local_addr(&self) -> SocketAddr {
+            socket2::SockRef::from(self)
+                .local_addr()
+                .unwrap()
+                .as_socket()
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn test_tokio_transport() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(round_trip::<tokio::net::UdpSocket>());
+    }
+
+    #[cfg(feature = "async-io")]
+    #[test]
+    fn test_async_io_transport() {
+        async_io::block_on(round_trip::<async_io::Async<UdpSocket>>());
+    }
+}
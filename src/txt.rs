@@ -0,0 +1,194 @@
+use thiserror::Error;
+
+/// Error adding an entry to a `TxtRecord`
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TxtError {
+    #[error("TXT key {0:?} is empty or contains '=' or non-printable characters")]
+    InvalidKey(String),
+    #[error("TXT key {0:?} is already present")]
+    DuplicateKey(String),
+    #[error("TXT entry for key {0:?} is longer than 255 bytes")]
+    EntryTooLong(String),
+    #[error("TXT record would exceed {0} bytes")]
+    TooLarge(usize),
+}
+
+/// Key/value data for a service's TXT record, as described in RFC 6763 section 6.
+///
+/// # Example
+/// ```
+/// use libmdns::TxtRecord;
+///
+/// # fn main() -> Result<(), libmdns::TxtError> {
+/// let mut txt = TxtRecord::new();
+/// txt.insert("path", "/")?.insert_bool("secure")?;
+/// assert!(txt.insert("PATH", "/other").is_err());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxtRecord {
+    data: Vec<u8>,
+    keys: Vec<String>,
+    max_size: usize,
+}
+
+impl Default for TxtRecord {
+    fn default() -> TxtRecord {
+        TxtRecord {
+            data: Vec::new(),
+            keys: Vec::new(),
+            max_size: TxtRecord::RECOMMENDED_MAX_SIZE,
+        }
+    }
+}
+
+impl TxtRecord {
+    /// Upper bound on the total size recommended by RFC 6763 section 6.2, so that
+    /// responses fit in a single packet.
+    pub const RECOMMENDED_MAX_SIZE: usize = 1300;
+
+    pub fn new() -> TxtRecord {
+        TxtRecord::default()
+    }
+
+    /// Change the total size limit from `RECOMMENDED_MAX_SIZE`.
+    pub fn set_max_size(&mut self, max_size: usize) -> &mut Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Add a `key=value` entry.
+    pub fn insert<V: AsRef<[u8]>>(&mut self, key: &str, value: V) -> Result<&mut Self, TxtError> {
+        self.push(key, Some(value.as_ref()))
+    }
+
+    /// Add an entry with no `=`, meaning the boolean attribute `key` is present.
+    pub fn insert_bool(&mut self, key: &str) -> Result<&mut Self, TxtError> {
+        self.push(key, None)
+    }
+
+    fn push(&mut self, key: &str, value: Option<&[u8]>) -> Result<&mut Self, TxtError> {
+        if key.is_empty() || !key.bytes().all(|b| (0x20..=0x7e).contains(&b) && b != b'=') {
+            return Err(TxtError::InvalidKey(key.to_owned()));
+        }
+        // Keys are case insensitive
+        if self.keys.iter().any(|k| k.eq_ignore_ascii_case(key)) {
+            return Err(TxtError::DuplicateKey(key.to_owned()));
+        }
+
+        let len = key.len() + value.map_or(0, |value| value.len() + 1);
+        if len > 255 {
+            return Err(TxtError::EntryTooLong(key.to_owned()));
+        }
+        if self.data.len() + len + 1 > self.max_size {
+            return Err(TxtError::TooLarge(self.max_size));
+        }
+
+        self.data.push(len as u8);
+        self.data.extend_from_slice(key.as_bytes());
+        if let Some(value) = value {
+            self.data.push(b'=');
+            self.data.extend_from_slice(value);
+        }
+        self.keys.push(key.to_owned());
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The TXT RDATA. An empty record is a single empty string, per RFC 6763 section 6.1.
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        if self.data.is_empty() {
+            vec![0]
+        } else {
+            self.data
+        }
+    }
+}
+
+/// Raw entries are used as given, without checking keys.
+///
+/// # Panics
+///
+/// If an entry is longer than 255 bytes.
+impl<'a> From<&'a [&'a str]> for TxtRecord {
+    fn from(entries: &'a [&'a str]) -> TxtRecord {
+        let data = entries
+            .iter()
+            .flat_map(|entry| {
+                let entry = entry.as_bytes();
+                if entry.len() > 255 {
+                    panic!("{:?} is too long for a TXT record", entry);
+                }
+                std::iter::once(entry.len() as u8).chain(entry.iter().cloned())
+            })
+            .collect();
+
+        TxtRecord {
+            data,
+            ..TxtRecord::default()
+        }
+    }
+}
+
+impl<'a, const N: usize> From<&'a [&'a str; N]> for TxtRecord {
+    fn from(entries: &'a [&'a str; N]) -> TxtRecord {
+        TxtRecord::from(&entries[..])
+    }
+}
+
+impl<'a> From<&'a mut TxtRecord> for TxtRecord {
+    fn from(txt: &'a mut TxtRecord) -> TxtRecord {
+        txt.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        let mut txt = TxtRecord::new();
+        txt.insert("path", "/")
+            .unwrap()
+            .insert_bool("secure")
+            .unwrap();
+        assert_eq!(txt.into_bytes(), b"\x06path=/\x06secure");
+        assert_eq!(TxtRecord::new().into_bytes(), b"\x00");
+        assert_eq!(
+            TxtRecord::from(&["path=/"]).into_bytes(),
+            TxtRecord::from(&["path=/"][..]).into_bytes()
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut txt = TxtRecord::new();
+        txt.insert("path", "/").unwrap();
+        assert_eq!(
+            txt.insert("Path", "/"),
+            Err(TxtError::DuplicateKey("Path".to_owned()))
+        );
+        assert_eq!(
+            txt.insert("", "x"),
+            Err(TxtError::InvalidKey("".to_owned()))
+        );
+        assert_eq!(
+            txt.insert("a=b", "x"),
+            Err(TxtError::InvalidKey("a=b".to_owned()))
+        );
+        assert_eq!(
+            txt.insert("long", [0u8; 251]),
+            Err(TxtError::EntryTooLong("long".to_owned()))
+        );
+
+        let mut small = TxtRecord::new();
+        small.set_max_size(10);
+        small.insert("a", "1234").unwrap();
+        assert_eq!(small.insert("b", "1"), Err(TxtError::TooLarge(10)));
+    }
+}
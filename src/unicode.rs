@@ -0,0 +1,41 @@
+//! Unicode normalization of instance names. Some platforms, notably macOS, send
+//! names in decomposed form (NFD) while others register them composed (NFC), so
+//! names are registered and looked up in NFC.
+
+use crate::dns_parser::Name;
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+/// `name` in NFC, or `None` if it already is
+pub fn nfc(name: &str) -> Option<String> {
+    if name.is_ascii() || is_nfc_quick(name.chars()) == IsNormalized::Yes {
+        return None;
+    }
+    let normalized: String = name.nfc().collect();
+    (normalized != name).then_some(normalized)
+}
+
+/// `name` in NFC, or `None` if it already is
+pub fn nfc_name(name: &Name) -> Option<Name<'static>> {
+    if name.labels().all(|label| label.is_ascii()) {
+        return None;
+    }
+    nfc(&name.to_string()).map(|name| Name::from_str(name).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nfc() {
+        assert_eq!(nfc("Cafe\u{301}").as_deref(), Some("Caf\u{e9}"));
+        assert_eq!(nfc("Caf\u{e9}"), None);
+        assert_eq!(nfc("printer"), None);
+
+        let name = Name::from_str("Cafe\u{301}._http._tcp.local").unwrap();
+        assert_eq!(
+            nfc_name(&name).unwrap(),
+            Name::from_str("Caf\u{e9}._http._tcp.local").unwrap()
+        );
+    }
+}
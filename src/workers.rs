@@ -0,0 +1,220 @@
+//! Threads which parse queries and build their answers, for responders which get
+//! more queries than one task keeps up with. Sockets, announcements and records
+//! from other responders stay with the task.
+
+use crate::address_family::AddressFamily;
+use crate::dns_parser::Header;
+use crate::fsm::{Answer, Handler, Shared};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::thread;
+use tokio::sync::mpsc;
+
+/// How many queries may wait for each worker before the task answers them itself
+const QUEUE_PER_WORKER: usize = 64;
+
+struct Job {
+    packet: Vec<u8>,
+    addr: SocketAddr,
+}
+
+/// Workers for one address family. They stop once the pool is dropped.
+pub struct Pool {
+    jobs: SyncSender<Job>,
+    answers: mpsc::UnboundedReceiver<Answer>,
+}
+
+impl Pool {
+    pub fn new<AF: AddressFamily>(shared: &Shared, workers: usize) -> io::Result<Pool> {
+        let (jobs, queue) = sync_channel(workers * QUEUE_PER_WORKER);
+        let (answered, answers) = mpsc::unbounded_channel();
+        let queue = Arc::new(Mutex::new(queue));
+
+        for i in 0..workers {
+            let handler = Handler::<AF>::answer_only(shared);
+            let queue = queue.clone();
+            let answered = answered.clone();
+            thread::Builder::new()
+                .name(format!("mdns-worker-{}", i))
+                .spawn(move || work(handler, &queue, &answered))?;
+        }
+
+        Ok(Pool { jobs, answers })
+    }
+
+    /// Hand `packet` to a worker if it is a query and one can take it soon,
+    /// returns whether it was.
+    pub fn try_submit(&self, packet: &[u8], addr: SocketAddr) -> bool {
+        if !Header::parse(packet).is_ok_and(|header| header.query) {
+            return false;
+        }
+        let job = Job {
+            packet: packet.to_vec(),
+            addr,
+        };
+        match self.jobs.try_send(job) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    pub fn poll_answer(&mut self, cx: &mut Context) -> Poll<Option<Answer>> {
+        self.answers.poll_recv(cx)
+    }
+}
+
+fn work<AF: AddressFamily>(
+    mut handler: Handler<AF>,
+    queue: &Mutex<Receiver<Job>>,
+    answered: &mpsc::UnboundedSender<Answer>,
+) {
+    loop {
+        // Only held while waiting, the answer is built without it
+        let job = match queue.lock().unwrap_or_else(PoisonError::into_inner).recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        handler.handle_packet(&job.packet, job.addr);
+        while let Some(answer) = handler.pop_response() {
+            if answered.send(answer).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_family::Inet;
+    use crate::config::{NetworkProfile, ResponderConfig};
+    use crate::dns_parser::{Builder, Name, QueryClass, QueryType};
+    use crate::host::FixedHostData;
+    use crate::service_builder::ServiceBuilder;
+    use crate::services::ServicesInner;
+    use std::sync::RwLock;
+    use std::time::Instant;
+
+    fn shared() -> Shared {
+        let services = Arc::new(RwLock::new(ServicesInner::new(
+            "test-hostname.local".into(),
+        )));
+        for i in 0..5u16 {
+            let svc = ServiceBuilder::new("_http._tcp", &format!("instance-{}", i), 8000 + i)
+                .txt(&["path=/"])
+                .build()
+                .unwrap();
+            services.write().unwrap().register(svc).unwrap();
+        }
+        let host = FixedHostData::new("test-hostname".into(), vec!["192.0.2.9".parse().unwrap()]);
+        let config = ResponderConfig {
+            host_data: Some(Arc::new(host)),
+            // Duplicate detection would otherwise remember every query of a benchmark
+            network_profile: NetworkProfile::Lossy,
+            ..ResponderConfig::default()
+        };
+        Shared::new(&services, config)
+    }
+
+    fn browse(id: u16) -> Vec<u8> {
+        Builder::new_query(id, false)
+            .add_question(
+                &Name::from_str("_http._tcp.local").unwrap(),
+                QueryType::PTR,
+                QueryClass::IN,
+            )
+            .build()
+            .unwrap()
+    }
+
+    /// Submit `count` queries, returns how many answers came back
+    fn run(pool: &mut Pool, count: u16) -> usize {
+        let source = "169.254.0.1:5353".parse().unwrap();
+        let mut answers = 0;
+        for id in 0..count {
+            while !pool.try_submit(&browse(id), source) {
+                answers += usize::from(pool.answers.blocking_recv().is_some());
+            }
+        }
+        // Each browse is answered over multicast and, as lossy, unicast too
+        while answers < 2 * count as usize {
+            answers += usize::from(pool.answers.blocking_recv().is_some());
+        }
+        answers
+    }
+
+    #[test]
+    fn test_pool() {
+        let shared = shared();
+        let mut pool = Pool::new::<Inet>(&shared, 2).unwrap();
+        assert_eq!(run(&mut pool, 100), 200);
+        #[cfg(feature = "metrics-minimal")]
+        assert_eq!(shared.counters.metrics().packets_answered, 100);
+
+        // Responses from other responders are left to the task
+        let response = Builder::new_response(0, false, true).build().unwrap();
+        assert!(!pool.try_submit(&response, "169.254.0.1:5353".parse().unwrap()));
+    }
+
+    #[cfg(feature = "metrics-minimal")]
+    #[test]
+    fn test_pool_shares_state() {
+        let shared = shared();
+        // Switched on the task, followed by the workers
+        Handler::<Inet>::new(&shared).set_network_profile(NetworkProfile::Normal);
+        let mut pool = Pool::new::<Inet>(&shared, 2).unwrap();
+
+        // Whichever workers get them, the copies of a query are answered once
+        let source = "169.254.0.1:5353".parse().unwrap();
+        assert!(pool.try_submit(&browse(1), source));
+        assert!(pool.try_submit(&browse(1), source));
+        let handled = || {
+            let metrics = shared.counters.metrics();
+            (metrics.packets_answered, metrics.duplicate_queries)
+        };
+        let start = Instant::now();
+        while handled().0 + handled().1 < 2 {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5));
+            thread::yield_now();
+        }
+        assert_eq!(handled(), (1, 1));
+        assert!(pool.answers.blocking_recv().is_some());
+        assert!(pool.answers.try_recv().is_err());
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture bench_`.
+    #[test]
+    #[ignore]
+    fn bench_worker_pool() {
+        const QUERIES: u16 = 50_000;
+
+        let shared = shared();
+        let mut handler = Handler::<Inet>::new(&shared);
+        let source = "169.254.0.1:5353".parse().unwrap();
+        let start = Instant::now();
+        for id in 0..QUERIES {
+            handler.handle_packet(&browse(id), source);
+            while handler.pop_response().is_some() {}
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "on the task: {:.0} queries/s",
+            f64::from(QUERIES) / elapsed.as_secs_f64()
+        );
+
+        for workers in [1, 2, 4, 8] {
+            let mut pool = Pool::new::<Inet>(&shared, workers).unwrap();
+            let start = Instant::now();
+            run(&mut pool, QUERIES);
+            let elapsed = start.elapsed();
+            println!(
+                "{} workers: {:.0} queries/s",
+                workers,
+                f64::from(QUERIES) / elapsed.as_secs_f64()
+            );
+        }
+    }
+}